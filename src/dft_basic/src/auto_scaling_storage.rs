@@ -1,23 +1,72 @@
+use std::cell::RefCell;
 use std::collections::VecDeque;
 use std::ops::{Add, Sub};
 use std::sync::Arc;
+use std::time::Duration;
 
 use candid::encode_args;
+use ic_cdk::api;
 use ic_cdk::export::{candid::Nat, Principal};
+use ic_cdk_timers::TimerId;
 use log::{debug, error, info};
 use num_bigint::BigUint;
 use num_traits::ops::checked::CheckedSub;
+use num_traits::ToPrimitive;
 
 use dft_types::constants::*;
 use dft_types::*;
+use dft_utils::sha256::compute_hash;
 
 use crate::canister_api::*;
-use crate::service::blockchain_service;
+use crate::service::{basic_service, blockchain_service, management_service};
 
 // Auto-scaling tx  storage canister wasm package bytes
 const AUTO_SCALING_STORAGE_CANISTER_WASM: &[u8] =
     std::include_bytes!("../../target/wasm32-unknown-unknown/release/dft_tx_storage.wasm");
 
+thread_local! {
+    static ARCHIVING_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+    static CYCLES_MONITOR_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+}
+
+// (Re)start the periodic archiving timer. Timers do not survive upgrades,
+// so this must be called from both init and post_upgrade.
+#[cfg_attr(coverage_nightly, no_coverage)]
+pub fn start_archiving_timer() {
+    let interval = Duration::from_secs(blockchain_service::archive_interval_seconds().max(1));
+    let timer_id = ic_cdk_timers::set_timer_interval(interval, || {
+        ic_cdk::spawn(async {
+            AutoScalingStorageService::new(api::id())
+                .exec_auto_scaling_strategy(api::time())
+                .await
+        })
+    });
+    ARCHIVING_TIMER.with(|t| {
+        if let Some(previous) = t.borrow_mut().replace(timer_id) {
+            ic_cdk_timers::clear_timer(previous);
+        }
+    });
+}
+
+// (Re)start the timer checking the cycles of the archive canisters,
+// like the archiving timer it must be started from init and post_upgrade.
+#[cfg_attr(coverage_nightly, no_coverage)]
+pub fn start_cycles_monitor_timer() {
+    let interval = Duration::from_secs(ARCHIVE_CYCLES_CHECK_INTERVAL_SECONDS);
+    let timer_id = ic_cdk_timers::set_timer_interval(interval, || {
+        ic_cdk::spawn(async {
+            AutoScalingStorageService::new(api::id())
+                .monitor_archive_cycles(api::time(), api::canister_balance())
+                .await
+        })
+    });
+    CYCLES_MONITOR_TIMER.with(|t| {
+        if let Some(previous) = t.borrow_mut().replace(timer_id) {
+            ic_cdk_timers::clear_timer(previous);
+        }
+    });
+}
+
 pub struct AutoScalingStorageService {
     pub token_id: Principal,
     pub ic_management: Arc<dyn IICManagementAPI>,
@@ -32,20 +81,8 @@ impl AutoScalingStorageService {
             dft_tx_storage: Arc::new(DFTTxStorageAPI::default()),
         }
     }
-    pub async fn exec_auto_scaling_strategy(&self) {
-        let blocks_to_archive = blockchain_service::get_blocks_for_archiving();
-
-        let archive_size_bytes = blocks_to_archive
-            .iter()
-            .fold(0, |acc, block| acc + block.size_bytes());
-        let max_msg_size = MAX_MESSAGE_SIZE_BYTES;
-        if archive_size_bytes > max_msg_size as usize {
-            error!(
-                "batch_mint exec_auto_scaling_strategy failed: {}",
-                DFTError::ExceedTheByteSizeLimitOfOneRequest.to_string()
-            );
-            return;
-        }
+    pub async fn exec_auto_scaling_strategy(&self, now: u64) {
+        let mut blocks_to_archive = blockchain_service::get_blocks_for_archiving();
 
         let num_blocks = blocks_to_archive.len();
 
@@ -54,42 +91,205 @@ impl AutoScalingStorageService {
         }
 
         // if lock failed, return, lock failed means the archiving is already in progress
-        if !blockchain_service::lock_for_archiving() {
+        // or the last attempt failed and the retry backoff has not elapsed yet
+        if !blockchain_service::lock_for_archiving(now) {
             return;
         }
 
-        if (self
-            .send_blocks_to_archive(blocks_to_archive, archive_size_bytes)
-            .await)
-            .is_ok()
+        let archive_size_bytes = blocks_to_archive
+            .iter()
+            .fold(0, |acc, block| acc + block.size_bytes());
+        let archive_options = basic_service::archive_options();
+        let max_msg_size = archive_options.max_message_size_bytes.unwrap();
+        if archive_size_bytes > max_msg_size as usize {
+            let msg = DFTError::ExceedTheByteSizeLimitOfOneRequest.to_string();
+            error!("exec_auto_scaling_strategy failed: {}", msg);
+            blockchain_service::record_archiving_result(now, Some(msg));
+            return;
+        }
+
+        // archives store blocks compressed unless it is turned off,
+        // their capacity is checked against the stored size
+        let stored_size_bytes = stored_blocks_size_bytes(
+            blocks_to_archive.make_contiguous(),
+            archive_options.compress_blocks.unwrap_or(true),
+        );
+        match self
+            .send_blocks_to_archive(blocks_to_archive, stored_size_bytes)
+            .await
         {
-            info!(
-                "Archive size: {} bytes,max_msg_size: {} bytes,total blocks: {}",
-                archive_size_bytes, max_msg_size, num_blocks
-            );
-            let last_storage_index = blockchain_service::last_storage_canister_index();
-            let archived_end_block_height = blockchain_service::archived_blocks_num()
-                .add(num_blocks)
-                .sub(1u32);
-
-            blockchain_service::update_scaling_storage_blocks_range(
-                last_storage_index,
-                archived_end_block_height,
-            );
-            blockchain_service::remove_archived_blocks(num_blocks);
+            Ok(_) => {
+                info!(
+                    "Archive size: {} bytes,stored size: {} bytes,max_msg_size: {} bytes,total blocks: {}",
+                    archive_size_bytes, stored_size_bytes, max_msg_size, num_blocks
+                );
+                let last_storage_index = blockchain_service::last_storage_canister_index();
+                let archived_end_block_height = blockchain_service::archived_blocks_num()
+                    .add(num_blocks)
+                    .sub(1u32);
+
+                blockchain_service::update_scaling_storage_blocks_range(
+                    last_storage_index,
+                    archived_end_block_height,
+                );
+                blockchain_service::remove_archived_blocks(num_blocks);
+                blockchain_service::record_archiving_result(now, None);
+            }
+            Err(e) => blockchain_service::record_archiving_result(now, Some(e.to_string())),
         };
 
         // Ensure unlock
         blockchain_service::unlock_after_archiving();
     }
 
+    // Check the cycles of each archive canister and top up the ones below the
+    // threshold, as long as the token keeps enough cycles to create a new archive.
+    pub async fn monitor_archive_cycles(&self, now: u64, token_cycles: u64) {
+        let (min_archive_cycles, archive_cycles_top_up) =
+            blockchain_service::archive_cycles_settings();
+        let mut available_cycles = token_cycles.saturating_sub(MIN_TOKEN_CYCLES_AFTER_TOP_UP);
+
+        for canister_id in blockchain_service::storage_canisters() {
+            let id_record = CanisterIdRecord { canister_id };
+            let cycles = match self.ic_management.canister_status(id_record.clone()).await {
+                Ok(status) => status.cycles.0.to_u128().unwrap_or(u128::MAX),
+                Err(msg) => {
+                    let msg = format!("check archive canister status failed. details:{}", msg);
+                    error!("{}", msg);
+                    blockchain_service::record_archive_cycles(canister_id, now, None, 0, Some(msg));
+                    continue;
+                }
+            };
+            if cycles >= min_archive_cycles as u128 {
+                blockchain_service::record_archive_cycles(canister_id, now, Some(cycles), 0, None);
+                continue;
+            }
+
+            let (topped_up_cycles, error) = if available_cycles < archive_cycles_top_up {
+                (
+                    0,
+                    Some("insufficient token cycles to top up the archive".to_string()),
+                )
+            } else {
+                match self
+                    .ic_management
+                    .deposit_cycles(id_record, archive_cycles_top_up)
+                    .await
+                {
+                    Ok(_) => {
+                        available_cycles -= archive_cycles_top_up;
+                        info!(
+                            "topped up archive canister {} with {} cycles",
+                            canister_id, archive_cycles_top_up
+                        );
+                        (archive_cycles_top_up, None)
+                    }
+                    Err(msg) => (
+                        0,
+                        Some(format!("top up archive canister failed. details:{}", msg)),
+                    ),
+                }
+            };
+            if let Some(msg) = &error {
+                error!("{}", msg);
+            }
+            blockchain_service::record_archive_cycles(
+                canister_id,
+                now,
+                Some(cycles),
+                topped_up_cycles,
+                error,
+            );
+        }
+    }
+
+    // Upgrade the archive canisters to the embedded storage wasm one at a time,
+    // the canisters after the first failure are not touched.
+    pub async fn upgrade_archives(
+        &self,
+        caller: &Principal,
+    ) -> CommonResult<Vec<ArchiveUpgradeInfo>> {
+        management_service::lock_for_upgrading_archives(caller)?;
+
+        let module_hash = compute_hash(AUTO_SCALING_STORAGE_CANISTER_WASM).to_vec();
+        let mut upgrade_failed = false;
+        let mut res = vec![];
+        for canister_id in blockchain_service::storage_canisters() {
+            let status = if upgrade_failed {
+                ArchiveUpgradeStatus::Skipped
+            } else {
+                match self.upgrade_archive(canister_id, &module_hash).await {
+                    Ok(status) => status,
+                    Err(reason) => {
+                        error!("upgrade archive {} failed: {}", canister_id, reason);
+                        upgrade_failed = true;
+                        ArchiveUpgradeStatus::Failed { reason }
+                    }
+                }
+            };
+            res.push(ArchiveUpgradeInfo {
+                canister_id,
+                status,
+            });
+        }
+
+        blockchain_service::unlock_after_upgrading_archives();
+        Ok(res)
+    }
+
+    async fn upgrade_archive(
+        &self,
+        canister_id: Principal,
+        module_hash: &[u8],
+    ) -> Result<ArchiveUpgradeStatus, String> {
+        let id_record = CanisterIdRecord { canister_id };
+        let status = self
+            .ic_management
+            .canister_status(id_record.clone())
+            .await
+            .map_err(|e| format!("check archive canister status failed. details:{}", e))?;
+        if status.module_hash.as_deref() == Some(module_hash) {
+            return Ok(ArchiveUpgradeStatus::UpToDate);
+        }
+
+        let upgrade_args =
+            encode_args(()).map_err(|e| format!("encode_args failed. details:{:?}", e))?;
+        self.ic_management
+            .canister_install(
+                &canister_id,
+                AUTO_SCALING_STORAGE_CANISTER_WASM.to_vec(),
+                upgrade_args,
+                InstallMode::Upgrade,
+            )
+            .await
+            .map_err(|e| format!("upgrade archive canister failed. details:{}", e))?;
+
+        // make sure the canister really runs the embedded wasm now
+        let status = self
+            .ic_management
+            .canister_status(id_record)
+            .await
+            .map_err(|e| format!("check archive canister status failed. details:{}", e))?;
+        if status.module_hash.as_deref() != Some(module_hash) {
+            return Err(
+                "the module hash does not match the embedded wasm after upgrade".to_string(),
+            );
+        }
+        debug!("upgrade archive canister {} success", canister_id);
+        Ok(ArchiveUpgradeStatus::Upgraded)
+    }
+
+    // `stored_size_bytes` is the size the blocks take once stored (compressed) by the archive
     async fn get_or_create_available_storage_id(
         &self,
-        archive_size_bytes: usize,
+        stored_size_bytes: usize,
     ) -> CommonResult<Principal> {
         let mut last_storage_id = blockchain_service::last_auto_scaling_storage_canister_id();
 
         let mut is_necessary_create_new_storage_canister = last_storage_id.is_none();
+        let node_max_memory_size_bytes = basic_service::archive_options()
+            .node_max_memory_size_bytes
+            .unwrap();
 
         // check storage remain size
         if last_storage_id.is_some() {
@@ -100,14 +300,14 @@ impl AutoScalingStorageService {
             match status {
                 Ok(res) => {
                     debug!(
-                        "current scaling storage used memory_size is {},max is {},available memory_size is {},archive_size_bytes is {}",
-                        res.memory_size, MAX_CANISTER_STORAGE_BYTES,
-                        BigUint::from(MAX_CANISTER_STORAGE_BYTES)
-                        .checked_sub(&res.memory_size.clone().0.add(archive_size_bytes))
-                        .map_or(BigUint::from(0u8), |x| x),
-                        archive_size_bytes
+                        "current scaling storage used memory_size is {},max is {},available memory_size is {},stored_size_bytes is {}",
+                        res.memory_size, node_max_memory_size_bytes,
+                        BigUint::from(node_max_memory_size_bytes)
+                        .checked_sub(&res.memory_size.clone().0.add(stored_size_bytes))
+                        .unwrap_or_default(),
+                        stored_size_bytes
                     );
-                    if MAX_CANISTER_STORAGE_BYTES <= res.memory_size + archive_size_bytes {
+                    if node_max_memory_size_bytes <= res.memory_size + stored_size_bytes {
                         debug!("is_necessary_create_new_storage_canister");
                         is_necessary_create_new_storage_canister = true;
                     } else {
@@ -129,13 +329,11 @@ impl AutoScalingStorageService {
                 blockchain_service::scaling_storage_block_height_offset().into();
 
             // avoid re-create storage canister when install code failed
-            if last_storage_id.is_some() {
-                self.install_storage_canister_and_append_to_storage_records(
-                    last_storage_id.unwrap(),
-                    token_id,
-                    block_height_offset,
-                )
-                .await?;
+            if let Some(canister_id) = last_storage_id {
+                blockchain_service::set_archiving_state(ArchivingState::Installing);
+                self.install_storage_canister(canister_id, token_id, block_height_offset)
+                    .await?;
+                blockchain_service::append_scaling_storage_canister(canister_id);
             } else {
                 let new_scaling_storage_canister_id = self
                     .create_new_scaling_storage_canister(token_id, block_height_offset)
@@ -151,8 +349,57 @@ impl AutoScalingStorageService {
         token_id: Principal,
         block_height_offset: Nat,
     ) -> CommonResult<Principal> {
+        let canister_id = self.create_storage_canister(token_id).await?;
+        blockchain_service::pre_append_scaling_storage_canister(canister_id);
+        debug!(
+            "token new storage canister id : {} , block height offset : {}",
+            canister_id,
+            block_height_offset.clone()
+        );
+        blockchain_service::set_archiving_state(ArchivingState::Installing);
+        self.install_storage_canister(canister_id, token_id, block_height_offset)
+            .await?;
+        blockchain_service::append_scaling_storage_canister(canister_id);
+        Ok(canister_id)
+    }
+
+    // Give the storage canister at `storage_index` the mirrors the replication
+    // factor asks for, returns its mirrors.
+    async fn get_or_create_mirror_canisters(
+        &self,
+        storage_index: usize,
+    ) -> CommonResult<Vec<Principal>> {
+        let token_id = self.token_id;
+        while blockchain_service::missing_mirrors_count(storage_index) > 0 {
+            // avoid re-creating the mirror canister when install code failed
+            let canister_id = match blockchain_service::latest_mirror_canister() {
+                Some(canister_id) => canister_id,
+                None => {
+                    let canister_id = self.create_storage_canister(token_id).await?;
+                    blockchain_service::pre_append_mirror_canister(canister_id);
+                    canister_id
+                }
+            };
+            let block_height_offset: Nat =
+                blockchain_service::scaling_storage_block_height_offset().into();
+            debug!(
+                "token new mirror storage canister id : {} , block height offset : {}",
+                canister_id,
+                block_height_offset.clone()
+            );
+            blockchain_service::set_archiving_state(ArchivingState::Installing);
+            self.install_storage_canister(canister_id, token_id, block_height_offset)
+                .await?;
+            blockchain_service::append_mirror_canister(storage_index, canister_id);
+        }
+        Ok(blockchain_service::mirror_canisters(storage_index))
+    }
+
+    async fn create_storage_canister(&self, token_id: Principal) -> CommonResult<Principal> {
         let create_args = CreateCanisterArgs {
-            cycles: CYCLES_PER_AUTO_SCALING,
+            cycles: basic_service::archive_options()
+                .cycles_for_archive_creation
+                .unwrap(),
             settings: CanisterSettings {
                 controllers: Some(vec![token_id]),
                 compute_allocation: None,
@@ -161,24 +408,9 @@ impl AutoScalingStorageService {
             },
         };
         debug!("creating token storage...");
-        let create_result = self.ic_management.create_canister(create_args).await;
-
-        match create_result {
-            Ok(cdr) => {
-                blockchain_service::pre_append_scaling_storage_canister(cdr.canister_id);
-                debug!(
-                    "token new storage canister id : {} , block height offset : {}",
-                    cdr.canister_id,
-                    block_height_offset.clone()
-                );
-                self.install_storage_canister_and_append_to_storage_records(
-                    cdr.canister_id,
-                    token_id,
-                    block_height_offset,
-                )
-                .await?;
-                Ok(cdr.canister_id)
-            }
+        blockchain_service::set_archiving_state(ArchivingState::CreatingCanister);
+        match self.ic_management.create_canister(create_args).await {
+            Ok(cdr) => Ok(cdr.canister_id),
             Err(msg) => {
                 let msg = format!("create new storage canister failed {}", msg);
                 error!("{}", msg);
@@ -187,13 +419,21 @@ impl AutoScalingStorageService {
         }
     }
 
-    async fn install_storage_canister_and_append_to_storage_records(
+    async fn install_storage_canister(
         &self,
         canister_id: Principal,
         token_id: Principal,
         block_height_offset: Nat,
     ) -> CommonResult<()> {
-        match encode_args((token_id, block_height_offset.clone())) {
+        let compress_blocks = basic_service::archive_options().compress_blocks;
+        // the first block of the archive is still local, the archive checks it links to its parent
+        let parent_hash = blockchain_service::parent_hash_of(block_height_offset.0.clone());
+        match encode_args((
+            token_id,
+            block_height_offset.clone(),
+            compress_blocks,
+            parent_hash,
+        )) {
             Ok(install_args) => {
                 match self
                     .ic_management
@@ -201,12 +441,12 @@ impl AutoScalingStorageService {
                         &canister_id,
                         AUTO_SCALING_STORAGE_CANISTER_WASM.to_vec(),
                         install_args,
+                        InstallMode::Install,
                     )
                     .await
                 {
                     Ok(_) => {
                         debug!("install storage canister success");
-                        blockchain_service::append_scaling_storage_canister(canister_id);
                         Ok(())
                     }
                     Err(msg) => {
@@ -230,16 +470,30 @@ impl AutoScalingStorageService {
     async fn send_blocks_to_archive(
         &self,
         blocks_to_archive: VecDeque<EncodedBlock>,
-        archive_size_bytes: usize,
+        stored_size_bytes: usize,
     ) -> CommonResult<()> {
         let storage_canister_id = self
-            .get_or_create_available_storage_id(archive_size_bytes)
+            .get_or_create_available_storage_id(stored_size_bytes)
+            .await?;
+        let mirror_canister_ids = self
+            .get_or_create_mirror_canisters(blockchain_service::last_storage_canister_index())
             .await?;
 
         debug!("storage_canister_id is {}", storage_canister_id.to_text());
-        self.dft_tx_storage
-            .batch_append(storage_canister_id, blocks_to_archive)
-            .await
+        blockchain_service::set_archiving_state(ArchivingState::Appending);
+        // Every replica of the range must store the batch before it is removed
+        // from the token. The storage canisters ignore blocks they already have,
+        // so a retry after a failure or a lost reply does not duplicate them.
+        for canister_id in std::iter::once(storage_canister_id).chain(mirror_canister_ids) {
+            self.dft_tx_storage
+                .batch_append(
+                    canister_id,
+                    blockchain_service::archived_blocks_num(),
+                    blocks_to_archive.clone(),
+                )
+                .await?;
+        }
+        Ok(())
     }
 }
 
@@ -1,5 +1,5 @@
-use std::collections::VecDeque;
-use std::sync::Arc;
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
 
 use async_trait::async_trait;
 use candid::{Nat, Principal};
@@ -8,14 +8,16 @@ use num_bigint::BigUint;
 use rstest::*;
 
 use dft_types::constants::{
-    DEFAULT_FEE_RATE_DECIMALS, MAX_CANISTER_STORAGE_BYTES, MIN_CANISTER_STORAGE_BYTES,
+    DEFAULT_ARCHIVE_CYCLES_TOP_UP, DEFAULT_FEE_RATE_DECIMALS, DEFAULT_MIN_ARCHIVE_CYCLES,
+    MAX_CANISTER_STORAGE_BYTES, MIN_CANISTER_STORAGE_BYTES, MIN_TOKEN_CYCLES_AFTER_TOP_UP,
 };
 use dft_types::*;
+use dft_utils::sha256::compute_hash;
 
 use crate::canister_api::*;
 use crate::service::{basic_service, blockchain_service, management_service};
 
-use super::AutoScalingStorageService;
+use super::{AutoScalingStorageService, AUTO_SCALING_STORAGE_CANISTER_WASM};
 
 #[fixture]
 fn test_owner() -> Principal {
@@ -48,6 +50,11 @@ fn test_auto_scaling_storage_id2() -> Principal {
     Principal::from_text("r7inp-6aaaa-aaaaa-aaabq-cai").unwrap()
 }
 
+#[fixture]
+fn test_auto_scaling_storage_id3() -> Principal {
+    Principal::from_text("rno2w-sqaaa-aaaaa-aaacq-cai").unwrap()
+}
+
 #[fixture]
 fn test_name() -> String {
     "Deland Labs Token".to_string()
@@ -110,7 +117,8 @@ mock! {
     impl IICManagementAPI for ICManagementAPI {
         async fn create_canister(&self, args: CreateCanisterArgs) -> Result<CanisterIdRecord, String>;
         async fn canister_status(&self, id_record: CanisterIdRecord) -> Result<CanisterStatusResponse, String>;
-        async fn canister_install(&self, canister_id: &Principal, wasm_module: Vec<u8>, args: Vec<u8>) -> Result<(), String>;
+        async fn canister_install(&self, canister_id: &Principal, wasm_module: Vec<u8>, args: Vec<u8>, mode: InstallMode) -> Result<(), String>;
+        async fn deposit_cycles(&self, id_record: CanisterIdRecord, cycles: u64) -> Result<(), String>;
     }
 }
 
@@ -124,7 +132,8 @@ mock! {
     }
     #[async_trait]
     impl IDFTTxStorageAPI for DFTTxStorageAPI {
-        async fn batch_append(&self, storage_canister_id: Principal, blocks: VecDeque<EncodedBlock>) -> CommonResult<()>;
+        async fn batch_append(&self, storage_canister_id: Principal, start_block_height: BlockHeight, blocks: VecDeque<EncodedBlock>) -> CommonResult<()>;
+        async fn encoded_blocks_by_query(&self, storage_canister_id: Principal, start_block_height: BlockHeight, size: usize) -> CommonResult<Vec<EncodedBlock>>;
     }
 }
 
@@ -139,6 +148,40 @@ pub fn service(test_token_id: Principal) -> AutoScalingStorageService {
     AutoScalingStorageService::new(test_token_id)
 }
 
+#[rstest]
+async fn test_auto_scaling_storage_below_trigger_threshold_does_nothing(
+    mut service: AutoScalingStorageService,
+    mock_dft_tx_storage_api: MockDFTTxStorageAPI,
+    mock_ic_management_api: MockICManagementAPI,
+    test_owner: Principal,
+    other_caller: Principal,
+    now: u64,
+) {
+    test_token();
+
+    // mocks without expectations panic if the archiver tries to call them
+    service.ic_management = Arc::new(mock_ic_management_api);
+    service.dft_tx_storage = Arc::new(mock_dft_tx_storage_api);
+    for i in 0..100u64 {
+        let call_res = management_service::set_fee_to(
+            &test_owner,
+            TokenHolder::new(other_caller, None),
+            None,
+            now + i,
+        );
+        assert!(call_res.is_ok());
+        service.exec_auto_scaling_strategy(now + i).await
+    }
+
+    let status = basic_service::archiving_status();
+    assert_eq!(status.last_run_at, None);
+    assert_eq!(status.last_error, None);
+    assert_eq!(
+        blockchain_service::archived_blocks_num(),
+        BigUint::from(0u32)
+    );
+}
+
 #[rstest]
 async fn test_auto_scaling_storage_with_create_storage_fail(
     mut service: AutoScalingStorageService,
@@ -157,7 +200,7 @@ async fn test_auto_scaling_storage_with_create_storage_fail(
 
     mock_dft_tx_storage_api
         .expect_batch_append()
-        .returning(|_, _| Ok(()));
+        .returning(|_, _, _| Ok(()));
 
     service.ic_management = Arc::new(mock_ic_management_api);
     service.dft_tx_storage = Arc::new(mock_dft_tx_storage_api);
@@ -170,13 +213,26 @@ async fn test_auto_scaling_storage_with_create_storage_fail(
         let call_res =
             management_service::set_fee_to(&test_owner, new_fee_to, None, now.clone() + i);
         assert_eq!(call_res.is_ok(), true);
-        service.exec_auto_scaling_strategy().await
+        service.exec_auto_scaling_strategy(now + i).await
     }
 
     assert_eq!(
         blockchain_service::archived_blocks_num(),
         BigUint::from(0u32)
     );
+    // the first failure happens once the trigger threshold is exceeded,
+    // subsequent calls are within the retry backoff and do nothing
+    let status = basic_service::archiving_status();
+    assert_eq!(status.last_run_at, Some(now + 1999));
+    assert_eq!(status.last_success_at, None);
+    assert!(status.next_retry_at.unwrap() > now + 3000);
+    match status.state {
+        ArchivingState::Failed { reason, attempts } => {
+            assert_eq!(attempts, 1);
+            assert!(reason.contains("create new storage canister failed"));
+        }
+        state => panic!("unexpected archiving state {:?}", state),
+    }
 }
 
 #[rstest]
@@ -201,11 +257,11 @@ async fn test_auto_scaling_storage_with_create_storage_success_and_install_fail(
 
     mock_ic_management_api
         .expect_canister_install()
-        .returning(|_, _, _| Err("install canister failed".to_string()));
+        .returning(|_, _, _, _| Err("install canister failed".to_string()));
 
     mock_dft_tx_storage_api
         .expect_batch_append()
-        .returning(|_, _| Ok(()));
+        .returning(|_, _, _| Ok(()));
 
     service.ic_management = Arc::new(mock_ic_management_api);
     service.dft_tx_storage = Arc::new(mock_dft_tx_storage_api);
@@ -218,7 +274,7 @@ async fn test_auto_scaling_storage_with_create_storage_success_and_install_fail(
         let call_res =
             management_service::set_fee_to(&test_owner, new_fee_to, None, now.clone() + i);
         assert_eq!(call_res.is_ok(), true);
-        service.exec_auto_scaling_strategy().await
+        service.exec_auto_scaling_strategy(now + i).await
     }
 
     assert_eq!(
@@ -251,7 +307,7 @@ async fn test_auto_scaling_storage_with_create_storage_success_and_install_succe
     mock_ic_management_api
         .expect_canister_install()
         .times(1)
-        .returning(|_, _, _| Ok(()));
+        .returning(|_, _, _, _| Ok(()));
 
     mock_ic_management_api
         .expect_canister_status()
@@ -273,7 +329,7 @@ async fn test_auto_scaling_storage_with_create_storage_success_and_install_succe
 
     mock_dft_tx_storage_api
         .expect_batch_append()
-        .returning(|_, _| Err(DFTError::MoveTxToScalingStorageFailed));
+        .returning(|_, _, _| Err(DFTError::MoveTxToScalingStorageFailed));
 
     service.ic_management = Arc::new(mock_ic_management_api);
     service.dft_tx_storage = Arc::new(mock_dft_tx_storage_api);
@@ -292,7 +348,7 @@ async fn test_auto_scaling_storage_with_create_storage_success_and_install_succe
                 test_auto_scaling_storage_id()
             );
         }
-        service.exec_auto_scaling_strategy().await;
+        service.exec_auto_scaling_strategy(now + i).await;
     }
 
     assert_eq!(
@@ -325,7 +381,7 @@ async fn test_auto_scaling_storage_with_create_storage_success_and_install_succe
     mock_ic_management_api
         .expect_canister_install()
         .times(1)
-        .returning(|_, _, _| Ok(()));
+        .returning(|_, _, _, _| Ok(()));
 
     mock_ic_management_api
         .expect_canister_status()
@@ -347,7 +403,15 @@ async fn test_auto_scaling_storage_with_create_storage_success_and_install_succe
 
     mock_dft_tx_storage_api
         .expect_batch_append()
-        .returning(|_, _| Ok(()));
+        .returning(|_, start_block_height, blocks| {
+            // each batch starts right after the blocks archived so far
+            assert_eq!(
+                start_block_height,
+                blockchain_service::archived_blocks_num()
+            );
+            assert_eq!(blocks.len(), 1000);
+            Ok(())
+        });
 
     service.ic_management = Arc::new(mock_ic_management_api);
     service.dft_tx_storage = Arc::new(mock_dft_tx_storage_api);
@@ -366,13 +430,398 @@ async fn test_auto_scaling_storage_with_create_storage_success_and_install_succe
                 test_auto_scaling_storage_id()
             );
         }
-        service.exec_auto_scaling_strategy().await;
+        service.exec_auto_scaling_strategy(now + i).await;
+    }
+
+    assert_eq!(
+        blockchain_service::archived_blocks_num(),
+        BigUint::from(2000u32)
+    );
+    let status = basic_service::archiving_status();
+    assert!(status.last_success_at.is_some());
+    assert_eq!(status.last_error, None);
+}
+
+#[rstest]
+async fn test_auto_scaling_storage_checks_the_compressed_size(
+    mut service: AutoScalingStorageService,
+    mut mock_dft_tx_storage_api: MockDFTTxStorageAPI,
+    mut mock_ic_management_api: MockICManagementAPI,
+    test_owner: Principal,
+    other_caller: Principal,
+    test_fee_to: Principal,
+    now: u64,
+) {
+    test_token();
+
+    // the second batch fits in the first archive only once compressed
+    mock_ic_management_api
+        .expect_create_canister()
+        .times(1)
+        .returning(|_| {
+            Ok(CanisterIdRecord {
+                canister_id: test_auto_scaling_storage_id(),
+            })
+        });
+    mock_ic_management_api
+        .expect_canister_install()
+        .times(1)
+        .returning(|_, _, _, _| Ok(()));
+    mock_ic_management_api
+        .expect_canister_status()
+        .returning(|_| {
+            let blocks_to_archive: Vec<EncodedBlock> =
+                blockchain_service::get_blocks_for_archiving().into();
+            let raw_size_bytes: usize = blocks_to_archive.iter().map(|b| b.size_bytes()).sum();
+            assert!(stored_blocks_size_bytes(&blocks_to_archive, true) < raw_size_bytes);
+            let node_max_memory_size_bytes = basic_service::archive_options()
+                .node_max_memory_size_bytes
+                .unwrap();
+            Ok(CanisterStatusResponse {
+                status: CanisterStatus::Running,
+                settings: CanisterSettings {
+                    controllers: None,
+                    compute_allocation: None,
+                    memory_allocation: None,
+                    freezing_threshold: None,
+                },
+                module_hash: None,
+                controller: test_token_id(),
+                memory_size: (node_max_memory_size_bytes - raw_size_bytes as u32).into(),
+                cycles: 0u32.into(),
+            })
+        });
+    mock_dft_tx_storage_api
+        .expect_batch_append()
+        .returning(|storage_canister_id, _, _| {
+            assert_eq!(storage_canister_id, test_auto_scaling_storage_id());
+            Ok(())
+        });
+
+    service.ic_management = Arc::new(mock_ic_management_api);
+    service.dft_tx_storage = Arc::new(mock_dft_tx_storage_api);
+    for i in 0..=3000u64 {
+        let new_fee_to = if i % 2u64 == 0u64 {
+            TokenHolder::new(test_fee_to, None)
+        } else {
+            TokenHolder::new(other_caller, None)
+        };
+        let call_res = management_service::set_fee_to(&test_owner, new_fee_to, None, now + i);
+        assert!(call_res.is_ok());
+        service.exec_auto_scaling_strategy(now + i).await;
     }
 
     assert_eq!(
         blockchain_service::archived_blocks_num(),
         BigUint::from(2000u32)
     );
+    assert_eq!(
+        blockchain_service::last_auto_scaling_storage_canister_id(),
+        Some(test_auto_scaling_storage_id())
+    );
+    assert_eq!(basic_service::archiving_status().last_error, None);
+}
+
+#[rstest]
+async fn test_auto_scaling_storage_installs_archives_without_compression(
+    mut service: AutoScalingStorageService,
+    mut mock_dft_tx_storage_api: MockDFTTxStorageAPI,
+    mut mock_ic_management_api: MockICManagementAPI,
+    test_owner: Principal,
+    other_caller: Principal,
+    now: u64,
+) {
+    test_token();
+    management_service::set_archive_options(
+        &test_owner,
+        ArchiveOptions {
+            compress_blocks: Some(false),
+            ..basic_service::archive_options()
+        },
+        None,
+        now,
+    )
+    .unwrap();
+
+    mock_ic_management_api
+        .expect_create_canister()
+        .times(1)
+        .returning(|_| {
+            Ok(CanisterIdRecord {
+                canister_id: test_auto_scaling_storage_id(),
+            })
+        });
+    mock_ic_management_api
+        .expect_canister_install()
+        .times(1)
+        .returning(|_, _, install_args, _| {
+            let (token_id, _, compress_blocks, parent_hash): (
+                Principal,
+                Nat,
+                Option<bool>,
+                Option<BlockHash>,
+            ) = candid::decode_args(&install_args).unwrap();
+            assert_eq!(token_id, test_token_id());
+            assert_eq!(compress_blocks, Some(false));
+            // the first archive starts at the genesis block
+            assert_eq!(parent_hash, Some(compute_hash(test_token_id().as_slice())));
+            Ok(())
+        });
+    mock_ic_management_api
+        .expect_canister_status()
+        .returning(|_| {
+            Ok(CanisterStatusResponse {
+                status: CanisterStatus::Running,
+                settings: CanisterSettings {
+                    controllers: None,
+                    compute_allocation: None,
+                    memory_allocation: None,
+                    freezing_threshold: None,
+                },
+                module_hash: None,
+                controller: test_token_id(),
+                memory_size: MIN_CANISTER_STORAGE_BYTES.into(),
+                cycles: 0u32.into(),
+            })
+        });
+    mock_dft_tx_storage_api
+        .expect_batch_append()
+        .times(1)
+        .returning(|_, _, _| Ok(()));
+
+    service.ic_management = Arc::new(mock_ic_management_api);
+    service.dft_tx_storage = Arc::new(mock_dft_tx_storage_api);
+    for i in 1..=3000u64 {
+        management_service::set_fee_to(
+            &test_owner,
+            TokenHolder::new(other_caller, None),
+            None,
+            now + i,
+        )
+        .unwrap();
+    }
+    service.exec_auto_scaling_strategy(now + 3001).await;
+    assert!(blockchain_service::archived_blocks_num() > BigUint::from(0u32));
+}
+
+#[rstest]
+async fn test_auto_scaling_storage_writes_every_replica(
+    mut service: AutoScalingStorageService,
+    mut mock_dft_tx_storage_api: MockDFTTxStorageAPI,
+    mut mock_ic_management_api: MockICManagementAPI,
+    test_owner: Principal,
+    other_caller: Principal,
+    now: u64,
+) {
+    test_token();
+    management_service::set_archive_options(
+        &test_owner,
+        ArchiveOptions {
+            replication_factor: Some(2),
+            ..basic_service::archive_options()
+        },
+        None,
+        now,
+    )
+    .unwrap();
+
+    let created = Arc::new(Mutex::new(0));
+    mock_ic_management_api
+        .expect_create_canister()
+        .times(2)
+        .returning(move |_| {
+            let mut created = created.lock().unwrap();
+            *created += 1;
+            let canister_id = if *created == 1 {
+                test_auto_scaling_storage_id()
+            } else {
+                test_auto_scaling_storage_id2()
+            };
+            Ok(CanisterIdRecord { canister_id })
+        });
+    // the first install of the mirror fails
+    let installed = Arc::new(Mutex::new(0));
+    mock_ic_management_api
+        .expect_canister_install()
+        .times(3)
+        .returning(move |_, _, _, _| {
+            let mut installed = installed.lock().unwrap();
+            *installed += 1;
+            if *installed == 2 {
+                Err("install failed".to_string())
+            } else {
+                Ok(())
+            }
+        });
+    mock_ic_management_api
+        .expect_canister_status()
+        .returning(|_| {
+            Ok(CanisterStatusResponse {
+                status: CanisterStatus::Running,
+                settings: CanisterSettings {
+                    controllers: None,
+                    compute_allocation: None,
+                    memory_allocation: None,
+                    freezing_threshold: None,
+                },
+                module_hash: None,
+                controller: test_token_id(),
+                memory_size: MIN_CANISTER_STORAGE_BYTES.into(),
+                cycles: 0u32.into(),
+            })
+        });
+    let appended_to = Arc::new(Mutex::new(vec![]));
+    let appended_to_copy = appended_to.clone();
+    mock_dft_tx_storage_api
+        .expect_batch_append()
+        .returning(move |storage_canister_id, _, _| {
+            appended_to_copy.lock().unwrap().push(storage_canister_id);
+            Ok(())
+        });
+
+    service.ic_management = Arc::new(mock_ic_management_api);
+    service.dft_tx_storage = Arc::new(mock_dft_tx_storage_api);
+    for i in 0..=3000u64 {
+        let call_res = management_service::set_fee_to(
+            &test_owner,
+            TokenHolder::new(other_caller, None),
+            None,
+            now + i,
+        );
+        assert!(call_res.is_ok());
+    }
+
+    service.exec_auto_scaling_strategy(now).await;
+    assert_eq!(
+        blockchain_service::archived_blocks_num(),
+        BigUint::from(0u32)
+    );
+    assert_eq!(
+        blockchain_service::storage_canisters(),
+        vec![test_auto_scaling_storage_id()]
+    );
+    assert!(appended_to.lock().unwrap().is_empty());
+
+    // the retry installs the mirror created by the failed attempt
+    let retry_at = basic_service::archiving_status().next_retry_at.unwrap();
+    service.exec_auto_scaling_strategy(retry_at).await;
+    service.exec_auto_scaling_strategy(retry_at + 1).await;
+    assert_eq!(
+        blockchain_service::archived_blocks_num(),
+        BigUint::from(2000u32)
+    );
+    let replicas = vec![
+        test_auto_scaling_storage_id(),
+        test_auto_scaling_storage_id2(),
+    ];
+    assert_eq!(blockchain_service::storage_canisters(), replicas);
+    assert_eq!(
+        *appended_to.lock().unwrap(),
+        [replicas.clone(), replicas.clone()].concat()
+    );
+
+    // reads fall back to the mirror when the first replica is unreachable
+    assert_eq!(
+        basic_service::block_by_height(0u32.into()),
+        BlockResult::Forward(test_auto_scaling_storage_id())
+    );
+    blockchain_service::record_archive_cycles(
+        test_auto_scaling_storage_id(),
+        retry_at,
+        None,
+        0,
+        Some("check archive canister status failed".to_string()),
+    );
+    assert_eq!(
+        basic_service::block_by_height(0u32.into()),
+        BlockResult::Forward(test_auto_scaling_storage_id2())
+    );
+}
+
+// A higher replication factor only mirrors the archive canisters created
+// afterwards, the blocks keep going to the storage canister already in use.
+#[rstest]
+async fn test_auto_scaling_storage_does_not_mirror_existing_archives(
+    mut service: AutoScalingStorageService,
+    mut mock_dft_tx_storage_api: MockDFTTxStorageAPI,
+    mut mock_ic_management_api: MockICManagementAPI,
+    test_owner: Principal,
+    other_caller: Principal,
+    now: u64,
+) {
+    test_token();
+
+    mock_ic_management_api
+        .expect_create_canister()
+        .times(1)
+        .returning(|_| {
+            Ok(CanisterIdRecord {
+                canister_id: test_auto_scaling_storage_id(),
+            })
+        });
+    mock_ic_management_api
+        .expect_canister_install()
+        .times(1)
+        .returning(|_, _, _, _| Ok(()));
+    mock_ic_management_api
+        .expect_canister_status()
+        .returning(|_| {
+            Ok(CanisterStatusResponse {
+                status: CanisterStatus::Running,
+                settings: CanisterSettings {
+                    controllers: None,
+                    compute_allocation: None,
+                    memory_allocation: None,
+                    freezing_threshold: None,
+                },
+                module_hash: None,
+                controller: test_token_id(),
+                memory_size: MIN_CANISTER_STORAGE_BYTES.into(),
+                cycles: 0u32.into(),
+            })
+        });
+    mock_dft_tx_storage_api
+        .expect_batch_append()
+        .returning(|storage_canister_id, _, _| {
+            assert_eq!(storage_canister_id, test_auto_scaling_storage_id());
+            Ok(())
+        });
+
+    service.ic_management = Arc::new(mock_ic_management_api);
+    service.dft_tx_storage = Arc::new(mock_dft_tx_storage_api);
+    let add_blocks = |from: u64, to: u64| {
+        for i in from..to {
+            management_service::set_fee_to(
+                &test_owner,
+                TokenHolder::new(other_caller, None),
+                None,
+                now + i,
+            )
+            .unwrap();
+        }
+    };
+    add_blocks(0, 3001);
+    service.exec_auto_scaling_strategy(now + 3001).await;
+    let archived = blockchain_service::archived_blocks_num();
+    assert!(archived > BigUint::from(0u32));
+
+    management_service::set_archive_options(
+        &test_owner,
+        ArchiveOptions {
+            replication_factor: Some(2),
+            ..basic_service::archive_options()
+        },
+        None,
+        now + 3001,
+    )
+    .unwrap();
+    add_blocks(3002, 5002);
+    service.exec_auto_scaling_strategy(now + 5002).await;
+    assert!(blockchain_service::archived_blocks_num() > archived);
+    assert_eq!(
+        blockchain_service::storage_canisters(),
+        vec![test_auto_scaling_storage_id()]
+    );
 }
 
 #[rstest]
@@ -387,6 +836,8 @@ async fn test_auto_scaling_storage_with_create_storage_success_and_install_succe
 ) {
     test_token();
     let mut toggle_return = false;
+    let installed_parent_hashes = Arc::new(Mutex::new(vec![]));
+    let mut parent_hashes = vec![];
 
     mock_ic_management_api
         .expect_create_canister()
@@ -403,9 +854,22 @@ async fn test_auto_scaling_storage_with_create_storage_success_and_install_succe
                 })
             }
         });
+    let installed = installed_parent_hashes.clone();
     mock_ic_management_api
         .expect_canister_install()
-        .returning(move |_, _, _| Ok(()));
+        .returning(move |_, _, install_args, _| {
+            let (_, block_height_offset, _, parent_hash): (
+                Principal,
+                Nat,
+                Option<bool>,
+                Option<BlockHash>,
+            ) = candid::decode_args(&install_args).unwrap();
+            installed
+                .lock()
+                .unwrap()
+                .push((block_height_offset, parent_hash));
+            Ok(())
+        });
 
     mock_ic_management_api
         .expect_canister_status()
@@ -420,7 +884,8 @@ async fn test_auto_scaling_storage_with_create_storage_success_and_install_succe
                 },
                 module_hash: None,
                 controller: test_token_id(),
-                memory_size: (MAX_CANISTER_STORAGE_BYTES - 161000u32).into(),
+                // not enough room left for the next batch, even compressed
+                memory_size: (MAX_CANISTER_STORAGE_BYTES - 10000u32).into(),
                 cycles: 0u32.into(),
             })
         });
@@ -443,7 +908,7 @@ async fn test_auto_scaling_storage_with_create_storage_success_and_install_succe
         });
     mock_dft_tx_storage_api
         .expect_batch_append()
-        .returning(move |_, _| Ok(()));
+        .returning(move |_, _, _| Ok(()));
 
     service.ic_management = Arc::new(mock_ic_management_api);
     service.dft_tx_storage = Arc::new(mock_dft_tx_storage_api);
@@ -456,12 +921,15 @@ async fn test_auto_scaling_storage_with_create_storage_success_and_install_succe
         let call_res =
             management_service::set_fee_to(&test_owner, new_fee_to, None, now.clone() + i);
         assert_eq!(call_res.is_ok(), true);
-        service.exec_auto_scaling_strategy().await;
+        service.exec_auto_scaling_strategy(now + i).await;
 
         let block_res = basic_service::block_by_height(i.into());
 
         if let BlockResult::Ok(block) = block_res {
             assert_eq!(block.timestamp, now.clone() + i);
+            if i == 0 || i == 1000 {
+                parent_hashes.push((Nat::from(i), Some(block.parent_hash)));
+            }
         }
 
         if i >= 2000u64 && i < 2999u64 {
@@ -482,6 +950,9 @@ async fn test_auto_scaling_storage_with_create_storage_success_and_install_succe
         BigUint::from(2000u32)
     );
 
+    // each archive links its first block to the last block of the previous one
+    assert_eq!(*installed_parent_hashes.lock().unwrap(), parent_hashes);
+
     let block_res = basic_service::block_by_height(999u32.into());
 
     if let BlockResult::Forward(f) = block_res {
@@ -555,4 +1026,257 @@ async fn test_auto_scaling_storage_with_create_storage_success_and_install_succe
     assert_eq!(block_res.blocks.len(), 100);
     assert_eq!(block_res.first_block_index, Nat::from(2000u32));
     assert_eq!(block_res.archived_blocks, vec![]);
+
+    // block i has the timestamp now + i, one block out of 64 is indexed
+    let res = basic_service::blocks_by_time_range(now + 500, now + 1500, 100, None).unwrap();
+    assert_eq!(res.blocks.len(), 0);
+    assert_eq!(res.next_cursor, None);
+    assert_eq!(
+        res.archived_blocks,
+        vec![
+            ArchivedBlocksRange {
+                start: Nat::from(448u32),
+                length: 552,
+                storage_canister_id: test_auto_scaling_storage_id(),
+            },
+            ArchivedBlocksRange {
+                start: Nat::from(1000u32),
+                length: 536,
+                storage_canister_id: test_auto_scaling_storage_id2(),
+            }
+        ]
+    );
+
+    let res = basic_service::blocks_by_time_range(now + 1990, now + 2010, 100, None).unwrap();
+    assert_eq!(res.blocks.len(), 10);
+    assert_eq!(res.blocks[0].timestamp, now + 2000);
+    assert_eq!(
+        res.archived_blocks,
+        vec![ArchivedBlocksRange {
+            start: Nat::from(1984u32),
+            length: 16,
+            storage_canister_id: test_auto_scaling_storage_id2(),
+        }]
+    );
+
+    let res = basic_service::blocks_by_time_range(now + 2050, now + 2100, 20, None).unwrap();
+    assert_eq!(res.blocks.len(), 20);
+    assert_eq!(res.blocks[0].timestamp, now + 2050);
+    assert_eq!(res.archived_blocks, vec![]);
+    assert_eq!(res.next_cursor, Some(Nat::from(2070u32)));
+    let res = basic_service::blocks_by_time_range(now + 2050, now + 2100, 20, Some(2070u32.into()))
+        .unwrap();
+    assert_eq!(res.blocks.len(), 20);
+    assert_eq!(res.blocks[0].timestamp, now + 2070);
+    assert_eq!(res.next_cursor, Some(Nat::from(2090u32)));
+
+    assert_eq!(
+        basic_service::blocks_by_time_range(now + 1, now, 100, None).unwrap_err(),
+        DFTError::InvalidTimeRange
+    );
+}
+
+// register archive canisters as if they were created by previous archiving runs
+fn test_archives(canister_ids: &[Principal], now: u64) {
+    for (i, canister_id) in canister_ids.iter().enumerate() {
+        assert!(blockchain_service::lock_for_archiving(now));
+        blockchain_service::pre_append_scaling_storage_canister(*canister_id);
+        blockchain_service::append_scaling_storage_canister(*canister_id);
+        blockchain_service::update_scaling_storage_blocks_range(i, ((i + 1) * 1000 - 1).into());
+        blockchain_service::record_archiving_result(now, None);
+    }
+}
+
+fn canister_status_with_module_hash(module_hash: Option<Vec<u8>>) -> CanisterStatusResponse {
+    CanisterStatusResponse {
+        status: CanisterStatus::Running,
+        settings: CanisterSettings {
+            controllers: None,
+            compute_allocation: None,
+            memory_allocation: None,
+            freezing_threshold: None,
+        },
+        module_hash,
+        controller: test_token_id(),
+        memory_size: MIN_CANISTER_STORAGE_BYTES.into(),
+        cycles: 0u32.into(),
+    }
+}
+
+#[rstest]
+async fn test_upgrade_archives_upgrades_outdated_canisters(
+    mut service: AutoScalingStorageService,
+    mut mock_ic_management_api: MockICManagementAPI,
+    test_owner: Principal,
+    now: u64,
+) {
+    test_token();
+    test_archives(
+        &[
+            test_auto_scaling_storage_id(),
+            test_auto_scaling_storage_id2(),
+        ],
+        now,
+    );
+
+    // the second archive already runs the embedded wasm
+    let upgraded = Arc::new(Mutex::new(HashSet::from([test_auto_scaling_storage_id2()])));
+    let module_hash = compute_hash(AUTO_SCALING_STORAGE_CANISTER_WASM).to_vec();
+    let upgraded_status = upgraded.clone();
+    mock_ic_management_api
+        .expect_canister_status()
+        .returning(move |id_record| {
+            let is_upgraded = upgraded_status
+                .lock()
+                .unwrap()
+                .contains(&id_record.canister_id);
+            Ok(canister_status_with_module_hash(
+                is_upgraded.then(|| module_hash.clone()),
+            ))
+        });
+    mock_ic_management_api
+        .expect_canister_install()
+        .times(1)
+        .withf(|canister_id, wasm_module, _, mode| {
+            *canister_id == test_auto_scaling_storage_id()
+                && wasm_module == AUTO_SCALING_STORAGE_CANISTER_WASM
+                && *mode == InstallMode::Upgrade
+        })
+        .returning(move |canister_id, _, _, _| {
+            upgraded.lock().unwrap().insert(*canister_id);
+            Ok(())
+        });
+    service.ic_management = Arc::new(mock_ic_management_api);
+
+    let res = service.upgrade_archives(&test_owner).await.unwrap();
+    assert_eq!(
+        res,
+        vec![
+            ArchiveUpgradeInfo {
+                canister_id: test_auto_scaling_storage_id(),
+                status: ArchiveUpgradeStatus::Upgraded,
+            },
+            ArchiveUpgradeInfo {
+                canister_id: test_auto_scaling_storage_id2(),
+                status: ArchiveUpgradeStatus::UpToDate,
+            },
+        ]
+    );
+    assert_eq!(
+        basic_service::archiving_status().state,
+        ArchivingState::Idle
+    );
+}
+
+#[rstest]
+async fn test_upgrade_archives_stops_on_failure(
+    mut service: AutoScalingStorageService,
+    mut mock_ic_management_api: MockICManagementAPI,
+    test_owner: Principal,
+    now: u64,
+) {
+    test_token();
+    test_archives(
+        &[
+            test_auto_scaling_storage_id(),
+            test_auto_scaling_storage_id2(),
+        ],
+        now,
+    );
+
+    // the install succeeds but the canister does not report the expected module hash
+    mock_ic_management_api
+        .expect_canister_status()
+        .returning(|_| Ok(canister_status_with_module_hash(None)));
+    mock_ic_management_api
+        .expect_canister_install()
+        .times(1)
+        .returning(|_, _, _, _| Ok(()));
+    service.ic_management = Arc::new(mock_ic_management_api);
+
+    let res = service.upgrade_archives(&test_owner).await.unwrap();
+    assert_eq!(res.len(), 2);
+    match &res[0].status {
+        ArchiveUpgradeStatus::Failed { reason } => assert!(reason.contains("module hash")),
+        status => panic!("unexpected upgrade status {:?}", status),
+    }
+    assert_eq!(res[1].canister_id, test_auto_scaling_storage_id2());
+    assert_eq!(res[1].status, ArchiveUpgradeStatus::Skipped);
+    // archiving can run again
+    assert!(blockchain_service::lock_for_archiving(now));
+}
+
+#[rstest]
+async fn test_upgrade_archives_is_refused(
+    mut service: AutoScalingStorageService,
+    mock_ic_management_api: MockICManagementAPI,
+    test_owner: Principal,
+    other_caller: Principal,
+    now: u64,
+) {
+    test_token();
+    test_archives(&[test_auto_scaling_storage_id()], now);
+    service.ic_management = Arc::new(mock_ic_management_api);
+
+    assert_eq!(
+        service.upgrade_archives(&other_caller).await,
+        Err(DFTError::OnlyOwnerAllowCallIt)
+    );
+
+    assert!(blockchain_service::lock_for_archiving(now));
+    assert_eq!(
+        service.upgrade_archives(&test_owner).await,
+        Err(DFTError::ArchivingInProgress)
+    );
+}
+
+#[rstest]
+async fn test_monitor_archive_cycles_tops_up_low_archives(
+    mut service: AutoScalingStorageService,
+    mut mock_ic_management_api: MockICManagementAPI,
+    now: u64,
+) {
+    test_token();
+    test_archives(
+        &[
+            test_auto_scaling_storage_id(),
+            test_auto_scaling_storage_id2(),
+            test_auto_scaling_storage_id3(),
+        ],
+        now,
+    );
+
+    // only the first archive has enough cycles
+    mock_ic_management_api
+        .expect_canister_status()
+        .returning(|id_record| {
+            let mut status = canister_status_with_module_hash(None);
+            status.cycles = if id_record.canister_id == test_auto_scaling_storage_id() {
+                DEFAULT_MIN_ARCHIVE_CYCLES.into()
+            } else {
+                (DEFAULT_MIN_ARCHIVE_CYCLES - 1).into()
+            };
+            Ok(status)
+        });
+    mock_ic_management_api
+        .expect_deposit_cycles()
+        .times(1)
+        .withf(|id_record, cycles| {
+            id_record.canister_id == test_auto_scaling_storage_id2()
+                && *cycles == DEFAULT_ARCHIVE_CYCLES_TOP_UP
+        })
+        .returning(|_, _| Ok(()));
+    service.ic_management = Arc::new(mock_ic_management_api);
+
+    // the token can afford a single top-up
+    service
+        .monitor_archive_cycles(
+            now,
+            MIN_TOKEN_CYCLES_AFTER_TOP_UP + DEFAULT_ARCHIVE_CYCLES_TOP_UP,
+        )
+        .await;
+
+    let metrics = basic_service::token_metrics();
+    assert_eq!(metrics.archives_low_on_cycles, 1);
+    assert_eq!(metrics.archives_with_cycles_errors, 1);
 }
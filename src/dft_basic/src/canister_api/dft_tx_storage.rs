@@ -1,6 +1,8 @@
 use async_trait::async_trait;
-use candid::Principal;
-use dft_types::{BooleanResult, CommonResult, DFTError, EncodedBlock};
+use candid::{Nat, Principal};
+use dft_types::{
+    BlockHeight, BooleanResult, CommonResult, DFTError, EncodedBlock, EncodedBlockListResult,
+};
 use ic_cdk::api;
 use ic_cdk::api::call::RejectionCode;
 use log::{debug, error};
@@ -11,8 +13,15 @@ pub trait IDFTTxStorageAPI {
     async fn batch_append(
         &self,
         storage_canister_id: Principal,
+        start_block_height: BlockHeight,
         blocks: VecDeque<EncodedBlock>,
     ) -> CommonResult<()>;
+    async fn encoded_blocks_by_query(
+        &self,
+        storage_canister_id: Principal,
+        start_block_height: BlockHeight,
+        size: usize,
+    ) -> CommonResult<Vec<EncodedBlock>>;
 }
 #[derive(Default)]
 pub struct DFTTxStorageAPI;
@@ -23,11 +32,16 @@ impl IDFTTxStorageAPI for DFTTxStorageAPI {
     async fn batch_append(
         &self,
         storage_canister_id: Principal,
+        start_block_height: BlockHeight,
         blocks: VecDeque<EncodedBlock>,
     ) -> CommonResult<()> {
         //save the txs to auto-scaling storage
-        let res: Result<(BooleanResult,), (RejectionCode, String)> =
-            api::call::call(storage_canister_id, "batchAppend", (blocks,)).await;
+        let res: Result<(BooleanResult,), (RejectionCode, String)> = api::call::call(
+            storage_canister_id,
+            "batchAppend",
+            (Nat::from(start_block_height), blocks),
+        )
+        .await;
         match res {
             Ok((res,)) => match res {
                 BooleanResult::Ok(success) => {
@@ -47,4 +61,29 @@ impl IDFTTxStorageAPI for DFTTxStorageAPI {
             }
         }
     }
+
+    async fn encoded_blocks_by_query(
+        &self,
+        storage_canister_id: Principal,
+        start_block_height: BlockHeight,
+        size: usize,
+    ) -> CommonResult<Vec<EncodedBlock>> {
+        let res: Result<(EncodedBlockListResult,), (RejectionCode, String)> = api::call::call(
+            storage_canister_id,
+            "encodedBlocksByQuery",
+            (Nat::from(start_block_height), size),
+        )
+        .await;
+        match res {
+            Ok((EncodedBlockListResult::Ok(blocks),)) => Ok(blocks),
+            Ok((EncodedBlockListResult::Err(err),)) => Err(err.into()),
+            Err((_, msg)) => {
+                error!(
+                    "encodedBlocksByQuery: read from auto-scaling storage failed,{0}",
+                    msg
+                );
+                Err(DFTError::Unknown { detail: msg })
+            }
+        }
+    }
 }
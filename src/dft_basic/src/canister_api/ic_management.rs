@@ -38,8 +38,8 @@ pub struct CanisterStatusResponse {
 }
 
 // Install Wasm
-#[derive(CandidType, Deserialize)]
-enum InstallMode {
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstallMode {
     #[serde(rename = "install")]
     Install,
     #[serde(rename = "reinstall")]
@@ -76,7 +76,9 @@ pub trait IICManagementAPI {
         canister_id: &Principal,
         wasm_module: Vec<u8>,
         args: Vec<u8>,
+        mode: InstallMode,
     ) -> Result<(), String>;
+    async fn deposit_cycles(&self, id_record: CanisterIdRecord, cycles: u64) -> Result<(), String>;
 }
 
 #[derive(Default)]
@@ -138,15 +140,16 @@ impl IICManagementAPI for ICManagementAPI {
         canister_id: &Principal,
         wasm_module: Vec<u8>,
         args: Vec<u8>,
+        mode: InstallMode,
     ) -> Result<(), String> {
         let install_config = CanisterInstall {
-            mode: InstallMode::Install,
+            mode,
             canister_id: *canister_id,
             wasm_module: wasm_module.clone(),
             arg: args,
         };
 
-        match api::call::call(
+        match api::call::call::<_, ()>(
             Principal::management_canister(),
             "install_code",
             (install_config,),
@@ -163,4 +166,17 @@ impl IICManagementAPI for ICManagementAPI {
         };
         Ok(())
     }
+
+    async fn deposit_cycles(&self, id_record: CanisterIdRecord, cycles: u64) -> Result<(), String> {
+        let res: Result<(), _> = api::call::call_with_payment(
+            Principal::management_canister(),
+            "deposit_cycles",
+            (id_record,),
+            cycles,
+        )
+        .await;
+        res.map_err(|(code, msg)| {
+            format!("An error happened during the call: {}: {}", code as u8, msg)
+        })
+    }
 }
@@ -11,6 +11,26 @@ pub trait ITransferNotifyAPI {
         transfer_from: &TokenHolder,
         transfer_value: &TokenAmount,
     );
+
+    // Notify the recipients of a multi transfer, once per recipient
+    // with the total value it received.
+    fn notify_outputs(
+        &self,
+        outputs: &[(String, TokenAmount)],
+        block_height: &BlockHeight,
+        transfer_from: &TokenHolder,
+    ) {
+        let mut received: Vec<(&String, TokenAmount)> = Vec::new();
+        for (receiver, value) in outputs {
+            match received.iter_mut().find(|(r, _)| *r == receiver) {
+                Some((_, total)) => *total += value,
+                None => received.push((receiver, value.clone())),
+            }
+        }
+        for (receiver, value) in received {
+            self.notify(receiver, block_height, transfer_from, &value);
+        }
+    }
 }
 
 #[derive(Default)]
@@ -49,3 +69,62 @@ impl ITransferNotifyAPI for TransferNotifyAPI {
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use mockall::mock;
+    use mockall::predicate::eq;
+
+    use super::*;
+
+    mock! {
+        Notify {}
+        impl ITransferNotifyAPI for Notify {
+            fn notify(
+                &self,
+                receiver: &String,
+                block_height: &BlockHeight,
+                transfer_from: &TokenHolder,
+                transfer_value: &TokenAmount,
+            );
+        }
+    }
+
+    #[test]
+    fn test_notify_outputs_once_per_recipient() {
+        let canister = "rrkah-fqaaa-aaaaa-aaaaq-cai".to_string();
+        let other = "ryjl3-tyaaa-aaaaa-aaaba-cai".to_string();
+        let from = TokenHolder::empty();
+        let mut notify = MockNotify::new();
+        notify
+            .expect_notify()
+            .with(
+                eq(canister.clone()),
+                eq(BlockHeight::from(7u32)),
+                eq(from),
+                eq(TokenAmount::from(30u32)),
+            )
+            .times(1)
+            .return_const(());
+        notify
+            .expect_notify()
+            .with(
+                eq(other.clone()),
+                eq(BlockHeight::from(7u32)),
+                eq(from),
+                eq(TokenAmount::from(5u32)),
+            )
+            .times(1)
+            .return_const(());
+
+        notify.notify_outputs(
+            &[
+                (canister.clone(), 10u32.into()),
+                (other, 5u32.into()),
+                (canister, 20u32.into()),
+            ],
+            &7u32.into(),
+            &from,
+        );
+    }
+}
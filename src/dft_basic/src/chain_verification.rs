@@ -0,0 +1,170 @@
+use std::cell::{Cell, RefCell};
+use std::sync::Arc;
+use std::time::Duration;
+
+use ic_cdk::api;
+use ic_cdk::export::Principal;
+use ic_cdk_timers::TimerId;
+use log::error;
+use num_traits::ToPrimitive;
+
+use dft_types::constants::{
+    CHAIN_VERIFICATION_BLOCKS_PER_ROUND, CHAIN_VERIFICATION_HOLDERS_PER_ROUND,
+    CHAIN_VERIFICATION_RETRY_SECONDS, MAX_BLOCKS_PER_REQUEST,
+};
+use dft_types::*;
+
+use crate::canister_api::*;
+use crate::service::basic_service;
+use crate::state::STATE;
+
+thread_local! {
+    static CHAIN_VERIFICATION_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+    // a round awaiting an archive must not run concurrently with the next one
+    static ROUND_IN_PROGRESS: Cell<bool> = const { Cell::new(false) };
+}
+
+// Run the verification rounds one after the other while a verification is running.
+// Timers do not survive upgrades, so this is also called from post_upgrade.
+#[cfg_attr(coverage_nightly, no_coverage)]
+pub fn start_chain_verification_timer() {
+    if !STATE.with(|s| s.chain_verifier.borrow().is_running()) {
+        return;
+    }
+    schedule_round(Duration::ZERO);
+}
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+fn schedule_round(delay: Duration) {
+    let timer_id = ic_cdk_timers::set_timer(delay, || {
+        if ROUND_IN_PROGRESS.with(|r| r.replace(true)) {
+            return;
+        }
+        ic_cdk::spawn(async {
+            let service = ChainVerificationService::new(api::id());
+            let running = service.run_round(api::time()).await;
+            ROUND_IN_PROGRESS.with(|r| r.set(false));
+            if running {
+                let failed =
+                    STATE.with(|s| s.chain_verifier.borrow().progress().last_error.is_some());
+                schedule_round(if failed {
+                    Duration::from_secs(CHAIN_VERIFICATION_RETRY_SECONDS)
+                } else {
+                    Duration::ZERO
+                });
+            }
+        })
+    });
+    CHAIN_VERIFICATION_TIMER.with(|t| {
+        if let Some(previous) = t.borrow_mut().replace(timer_id) {
+            ic_cdk_timers::clear_timer(previous);
+        }
+    });
+}
+
+pub struct ChainVerificationService {
+    pub token_id: Principal,
+    pub dft_tx_storage: Arc<dyn IDFTTxStorageAPI>,
+}
+
+impl ChainVerificationService {
+    pub fn new(token_id: Principal) -> Self {
+        Self {
+            token_id,
+            dft_tx_storage: Arc::new(DFTTxStorageAPI),
+        }
+    }
+
+    // One round: replay the next archived blocks, or the next local blocks,
+    // or once the chain tip is reached, compare the next balances.
+    // Returns whether the verification is still running.
+    pub async fn run_round(&self, now: u64) -> bool {
+        if !STATE.with(|s| s.chain_verifier.borrow().is_running()) {
+            return false;
+        }
+        let (next_block_height, num_archived_blocks) = STATE.with(|s| {
+            (
+                s.chain_verifier
+                    .borrow()
+                    .progress()
+                    .next_block_height
+                    .clone(),
+                s.blockchain.borrow().num_archived_blocks(),
+            )
+        });
+
+        if next_block_height < num_archived_blocks {
+            let storage_canister_id =
+                match basic_service::block_by_height(next_block_height.clone()) {
+                    BlockResult::Forward(canister_id) => canister_id,
+                    _ => {
+                        self.record_error(format!(
+                            "no archive stores the block {}",
+                            next_block_height
+                        ));
+                        return true;
+                    }
+                };
+            let size = (num_archived_blocks - &next_block_height)
+                .to_usize()
+                .unwrap_or(usize::MAX)
+                .min(MAX_BLOCKS_PER_REQUEST as usize);
+            match self
+                .dft_tx_storage
+                .encoded_blocks_by_query(storage_canister_id, next_block_height.clone(), size)
+                .await
+            {
+                Ok(blocks) if !blocks.is_empty() => STATE.with(|s| {
+                    s.chain_verifier
+                        .borrow_mut()
+                        .replay_blocks(&self.token_id, &blocks, now)
+                }),
+                Ok(_) => self.record_error(format!(
+                    "the archive {} returned no block from {}",
+                    storage_canister_id, next_block_height
+                )),
+                Err(e) => self.record_error(e.to_string()),
+            }
+        } else {
+            // the local blocks and the live balances are read in one go,
+            // so the comparison only starts at the chain tip
+            STATE.with(|s| {
+                let blockchain = s.blockchain.borrow();
+                let mut verifier = s.chain_verifier.borrow_mut();
+                let next_block_height = verifier.progress().next_block_height.clone();
+                if next_block_height < blockchain.chain_length() {
+                    let start = (next_block_height - blockchain.num_archived_blocks())
+                        .to_u64()
+                        .unwrap();
+                    let end = blockchain
+                        .num_unarchived_blocks()
+                        .min(start + CHAIN_VERIFICATION_BLOCKS_PER_ROUND);
+                    let blocks: Vec<EncodedBlock> = (start..end)
+                        .map(|index| blockchain.blocks.get(index).unwrap())
+                        .collect();
+                    verifier.replay_blocks(&self.token_id, &blocks, now);
+                } else {
+                    if verifier.progress().state == ChainVerificationState::Replaying {
+                        verifier.finish_replay(blockchain.last_hash, now);
+                    }
+                    if verifier.is_running() {
+                        verifier.compare_balances(
+                            &s.balances.borrow(),
+                            CHAIN_VERIFICATION_HOLDERS_PER_ROUND,
+                            now,
+                        );
+                    }
+                }
+            });
+        }
+        STATE.with(|s| s.chain_verifier.borrow().is_running())
+    }
+
+    fn record_error(&self, error: String) {
+        error!("chain verification round failed: {}", error);
+        STATE.with(|s| s.chain_verifier.borrow_mut().record_error(Some(error)));
+    }
+}
+
+#[cfg(test)]
+mod tests;
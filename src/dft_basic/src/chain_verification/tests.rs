@@ -0,0 +1,338 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use candid::Principal;
+use mockall::mock;
+use num_bigint::BigUint;
+use num_traits::ToPrimitive;
+use rstest::*;
+
+use dft_types::constants::DEFAULT_FEE_RATE_DECIMALS;
+use dft_types::*;
+
+use crate::canister_api::*;
+use crate::service::{basic_service, blockchain_service, management_service};
+use crate::state::STATE;
+
+use super::ChainVerificationService;
+
+#[fixture]
+fn test_owner() -> Principal {
+    Principal::from_text("czjfo-ddpvm-6sibl-6zbox-ee5zq-bx3hc-e336t-s6pka-dupmy-wcxqi-fae").unwrap()
+}
+
+#[fixture]
+fn other_caller() -> Principal {
+    Principal::from_text("qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe").unwrap()
+}
+
+#[fixture]
+fn test_token_id() -> Principal {
+    Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()
+}
+
+#[fixture]
+fn test_storage_id() -> Principal {
+    Principal::from_text("rkp4c-7iaaa-aaaaa-aaaca-cai").unwrap()
+}
+
+#[fixture]
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
+
+mock! {
+    pub DFTTxStorageAPI {
+    }
+    #[async_trait]
+    impl IDFTTxStorageAPI for DFTTxStorageAPI {
+        async fn batch_append(&self, storage_canister_id: Principal, start_block_height: BlockHeight, blocks: std::collections::VecDeque<EncodedBlock>) -> CommonResult<()>;
+        async fn encoded_blocks_by_query(&self, storage_canister_id: Principal, start_block_height: BlockHeight, size: usize) -> CommonResult<Vec<EncodedBlock>>;
+    }
+}
+
+// As canister_init does: the owner account is the fee recipient and the
+// initial supply is minted to it in the first block.
+// The owner then pays the other caller 10 times and approves them once.
+fn test_chain(owner: Principal, other_caller: Principal, now: u64) {
+    dft_utils::ic_logger::init_test_logger();
+    let owner_holder = TokenHolder::new(owner, None);
+    basic_service::token_initialize(
+        &owner,
+        test_token_id(),
+        None,
+        "Deland Labs Token".to_string(),
+        "DLT".to_string(),
+        18,
+        InnerTokenFee {
+            minimum: 2u32.into(),
+            rate: 0,
+            rate_decimals: DEFAULT_FEE_RATE_DECIMALS,
+        },
+        owner_holder,
+        None,
+    );
+    STATE.with(|s| {
+        let settings = s.token_setting.borrow();
+        let tx = InnerTransaction {
+            operation: InnerOperation::Transfer {
+                caller: owner_holder,
+                from: TokenHolder::empty(),
+                to: owner_holder,
+                value: 100_000u32.into(),
+                fee: 0u32.into(),
+            },
+            created_at: now,
+        };
+        s.blockchain
+            .borrow_mut()
+            .add_tx_to_block(settings.token_id(), tx, now)
+            .unwrap();
+        s.balances
+            .borrow_mut()
+            .credit_balance(&owner_holder, 100_000u32.into());
+    });
+    let other_holder = TokenHolder::new(other_caller, None);
+    for i in 0..10u64 {
+        basic_service::transfer(
+            &owner,
+            None,
+            &owner_holder,
+            &other_holder,
+            100u32.into(),
+            None,
+            now + i,
+        )
+        .unwrap();
+    }
+    basic_service::approve(
+        &owner,
+        &owner_holder,
+        &other_holder,
+        500u32.into(),
+        None,
+        now + 10,
+    )
+    .unwrap();
+}
+
+// moves the first `len` blocks to the archive canister, as the archiver does
+fn archive_first_blocks(len: usize, storage_canister_id: Principal, now: u64) {
+    assert!(blockchain_service::lock_for_archiving(now));
+    blockchain_service::pre_append_scaling_storage_canister(storage_canister_id);
+    blockchain_service::append_scaling_storage_canister(storage_canister_id);
+    blockchain_service::update_scaling_storage_blocks_range(0, (len - 1).into());
+    blockchain_service::remove_archived_blocks(len);
+    blockchain_service::unlock_after_archiving();
+}
+
+async fn run_to_end(service: &ChainVerificationService, now: u64) -> ChainVerification {
+    for _ in 0..100 {
+        if !service.run_round(now).await {
+            break;
+        }
+    }
+    basic_service::chain_verification()
+}
+
+#[rstest]
+fn test_verify_chain_only_owner(test_owner: Principal, other_caller: Principal, now: u64) {
+    test_chain(test_owner, other_caller, now);
+
+    assert_eq!(
+        management_service::verify_chain(&other_caller, now),
+        Err(DFTError::OnlyOwnerAllowCallIt)
+    );
+    assert_eq!(
+        basic_service::chain_verification().state,
+        ChainVerificationState::NotStarted
+    );
+}
+
+#[rstest]
+async fn test_verify_local_chain(test_owner: Principal, other_caller: Principal, now: u64) {
+    test_chain(test_owner, other_caller, now);
+    let service = ChainVerificationService::new(test_token_id());
+
+    assert_eq!(management_service::verify_chain(&test_owner, now), Ok(true));
+    let verification = run_to_end(&service, now).await;
+    assert_eq!(verification.state, ChainVerificationState::Completed);
+    assert_eq!(verification.verified_blocks, 12u32);
+    assert_eq!(verification.compared_holders, 2);
+    assert!(verification.mismatches.is_empty());
+
+    // a balance changed without a block is reported by the next verification
+    let other_holder = TokenHolder::new(other_caller, None);
+    STATE.with(|s| {
+        s.balances
+            .borrow_mut()
+            .credit_balance(&other_holder, 1u32.into())
+    });
+    management_service::verify_chain(&test_owner, now).unwrap();
+    let verification = run_to_end(&service, now).await;
+    assert_eq!(verification.state, ChainVerificationState::Failed);
+    assert_eq!(
+        verification.mismatches[0].account,
+        Some(other_holder.to_hex())
+    );
+    assert_eq!(verification.mismatches.len(), 2);
+}
+
+// Without an initial supply the first block is an ordinary mint, the fees
+// go to the owner account until the fee recipient is changed.
+#[rstest]
+async fn test_verify_chain_without_initial_supply(
+    test_owner: Principal,
+    other_caller: Principal,
+    now: u64,
+) {
+    dft_utils::ic_logger::init_test_logger();
+    let owner_holder = TokenHolder::new(test_owner, None);
+    let other_holder = TokenHolder::new(other_caller, None);
+    let fee_holder = TokenHolder::new(
+        "o5y7v-htz2q-vk7fc-cqi4m-bqvwa-eth75-sc2wz-ubuev-curf2-rbipe-tae"
+            .parse()
+            .unwrap(),
+        None,
+    );
+    basic_service::token_initialize(
+        &test_owner,
+        test_token_id(),
+        None,
+        "Deland Labs Token".to_string(),
+        "DLT".to_string(),
+        18,
+        InnerTokenFee {
+            minimum: 2u32.into(),
+            rate: 0,
+            rate_decimals: DEFAULT_FEE_RATE_DECIMALS,
+        },
+        owner_holder,
+        None,
+    );
+    STATE.with(|s| {
+        let settings = s.token_setting.borrow();
+        let tx = InnerTransaction {
+            operation: InnerOperation::Transfer {
+                caller: owner_holder,
+                from: TokenHolder::empty(),
+                to: other_holder,
+                value: 1000u32.into(),
+                fee: 0u32.into(),
+            },
+            created_at: now,
+        };
+        s.blockchain
+            .borrow_mut()
+            .add_tx_to_block(settings.token_id(), tx, now)
+            .unwrap();
+        s.balances
+            .borrow_mut()
+            .credit_balance(&other_holder, 1000u32.into());
+    });
+    basic_service::transfer(
+        &other_caller,
+        None,
+        &other_holder,
+        &owner_holder,
+        100u32.into(),
+        None,
+        now + 1,
+    )
+    .unwrap();
+    management_service::set_fee_to(&test_owner, fee_holder, None, now + 2).unwrap();
+    basic_service::transfer(
+        &other_caller,
+        None,
+        &other_holder,
+        &owner_holder,
+        100u32.into(),
+        None,
+        now + 3,
+    )
+    .unwrap();
+    assert_eq!(basic_service::balance_of(&owner_holder), 202u32.into());
+    assert_eq!(basic_service::balance_of(&fee_holder), 2u32.into());
+
+    let service = ChainVerificationService::new(test_token_id());
+    management_service::verify_chain(&test_owner, now + 4).unwrap();
+    let verification = run_to_end(&service, now + 4).await;
+    assert!(
+        verification.mismatches.is_empty(),
+        "{:?}",
+        verification.mismatches
+    );
+    assert_eq!(verification.state, ChainVerificationState::Completed);
+    assert_eq!(verification.verified_blocks, 4u32);
+    assert_eq!(verification.compared_holders, 3);
+}
+
+#[rstest]
+async fn test_verify_archived_chain(
+    test_owner: Principal,
+    other_caller: Principal,
+    test_storage_id: Principal,
+    now: u64,
+) {
+    test_chain(test_owner, other_caller, now);
+    let archived: Vec<EncodedBlock> = STATE.with(|s| {
+        let blockchain = s.blockchain.borrow();
+        (0..8).map(|i| blockchain.blocks.get(i).unwrap()).collect()
+    });
+    archive_first_blocks(8, test_storage_id, now);
+
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let mut mock_dft_tx_storage_api = MockDFTTxStorageAPI::new();
+    let recorded_requests = requests.clone();
+    mock_dft_tx_storage_api
+        .expect_encoded_blocks_by_query()
+        .returning(move |canister_id, start: BigUint, size| {
+            assert_eq!(canister_id, test_storage_id);
+            recorded_requests
+                .lock()
+                .unwrap()
+                .push((start.clone(), size));
+            let start = start.to_usize().unwrap();
+            Ok(archived[start..(start + size).min(8)].to_vec())
+        });
+    let mut service = ChainVerificationService::new(test_token_id());
+    service.dft_tx_storage = Arc::new(mock_dft_tx_storage_api);
+
+    management_service::verify_chain(&test_owner, now).unwrap();
+    let verification = run_to_end(&service, now).await;
+    assert_eq!(verification.state, ChainVerificationState::Completed);
+    assert_eq!(verification.verified_blocks, 12u32);
+    assert_eq!(*requests.lock().unwrap(), vec![(BigUint::from(0u32), 8)]);
+}
+
+#[rstest]
+async fn test_verify_chain_retries_unreachable_archive(
+    test_owner: Principal,
+    other_caller: Principal,
+    test_storage_id: Principal,
+    now: u64,
+) {
+    test_chain(test_owner, other_caller, now);
+    archive_first_blocks(8, test_storage_id, now);
+
+    let mut mock_dft_tx_storage_api = MockDFTTxStorageAPI::new();
+    mock_dft_tx_storage_api
+        .expect_encoded_blocks_by_query()
+        .returning(|_, _, _| {
+            Err(DFTError::Unknown {
+                detail: "unreachable".to_string(),
+            })
+        });
+    let mut service = ChainVerificationService::new(test_token_id());
+    service.dft_tx_storage = Arc::new(mock_dft_tx_storage_api);
+
+    management_service::verify_chain(&test_owner, now).unwrap();
+    assert!(service.run_round(now).await);
+    let verification = basic_service::chain_verification();
+    assert_eq!(verification.state, ChainVerificationState::Replaying);
+    assert_eq!(verification.verified_blocks, 0u32);
+    assert!(verification.last_error.is_some());
+}
@@ -0,0 +1,33 @@
+use std::cell::RefCell;
+use std::time::Duration;
+
+use ic_cdk::api;
+use ic_cdk_timers::TimerId;
+use log::error;
+
+use dft_types::constants::{HOLD_EXPIRATION_CHECK_INTERVAL_SECONDS, MAX_EXPIRED_HOLDS_PER_CHECK};
+
+use crate::service::hold_service;
+
+thread_local! {
+    static HOLD_EXPIRATION_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+}
+
+// (Re)start the timer returning the expired holds to their owners,
+// like the archiving timer it must be started from init and post_upgrade.
+#[cfg_attr(coverage_nightly, no_coverage)]
+pub fn start_hold_expiration_timer() {
+    let interval = Duration::from_secs(HOLD_EXPIRATION_CHECK_INTERVAL_SECONDS);
+    let timer_id = ic_cdk_timers::set_timer_interval(interval, || {
+        for res in hold_service::release_expired_holds(api::time(), MAX_EXPIRED_HOLDS_PER_CHECK) {
+            if let Err(e) = res {
+                error!("release expired hold failed, {}", e);
+            }
+        }
+    });
+    HOLD_EXPIRATION_TIMER.with(|t| {
+        if let Some(previous) = t.borrow_mut().replace(timer_id) {
+            ic_cdk_timers::clear_timer(previous);
+        }
+    });
+}
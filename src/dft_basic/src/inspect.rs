@@ -5,18 +5,25 @@ use ic_cdk::api;
 use ic_cdk_macros::inspect_message;
 use log::{error, info};
 
-static QUERY_METHODS: [&str; 19] = [
+static QUERY_METHODS: [&str; 26] = [
     "allowance",
     "allowancesOf",
+    "allowancesBySpender",
     "archives",
+    "archivingStatus",
     "balanceOf",
     "decimals",
     "desc",
     "fee",
+    "heldBalanceOf",
+    "holdById",
     "logo",
     "meta",
     "minters",
     "name",
+    "operatorsOf",
+    "permitNonce",
+    "recurringAllowance",
     "owner",
     "symbol",
     "tokenInfo",
@@ -27,8 +34,18 @@ static QUERY_METHODS: [&str; 19] = [
     "__get_candid_interface_tmp_hack",
 ];
 
-static OWNER_METHODS: [&str; 6] = [
-    "setDesc", "setFee", "setFeeTo", "setFeeTo", "setLogo", "setOwner",
+static OWNER_METHODS: [&str; 11] = [
+    "setDesc",
+    "setFee",
+    "setFeeTo",
+    "setFeeTo",
+    "setLogo",
+    "setOwner",
+    "retryArchiving",
+    "resetArchiving",
+    "upgradeArchives",
+    "setArchiveOptions",
+    "verifyChain",
 ];
 static HOLDER_METHODS: [&str; 3] = ["approve", "transfer", "burn"];
 
@@ -56,14 +73,25 @@ fn inspect_message_inner(method: &String, caller: Principal) -> Result<(), Strin
                         api::call::arg_data::<(Option<Subaccount>, String, Nat)>();
                     TokenHolder::new(caller, sub_account)
                 }
+                // an operator acts on the account of the holder
                 "transfer" => {
-                    let (sub_account, _, _) =
-                        api::call::arg_data::<(Option<Subaccount>, String, Nat)>();
-                    TokenHolder::new(caller, sub_account)
+                    let (sub_account, _, _, _, on_behalf_of) = api::call::arg_data::<(
+                        Option<Subaccount>,
+                        String,
+                        Nat,
+                        Option<u64>,
+                        Option<String>,
+                    )>();
+                    acted_on_holder(caller, sub_account, on_behalf_of)
                 }
                 "burn" => {
-                    let (sub_account, _) = api::call::arg_data::<(Option<Subaccount>, Nat)>();
-                    TokenHolder::new(caller, sub_account)
+                    let (sub_account, _, _, on_behalf_of) = api::call::arg_data::<(
+                        Option<Subaccount>,
+                        Nat,
+                        Option<u64>,
+                        Option<String>,
+                    )>();
+                    acted_on_holder(caller, sub_account, on_behalf_of)
                 }
                 // never match this case
                 _ => TokenHolder::new(caller, None),
@@ -104,6 +132,17 @@ fn inspect_message_inner(method: &String, caller: Principal) -> Result<(), Strin
     }
 }
 
+fn acted_on_holder(
+    caller: Principal,
+    sub_account: Option<Subaccount>,
+    on_behalf_of: Option<String>,
+) -> TokenHolder {
+    match on_behalf_of.and_then(|holder| holder.parse::<TokenHolder>().ok()) {
+        Some(holder) => holder,
+        None => TokenHolder::new(caller, sub_account),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
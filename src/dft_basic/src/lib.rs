@@ -1,6 +1,9 @@
 #![cfg_attr(coverage_nightly, feature(no_coverage))]
 pub mod auto_scaling_storage;
 pub mod canister_api;
+pub mod chain_verification;
+pub mod hold_expiration;
 pub mod inspect;
+pub mod memory;
 pub mod service;
 pub mod state;
@@ -0,0 +1,134 @@
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, Memory as _};
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// small heap state (settings, metadata) serialized by pre_upgrade
+const UPGRADES_MEMORY_ID: MemoryId = MemoryId::new(0);
+const BALANCES_MEMORY_ID: MemoryId = MemoryId::new(1);
+const ALLOWANCES_MEMORY_ID: MemoryId = MemoryId::new(2);
+// the local blocks alternate between two stable logs
+const BLOCKS_INDEX_MEMORY_IDS: [MemoryId; 2] = [MemoryId::new(3), MemoryId::new(5)];
+const BLOCKS_DATA_MEMORY_IDS: [MemoryId; 2] = [MemoryId::new(4), MemoryId::new(6)];
+// transaction window used to detect duplicated transactions
+const TX_WINDOW_BY_HASH_MEMORY_ID: MemoryId = MemoryId::new(7);
+const TX_WINDOW_BY_HEIGHT_MEMORY_ID: MemoryId = MemoryId::new(8);
+// sparse block timestamp index used by time range queries
+const BLOCK_TIMESTAMP_INDEX_MEMORY_ID: MemoryId = MemoryId::new(9);
+// progress and replayed balances of the chain verification
+const CHAIN_VERIFICATION_MEMORY_ID: MemoryId = MemoryId::new(10);
+const CHAIN_VERIFICATION_BALANCES_MEMORY_ID: MemoryId = MemoryId::new(11);
+// spender -> owners index of the allowances
+const ALLOWANCES_BY_SPENDER_MEMORY_ID: MemoryId = MemoryId::new(12);
+// operators authorized by the holders
+const OPERATORS_MEMORY_ID: MemoryId = MemoryId::new(13);
+// the next permit nonce of the owners
+const PERMIT_NONCES_MEMORY_ID: MemoryId = MemoryId::new(14);
+// allowances of at most an amount per period
+const RECURRING_ALLOWANCES_MEMORY_ID: MemoryId = MemoryId::new(15);
+// the amounts held for payments, the open holds and their expirations
+const HELD_BALANCES_MEMORY_ID: MemoryId = MemoryId::new(16);
+const HOLDS_MEMORY_ID: MemoryId = MemoryId::new(17);
+const HOLD_EXPIRATIONS_MEMORY_ID: MemoryId = MemoryId::new(18);
+const CHAIN_VERIFICATION_HELD_MEMORY_ID: MemoryId = MemoryId::new(19);
+
+const WASM_PAGE_SIZE_BYTES: u64 = 64 * 1024;
+// the first bytes of stable memory once it is managed by the memory manager
+const MEMORY_MANAGER_MAGIC: &[u8; 3] = b"MGR";
+
+thread_local! {
+    static STABLE_MEMORY: DefaultMemoryImpl = DefaultMemoryImpl::default();
+    // initialized lazily, legacy stable memory must be read before the first access
+    static MEMORY_MANAGER: MemoryManager<DefaultMemoryImpl> =
+        MemoryManager::init(STABLE_MEMORY.with(|m| m.clone()));
+}
+
+fn get(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.get(id))
+}
+
+pub fn upgrades_memory() -> Memory {
+    get(UPGRADES_MEMORY_ID)
+}
+
+pub fn balances_memories() -> (Memory, Memory) {
+    (get(BALANCES_MEMORY_ID), get(HELD_BALANCES_MEMORY_ID))
+}
+
+pub fn holds_memories() -> (Memory, Memory) {
+    (get(HOLDS_MEMORY_ID), get(HOLD_EXPIRATIONS_MEMORY_ID))
+}
+
+pub fn allowances_memories() -> (Memory, Memory, Memory) {
+    (
+        get(ALLOWANCES_MEMORY_ID),
+        get(ALLOWANCES_BY_SPENDER_MEMORY_ID),
+        get(RECURRING_ALLOWANCES_MEMORY_ID),
+    )
+}
+
+pub fn operators_memory() -> Memory {
+    get(OPERATORS_MEMORY_ID)
+}
+
+pub fn permit_nonces_memory() -> Memory {
+    get(PERMIT_NONCES_MEMORY_ID)
+}
+
+pub fn blocks_memories() -> [(Memory, Memory); 2] {
+    [0, 1].map(|i| {
+        (
+            get(BLOCKS_INDEX_MEMORY_IDS[i]),
+            get(BLOCKS_DATA_MEMORY_IDS[i]),
+        )
+    })
+}
+
+pub fn tx_window_by_hash_memory() -> Memory {
+    get(TX_WINDOW_BY_HASH_MEMORY_ID)
+}
+
+pub fn tx_window_by_height_memory() -> Memory {
+    get(TX_WINDOW_BY_HEIGHT_MEMORY_ID)
+}
+
+pub fn block_timestamp_index_memory() -> Memory {
+    get(BLOCK_TIMESTAMP_INDEX_MEMORY_ID)
+}
+
+pub fn chain_verification_memories() -> (Memory, Memory, Memory) {
+    (
+        get(CHAIN_VERIFICATION_MEMORY_ID),
+        get(CHAIN_VERIFICATION_BALANCES_MEMORY_ID),
+        get(CHAIN_VERIFICATION_HELD_MEMORY_ID),
+    )
+}
+
+// Before the token state was kept in stable structures, pre_upgrade wrote the
+// whole bincode-serialized state at the beginning of stable memory.
+// Returns those bytes if stable memory still has that layout.
+pub fn take_legacy_state_bytes() -> Option<Vec<u8>> {
+    STABLE_MEMORY.with(|m| {
+        let size_bytes = m.size() * WASM_PAGE_SIZE_BYTES;
+        if size_bytes == 0 {
+            return None;
+        }
+        let mut magic = [0u8; 3];
+        m.read(0, &mut magic);
+        if &magic == MEMORY_MANAGER_MAGIC {
+            return None;
+        }
+        let mut bytes = vec![0u8; size_bytes as usize];
+        m.read(0, &mut bytes);
+        Some(bytes)
+    })
+}
+
+#[cfg(test)]
+pub fn write_legacy_state_bytes(bytes: &[u8]) {
+    STABLE_MEMORY.with(|m| {
+        let pages = (bytes.len() as u64).div_ceil(WASM_PAGE_SIZE_BYTES);
+        m.grow(pages);
+        m.write(0, bytes);
+    })
+}
@@ -4,11 +4,13 @@ use std::convert::TryInto;
 use candid::{Nat, Principal};
 use num_traits::{CheckedSub, ToPrimitive};
 
-use dft_types::constants::MAX_BLOCKS_PER_REQUEST;
+use dft_types::constants::{
+    MAX_ALLOWANCES_PER_REQUEST, MAX_BLOCKS_PER_REQUEST, MAX_MULTI_TRANSFER_OUTPUTS,
+};
 use dft_types::*;
 use dft_utils::*;
 
-use crate::state::STATE;
+use crate::state::{State, STATE};
 
 #[allow(clippy::too_many_arguments)]
 #[allow(clippy::clone_on_copy)]
@@ -47,7 +49,8 @@ pub fn token_initialize(
 
         let mut blockchain = s.blockchain.borrow_mut();
         if let Some(options) = archive_options {
-            blockchain.archive = Archive::new(options);
+            blockchain.archive =
+                Archive::new(options).unwrap_or_else(|e| ic_cdk::trap(&e.to_string()));
         }
     });
 }
@@ -104,8 +107,32 @@ pub fn allowance(holder: &TokenHolder, spender: &TokenHolder) -> TokenAmount {
     STATE.with(|s| s.allowances.borrow().allowance(holder, spender))
 }
 
-pub fn allowances_of(owner: &TokenHolder) -> Vec<(TokenHolder, TokenAmount)> {
-    STATE.with(|s| s.allowances.borrow().allowances_of(owner))
+pub fn allowances_of(
+    owner: &TokenHolder,
+    cursor: Option<TokenHolder>,
+    limit: usize,
+) -> Vec<(TokenHolder, TokenAmount)> {
+    STATE.with(|s| {
+        s.allowances.borrow().allowances_of(
+            owner,
+            cursor.as_ref(),
+            limit.min(MAX_ALLOWANCES_PER_REQUEST),
+        )
+    })
+}
+
+pub fn allowances_by_spender(
+    spender: &TokenHolder,
+    cursor: Option<TokenHolder>,
+    limit: usize,
+) -> Vec<(TokenHolder, TokenAmount)> {
+    STATE.with(|s| {
+        s.allowances.borrow().allowances_by_spender(
+            spender,
+            cursor.as_ref(),
+            limit.min(MAX_ALLOWANCES_PER_REQUEST),
+        )
+    })
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -116,14 +143,324 @@ pub fn approve(
     value: TokenAmount,
     created_at: Option<u64>,
     now: u64,
+) -> CommonResult<(BlockHeight, BlockHash, TransactionHash)> {
+    check_storable_amount(&value)?;
+    record_approval(
+        caller,
+        owner,
+        &value,
+        created_at,
+        now,
+        |fee| InnerOperation::Approve {
+            caller: (*caller).into(),
+            owner: *owner,
+            spender: *spender,
+            value: value.clone(),
+            fee,
+        },
+        |s| {
+            s.allowances
+                .borrow_mut()
+                .credit(owner, spender, value.clone())
+        },
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn increase_allowance(
+    caller: &Principal,
+    owner: &TokenHolder,
+    spender: &TokenHolder,
+    value: TokenAmount,
+    created_at: Option<u64>,
+    now: u64,
+) -> CommonResult<(BlockHeight, BlockHash, TransactionHash)> {
+    check_storable_amount(&(allowance(owner, spender) + value.clone()))?;
+    record_approval(
+        caller,
+        owner,
+        &value,
+        created_at,
+        now,
+        |fee| InnerOperation::IncreaseAllowance {
+            caller: (*caller).into(),
+            owner: *owner,
+            spender: *spender,
+            value: value.clone(),
+            fee,
+        },
+        |s| {
+            s.allowances
+                .borrow_mut()
+                .increase(owner, spender, value.clone())
+        },
+    )
+}
+
+// the allowance saturates at zero, so a spender spending in the meantime
+// never makes the decrease fail
+#[allow(clippy::too_many_arguments)]
+pub fn decrease_allowance(
+    caller: &Principal,
+    owner: &TokenHolder,
+    spender: &TokenHolder,
+    value: TokenAmount,
+    created_at: Option<u64>,
+    now: u64,
+) -> CommonResult<(BlockHeight, BlockHash, TransactionHash)> {
+    record_approval(
+        caller,
+        owner,
+        &value,
+        created_at,
+        now,
+        |fee| InnerOperation::DecreaseAllowance {
+            caller: (*caller).into(),
+            owner: *owner,
+            spender: *spender,
+            value: value.clone(),
+            fee,
+        },
+        |s| {
+            s.allowances
+                .borrow_mut()
+                .decrease(owner, spender, value.clone())
+        },
+    )
+}
+
+// Revoke the allowances of at most `limit` spenders of the owner, so one call
+// stays within the instruction limit whatever the number of spenders.
+// The block lists the revoked spenders, so the chain can be replayed.
+// Also returns whether the owner still has allowances to revoke.
+pub fn revoke_all_allowances(
+    caller: &Principal,
+    owner: &TokenHolder,
+    limit: usize,
+    created_at: Option<u64>,
+    now: u64,
+) -> CommonResult<((BlockHeight, BlockHash, TransactionHash), bool)> {
+    let (spenders, has_more) = STATE.with(|s| s.allowances.borrow().spenders_of(owner, limit));
+    if spenders.is_empty() {
+        return Err(DFTError::NoAllowancesToRevoke);
+    }
+    let res = record_approval(
+        caller,
+        owner,
+        &TokenAmount::default(),
+        created_at,
+        now,
+        |fee| InnerOperation::RevokeAllAllowances {
+            caller: (*caller).into(),
+            owner: *owner,
+            spenders: spenders.clone(),
+            fee,
+        },
+        |s| s.allowances.borrow_mut().revoke(owner, &spenders),
+    )?;
+    Ok((res, has_more))
+}
+
+pub fn recurring_allowance(
+    owner: &TokenHolder,
+    spender: &TokenHolder,
+    now: u64,
+) -> Option<RecurringAllowance> {
+    STATE.with(|s| {
+        s.allowances
+            .borrow()
+            .recurring_allowance(owner, spender)
+            .map(|allowance| allowance.to_candid(now))
+    })
+}
+
+// Allow the spender to spend at most `amount_per_period` in each period,
+// the periods start at `start` (now by default).
+// The spender can no longer use its one-shot allowance while this one exists,
+// a zero amount removes it.
+#[allow(clippy::too_many_arguments)]
+pub fn approve_recurring(
+    caller: &Principal,
+    owner: &TokenHolder,
+    spender: &TokenHolder,
+    amount_per_period: TokenAmount,
+    period: u64,
+    start: Option<u64>,
+    created_at: Option<u64>,
+    now: u64,
+) -> CommonResult<(BlockHeight, BlockHash, TransactionHash)> {
+    check_storable_amount(&amount_per_period)?;
+    let revoked = amount_per_period == TokenAmount::default();
+    if period == 0 && !revoked {
+        return Err(DFTError::InvalidRecurringPeriod);
+    }
+    let start = start.unwrap_or(now);
+    record_approval(
+        caller,
+        owner,
+        &amount_per_period,
+        created_at,
+        now,
+        |fee| InnerOperation::ApproveRecurring {
+            caller: (*caller).into(),
+            owner: *owner,
+            spender: *spender,
+            amount_per_period: amount_per_period.clone(),
+            period,
+            start,
+            fee,
+        },
+        |s| {
+            let allowance = if revoked {
+                None
+            } else {
+                Some(InnerRecurringAllowance::new(
+                    amount_per_period.clone(),
+                    period,
+                    start,
+                ))
+            };
+            s.allowances
+                .borrow_mut()
+                .set_recurring(owner, spender, allowance)
+        },
+    )
+}
+
+pub fn permit_nonce(owner: &TokenHolder) -> u64 {
+    STATE.with(|s| s.permit_nonces.borrow().nonce(owner))
+}
+
+// Approve on behalf of the owner who signed the permit.
+// Each nonce of an owner can be used once, in order.
+pub fn permit(
+    permit: &Permit,
+    now: u64,
+) -> CommonResult<(BlockHeight, BlockHash, TransactionHash)> {
+    if now > permit.deadline {
+        return Err(DFTError::PermitExpired);
+    }
+    let (signer, owner) = permit.verify(&token_id())?;
+    STATE.with(|s| s.permit_nonces.borrow().check(&owner, permit.nonce))?;
+    let res = approve(
+        &signer,
+        &owner,
+        &permit.spender,
+        permit.value.0.clone(),
+        None,
+        now,
+    )?;
+    STATE.with(|s| s.permit_nonces.borrow_mut().consume(&owner, permit.nonce))?;
+    Ok(res)
+}
+
+pub fn operators_of(holder: &TokenHolder, now: u64) -> Vec<OperatorInfo> {
+    STATE.with(|s| s.operators.borrow().operators_of(holder, now))
+}
+
+// the operator can move all the funds of the holder until `expires_at`
+pub fn authorize_operator(
+    caller: &Principal,
+    holder: &TokenHolder,
+    operator: &Principal,
+    expires_at: Option<u64>,
+    created_at: Option<u64>,
+    now: u64,
+) -> CommonResult<(BlockHeight, BlockHash, TransactionHash)> {
+    if matches!(expires_at, Some(expires_at) if expires_at <= now) {
+        return Err(DFTError::InvalidOperatorExpiration);
+    }
+    record_approval(
+        caller,
+        holder,
+        &TokenAmount::default(),
+        created_at,
+        now,
+        |fee| InnerOperation::AuthorizeOperator {
+            caller: (*caller).into(),
+            holder: *holder,
+            operator: *operator,
+            expires_at,
+            fee,
+        },
+        |s| {
+            s.operators
+                .borrow_mut()
+                .authorize(holder, operator, expires_at)
+        },
+    )
+}
+
+pub fn revoke_operator(
+    caller: &Principal,
+    holder: &TokenHolder,
+    operator: &Principal,
+    created_at: Option<u64>,
+    now: u64,
+) -> CommonResult<(BlockHeight, BlockHash, TransactionHash)> {
+    record_approval(
+        caller,
+        holder,
+        &TokenAmount::default(),
+        created_at,
+        now,
+        |fee| InnerOperation::RevokeOperator {
+            caller: (*caller).into(),
+            holder: *holder,
+            operator: *operator,
+            fee,
+        },
+        |s| {
+            s.operators.borrow_mut().revoke(holder, operator);
+        },
+    )
+}
+
+// the caller must be an operator of the holder
+pub fn check_operator(caller: &Principal, holder: &TokenHolder, now: u64) -> CommonResult<()> {
+    STATE.with(|s| {
+        s.token_setting.borrow().not_allow_anonymous(caller)?;
+        if s.operators.borrow().is_operator(holder, caller, now) {
+            Ok(())
+        } else {
+            Err(DFTError::NotAuthorizedOperator)
+        }
+    })
+}
+
+// The caller's account, which is recorded as the caller of a transaction on `from`.
+// When `from` is not that account the caller must be an operator of `from`.
+pub fn tx_invoker(
+    caller: &Principal,
+    caller_sub_account: Option<Subaccount>,
+    from: &TokenHolder,
+    now: u64,
+) -> CommonResult<TokenHolder> {
+    let invoker = TokenHolder::new(*caller, caller_sub_account);
+    if invoker != *from {
+        check_operator(caller, from, now)?;
+    }
+    Ok(invoker)
+}
+
+// Record an operation approving spenders or operators of the owner,
+// who pays the approve fee.
+#[allow(clippy::too_many_arguments)]
+fn record_approval(
+    caller: &Principal,
+    owner: &TokenHolder,
+    value: &TokenAmount,
+    created_at: Option<u64>,
+    now: u64,
+    operation: impl FnOnce(TokenAmount) -> InnerOperation,
+    change: impl FnOnce(&State),
 ) -> CommonResult<(BlockHeight, BlockHash, TransactionHash)> {
     verified_created_at(&created_at, &now)?;
     let mut approve_fee: TokenAmount = 0u32.into();
     let res = STATE.with(|s| {
         let settings = s.token_setting.borrow();
         let mut blockchain = s.blockchain.borrow_mut();
-        let mut allowances = s.allowances.borrow_mut();
-        let balances = s.balances.borrow_mut();
+        let balances = s.balances.borrow();
         settings.not_allow_anonymous(caller)?;
         let num_purged = blockchain.tx_window.purge_old_transactions(now);
         if num_purged == 0 {
@@ -131,22 +468,16 @@ pub fn approve(
         }
 
         let created_at = created_at.unwrap_or(now);
-        approve_fee = settings.fee().calc_approve_fee(&value);
+        approve_fee = settings.fee().calc_approve_fee(value);
         if balances.balance_of(owner) < approve_fee {
             Err(DFTError::InsufficientBalance)
         } else {
             let tx = InnerTransaction {
-                operation: InnerOperation::Approve {
-                    caller: (*caller).into(),
-                    owner: *owner,
-                    spender: *spender,
-                    value: value.clone(),
-                    fee: approve_fee.clone(),
-                },
+                operation: operation(approve_fee.clone()),
                 created_at,
             };
             let res = blockchain.add_tx_to_block(settings.token_id(), tx, now)?;
-            allowances.credit(owner, spender, value.clone());
+            change(s);
             Ok(res)
         }
     })?;
@@ -172,8 +503,8 @@ pub fn transfer_from(
         let allowances = s.allowances.borrow();
         settings.not_allow_anonymous(caller)?;
         let transfer_fee = calc_transfer_fee(&value);
-        // get spenders allowance
-        let spender_allowance = allowances.allowance(from, spender);
+        // get spenders allowance, the remainder of the current period for a recurring one
+        let spender_allowance = allowances.spendable(from, spender, now);
         let decreased_allowance = value.clone() + transfer_fee;
         // check allowance
         if spender_allowance < decreased_allowance {
@@ -188,14 +519,18 @@ pub fn transfer_from(
     STATE.with(|s| {
         let mut allowances = s.allowances.borrow_mut();
         // debit the spender's allowance
-        allowances.debit(from, spender, decreased_allowance)
+        allowances.spend(from, spender, decreased_allowance, now)
     })?;
 
     Ok(transfer_res)
 }
 
+// Transfer from the caller's own account, or from the account of a holder
+// who authorized the caller as an operator.
+#[allow(clippy::too_many_arguments)]
 pub fn transfer(
     caller: &Principal,
+    caller_sub_account: Option<Subaccount>,
     from: &TokenHolder,
     to: &TokenHolder,
     value: TokenAmount,
@@ -204,8 +539,66 @@ pub fn transfer(
 ) -> CommonResult<(BlockHeight, BlockHash, TransactionHash)> {
     verified_created_at(&created_at, &now)?;
     STATE.with(|s| s.token_setting.borrow().not_allow_anonymous(caller))?;
+    let invoker = tx_invoker(caller, caller_sub_account, from, now)?;
     let created_at = created_at.unwrap_or(now);
-    _transfer(from, from, to, value, created_at, now)
+    _transfer(&invoker, from, to, value, created_at, now)
+}
+
+// Transfer to all the outputs in one block.
+// The transfer fee is computed once, on the total value.
+pub fn multi_transfer(
+    caller: &Principal,
+    from: &TokenHolder,
+    outputs: Vec<(TokenHolder, TokenAmount)>,
+    created_at: Option<u64>,
+    now: u64,
+) -> CommonResult<(BlockHeight, BlockHash, TransactionHash)> {
+    verified_created_at(&created_at, &now)?;
+    if outputs.is_empty() || outputs.len() > MAX_MULTI_TRANSFER_OUTPUTS {
+        return Err(DFTError::InvalidMultiTransferOutputs);
+    }
+    let created_at = created_at.unwrap_or(now);
+    let total_value = outputs
+        .iter()
+        .fold(TokenAmount::default(), |total, (_, value)| total + value);
+    check_storable_amount(&total_value)?;
+    let transfer_fee = calc_transfer_fee(&total_value);
+    let res = STATE.with(|s| {
+        let settings = s.token_setting.borrow();
+        let mut balances = s.balances.borrow_mut();
+        let mut blockchain = s.blockchain.borrow_mut();
+        settings.not_allow_anonymous(caller)?;
+
+        let num_purged = blockchain.tx_window.purge_old_transactions(now);
+        if num_purged == 0 {
+            blockchain.tx_window.throttle_check(now)?
+        }
+
+        if balances.balance_of(from) < total_value.clone() + transfer_fee.clone() {
+            Err(DFTError::InsufficientBalance)
+        } else {
+            let tx = InnerTransaction {
+                operation: InnerOperation::MultiTransfer {
+                    caller: *from,
+                    from: *from,
+                    outputs: outputs.clone(),
+                    fee: transfer_fee.clone(),
+                },
+                created_at,
+            };
+            let res = blockchain.add_tx_to_block(settings.token_id(), tx, now)?;
+            balances.debit_balance(from, total_value)?;
+            for (to, value) in outputs {
+                balances.credit_balance(&to, value);
+            }
+            Ok(res)
+        }
+    });
+
+    if res.is_ok() && transfer_fee > 0u32.into() {
+        charge_transfer_fee(from, transfer_fee)?;
+    }
+    res
 }
 
 pub fn token_info() -> TokenInfo {
@@ -235,9 +628,11 @@ pub fn token_metrics() -> TokenMetrics {
         TokenMetrics {
             holders: balances.holder_count(),
             chain_length: blockchain.chain_length().into(),
-            local_block_count: (blockchain.blocks.len() as u64).into(),
+            local_block_count: blockchain.blocks.len().into(),
             allowance_size: allowances.allowance_size(),
             cycles_balance: Nat::from(0u64),
+            archives_low_on_cycles: blockchain.archive.archives_low_on_cycles(),
+            archives_with_cycles_errors: blockchain.archive.archives_with_cycles_errors(),
             certificate: None,
         }
     })
@@ -262,15 +657,15 @@ pub fn block_by_height(block_height: BlockHeight) -> BlockResult {
                 }
             });
             return match result {
-                Ok(i) => BlockResult::Forward(index[i].1),
+                Ok(i) => BlockResult::Forward(blockchain.archive.readable_replica(&index[i].1)),
                 Err(_) => BlockResult::Err(DFTError::NonExistentBlockHeight.into()),
             };
         }
 
-        let inner_index: usize = block_height
+        let inner_index: u64 = block_height
             .checked_sub(&blockchain.num_archived_blocks())
             .unwrap()
-            .to_usize()
+            .to_u64()
             .unwrap();
 
         match blockchain.blocks.get(inner_index) {
@@ -294,22 +689,24 @@ pub fn blocks_by_query(start: BlockHeight, count: usize) -> QueryBlocksResult {
         );
 
         let local_blocks: Vec<Block> = if !effective_local_range.is_empty() {
-            let local_start: usize = effective_local_range
+            let local_start: u64 = effective_local_range
                 .start
                 .clone()
                 .checked_sub(&local_range.start)
                 .unwrap()
-                .to_usize()
+                .to_u64()
                 .unwrap();
-            let range_len: usize = range_utils::range_len(&effective_local_range)
-                .to_usize()
+            let range_len: u64 = range_utils::range_len(&effective_local_range)
+                .to_u64()
                 .unwrap();
             let local_end = local_start + range_len;
 
-            blockchain.blocks[local_start..local_end]
-                .iter()
-                .map(|enc_block| -> Block {
-                    enc_block
+            (local_start..local_end)
+                .map(|index| -> Block {
+                    blockchain
+                        .blocks
+                        .get(index)
+                        .expect("bug: local block out of range")
                         .decode()
                         .expect("bug: failed to decode encoded block")
                         .into()
@@ -325,22 +722,7 @@ pub fn blocks_by_query(start: BlockHeight, count: usize) -> QueryBlocksResult {
             requested_range.start..effective_local_range.start.clone()
         };
 
-        let archived_blocks = blockchain
-            .archive
-            .index()
-            .iter()
-            .filter_map(|((from, to), canister_id)| {
-                let slice = range_utils::intersect(
-                    &(from.clone()..to.clone() + 1u32),
-                    &archived_blocks_range,
-                );
-                (!slice.is_empty()).then(|| ArchivedBlocksRange {
-                    start: slice.start.clone().into(),
-                    length: range_utils::range_len(&slice).try_into().unwrap(),
-                    storage_canister_id: *canister_id,
-                })
-            })
-            .collect();
+        let archived_blocks = archived_blocks_ranges(&blockchain.archive, &archived_blocks_range);
 
         let chain_length = blockchain.chain_length();
 
@@ -354,6 +736,75 @@ pub fn blocks_by_query(start: BlockHeight, count: usize) -> QueryBlocksResult {
     })
 }
 
+// the parts of `range` stored by each archive canister, read from a reachable replica
+fn archived_blocks_ranges(
+    archive: &Archive,
+    range: &std::ops::Range<BlockHeight>,
+) -> Vec<ArchivedBlocksRange> {
+    archive
+        .index()
+        .iter()
+        .filter_map(|((from, to), replicas)| {
+            let slice = range_utils::intersect(&(from.clone()..to.clone() + 1u32), range);
+            (!slice.is_empty()).then(|| ArchivedBlocksRange {
+                start: slice.start.clone().into(),
+                length: range_utils::range_len(&slice).try_into().unwrap(),
+                storage_canister_id: archive.readable_replica(replicas),
+            })
+        })
+        .collect()
+}
+
+// Blocks whose timestamp is in [start_ts, end_ts), starting from the `cursor` height.
+// Only the local blocks are returned, the archived ones are forwarded to the archive canisters.
+pub fn blocks_by_time_range(
+    start_ts: u64,
+    end_ts: u64,
+    limit: usize,
+    cursor: Option<BlockHeight>,
+) -> CommonResult<BlocksByTimeRange> {
+    if start_ts > end_ts {
+        return Err(DFTError::InvalidTimeRange);
+    }
+    let limit = MAX_BLOCKS_PER_REQUEST.min(limit as u32) as usize;
+    STATE.with(|s| {
+        let blockchain = s.blockchain.borrow();
+        let chain_length = blockchain.chain_length().to_u64().unwrap();
+        let num_archived_blocks = blockchain.num_archived_blocks().to_u64().unwrap();
+
+        let index = &blockchain.timestamp_index;
+        let start = index
+            .scan_start(start_ts)
+            .max(cursor.map_or(0, |cursor| cursor.to_u64().unwrap_or(u64::MAX)));
+        let end = index
+            .scan_end(end_ts)
+            .unwrap_or(chain_length)
+            .min(chain_length);
+
+        let archived_blocks = if start < num_archived_blocks.min(end) {
+            archived_blocks_ranges(
+                &blockchain.archive,
+                &(BlockHeight::from(start)..BlockHeight::from(num_archived_blocks.min(end))),
+            )
+        } else {
+            Vec::new()
+        };
+        let (blocks, next_cursor) = collect_blocks_in_time_range(
+            start.max(num_archived_blocks)..end.max(num_archived_blocks),
+            start_ts,
+            end_ts,
+            limit,
+            |height| blockchain.blocks.get(height - num_archived_blocks),
+        );
+
+        Ok(BlocksByTimeRange {
+            blocks,
+            archived_blocks,
+            next_cursor: next_cursor.map(Nat::from),
+        })
+    })
+}
+
 pub fn archives() -> Vec<ArchiveInfo> {
     STATE.with(|s| {
         let blockchain = s.blockchain.borrow();
@@ -361,6 +812,18 @@ pub fn archives() -> Vec<ArchiveInfo> {
     })
 }
 
+pub fn archive_options() -> ArchiveOptions {
+    STATE.with(|s| s.blockchain.borrow().archive.options())
+}
+
+pub fn archiving_status() -> ArchivingStatus {
+    STATE.with(|s| s.blockchain.borrow().archive.archiving_status())
+}
+
+pub fn chain_verification() -> ChainVerification {
+    STATE.with(|s| s.chain_verifier.borrow().progress().clone().into())
+}
+
 pub fn verified_created_at(created_at: &Option<u64>, now: &u64) -> CommonResult<()> {
     if created_at.is_none() {
         return Ok(());
@@ -435,6 +898,7 @@ pub fn _transfer(
     created_at: u64,
     now: u64,
 ) -> CommonResult<(BlockHeight, BlockHash, TransactionHash)> {
+    check_storable_amount(&value)?;
     // calc the transfer fee
     let transfer_fee = calc_transfer_fee(&value);
     let res = STATE.with(|s| {
@@ -0,0 +1,266 @@
+use std::collections::{HashMap, HashSet};
+
+use candid::Principal;
+use dft_types::constants::MAX_BATCH_ITEMS;
+use dft_types::*;
+
+use crate::service::basic_service::{calc_transfer_fee, verified_created_at};
+use crate::state::STATE;
+
+pub type BatchItemResult = CommonResult<(BlockHeight, BlockHash, TransactionHash)>;
+
+// Apply the items of a batch, the items which could not be parsed are passed as errors.
+// In the all or nothing mode, `validate` checks all the items before any is applied
+// and returns the error of each invalid item.
+pub fn apply_batch<T>(
+    mode: BatchMode,
+    items: Vec<CommonResult<T>>,
+    validate: impl FnOnce(&[CommonResult<T>]) -> Vec<CommonResult<()>>,
+    mut apply: impl FnMut(T) -> BatchItemResult,
+) -> Vec<BatchItemResult> {
+    if items.len() > MAX_BATCH_ITEMS {
+        return items
+            .iter()
+            .map(|_| Err(DFTError::TooManyBatchItems))
+            .collect();
+    }
+    match mode {
+        BatchMode::BestEffort => items
+            .into_iter()
+            .map(|item| item.and_then(&mut apply))
+            .collect(),
+        BatchMode::AllOrNothing => {
+            let checks = validate(&items);
+            if checks.iter().any(|check| check.is_err()) {
+                return checks
+                    .into_iter()
+                    .map(|check| Err(check.err().unwrap_or(DFTError::BatchAborted)))
+                    .collect();
+            }
+            items
+                .into_iter()
+                .map(|item| match item.and_then(&mut apply) {
+                    Ok(res) => Ok(res),
+                    // the validation simulates every item, a failure here is a bug;
+                    // trapping discards the items already applied
+                    Err(e) => panic!("batch item failed after its validation: {}", e),
+                })
+                .collect()
+        }
+    }
+}
+
+// the items fail with the error, or with their own one if they could not be parsed
+pub fn reject_all<T>(items: &[CommonResult<T>], error: DFTError) -> Vec<CommonResult<()>> {
+    items
+        .iter()
+        .map(|item| match item {
+            Ok(_) => Err(error.clone()),
+            Err(item_error) => Err(item_error.clone()),
+        })
+        .collect()
+}
+
+// The state a batch would leave behind, nothing is written until the batch is applied.
+pub struct BatchDryRun {
+    token_id: Principal,
+    created_at: u64,
+    now: u64,
+    balances: HashMap<TokenHolder, TokenAmount>,
+    allowances: HashMap<(TokenHolder, TokenHolder), TokenAmount>,
+    tx_hashes: HashSet<TransactionHash>,
+}
+
+impl BatchDryRun {
+    // the checks shared by all the items of the batch
+    pub fn new(created_at: Option<u64>, now: u64) -> CommonResult<Self> {
+        verified_created_at(&created_at, &now)?;
+        STATE.with(|s| {
+            s.blockchain
+                .borrow_mut()
+                .tx_window
+                .purge_old_transactions(now);
+            Ok(BatchDryRun {
+                token_id: *s.token_setting.borrow().token_id(),
+                created_at: created_at.unwrap_or(now),
+                now,
+                balances: HashMap::new(),
+                allowances: HashMap::new(),
+                tx_hashes: HashSet::new(),
+            })
+        })
+    }
+
+    // The hash of the transaction of the operation, which is rejected as a
+    // duplicate of an earlier item or of a recent transaction, or when it would
+    // be throttled after the transactions of the earlier items.
+    pub fn check_transaction(&self, operation: InnerOperation) -> CommonResult<TransactionHash> {
+        let tx_hash = InnerTransaction {
+            operation,
+            created_at: self.created_at,
+        }
+        .hash_with_token_id(&self.token_id);
+        STATE.with(|s| {
+            let blockchain = s.blockchain.borrow();
+            blockchain
+                .tx_window
+                .throttle_check_after(self.tx_hashes.len(), self.now)?;
+            if blockchain.tx_window.contains_transaction(tx_hash) {
+                return Err(DFTError::TxDuplicate);
+            }
+            Ok(())
+        })?;
+        if self.tx_hashes.contains(&tx_hash) {
+            return Err(DFTError::TxDuplicate);
+        }
+        Ok(tx_hash)
+    }
+
+    pub fn record_transaction(&mut self, tx_hash: TransactionHash) {
+        self.tx_hashes.insert(tx_hash);
+    }
+
+    fn balance_mut(&mut self, holder: &TokenHolder) -> &mut TokenAmount {
+        self.balances
+            .entry(*holder)
+            .or_insert_with(|| STATE.with(|s| s.balances.borrow().balance_of(holder)))
+    }
+
+    pub fn balance_of(&mut self, holder: &TokenHolder) -> TokenAmount {
+        self.balance_mut(holder).clone()
+    }
+
+    pub fn credit(&mut self, holder: &TokenHolder, value: &TokenAmount) {
+        *self.balance_mut(holder) += value;
+    }
+
+    // the balance was checked with `balance_of`
+    pub fn debit(&mut self, holder: &TokenHolder, value: &TokenAmount) {
+        *self.balance_mut(holder) -= value;
+    }
+
+    fn allowance_mut(
+        &mut self,
+        owner: &TokenHolder,
+        spender: &TokenHolder,
+        now: u64,
+    ) -> &mut TokenAmount {
+        self.allowances
+            .entry((*owner, *spender))
+            .or_insert_with(|| STATE.with(|s| s.allowances.borrow().spendable(owner, spender, now)))
+    }
+
+    // the allowance used by `transferFrom`, recurring or not
+    pub fn allowance(
+        &mut self,
+        owner: &TokenHolder,
+        spender: &TokenHolder,
+        now: u64,
+    ) -> TokenAmount {
+        self.allowance_mut(owner, spender, now).clone()
+    }
+
+    // the allowance was checked with `allowance`
+    pub fn spend_allowance(
+        &mut self,
+        owner: &TokenHolder,
+        spender: &TokenHolder,
+        value: &TokenAmount,
+        now: u64,
+    ) {
+        *self.allowance_mut(owner, spender, now) -= value;
+    }
+}
+
+// Validate transfers from `from`, by its spender if any, as `transfer` and `transferFrom` would apply them.
+pub fn validate_transfers(
+    caller: &Principal,
+    from: &TokenHolder,
+    spender: Option<&TokenHolder>,
+    items: &[CommonResult<(TokenHolder, TokenAmount)>],
+    created_at: Option<u64>,
+    now: u64,
+) -> Vec<CommonResult<()>> {
+    let dry_run = STATE
+        .with(|s| s.token_setting.borrow().not_allow_anonymous(caller))
+        .and_then(|_| BatchDryRun::new(created_at, now));
+    let mut dry_run = match dry_run {
+        Ok(dry_run) => dry_run,
+        Err(e) => return reject_all(items, e),
+    };
+    let fee_to = STATE.with(|s| s.token_setting.borrow().fee_to());
+    items
+        .iter()
+        .map(|item| {
+            let (to, value) = item.as_ref().map_err(|e| e.clone())?;
+            check_storable_amount(value)?;
+            let fee = calc_transfer_fee(value);
+            let total = value.clone() + fee.clone();
+            if let Some(spender) = spender {
+                if dry_run.allowance(from, spender, now) < total {
+                    return Err(DFTError::InsufficientAllowance);
+                }
+            }
+            if dry_run.balance_of(from) < total {
+                return Err(DFTError::InsufficientBalance);
+            }
+            let tx_hash = dry_run.check_transaction(InnerOperation::Transfer {
+                caller: *spender.unwrap_or(from),
+                from: *from,
+                to: *to,
+                value: value.clone(),
+                fee: fee.clone(),
+            })?;
+
+            dry_run.record_transaction(tx_hash);
+            if let Some(spender) = spender {
+                dry_run.spend_allowance(from, spender, &total, now);
+            }
+            dry_run.debit(from, &total);
+            dry_run.credit(to, value);
+            dry_run.credit(&fee_to, &fee);
+            Ok(())
+        })
+        .collect()
+}
+
+// Validate approvals of the spenders of `owner`, as `approve` would apply them.
+pub fn validate_approvals(
+    caller: &Principal,
+    owner: &TokenHolder,
+    items: &[CommonResult<(TokenHolder, TokenAmount)>],
+    created_at: Option<u64>,
+    now: u64,
+) -> Vec<CommonResult<()>> {
+    let dry_run = STATE
+        .with(|s| s.token_setting.borrow().not_allow_anonymous(caller))
+        .and_then(|_| BatchDryRun::new(created_at, now));
+    let mut dry_run = match dry_run {
+        Ok(dry_run) => dry_run,
+        Err(e) => return reject_all(items, e),
+    };
+    let fee_to = STATE.with(|s| s.token_setting.borrow().fee_to());
+    items
+        .iter()
+        .map(|item| {
+            let (spender, value) = item.as_ref().map_err(|e| e.clone())?;
+            check_storable_amount(value)?;
+            let fee = STATE.with(|s| s.token_setting.borrow().fee().calc_approve_fee(value));
+            if dry_run.balance_of(owner) < fee {
+                return Err(DFTError::InsufficientBalance);
+            }
+            let tx_hash = dry_run.check_transaction(InnerOperation::Approve {
+                caller: (*caller).into(),
+                owner: *owner,
+                spender: *spender,
+                value: value.clone(),
+                fee: fee.clone(),
+            })?;
+
+            dry_run.record_transaction(tx_hash);
+            dry_run.debit(owner, &fee);
+            dry_run.credit(&fee_to, &fee);
+            Ok(())
+        })
+        .collect()
+}
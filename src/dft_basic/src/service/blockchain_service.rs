@@ -10,6 +10,7 @@ pub fn get_blocks_for_archiving() -> VecDeque<EncodedBlock> {
         blockchain.get_blocks_for_archiving(
             blockchain.archive.trigger_threshold as usize,
             blockchain.archive.num_blocks_to_archive as usize,
+            blockchain.archive.max_message_size_bytes() as usize,
         )
     })
 }
@@ -37,6 +38,33 @@ pub fn last_auto_scaling_storage_canister_id() -> Option<Principal> {
     STATE.with(|s| s.blockchain.borrow().archive.last_storage_canister_id())
 }
 
+// the storage canisters and their mirrors
+pub fn storage_canisters() -> Vec<Principal> {
+    STATE.with(|s| s.blockchain.borrow().archive.all_storage_canisters())
+}
+
+pub fn mirror_canisters(storage_canister_index: usize) -> Vec<Principal> {
+    STATE.with(|s| {
+        s.blockchain
+            .borrow()
+            .archive
+            .mirror_canisters(storage_canister_index)
+    })
+}
+
+pub fn missing_mirrors_count(storage_canister_index: usize) -> usize {
+    STATE.with(|s| {
+        s.blockchain
+            .borrow()
+            .archive
+            .missing_mirrors_count(storage_canister_index)
+    })
+}
+
+pub fn latest_mirror_canister() -> Option<Principal> {
+    STATE.with(|s| s.blockchain.borrow().archive.latest_mirror_canister())
+}
+
 pub fn scaling_storage_block_height_offset() -> BlockHeight {
     STATE.with(|s| {
         s.blockchain
@@ -46,6 +74,17 @@ pub fn scaling_storage_block_height_offset() -> BlockHeight {
     })
 }
 
+// the parent hash of the local block at `height`, that is the hash of the block before it
+pub fn parent_hash_of(height: BlockHeight) -> Option<BlockHash> {
+    STATE.with(|s| {
+        s.blockchain
+            .borrow()
+            .get(height)
+            .and_then(|block| block.decode().ok())
+            .map(|block| block.parent_hash)
+    })
+}
+
 pub fn remove_archived_blocks(num_archived: usize) {
     STATE.with(|s| {
         s.blockchain
@@ -54,14 +93,40 @@ pub fn remove_archived_blocks(num_archived: usize) {
     })
 }
 
-pub fn lock_for_archiving() -> bool {
-    STATE.with(|s| s.blockchain.borrow_mut().archive.lock_for_archiving())
+pub fn lock_for_archiving(now: u64) -> bool {
+    STATE.with(|s| s.blockchain.borrow_mut().archive.lock_for_archiving(now))
 }
 
 pub fn unlock_after_archiving() {
     STATE.with(|s| s.blockchain.borrow_mut().archive.unlock_after_archiving())
 }
 
+pub fn unlock_after_upgrading_archives() {
+    STATE.with(|s| {
+        s.blockchain
+            .borrow_mut()
+            .archive
+            .unlock_after_upgrading_archives()
+    })
+}
+
+pub fn set_archiving_state(state: ArchivingState) {
+    STATE.with(|s| s.blockchain.borrow_mut().archive.set_archiving_state(state))
+}
+
+pub fn archive_interval_seconds() -> u64 {
+    STATE.with(|s| s.blockchain.borrow().archive.archive_interval_seconds)
+}
+
+pub fn record_archiving_result(now: u64, error: Option<String>) {
+    STATE.with(|s| {
+        s.blockchain
+            .borrow_mut()
+            .archive
+            .record_archiving_result(now, error)
+    })
+}
+
 pub fn pre_append_scaling_storage_canister(canister_id: Principal) {
     STATE.with(|s| {
         s.blockchain
@@ -79,6 +144,24 @@ pub fn append_scaling_storage_canister(storage_canister_id: Principal) {
     })
 }
 
+pub fn pre_append_mirror_canister(canister_id: Principal) {
+    STATE.with(|s| {
+        s.blockchain
+            .borrow_mut()
+            .archive
+            .pre_append_mirror_canister(canister_id)
+    })
+}
+
+pub fn append_mirror_canister(storage_canister_index: usize, canister_id: Principal) {
+    STATE.with(|s| {
+        s.blockchain
+            .borrow_mut()
+            .archive
+            .append_mirror_canister(storage_canister_index, canister_id)
+    })
+}
+
 pub fn update_scaling_storage_blocks_range(
     storage_canister_index: usize,
     end_block_height: BlockHeight,
@@ -90,3 +173,32 @@ pub fn update_scaling_storage_blocks_range(
             .update_scaling_storage_blocks_range(storage_canister_index, end_block_height)
     })
 }
+
+// the cycles threshold of the archive canisters and the amount of a top-up
+pub fn archive_cycles_settings() -> (u64, u64) {
+    STATE.with(|s| {
+        let blockchain = s.blockchain.borrow();
+        (
+            blockchain.archive.min_archive_cycles,
+            blockchain.archive.archive_cycles_top_up,
+        )
+    })
+}
+
+pub fn record_archive_cycles(
+    canister_id: Principal,
+    now: u64,
+    cycles: Option<u128>,
+    topped_up_cycles: u64,
+    error: Option<String>,
+) {
+    STATE.with(|s| {
+        s.blockchain.borrow_mut().archive.record_archive_cycles(
+            canister_id,
+            now,
+            cycles,
+            topped_up_cycles,
+            error,
+        )
+    })
+}
@@ -0,0 +1,204 @@
+use candid::Principal;
+use num_traits::ToPrimitive;
+
+use dft_types::constants::MAX_HOLD_DURATION;
+use dft_types::*;
+
+use crate::service::basic_service::{calc_transfer_fee, charge_transfer_fee, verified_created_at};
+use crate::state::STATE;
+
+pub fn held_balance_of(holder: &TokenHolder) -> TokenAmount {
+    STATE.with(|s| s.balances.borrow().held_balance_of(holder))
+}
+
+pub fn hold_by_id(hold_id: u64) -> Option<InnerHold> {
+    STATE.with(|s| s.holds.borrow().get(hold_id))
+}
+
+// Reserve `amount` of the owner for the merchant until `expires_at`,
+// the hold id is the height of the returned block.
+// The transfer fee is charged now, capturing the hold later is free.
+pub fn hold(
+    caller: &Principal,
+    owner: &TokenHolder,
+    merchant: &TokenHolder,
+    amount: TokenAmount,
+    expires_at: u64,
+    created_at: Option<u64>,
+    now: u64,
+) -> CommonResult<(BlockHeight, BlockHash, TransactionHash)> {
+    verified_created_at(&created_at, &now)?;
+    if expires_at <= now || expires_at - now > MAX_HOLD_DURATION {
+        return Err(DFTError::InvalidHoldExpiration);
+    }
+    let created_at = created_at.unwrap_or(now);
+    let fee = calc_transfer_fee(&amount);
+    let res = STATE.with(|s| {
+        let settings = s.token_setting.borrow();
+        let mut balances = s.balances.borrow_mut();
+        let mut blockchain = s.blockchain.borrow_mut();
+        settings.not_allow_anonymous(caller)?;
+
+        let num_purged = blockchain.tx_window.purge_old_transactions(now);
+        if num_purged == 0 {
+            blockchain.tx_window.throttle_check(now)?
+        }
+
+        if balances.balance_of(owner) < amount.clone() + fee.clone() {
+            return Err(DFTError::InsufficientBalance);
+        }
+        let tx = InnerTransaction {
+            operation: InnerOperation::Hold {
+                caller: *owner,
+                owner: *owner,
+                merchant: *merchant,
+                amount: amount.clone(),
+                expires_at,
+                fee: fee.clone(),
+            },
+            created_at,
+        };
+        let res = blockchain.add_tx_to_block(settings.token_id(), tx, now)?;
+        balances.hold_balance(owner, amount.clone())?;
+        s.holds.borrow_mut().insert(
+            res.0.to_u64().unwrap(),
+            InnerHold {
+                owner: *owner,
+                merchant: *merchant,
+                amount,
+                expires_at,
+            },
+        );
+        Ok(res)
+    });
+
+    if res.is_ok() && fee > 0u32.into() {
+        charge_transfer_fee(owner, fee)?;
+    }
+    res
+}
+
+// Move `amount` of the hold to the merchant, what is left stays held until
+// it is captured, released or expires.
+pub fn capture(
+    caller: &Principal,
+    merchant: &TokenHolder,
+    hold_id: u64,
+    amount: TokenAmount,
+    created_at: Option<u64>,
+    now: u64,
+) -> CommonResult<(BlockHeight, BlockHash, TransactionHash)> {
+    verified_created_at(&created_at, &now)?;
+    let created_at = created_at.unwrap_or(now);
+    STATE.with(|s| {
+        let settings = s.token_setting.borrow();
+        let mut balances = s.balances.borrow_mut();
+        let mut blockchain = s.blockchain.borrow_mut();
+        let mut holds = s.holds.borrow_mut();
+        settings.not_allow_anonymous(caller)?;
+
+        let hold = holds.get(hold_id).ok_or(DFTError::HoldNotFound)?;
+        if hold.is_expired(now) {
+            return Err(DFTError::HoldExpired);
+        }
+        if hold.merchant != *merchant {
+            return Err(DFTError::OnlyMerchantAllowCallIt);
+        }
+        if amount == TokenAmount::from(0u32) {
+            return Err(DFTError::InvalidCaptureAmount);
+        }
+        if amount > hold.amount {
+            return Err(DFTError::HeldAmountExceeded);
+        }
+
+        let num_purged = blockchain.tx_window.purge_old_transactions(now);
+        if num_purged == 0 {
+            blockchain.tx_window.throttle_check(now)?
+        }
+
+        let tx = InnerTransaction {
+            operation: InnerOperation::CaptureHold {
+                caller: *merchant,
+                hold_id,
+                owner: hold.owner,
+                merchant: hold.merchant,
+                amount: amount.clone(),
+            },
+            created_at,
+        };
+        let res = blockchain.add_tx_to_block(settings.token_id(), tx, now)?;
+        balances.debit_held(&hold.owner, amount.clone())?;
+        balances.credit_balance(&hold.merchant, amount.clone());
+        holds.set_amount(hold_id, hold.amount - amount);
+        Ok(res)
+    })
+}
+
+// Return what is left of the hold to its owner. The merchant can release a hold
+// at any time, anyone else once it has expired.
+pub fn release(
+    caller: &Principal,
+    releaser: &TokenHolder,
+    hold_id: u64,
+    created_at: Option<u64>,
+    now: u64,
+) -> CommonResult<(BlockHeight, BlockHash, TransactionHash)> {
+    verified_created_at(&created_at, &now)?;
+    STATE.with(|s| s.token_setting.borrow().not_allow_anonymous(caller))?;
+    let hold = hold_by_id(hold_id).ok_or(DFTError::HoldNotFound)?;
+    if hold.merchant != *releaser && !hold.is_expired(now) {
+        return Err(DFTError::OnlyMerchantAllowCallIt);
+    }
+    release_hold(releaser, hold_id, created_at.unwrap_or(now), now, true)
+}
+
+// Release the holds expired at `now`, at most `limit` of them.
+// The blocks are written on behalf of the token canister and are not throttled,
+// otherwise a busy window would keep the expired funds held.
+pub fn release_expired_holds(now: u64, limit: usize) -> Vec<CommonResult<u64>> {
+    let (token_id, expired) = STATE.with(|s| {
+        (
+            *s.token_setting.borrow().token_id(),
+            s.holds.borrow().expired(now, limit),
+        )
+    });
+    expired
+        .into_iter()
+        .map(|hold_id| release_hold(&token_id.into(), hold_id, now, now, false).map(|_| hold_id))
+        .collect()
+}
+
+fn release_hold(
+    caller: &TokenHolder,
+    hold_id: u64,
+    created_at: u64,
+    now: u64,
+    throttled: bool,
+) -> CommonResult<(BlockHeight, BlockHash, TransactionHash)> {
+    STATE.with(|s| {
+        let settings = s.token_setting.borrow();
+        let mut balances = s.balances.borrow_mut();
+        let mut blockchain = s.blockchain.borrow_mut();
+        let mut holds = s.holds.borrow_mut();
+        let hold = holds.get(hold_id).ok_or(DFTError::HoldNotFound)?;
+
+        let num_purged = blockchain.tx_window.purge_old_transactions(now);
+        if throttled && num_purged == 0 {
+            blockchain.tx_window.throttle_check(now)?
+        }
+
+        let tx = InnerTransaction {
+            operation: InnerOperation::ReleaseHold {
+                caller: *caller,
+                hold_id,
+                owner: hold.owner,
+                amount: hold.amount.clone(),
+            },
+            created_at,
+        };
+        let res = blockchain.add_tx_to_block(settings.token_id(), tx, now)?;
+        balances.release_held(&hold.owner, hold.amount)?;
+        holds.remove(hold_id);
+        Ok(res)
+    })
+}
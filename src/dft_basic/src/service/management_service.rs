@@ -5,7 +5,7 @@ use candid::Principal;
 use dft_types::*;
 use dft_utils::*;
 
-use crate::state::STATE;
+use crate::state::{State, STATE};
 
 use super::basic_service::verified_created_at;
 
@@ -78,6 +78,20 @@ pub fn set_fee(
     })
 }
 
+// Start a chain verification from genesis, or keep the running one going.
+// The blocks are replayed by the chain verification timer.
+pub fn verify_chain(caller: &Principal, now: u64) -> CommonResult<bool> {
+    STATE.with(|s| {
+        let settings = s.token_setting.borrow();
+        settings.only_owner(caller)?;
+        let mut verifier = s.chain_verifier.borrow_mut();
+        if !verifier.is_running() {
+            verifier.start(settings.genesis_fee_to(), now);
+        }
+        Ok(true)
+    })
+}
+
 pub fn set_fee_to(
     caller: &Principal,
     new_fee_to: TokenHolder,
@@ -133,3 +147,101 @@ pub fn set_logo(caller: &Principal, logo: Option<Vec<u8>>) -> CommonResult<bool>
         Ok(true)
     })
 }
+
+pub fn retry_archiving(caller: &Principal) -> CommonResult<bool> {
+    STATE.with(|s| {
+        let settings = s.token_setting.borrow();
+        settings.only_owner(caller)?;
+        s.blockchain.borrow_mut().archive.clear_retry_backoff();
+        Ok(true)
+    })
+}
+
+pub fn reset_archiving(caller: &Principal) -> CommonResult<bool> {
+    STATE.with(|s| {
+        let settings = s.token_setting.borrow();
+        settings.only_owner(caller)?;
+        s.blockchain.borrow_mut().archive.reset_archiving();
+        Ok(true)
+    })
+}
+
+pub fn lock_for_upgrading_archives(caller: &Principal) -> CommonResult<bool> {
+    STATE.with(|s| {
+        let settings = s.token_setting.borrow();
+        settings.only_owner(caller)?;
+        if !s
+            .blockchain
+            .borrow_mut()
+            .archive
+            .lock_for_upgrading_archives()
+        {
+            return Err(DFTError::ArchivingInProgress);
+        }
+        Ok(true)
+    })
+}
+
+pub fn set_archive_options(
+    caller: &Principal,
+    options: ArchiveOptions,
+    created_at: Option<u64>,
+    now: u64,
+) -> CommonResult<bool> {
+    verified_created_at(&created_at, &now)?;
+
+    STATE.with(|s| {
+        s.token_setting.borrow().only_owner(caller)?;
+        apply_archive_options(s, caller, options, created_at.unwrap_or(now), now)
+    })
+}
+
+// Options passed as upgrade args, the caller is a controller of the token.
+pub fn set_archive_options_on_upgrade(
+    caller: &Principal,
+    options: ArchiveOptions,
+    now: u64,
+) -> CommonResult<bool> {
+    STATE.with(|s| {
+        // upgrades usually pass the same options again, only a change is recorded
+        let current = s.blockchain.borrow().archive.clone();
+        let mut archive = current.clone();
+        archive.set_options(options.clone())?;
+        if archive.options() == current.options() {
+            return Ok(false);
+        }
+        apply_archive_options(s, caller, options, now, now)
+    })
+}
+
+fn apply_archive_options(
+    s: &State,
+    caller: &Principal,
+    options: ArchiveOptions,
+    created_at: u64,
+    now: u64,
+) -> CommonResult<bool> {
+    let settings = s.token_setting.borrow();
+    let mut blockchain = s.blockchain.borrow_mut();
+
+    // validate before recording the change, the block holds the resulting options
+    let mut archive = blockchain.archive.clone();
+    archive.set_options(options)?;
+
+    let num_purged = blockchain.tx_window.purge_old_transactions(now);
+    if num_purged == 0 {
+        blockchain.tx_window.throttle_check(now)?
+    }
+
+    let tx = InnerTransaction {
+        operation: InnerOperation::ArchiveOptionsModify {
+            caller: (*caller).into(),
+            new_options: archive.options(),
+        },
+        created_at,
+    };
+
+    blockchain.add_tx_to_block(settings.token_id(), tx, now)?;
+    blockchain.archive = archive;
+    Ok(true)
+}
@@ -1,3 +1,5 @@
 pub mod basic_service;
+pub mod batch_service;
 pub mod blockchain_service;
+pub mod hold_service;
 pub mod management_service;
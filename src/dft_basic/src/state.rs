@@ -1,69 +1,246 @@
+use crate::memory;
 use dft_types::*;
-use ic_cdk::api::stable::{stable_bytes, StableWriter};
 use ic_cdk_macros::*;
+use ic_stable_structures::StableCell;
 use log::{error, info};
 use std::cell::RefCell;
 
 thread_local! {
       pub static STATE : State = State::default();
 }
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct State {
     pub token_setting: RefCell<TokenSetting>,
     pub token_desc: RefCell<TokenDescription>,
-    pub blockchain: RefCell<Blockchain>,
-    pub balances: RefCell<TokenBalances>,
-    pub allowances: RefCell<TokenAllowances>,
+    pub blockchain: RefCell<Blockchain<memory::Memory>>,
+    pub balances: RefCell<TokenBalances<memory::Memory>>,
+    pub allowances: RefCell<TokenAllowances<memory::Memory>>,
+    pub operators: RefCell<TokenOperators<memory::Memory>>,
+    pub permit_nonces: RefCell<PermitNonces<memory::Memory>>,
+    pub holds: RefCell<TokenHolds<memory::Memory>>,
+    pub chain_verifier: RefCell<ChainVerifier<memory::Memory>>,
 }
 
+// balances, allowances, operators, permit nonces, holds, local blocks, the transaction window
+// and the chain verification live in stable memory, they are loaded from the managed memories
+impl Default for State {
+    fn default() -> Self {
+        State {
+            token_setting: RefCell::new(TokenSetting::default()),
+            token_desc: RefCell::new(TokenDescription::default()),
+            blockchain: RefCell::new(Blockchain::init(
+                LocalBlocks::init(memory::blocks_memories()),
+                TokenTransactionWindow::init(
+                    memory::tx_window_by_hash_memory(),
+                    memory::tx_window_by_height_memory(),
+                ),
+                BlockTimestampIndex::init(memory::block_timestamp_index_memory()),
+            )),
+            balances: RefCell::new({
+                let (balances_memory, held_memory) = memory::balances_memories();
+                TokenBalances::init(balances_memory, held_memory)
+            }),
+            allowances: RefCell::new({
+                let (allowances_memory, by_spender_memory, recurring_memory) =
+                    memory::allowances_memories();
+                TokenAllowances::init(allowances_memory, by_spender_memory, recurring_memory)
+            }),
+            operators: RefCell::new(TokenOperators::init(memory::operators_memory())),
+            permit_nonces: RefCell::new(PermitNonces::init(memory::permit_nonces_memory())),
+            holds: RefCell::new({
+                let (holds_memory, expirations_memory) = memory::holds_memories();
+                TokenHolds::init(holds_memory, expirations_memory)
+            }),
+            chain_verifier: RefCell::new({
+                let (progress_memory, balances_memory, held_memory) =
+                    memory::chain_verification_memories();
+                ChainVerifier::init(progress_memory, balances_memory, held_memory)
+            }),
+        }
+    }
+}
+
+// Layouts of the blob saved on upgrade:
+// v1: the whole state serialized into one heap blob at the start of stable memory
+// v2: settings and the metadata of the stable structures, in the upgrades memory
+// v3: the archive monitors the cycles of the archive canisters
+// v4: the archived block ranges can be mirrored
+// v5: the settings record the fee recipient the token was initialized with,
+//     the compression of the archived blocks can be turned off and
+//     the backfill of the block timestamp index is recorded
+const STATE_SCHEMA: StableStateSchema<State> = StableStateSchema::new(&[
+    State::migrate_v1_to_v2,
+    State::migrate_v2_to_v3,
+    State::migrate_v3_to_v4,
+    State::migrate_v4_to_v5,
+]);
+
 impl State {
     pub fn replace(&self, new_state: State) {
-        self.token_setting.replace(new_state.token_setting.take());
-        self.token_desc.replace(new_state.token_desc.take());
-        self.blockchain.replace(new_state.blockchain.take());
-        self.balances.replace(new_state.balances.take());
-        self.allowances.replace(new_state.allowances.take());
+        self.token_setting
+            .replace(new_state.token_setting.into_inner());
+        self.token_desc.replace(new_state.token_desc.into_inner());
+        self.blockchain.replace(new_state.blockchain.into_inner());
+        self.balances.replace(new_state.balances.into_inner());
+        self.allowances.replace(new_state.allowances.into_inner());
+        self.operators.replace(new_state.operators.into_inner());
+        self.permit_nonces
+            .replace(new_state.permit_nonces.into_inner());
+        self.holds.replace(new_state.holds.into_inner());
+        self.chain_verifier
+            .replace(new_state.chain_verifier.into_inner());
     }
-}
 
-impl StableState for State {
-    fn encode(&self) -> Vec<u8> {
+    // Only the settings and the metadata of the stable structures are saved,
+    // so upgrades cost the same whatever the number of holders and blocks.
+    fn encode_payload(&self) -> Vec<u8> {
         bincode::serialize(&(
             self.token_setting.borrow().encode(),
             self.token_desc.borrow().encode(),
-            self.blockchain.borrow().encode(),
-            self.balances.borrow().encode(),
-            self.allowances.borrow().encode(),
+            self.blockchain.borrow().encode_metadata(),
+            self.balances.borrow().total_supply(),
         ))
         .unwrap()
     }
 
+    fn restore(&self, payload: Vec<u8>) -> Result<(), String> {
+        let (token_setting_bytes, token_desc_bytes, blockchain_metadata, total_supply): (
+            Vec<u8>,
+            Vec<u8>,
+            Vec<u8>,
+            TokenAmount,
+        ) = bincode::deserialize(&payload)
+            .map_err(|e| format!("decode token state failed, {}", e))?;
+
+        self.token_setting
+            .replace(TokenSetting::decode(token_setting_bytes)?);
+        self.token_desc
+            .replace(TokenDescription::decode(token_desc_bytes)?);
+        self.blockchain.borrow_mut().restore(blockchain_metadata)?;
+        self.balances.borrow_mut().restore(total_supply);
+        Ok(())
+    }
+
+    // Balances, allowances, blocks and the transaction window of the heap blob
+    // are moved into the stable structures.
     #[allow(clippy::type_complexity)]
-    fn decode(bytes: Vec<u8>) -> Result<Self, String> {
+    fn migrate_v1_to_v2(&mut self, bytes: Vec<u8>) -> Result<Vec<u8>, String> {
         let (
             token_setting_bytes,
             token_desc_bytes,
             blockchain_bytes,
             balances_bytes,
             allowances_bytes,
-        ): (Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>) = bincode::deserialize(&bytes).unwrap();
+        ): (Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>) = bincode::deserialize(&bytes)
+            .map_err(|e| format!("decode heap token state failed, {}", e))?;
+
+        let blockchain_metadata = self
+            .blockchain
+            .borrow_mut()
+            .migrate_from_heap_format(blockchain_bytes)?;
+        self.balances
+            .borrow_mut()
+            .migrate_from_heap_format(balances_bytes)?;
+        self.allowances
+            .borrow_mut()
+            .migrate_from_heap_format(allowances_bytes)?;
+        Ok(bincode::serialize(&(
+            token_setting_bytes,
+            token_desc_bytes,
+            blockchain_metadata,
+            self.balances.borrow().total_supply(),
+        ))
+        .unwrap())
+    }
 
-        Ok(State {
-            token_setting: RefCell::new(TokenSetting::decode(token_setting_bytes)?),
-            token_desc: RefCell::new(TokenDescription::decode(token_desc_bytes)?),
-            blockchain: RefCell::new(Blockchain::decode(blockchain_bytes)?),
-            balances: RefCell::new(TokenBalances::decode(balances_bytes)?),
-            allowances: RefCell::new(TokenAllowances::decode(allowances_bytes)?),
-        })
+    fn migrate_v2_to_v3(&mut self, bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+        let (token_setting_bytes, token_desc_bytes, blockchain_metadata, total_supply): (
+            Vec<u8>,
+            Vec<u8>,
+            Vec<u8>,
+            TokenAmount,
+        ) = bincode::deserialize(&bytes)
+            .map_err(|e| format!("decode token state failed, {}", e))?;
+
+        Ok(bincode::serialize(&(
+            token_setting_bytes,
+            token_desc_bytes,
+            Blockchain::<memory::Memory>::migrate_metadata_from_v2(blockchain_metadata)?,
+            total_supply,
+        ))
+        .unwrap())
+    }
+
+    fn migrate_v3_to_v4(&mut self, bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+        let (token_setting_bytes, token_desc_bytes, blockchain_metadata, total_supply): (
+            Vec<u8>,
+            Vec<u8>,
+            Vec<u8>,
+            TokenAmount,
+        ) = bincode::deserialize(&bytes)
+            .map_err(|e| format!("decode token state failed, {}", e))?;
+
+        Ok(bincode::serialize(&(
+            token_setting_bytes,
+            token_desc_bytes,
+            Blockchain::<memory::Memory>::migrate_metadata_from_v3(blockchain_metadata)?,
+            total_supply,
+        ))
+        .unwrap())
+    }
+
+    fn migrate_v4_to_v5(&mut self, bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+        let (token_setting_bytes, token_desc_bytes, blockchain_metadata, total_supply): (
+            Vec<u8>,
+            Vec<u8>,
+            Vec<u8>,
+            TokenAmount,
+        ) = bincode::deserialize(&bytes)
+            .map_err(|e| format!("decode token state failed, {}", e))?;
+
+        Ok(bincode::serialize(&(
+            TokenSetting::migrate_from_v4(token_setting_bytes)?,
+            token_desc_bytes,
+            Blockchain::<memory::Memory>::migrate_metadata_from_v4(blockchain_metadata)?,
+            total_supply,
+        ))
+        .unwrap())
     }
 }
 
+impl StableState for State {
+    fn encode(&self) -> Vec<u8> {
+        STATE_SCHEMA.encode(self.encode_payload())
+    }
+
+    fn decode(bytes: Vec<u8>) -> Result<Self, String> {
+        let mut state = State::default();
+        let payload = STATE_SCHEMA.migrate(&mut state, bytes)?;
+        state.restore(payload)?;
+        Ok(state)
+    }
+}
+
+fn upgrades_cell() -> StableCell<Vec<u8>, memory::Memory> {
+    StableCell::init(memory::upgrades_memory(), Vec::new())
+        .expect("failed to initialize the upgrades memory")
+}
+
+// The upgrade args are optional, `(opt ArchiveOptions)` replaces the archive options.
+fn decode_upgrade_args(bytes: &[u8]) -> Result<Option<ArchiveOptions>, String> {
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    candid::decode_one(bytes).map_err(|e| format!("Decoding upgrade args failed, {}", e))
+}
+
 #[pre_upgrade]
 fn pre_upgrade() {
     STATE.with(|s| {
         let bytes = s.encode();
-        match StableWriter::default().write(bytes.as_slice()) {
-            Ok(size) => {
+        let size = bytes.len();
+        match upgrades_cell().set(bytes) {
+            Ok(_) => {
                 info!("after pre_upgrade stable_write size{}", size);
             }
             Err(_) => {
@@ -75,18 +252,88 @@ fn pre_upgrade() {
 
 #[post_upgrade]
 fn post_upgrade() {
-    STATE.with(|s| {
-        let bytes = stable_bytes();
-        let restore_state = State::decode(bytes).expect("Decoding stable memory failed");
-        s.replace(restore_state);
-    })
+    // must be checked before anything touches the memory manager,
+    // which takes over the stable memory when initialized
+    let bytes = memory::take_legacy_state_bytes().unwrap_or_else(|| upgrades_cell().get().clone());
+    match State::decode(bytes) {
+        Ok(restore_state) => STATE.with(|s| s.replace(restore_state)),
+        Err(e) => ic_cdk::trap(&format!("Decoding stable memory failed, {}", e)),
+    }
+    match decode_upgrade_args(&ic_cdk::api::call::arg_data_raw()) {
+        Ok(Some(options)) => {
+            if let Err(e) = crate::service::management_service::set_archive_options_on_upgrade(
+                &ic_cdk::api::caller(),
+                options,
+                ic_cdk::api::time(),
+            ) {
+                ic_cdk::trap(&e.to_string());
+            }
+        }
+        Ok(None) => {}
+        Err(e) => ic_cdk::trap(&e),
+    }
+    // timers are cleared on upgrade, re-arm the archiving, chain verification
+    // and hold expiration timers
+    crate::auto_scaling_storage::start_archiving_timer();
+    crate::auto_scaling_storage::start_cycles_monitor_timer();
+    crate::chain_verification::start_chain_verification_timer();
+    crate::hold_expiration::start_hold_expiration_timer();
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use candid::Principal;
     use num_bigint::BigUint;
 
+    // the state the fixtures were written from: the token is initialized with
+    // archive options (3000, 500), then add_minter, mint 10000 to the minter,
+    // the minter approves 1000 to the spender and transfers 100 to the other holder,
+    // approve and transfer pay a fee of 2
+    const STATE_V1_FIXTURE: &[u8] = include_bytes!("../fixtures/state_v1.bin");
+    const STATE_V2_FIXTURE: &[u8] = include_bytes!("../fixtures/state_v2.bin");
+    const STATE_V3_FIXTURE: &[u8] = include_bytes!("../fixtures/state_v3.bin");
+    const STATE_V4_FIXTURE: &[u8] = include_bytes!("../fixtures/state_v4.bin");
+    const FIXTURE_NOW: u64 = 1_670_000_000_000_000_000;
+
+    fn holder(principal: &str) -> TokenHolder {
+        TokenHolder::new(Principal::from_text(principal).unwrap(), None)
+    }
+
+    fn fixture_minter() -> TokenHolder {
+        holder("o5y7v-htz2q-vk7fc-cqi4m-bqvwa-eth75-sc2wz-ubuev-curf2-rbipe-tae")
+    }
+
+    fn fixture_spender() -> TokenHolder {
+        holder("7zap4-dnqjf-k2oei-jj2uj-sw6db-eksrj-kzc5h-nmki4-x5fcn-w53an-gae")
+    }
+
+    fn fixture_other() -> TokenHolder {
+        holder("qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe")
+    }
+
+    fn assert_fixture_settings(state: &State) {
+        let setting = state.token_setting.borrow();
+        assert_eq!(
+            setting.token_id(),
+            &Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()
+        );
+        assert_eq!(setting.metadata().name(), "Deland Labs Token");
+        assert_eq!(setting.metadata().symbol(), "DLT");
+        assert_eq!(setting.metadata().decimals(), &18);
+        // not recorded before version 5, the current fee recipient is assumed
+        assert_eq!(setting.genesis_fee_to(), setting.fee_to());
+        let blockchain = state.blockchain.borrow();
+        assert_eq!(blockchain.archive.trigger_threshold, 3000);
+        assert_eq!(blockchain.archive.num_blocks_to_archive, 500);
+        assert_eq!(blockchain.archive.cycles_for_archive_creation, 1_000_000);
+        assert_eq!(blockchain.last_timestamp, FIXTURE_NOW + 3);
+        assert_eq!(
+            state.balances.borrow().total_supply(),
+            BigUint::from(10000u32)
+        );
+    }
+
     #[test]
     fn test_state_write_read() {
         let state = State::default();
@@ -110,4 +357,133 @@ mod tests {
         assert_eq!(copy_state.balances.borrow().balance_of(&owner), balance);
         assert_eq!(bytes, restore_bytes);
     }
+
+    #[test]
+    fn test_decode_state_v1_fixture() {
+        memory::write_legacy_state_bytes(STATE_V1_FIXTURE);
+
+        let bytes = memory::take_legacy_state_bytes().unwrap();
+        let state = State::decode(bytes).unwrap();
+        // the memory manager owns the stable memory from now on
+        assert!(memory::take_legacy_state_bytes().is_none());
+
+        assert_fixture_settings(&state);
+        let balances = state.balances.borrow();
+        assert_eq!(balances.balance_of(&fixture_minter()), 9896u32.into());
+        assert_eq!(balances.balance_of(&fixture_other()), 100u32.into());
+        assert_eq!(balances.holder_count(), 3);
+        assert_eq!(
+            state
+                .allowances
+                .borrow()
+                .allowance(&fixture_minter(), &fixture_spender()),
+            BigUint::from(1000u32)
+        );
+        let blockchain = state.blockchain.borrow();
+        assert_eq!(blockchain.chain_length(), BigUint::from(4u32));
+        assert_eq!(blockchain.tx_window.transactions_count_in_window(), 4);
+        let last_block = blockchain.get(3u32.into()).unwrap();
+        assert_eq!(
+            blockchain.last_hash,
+            Some(last_block.hash_with_token_id(state.token_setting.borrow().token_id()))
+        );
+        assert_eq!(
+            blockchain.archive.archive_interval_seconds,
+            constants::DEFAULT_ARCHIVE_INTERVAL_SECONDS
+        );
+    }
+
+    #[test]
+    fn test_decode_state_v2_fixture() {
+        // v2 only holds the metadata, the stable structures are empty here
+        let state = State::decode(STATE_V2_FIXTURE.to_vec()).unwrap();
+        assert_fixture_settings(&state);
+        assert_eq!(
+            state.blockchain.borrow().archive.min_archive_cycles,
+            constants::DEFAULT_MIN_ARCHIVE_CYCLES
+        );
+    }
+
+    #[test]
+    fn test_decode_state_v3_fixture() {
+        let state = State::decode(STATE_V3_FIXTURE.to_vec()).unwrap();
+        assert_fixture_settings(&state);
+        let blockchain = state.blockchain.borrow();
+        assert_eq!(
+            blockchain.archive.replication_factor(),
+            constants::DEFAULT_ARCHIVE_REPLICATION_FACTOR
+        );
+        assert!(blockchain.archive.all_storage_canisters().is_empty());
+        assert!(blockchain.archive.compress_blocks());
+    }
+
+    #[test]
+    fn test_decode_state_v4_fixture() {
+        let state = State::decode(STATE_V4_FIXTURE.to_vec()).unwrap();
+        assert_fixture_settings(&state);
+        let blockchain = state.blockchain.borrow();
+        assert_eq!(
+            blockchain.archive.replication_factor(),
+            constants::DEFAULT_ARCHIVE_REPLICATION_FACTOR
+        );
+        // archives installed by version 4 always compressed the blocks
+        assert!(blockchain.archive.compress_blocks());
+        assert!(blockchain.timestamp_index_backfilled);
+    }
+
+    #[test]
+    fn test_decode_state_errors_are_readable() {
+        let mut bytes = STATE_V2_FIXTURE.to_vec();
+        bytes.truncate(20);
+        let err = State::decode(bytes).unwrap_err();
+        assert!(
+            err.starts_with(
+                "migrate stable state from version 2 to 3 failed, decode token state failed"
+            ),
+            "{}",
+            err
+        );
+
+        let err = State::decode(b"not a state".to_vec()).unwrap_err();
+        assert!(
+            err.starts_with("migrate stable state from version 1 to 2 failed"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_decode_upgrade_args() {
+        assert_eq!(decode_upgrade_args(&[]), Ok(None));
+        assert_eq!(
+            decode_upgrade_args(&candid::encode_args(()).unwrap()),
+            Ok(None)
+        );
+
+        let options = ArchiveOptions {
+            trigger_threshold: 3000,
+            num_blocks_to_archive: 500,
+            node_max_memory_size_bytes: None,
+            max_message_size_bytes: None,
+            cycles_for_archive_creation: Some(1_000_000),
+            archive_interval_seconds: Some(30),
+            min_archive_cycles: None,
+            archive_cycles_top_up: None,
+            replication_factor: Some(2),
+            compress_blocks: Some(false),
+        };
+        assert_eq!(
+            decode_upgrade_args(&candid::encode_one(Some(options.clone())).unwrap()),
+            Ok(Some(options))
+        );
+
+        // candid decodes a value of another type as null, e.g. the init args
+        assert_eq!(
+            decode_upgrade_args(&candid::encode_one("options").unwrap()),
+            Ok(None)
+        );
+
+        let err = decode_upgrade_args(b"not candid").unwrap_err();
+        assert!(err.starts_with("Decoding upgrade args failed"), "{}", err);
+    }
 }
@@ -1,18 +1,26 @@
 use candid::Principal;
-use dft_basic::{service::basic_service::verified_created_at, state::STATE};
+use dft_basic::{
+    service::basic_service::{tx_invoker, verified_created_at},
+    service::batch_service::{reject_all, BatchDryRun},
+    state::STATE,
+};
 use dft_types::*;
 
+// Burn from the caller's own account, or from the account of a holder
+// who authorized the caller as an operator.
 pub fn burn(
     caller: &Principal,
+    caller_sub_account: Option<Subaccount>,
     owner: &TokenHolder,
     value: TokenAmount,
     created_at: Option<u64>,
     now: u64,
 ) -> CommonResult<(BlockHeight, BlockHash, TransactionHash)> {
     verified_created_at(&created_at, &now)?;
+    STATE.with(|s| s.token_setting.borrow().not_allow_anonymous(caller))?;
+    let invoker = tx_invoker(caller, caller_sub_account, owner, now)?;
     STATE.with(|s| {
         let settings = s.token_setting.borrow();
-        settings.not_allow_anonymous(caller)?;
 
         let mut blockchain = s.blockchain.borrow_mut();
 
@@ -35,7 +43,7 @@ pub fn burn(
 
         let tx = InnerTransaction {
             operation: InnerOperation::Transfer {
-                caller: *owner,
+                caller: invoker,
                 from: *owner,
                 to: TokenHolder::empty(),
                 value: value.clone(),
@@ -50,6 +58,47 @@ pub fn burn(
         Ok(res)
     })
 }
+
+// Validate burns from the caller's accounts, as `burn` would apply them, nothing is written.
+pub fn validate_burns(
+    caller: &Principal,
+    items: &[CommonResult<(Option<Subaccount>, TokenAmount)>],
+    created_at: Option<u64>,
+    now: u64,
+) -> Vec<CommonResult<()>> {
+    let dry_run = STATE
+        .with(|s| s.token_setting.borrow().not_allow_anonymous(caller))
+        .and_then(|_| BatchDryRun::new(created_at, now));
+    let mut dry_run = match dry_run {
+        Ok(dry_run) => dry_run,
+        Err(e) => return reject_all(items, e),
+    };
+    let minimum = STATE.with(|s| s.token_setting.borrow().fee().minimum);
+    items
+        .iter()
+        .map(|item| {
+            let (sub_account, value) = item.as_ref().map_err(|e| e.clone())?;
+            let owner = &TokenHolder::new(*caller, *sub_account);
+            if *value < minimum {
+                return Err(DFTError::BurnValueTooSmall);
+            }
+            if dry_run.balance_of(owner) < *value {
+                return Err(DFTError::InsufficientBalance);
+            }
+            let tx_hash = dry_run.check_transaction(InnerOperation::Transfer {
+                caller: *owner,
+                from: *owner,
+                to: TokenHolder::empty(),
+                value: value.clone(),
+                fee: 0u32.into(),
+            })?;
+            dry_run.record_transaction(tx_hash);
+            dry_run.debit(owner, value);
+            Ok(())
+        })
+        .collect()
+}
+
 pub fn burn_from(
     caller: &Principal,
     owner: &TokenHolder,
@@ -93,7 +142,7 @@ pub fn burn_from(
             let res = blockchain.add_tx_to_block(settings.token_id(), tx, now)?;
             s.allowances
                 .borrow_mut()
-                .debit(owner, spender, value.clone())?;
+                .spend(owner, spender, value.clone(), now)?;
             // burn does not charge the transfer fee
             // debit the burn from holder's balance
             balances.debit_balance(owner, value)?;
@@ -1,6 +1,7 @@
 use candid::Principal;
 
 use dft_basic::service::basic_service::verified_created_at;
+use dft_basic::service::batch_service::{reject_all, BatchDryRun};
 use dft_basic::state::STATE;
 use dft_types::*;
 
@@ -16,6 +17,8 @@ pub fn mint(
     STATE.with(|s| {
         let settings = s.token_setting.borrow();
         settings.only_minter(caller)?;
+        // no balance can exceed the total supply
+        check_storable_amount(&(s.balances.borrow().total_supply() + value.clone()))?;
 
         let mut blockchain = s.blockchain.borrow_mut();
         let num_purged = blockchain.tx_window.purge_old_transactions(now);
@@ -41,6 +44,40 @@ pub fn mint(
     })
 }
 
+// Validate mints as `mint` would apply them, nothing is written.
+pub fn validate_mints(
+    caller: &Principal,
+    items: &[CommonResult<(TokenHolder, TokenAmount)>],
+    created_at: Option<u64>,
+    now: u64,
+) -> Vec<CommonResult<()>> {
+    let dry_run = STATE
+        .with(|s| s.token_setting.borrow().only_minter(caller))
+        .and_then(|_| BatchDryRun::new(created_at, now));
+    let mut dry_run = match dry_run {
+        Ok(dry_run) => dry_run,
+        Err(e) => return reject_all(items, e),
+    };
+    let mut total_supply = STATE.with(|s| s.balances.borrow().total_supply());
+    items
+        .iter()
+        .map(|item| {
+            let (to, value) = item.as_ref().map_err(|e| e.clone())?;
+            check_storable_amount(&(total_supply.clone() + value))?;
+            let tx_hash = dry_run.check_transaction(InnerOperation::Transfer {
+                caller: TokenHolder::new(*caller, None),
+                from: TokenHolder::empty(),
+                to: *to,
+                value: value.clone(),
+                fee: 0u32.into(),
+            })?;
+            dry_run.record_transaction(tx_hash);
+            total_supply += value;
+            Ok(())
+        })
+        .collect()
+}
+
 pub fn minters() -> Vec<Principal> {
     STATE.with(|s| {
         let settings = s.token_setting.borrow();
@@ -1,7 +1,9 @@
 use candid::{candid_method, Nat};
-use dft_basic::auto_scaling_storage::AutoScalingStorageService;
+use dft_basic::auto_scaling_storage::{start_archiving_timer, start_cycles_monitor_timer};
 use dft_basic::canister_api::{ITransferNotifyAPI, TransferNotifyAPI};
-use dft_basic::service::basic_service;
+use dft_basic::hold_expiration::start_hold_expiration_timer;
+use dft_basic::service::{basic_service, hold_service};
+use dft_types::constants::{MAX_ALLOWANCES_PER_REQUEST, MAX_REVOKED_ALLOWANCES_PER_CALL};
 use dft_types::*;
 use dft_utils::ic_logger::ICLogger;
 use ic_cdk::api::{data_certificate, set_certified_data};
@@ -24,7 +26,7 @@ pub(crate) fn canister_module_init() {
 #[candid_method(init)]
 #[allow(clippy::too_many_arguments)]
 #[allow(clippy::clone_on_copy)]
-async fn canister_init(
+fn canister_init(
     sub_account: Option<Subaccount>,
     logo: Option<Vec<u8>>,
     name: String,
@@ -50,6 +52,9 @@ async fn canister_init(
         owner_holder.clone(),
         archive_option,
     );
+    start_archiving_timer();
+    start_cycles_monitor_timer();
+    start_hold_expiration_timer();
     if total_supply == 0u32 {
         return;
     }
@@ -160,14 +165,13 @@ fn allowance(owner: String, spender: String) -> Nat {
 #[cfg_attr(coverage_nightly, no_coverage)]
 #[update(name = "approve")]
 #[candid_method(update, rename = "approve")]
-async fn approve(
+fn approve(
     owner_sub_account: Option<Subaccount>,
     spender: String,
     value: Nat,
     created_at: Option<u64>,
 ) -> OperationResult {
     let caller = api::caller();
-    let token_id = api::id();
     let owner_holder = TokenHolder::new(caller, owner_sub_account);
     match spender.parse::<TokenHolder>() {
         Ok(spender_holder) => {
@@ -182,8 +186,6 @@ async fn approve(
                 Ok((block_height, block_hash, tx_hash)) => {
                     set_certified_data(&block_hash);
                     let tx_id = hex::encode(tx_hash.as_ref());
-                    let auto_scaling_service = AutoScalingStorageService::new(token_id);
-                    auto_scaling_service.exec_auto_scaling_strategy().await;
                     OperationResult::Ok {
                         tx_id,
                         block_height: block_height.into(),
@@ -196,23 +198,221 @@ async fn approve(
     }
 }
 
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[update(name = "increaseAllowance")]
+#[candid_method(update, rename = "increaseAllowance")]
+fn increase_allowance(
+    owner_sub_account: Option<Subaccount>,
+    spender: String,
+    value: Nat,
+    created_at: Option<u64>,
+) -> OperationResult {
+    let caller = api::caller();
+    let owner_holder = TokenHolder::new(caller, owner_sub_account);
+    match spender.parse::<TokenHolder>() {
+        Ok(spender_holder) => {
+            match basic_service::increase_allowance(
+                &caller,
+                &owner_holder,
+                &spender_holder,
+                value.0,
+                created_at,
+                api::time(),
+            ) {
+                Ok((block_height, block_hash, tx_hash)) => {
+                    set_certified_data(&block_hash);
+                    let tx_id = hex::encode(tx_hash.as_ref());
+                    OperationResult::Ok {
+                        tx_id,
+                        block_height: block_height.into(),
+                    }
+                }
+                Err(e) => OperationResult::Err(e.into()),
+            }
+        }
+        Err(_) => OperationResult::Err(DFTError::InvalidSpender.into()),
+    }
+}
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[update(name = "decreaseAllowance")]
+#[candid_method(update, rename = "decreaseAllowance")]
+fn decrease_allowance(
+    owner_sub_account: Option<Subaccount>,
+    spender: String,
+    value: Nat,
+    created_at: Option<u64>,
+) -> OperationResult {
+    let caller = api::caller();
+    let owner_holder = TokenHolder::new(caller, owner_sub_account);
+    match spender.parse::<TokenHolder>() {
+        Ok(spender_holder) => {
+            match basic_service::decrease_allowance(
+                &caller,
+                &owner_holder,
+                &spender_holder,
+                value.0,
+                created_at,
+                api::time(),
+            ) {
+                Ok((block_height, block_hash, tx_hash)) => {
+                    set_certified_data(&block_hash);
+                    let tx_id = hex::encode(tx_hash.as_ref());
+                    OperationResult::Ok {
+                        tx_id,
+                        block_height: block_height.into(),
+                    }
+                }
+                Err(e) => OperationResult::Err(e.into()),
+            }
+        }
+        Err(_) => OperationResult::Err(DFTError::InvalidSpender.into()),
+    }
+}
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[update(name = "approveRecurring")]
+#[candid_method(update, rename = "approveRecurring")]
+fn approve_recurring(
+    owner_sub_account: Option<Subaccount>,
+    spender: String,
+    amount_per_period: Nat,
+    period: u64,
+    start: Option<u64>,
+    created_at: Option<u64>,
+) -> OperationResult {
+    let caller = api::caller();
+    let owner_holder = TokenHolder::new(caller, owner_sub_account);
+    match spender.parse::<TokenHolder>() {
+        Ok(spender_holder) => {
+            match basic_service::approve_recurring(
+                &caller,
+                &owner_holder,
+                &spender_holder,
+                amount_per_period.0,
+                period,
+                start,
+                created_at,
+                api::time(),
+            ) {
+                Ok((block_height, block_hash, tx_hash)) => {
+                    set_certified_data(&block_hash);
+                    let tx_id = hex::encode(tx_hash.as_ref());
+                    OperationResult::Ok {
+                        tx_id,
+                        block_height: block_height.into(),
+                    }
+                }
+                Err(e) => OperationResult::Err(e.into()),
+            }
+        }
+        Err(_) => OperationResult::Err(DFTError::InvalidSpender.into()),
+    }
+}
+
+// the remaining amount of the current period and when it is reset
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[query(name = "recurringAllowance")]
+#[candid_method(query, rename = "recurringAllowance")]
+fn recurring_allowance(owner: String, spender: String) -> Option<RecurringAllowance> {
+    match (owner.parse::<TokenHolder>(), spender.parse::<TokenHolder>()) {
+        (Ok(owner), Ok(spender)) => {
+            basic_service::recurring_allowance(&owner, &spender, api::time())
+        }
+        _ => None,
+    }
+}
+
+// revokes the one-shot and recurring allowances of the caller's spenders
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[update(name = "revokeAllAllowances")]
+#[candid_method(update, rename = "revokeAllAllowances")]
+fn revoke_all_allowances(
+    owner_sub_account: Option<Subaccount>,
+    created_at: Option<u64>,
+) -> RevokeAllAllowancesResult {
+    let caller = api::caller();
+    let owner_holder = TokenHolder::new(caller, owner_sub_account);
+    match basic_service::revoke_all_allowances(
+        &caller,
+        &owner_holder,
+        MAX_REVOKED_ALLOWANCES_PER_CALL,
+        created_at,
+        api::time(),
+    ) {
+        Ok(((block_height, block_hash, tx_hash), has_more)) => {
+            set_certified_data(&block_hash);
+            let tx_id = hex::encode(tx_hash.as_ref());
+            RevokeAllAllowancesResult::Ok {
+                tx_id,
+                block_height: block_height.into(),
+                has_more,
+            }
+        }
+        Err(e) => RevokeAllAllowancesResult::Err(e.into()),
+    }
+}
+
+// the one-shot allowances only, recurring allowances are read with `recurringAllowance`
 #[cfg_attr(coverage_nightly, no_coverage)]
 #[query(name = "allowancesOf")]
 #[candid_method(query, rename = "allowancesOf")]
-fn allowances_of_holder(holder: String) -> Vec<(TokenHolder, Nat)> {
-    match holder.parse::<TokenHolder>() {
-        Ok(token_holder) => basic_service::allowances_of(&token_holder)
-            .into_iter()
-            .map(|(v, n)| (v, n.into()))
-            .collect(),
-        Err(_) => Vec::new(),
+fn allowances_of_holder(
+    holder: String,
+    cursor: Option<String>,
+    limit: Option<usize>,
+) -> Vec<(TokenHolder, Nat)> {
+    match parse_allowances_page(holder, cursor) {
+        Some((token_holder, cursor)) => basic_service::allowances_of(
+            &token_holder,
+            cursor,
+            limit.unwrap_or(MAX_ALLOWANCES_PER_REQUEST),
+        )
+        .into_iter()
+        .map(|(v, n)| (v, n.into()))
+        .collect(),
+        None => Vec::new(),
+    }
+}
+
+// the one-shot allowances only, as `allowancesOf`
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[query(name = "allowancesBySpender")]
+#[candid_method(query, rename = "allowancesBySpender")]
+fn allowances_by_spender(
+    spender: String,
+    cursor: Option<String>,
+    limit: Option<usize>,
+) -> Vec<(TokenHolder, Nat)> {
+    match parse_allowances_page(spender, cursor) {
+        Some((spender_holder, cursor)) => basic_service::allowances_by_spender(
+            &spender_holder,
+            cursor,
+            limit.unwrap_or(MAX_ALLOWANCES_PER_REQUEST),
+        )
+        .into_iter()
+        .map(|(v, n)| (v, n.into()))
+        .collect(),
+        None => Vec::new(),
+    }
+}
+
+// the cursor is the last account of the previous page
+fn parse_allowances_page(
+    holder: String,
+    cursor: Option<String>,
+) -> Option<(TokenHolder, Option<TokenHolder>)> {
+    let holder = holder.parse::<TokenHolder>().ok()?;
+    match cursor {
+        Some(cursor) => Some((holder, Some(cursor.parse::<TokenHolder>().ok()?))),
+        None => Some((holder, None)),
     }
 }
 
 #[cfg_attr(coverage_nightly, no_coverage)]
 #[update(name = "transferFrom")]
 #[candid_method(update, rename = "transferFrom")]
-async fn transfer_from(
+fn transfer_from(
     spender_sub_account: Option<Subaccount>,
     from: String,
     to: String,
@@ -220,7 +420,6 @@ async fn transfer_from(
     created_at: Option<u64>,
 ) -> OperationResult {
     let caller = api::caller();
-    let token_id = api::id();
     let now = api::time();
     let spender = TokenHolder::new(caller, spender_sub_account);
 
@@ -243,9 +442,6 @@ async fn transfer_from(
                 ) {
                     Ok((block_height, block_hash, tx_hash)) => {
                         set_certified_data(&block_hash);
-                        AutoScalingStorageService::new(token_id)
-                            .exec_auto_scaling_strategy()
-                            .await;
                         TransferNotifyAPI::default().notify(
                             &to,
                             &block_height,
@@ -269,16 +465,21 @@ async fn transfer_from(
 #[cfg_attr(coverage_nightly, no_coverage)]
 #[update(name = "transfer")]
 #[candid_method(update, rename = "transfer")]
-async fn transfer(
+fn transfer(
     from_sub_account: Option<Subaccount>,
     to: String,
     value: Nat,
     created_at: Option<u64>,
+    on_behalf_of: Option<String>,
 ) -> OperationResult {
     let caller = api::caller();
-    let token_id = api::id();
     let now = api::time();
-    let transfer_from = TokenHolder::new(caller, from_sub_account);
+    // an operator transfers from the account of the holder
+    let transfer_from = match on_behalf_of.map(|holder| holder.parse::<TokenHolder>()) {
+        None => TokenHolder::new(caller, from_sub_account),
+        Some(Ok(holder)) => holder,
+        Some(Err(_)) => return OperationResult::Err(DFTError::InvalidArgFormatFrom.into()),
+    };
     let receiver_parse_result = to.parse::<TokenReceiver>();
 
     match receiver_parse_result {
@@ -290,6 +491,7 @@ async fn transfer(
             //transfer token
             match basic_service::transfer(
                 &caller,
+                from_sub_account,
                 &transfer_from,
                 &receiver,
                 value.0.clone(),
@@ -298,9 +500,6 @@ async fn transfer(
             ) {
                 Ok((block_height, block_hash, tx_hash)) => {
                     set_certified_data(&block_hash);
-                    AutoScalingStorageService::new(token_id)
-                        .exec_auto_scaling_strategy()
-                        .await;
                     TransferNotifyAPI::default().notify(
                         &to,
                         &block_height,
@@ -319,6 +518,242 @@ async fn transfer(
     }
 }
 
+// one block for all the outputs, each canister recipient is notified once
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[update(name = "multiTransfer")]
+#[candid_method(update, rename = "multiTransfer")]
+fn multi_transfer(
+    from_sub_account: Option<Subaccount>,
+    outputs: Vec<(String, Nat)>,
+    created_at: Option<u64>,
+) -> OperationResult {
+    let caller = api::caller();
+    let now = api::time();
+    let transfer_from = TokenHolder::new(caller, from_sub_account);
+
+    let mut receivers = Vec::with_capacity(outputs.len());
+    for (to, value) in outputs.iter() {
+        match to.parse::<TokenReceiver>() {
+            Ok(receiver) => {
+                //exec before-transfer check
+                if let Err(e) = before_token_sending(&transfer_from, &receiver, &value.0) {
+                    return OperationResult::Err(e);
+                };
+                receivers.push((receiver, value.0.clone()));
+            }
+            _ => return OperationResult::Err(DFTError::InvalidArgFormatTo.into()),
+        }
+    }
+    match basic_service::multi_transfer(&caller, &transfer_from, receivers, created_at, now) {
+        Ok((block_height, block_hash, tx_hash)) => {
+            set_certified_data(&block_hash);
+            let outputs: Vec<(String, TokenAmount)> = outputs
+                .into_iter()
+                .map(|(to, value)| (to, value.0))
+                .collect();
+            TransferNotifyAPI.notify_outputs(&outputs, &block_height, &transfer_from);
+            OperationResult::Ok {
+                tx_id: hex::encode(tx_hash.as_ref()),
+                block_height: block_height.into(),
+            }
+        }
+        Err(e) => OperationResult::Err(e.into()),
+    }
+}
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[update(name = "hold")]
+#[candid_method(update, rename = "hold")]
+fn hold(
+    from_sub_account: Option<Subaccount>,
+    merchant: String,
+    amount: Nat,
+    expires_at: u64,
+    created_at: Option<u64>,
+) -> OperationResult {
+    let caller = api::caller();
+    let owner_holder = TokenHolder::new(caller, from_sub_account);
+    let merchant_holder = match merchant.parse::<TokenHolder>() {
+        Ok(merchant_holder) => merchant_holder,
+        Err(_) => return OperationResult::Err(DFTError::InvalidArgFormatTo.into()),
+    };
+    match hold_service::hold(
+        &caller,
+        &owner_holder,
+        &merchant_holder,
+        amount.0,
+        expires_at,
+        created_at,
+        api::time(),
+    ) {
+        Ok((block_height, block_hash, tx_hash)) => {
+            set_certified_data(&block_hash);
+            OperationResult::Ok {
+                tx_id: hex::encode(tx_hash.as_ref()),
+                block_height: block_height.into(),
+            }
+        }
+        Err(e) => OperationResult::Err(e.into()),
+    }
+}
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[update(name = "capture")]
+#[candid_method(update, rename = "capture")]
+fn capture(
+    merchant_sub_account: Option<Subaccount>,
+    hold_id: u64,
+    amount: Nat,
+    created_at: Option<u64>,
+) -> OperationResult {
+    let caller = api::caller();
+    let merchant_holder = TokenHolder::new(caller, merchant_sub_account);
+    match hold_service::capture(
+        &caller,
+        &merchant_holder,
+        hold_id,
+        amount.0,
+        created_at,
+        api::time(),
+    ) {
+        Ok((block_height, block_hash, tx_hash)) => {
+            set_certified_data(&block_hash);
+            OperationResult::Ok {
+                tx_id: hex::encode(tx_hash.as_ref()),
+                block_height: block_height.into(),
+            }
+        }
+        Err(e) => OperationResult::Err(e.into()),
+    }
+}
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[update(name = "release")]
+#[candid_method(update, rename = "release")]
+fn release(
+    sub_account: Option<Subaccount>,
+    hold_id: u64,
+    created_at: Option<u64>,
+) -> OperationResult {
+    let caller = api::caller();
+    let releaser = TokenHolder::new(caller, sub_account);
+    match hold_service::release(&caller, &releaser, hold_id, created_at, api::time()) {
+        Ok((block_height, block_hash, tx_hash)) => {
+            set_certified_data(&block_hash);
+            OperationResult::Ok {
+                tx_id: hex::encode(tx_hash.as_ref()),
+                block_height: block_height.into(),
+            }
+        }
+        Err(e) => OperationResult::Err(e.into()),
+    }
+}
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[query(name = "heldBalanceOf")]
+#[candid_method(query, rename = "heldBalanceOf")]
+fn held_balance_of(holder: String) -> Nat {
+    match holder.parse::<TokenHolder>() {
+        Ok(token_holder) => hold_service::held_balance_of(&token_holder).into(),
+        _ => 0u32.into(),
+    }
+}
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[query(name = "holdById")]
+#[candid_method(query, rename = "holdById")]
+fn hold_by_id(hold_id: u64) -> Option<Hold> {
+    hold_service::hold_by_id(hold_id).map(Hold::from)
+}
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[update(name = "permit")]
+#[candid_method(update, rename = "permit")]
+fn apply_permit(permit: Permit) -> OperationResult {
+    match basic_service::permit(&permit, api::time()) {
+        Ok((block_height, block_hash, tx_hash)) => {
+            set_certified_data(&block_hash);
+            OperationResult::Ok {
+                tx_id: hex::encode(tx_hash.as_ref()),
+                block_height: block_height.into(),
+            }
+        }
+        Err(e) => OperationResult::Err(e.into()),
+    }
+}
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[query(name = "permitNonce")]
+#[candid_method(query, rename = "permitNonce")]
+fn permit_nonce(owner: String) -> u64 {
+    match owner.parse::<TokenHolder>() {
+        Ok(owner_holder) => basic_service::permit_nonce(&owner_holder),
+        Err(_) => 0,
+    }
+}
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[update(name = "authorizeOperator")]
+#[candid_method(update, rename = "authorizeOperator")]
+fn authorize_operator(
+    sub_account: Option<Subaccount>,
+    operator: Principal,
+    expires_at: Option<u64>,
+    created_at: Option<u64>,
+) -> OperationResult {
+    let caller = api::caller();
+    let holder = TokenHolder::new(caller, sub_account);
+    match basic_service::authorize_operator(
+        &caller,
+        &holder,
+        &operator,
+        expires_at,
+        created_at,
+        api::time(),
+    ) {
+        Ok((block_height, block_hash, tx_hash)) => {
+            set_certified_data(&block_hash);
+            OperationResult::Ok {
+                tx_id: hex::encode(tx_hash.as_ref()),
+                block_height: block_height.into(),
+            }
+        }
+        Err(e) => OperationResult::Err(e.into()),
+    }
+}
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[update(name = "revokeOperator")]
+#[candid_method(update, rename = "revokeOperator")]
+fn revoke_operator(
+    sub_account: Option<Subaccount>,
+    operator: Principal,
+    created_at: Option<u64>,
+) -> OperationResult {
+    let caller = api::caller();
+    let holder = TokenHolder::new(caller, sub_account);
+    match basic_service::revoke_operator(&caller, &holder, &operator, created_at, api::time()) {
+        Ok((block_height, block_hash, tx_hash)) => {
+            set_certified_data(&block_hash);
+            OperationResult::Ok {
+                tx_id: hex::encode(tx_hash.as_ref()),
+                block_height: block_height.into(),
+            }
+        }
+        Err(e) => OperationResult::Err(e.into()),
+    }
+}
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[query(name = "operatorsOf")]
+#[candid_method(query, rename = "operatorsOf")]
+fn operators_of(holder: String) -> Vec<OperatorInfo> {
+    match holder.parse::<TokenHolder>() {
+        Ok(token_holder) => basic_service::operators_of(&token_holder, api::time()),
+        Err(_) => Vec::new(),
+    }
+}
+
 #[cfg_attr(coverage_nightly, no_coverage)]
 #[query(name = "tokenInfo")]
 #[candid_method(query, rename = "tokenInfo")]
@@ -354,6 +789,19 @@ fn blocks_by_query(start: Nat, count: usize) -> QueryBlocksResult {
     res
 }
 
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[query(name = "blocksByTimeRange")]
+#[candid_method(query, rename = "blocksByTimeRange")]
+fn blocks_by_time_range(
+    start_ts: u64,
+    end_ts: u64,
+    limit: usize,
+    cursor: Option<Nat>,
+) -> BlocksByTimeRangeResult {
+    basic_service::blocks_by_time_range(start_ts, end_ts, limit, cursor.map(|cursor| cursor.0))
+        .into()
+}
+
 #[cfg_attr(coverage_nightly, no_coverage)]
 #[query(name = "archives")]
 #[candid_method(query, rename = "archives")]
@@ -361,6 +809,20 @@ fn archives() -> Vec<ArchiveInfo> {
     basic_service::archives()
 }
 
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[query(name = "archiveOptions")]
+#[candid_method(query, rename = "archiveOptions")]
+fn archive_options() -> ArchiveOptions {
+    basic_service::archive_options()
+}
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[query(name = "archivingStatus")]
+#[candid_method(query, rename = "archivingStatus")]
+fn archiving_status() -> ArchivingStatus {
+    basic_service::archiving_status()
+}
+
 // do something before sending
 fn before_token_sending(
     _transfer_from: &TokenHolder,
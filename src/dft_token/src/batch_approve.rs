@@ -0,0 +1,46 @@
+use candid::{candid_method, Nat};
+use dft_basic::service::{basic_service, batch_service};
+use dft_types::*;
+use ic_cdk::api;
+use ic_cdk_macros::*;
+use std::string::String;
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[update(name = "batchApprove")]
+#[candid_method(update, rename = "batchApprove")]
+fn batch_approve(
+    owner_sub_account: Option<Subaccount>,
+    approve_requests: Vec<(String, Nat)>,
+    created_at: Option<u64>,
+    mode: Option<BatchMode>,
+) -> Vec<OperationResult> {
+    let caller = api::caller();
+    let now = api::time();
+    let owner_holder = TokenHolder::new(caller, owner_sub_account);
+    let items: Vec<CommonResult<(TokenHolder, TokenAmount)>> = approve_requests
+        .into_iter()
+        .map(|(spender, value)| match spender.parse::<TokenHolder>() {
+            Ok(spender_holder) => Ok((spender_holder, value.0)),
+            Err(_) => Err(DFTError::InvalidSpender),
+        })
+        .collect();
+
+    batch_service::apply_batch(
+        mode.unwrap_or_default(),
+        items,
+        |items| batch_service::validate_approvals(&caller, &owner_holder, items, created_at, now),
+        |(spender_holder, value)| {
+            basic_service::approve(
+                &caller,
+                &owner_holder,
+                &spender_holder,
+                value,
+                created_at,
+                now,
+            )
+        },
+    )
+    .into_iter()
+    .map(OperationResult::from)
+    .collect()
+}
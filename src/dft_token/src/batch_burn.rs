@@ -0,0 +1,34 @@
+use candid::{candid_method, Nat};
+use dft_basic::service::batch_service;
+use dft_types::*;
+use ic_cdk::api;
+use ic_cdk_macros::*;
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[update(name = "batchBurn")]
+#[candid_method(update, rename = "batchBurn")]
+fn batch_burn(
+    burn_requests: Vec<(Option<Subaccount>, Nat)>,
+    created_at: Option<u64>,
+    mode: Option<BatchMode>,
+) -> Vec<OperationResult> {
+    let caller = api::caller();
+    let now = api::time();
+    let items: Vec<CommonResult<(Option<Subaccount>, TokenAmount)>> = burn_requests
+        .into_iter()
+        .map(|(sub_account, value)| Ok((sub_account, value.0)))
+        .collect();
+
+    batch_service::apply_batch(
+        mode.unwrap_or_default(),
+        items,
+        |items| dft_burnable::validate_burns(&caller, items, created_at, now),
+        |(sub_account, value)| {
+            let owner = TokenHolder::new(caller, sub_account);
+            dft_burnable::burn(&caller, sub_account, &owner, value, created_at, now)
+        },
+    )
+    .into_iter()
+    .map(OperationResult::from)
+    .collect()
+}
@@ -1,5 +1,4 @@
 use candid::{candid_method, Nat};
-use dft_basic::auto_scaling_storage::AutoScalingStorageService;
 use dft_types::*;
 use ic_cdk::api;
 use ic_cdk_macros::*;
@@ -8,14 +7,13 @@ use std::string::String;
 #[cfg_attr(coverage_nightly, no_coverage)]
 #[update(name = "burnFrom")]
 #[candid_method(update, rename = "burnFrom")]
-async fn burn_from(
+fn burn_from(
     from_sub_account: Option<Subaccount>,
     owner: String,
     value: Nat,
     created_at: Option<u64>,
 ) -> OperationResult {
     let caller = api::caller();
-    let token_id = api::id();
     let spender = TokenHolder::new(caller, from_sub_account);
     let owner_parse_res = owner.parse::<TokenHolder>();
     match owner_parse_res {
@@ -28,14 +26,10 @@ async fn burn_from(
                 created_at,
                 api::time(),
             ) {
-                Ok((block_height, _, tx_hash)) => {
-                    let auto_scaling_service = AutoScalingStorageService::new(token_id);
-                    auto_scaling_service.exec_auto_scaling_strategy().await;
-                    OperationResult::Ok {
-                        tx_id: hex::encode(tx_hash.as_ref()),
-                        block_height: block_height.into(),
-                    }
-                }
+                Ok((block_height, _, tx_hash)) => OperationResult::Ok {
+                    tx_id: hex::encode(tx_hash.as_ref()),
+                    block_height: block_height.into(),
+                },
                 Err(e) => OperationResult::Err(e.into()),
             }
         }
@@ -47,23 +41,31 @@ async fn burn_from(
 #[cfg_attr(coverage_nightly, no_coverage)]
 #[update(name = "burn")]
 #[candid_method(update, rename = "burn")]
-async fn burn(
+fn burn(
     from_sub_account: Option<Subaccount>,
     value: Nat,
     created_at: Option<u64>,
+    on_behalf_of: Option<String>,
 ) -> OperationResult {
     let caller = api::caller();
-    let token_id = api::id();
-    let transfer_from = TokenHolder::new(caller, from_sub_account);
-    match dft_burnable::burn(&caller, &transfer_from, value.0, created_at, api::time()) {
-        Ok((block_height, _, tx_hash)) => {
-            let auto_scaling_service = AutoScalingStorageService::new(token_id);
-            auto_scaling_service.exec_auto_scaling_strategy().await;
-            OperationResult::Ok {
-                tx_id: hex::encode(tx_hash.as_ref()),
-                block_height: block_height.into(),
-            }
-        }
+    // an operator burns from the account of the holder
+    let burn_from = match on_behalf_of.map(|holder| holder.parse::<TokenHolder>()) {
+        None => TokenHolder::new(caller, from_sub_account),
+        Some(Ok(holder)) => holder,
+        Some(Err(_)) => return OperationResult::Err(DFTError::InvalidArgFormatFrom.into()),
+    };
+    match dft_burnable::burn(
+        &caller,
+        from_sub_account,
+        &burn_from,
+        value.0,
+        created_at,
+        api::time(),
+    ) {
+        Ok((block_height, _, tx_hash)) => OperationResult::Ok {
+            tx_id: hex::encode(tx_hash.as_ref()),
+            block_height: block_height.into(),
+        },
         Err(e) => OperationResult::Err(e.into()),
     }
 }
@@ -33,12 +33,14 @@ fn http_request(req: HttpRequest) -> HttpResponse {
             );
 
             let metrics_json = format!(
-                "{{totalBlockHeight : {},localBlockCount : {},cycles : {},holders : {},allowanceSize : {}}}",
+                "{{totalBlockHeight : {},localBlockCount : {},cycles : {},holders : {},allowanceSize : {},archivesLowOnCycles : {},archivesWithCyclesErrors : {}}}",
                 metrics.chain_length,
                 metrics.local_block_count,
                 cycles,
                 metrics.holders,
                 metrics.allowance_size,
+                metrics.archives_low_on_cycles,
+                metrics.archives_with_cycles_errors,
             );
             let json = format!("{{token:{},metrics:{}}}", token_info_json, metrics_json);
             let formatter = PrettyFormatter::from_str(json.as_str());
@@ -10,6 +10,10 @@ mod management;
 
 #[cfg(feature = "basic")]
 mod basic;
+#[cfg(feature = "batch_approve")]
+mod batch_approve;
+#[cfg(feature = "batch_burn")]
+mod batch_burn;
 #[cfg(feature = "batch_mint")]
 mod batch_mint;
 #[cfg(feature = "batch_transfer")]
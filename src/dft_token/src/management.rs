@@ -1,5 +1,7 @@
 use candid::candid_method;
-use dft_basic::service::management_service;
+use dft_basic::auto_scaling_storage::{start_archiving_timer, AutoScalingStorageService};
+use dft_basic::chain_verification::start_chain_verification_timer;
+use dft_basic::service::{basic_service, management_service};
 use dft_types::*;
 use ic_cdk::{api, export::Principal};
 use ic_cdk_macros::*;
@@ -50,3 +52,66 @@ fn set_fee_to(fee_to: String, created_at: Option<u64>) -> BooleanResult {
         Err(_) => BooleanResult::Err(DFTError::InvalidArgFormatFeeTo.into()),
     }
 }
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[update(name = "verifyChain")]
+#[candid_method(update, rename = "verifyChain")]
+fn verify_chain() -> BooleanResult {
+    let res = management_service::verify_chain(&api::caller(), api::time());
+    if res.is_ok() {
+        start_chain_verification_timer();
+    }
+    res.into()
+}
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[query(name = "chainVerification")]
+#[candid_method(query, rename = "chainVerification")]
+fn chain_verification() -> ChainVerification {
+    basic_service::chain_verification()
+}
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[update(name = "setArchiveOptions")]
+#[candid_method(update, rename = "setArchiveOptions")]
+fn set_archive_options(options: ArchiveOptions, created_at: Option<u64>) -> BooleanResult {
+    let res =
+        management_service::set_archive_options(&api::caller(), options, created_at, api::time());
+    if res.is_ok() {
+        // the archive interval may have changed
+        start_archiving_timer();
+    }
+    res.into()
+}
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[update(name = "retryArchiving")]
+#[candid_method(update, rename = "retryArchiving")]
+async fn retry_archiving() -> BooleanResult {
+    match management_service::retry_archiving(&api::caller()) {
+        Ok(res) => {
+            AutoScalingStorageService::new(api::id())
+                .exec_auto_scaling_strategy(api::time())
+                .await;
+            BooleanResult::Ok(res)
+        }
+        Err(e) => BooleanResult::Err(e.into()),
+    }
+}
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[update(name = "resetArchiving")]
+#[candid_method(update, rename = "resetArchiving")]
+fn reset_archiving() -> BooleanResult {
+    management_service::reset_archiving(&api::caller()).into()
+}
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[update(name = "upgradeArchives")]
+#[candid_method(update, rename = "upgradeArchives")]
+async fn upgrade_archives() -> UpgradeArchivesResult {
+    AutoScalingStorageService::new(api::id())
+        .upgrade_archives(&api::caller())
+        .await
+        .into()
+}
@@ -1,5 +1,4 @@
 use candid::{candid_method, Nat, Principal};
-use dft_basic::auto_scaling_storage::AutoScalingStorageService;
 use dft_types::*;
 use ic_cdk::api;
 use ic_cdk_macros::*;
@@ -29,21 +28,16 @@ fn remove_minter(minter: Principal, created_at: Option<u64>) -> BooleanResult {
 #[cfg_attr(coverage_nightly, no_coverage)]
 #[update(name = "mint")]
 #[candid_method(update, rename = "mint")]
-async fn mint(to: String, value: Nat, created_at: Option<u64>) -> OperationResult {
-    let token_id = api::id();
+fn mint(to: String, value: Nat, created_at: Option<u64>) -> OperationResult {
     let holder_parse_res = to.parse::<TokenHolder>();
 
     match holder_parse_res {
         Ok(holder) => {
             match dft_mintable::mint(&api::caller(), &holder, value.0, created_at, api::time()) {
-                Ok((block_height, _, tx_hash)) => {
-                    let auto_scaling_service = AutoScalingStorageService::new(token_id);
-                    auto_scaling_service.exec_auto_scaling_strategy().await;
-                    OperationResult::Ok {
-                        tx_id: hex::encode(tx_hash.as_ref()),
-                        block_height: block_height.into(),
-                    }
-                }
+                Ok((block_height, _, tx_hash)) => OperationResult::Ok {
+                    tx_id: hex::encode(tx_hash.as_ref()),
+                    block_height: block_height.into(),
+                },
                 Err(e) => OperationResult::Err(e.into()),
             }
         }
@@ -3,11 +3,14 @@ use std::io::Read;
 use std::ops::Mul;
 
 use candid::Principal;
-use num_traits::CheckedSub;
+use num_traits::{CheckedSub, ToPrimitive};
 use rstest::*;
 
-use dft_basic::service::{basic_service, management_service};
-use dft_types::constants::DEFAULT_FEE_RATE_DECIMALS;
+use dft_basic::service::{basic_service, batch_service, hold_service, management_service};
+use dft_types::constants::{
+    DEFAULT_FEE_RATE_DECIMALS, MAX_BATCH_ITEMS, MAX_HOLD_DURATION, MAX_MULTI_TRANSFER_OUTPUTS,
+    MAX_REVOKED_ALLOWANCES_PER_CALL,
+};
 use dft_types::*;
 
 #[fixture]
@@ -268,6 +271,110 @@ fn test_token_basic_set_logo(test_owner: Principal, new_logo: Vec<u8>) {
     assert_eq!(basic_service::logo().clone().unwrap_or(vec![]), new_logo);
 }
 
+#[rstest]
+fn test_token_basic_retry_and_reset_archiving(test_owner: Principal) {
+    test_token_with_0_fee_rate();
+    // retry/reset archiving by other caller will failed
+    let res = management_service::retry_archiving(&other_caller());
+    assert!(res.is_err(), "retry_archiving should be err");
+    let res = management_service::reset_archiving(&other_caller());
+    assert!(res.is_err(), "reset_archiving should be err");
+    // retry/reset archiving by owner will ok
+    let res = management_service::retry_archiving(&test_owner);
+    assert!(res.is_ok(), "retry_archiving should be ok");
+    let res = management_service::reset_archiving(&test_owner);
+    assert!(res.is_ok(), "reset_archiving should be ok");
+    assert_eq!(
+        basic_service::archiving_status().state,
+        ArchivingState::Idle
+    );
+}
+
+#[rstest]
+fn test_token_basic_set_archive_options(test_owner: Principal, now: u64) {
+    test_token_with_0_fee_rate();
+    let default_options = basic_service::archive_options();
+    let options = ArchiveOptions {
+        trigger_threshold: 3000,
+        num_blocks_to_archive: 500,
+        node_max_memory_size_bytes: None,
+        max_message_size_bytes: Some(1024 * 1024),
+        cycles_for_archive_creation: None,
+        archive_interval_seconds: Some(30),
+        min_archive_cycles: None,
+        archive_cycles_top_up: None,
+        replication_factor: Some(2),
+        compress_blocks: Some(false),
+    };
+    // set archive options by other caller will failed
+    let res = management_service::set_archive_options(&other_caller(), options.clone(), None, now);
+    assert_eq!(res, Err(DFTError::OnlyOwnerAllowCallIt));
+    // invalid options are rejected
+    let res = management_service::set_archive_options(
+        &test_owner,
+        ArchiveOptions {
+            num_blocks_to_archive: 4000,
+            ..options.clone()
+        },
+        None,
+        now,
+    );
+    assert!(matches!(res, Err(DFTError::InvalidArchiveOptions { .. })));
+    assert_eq!(basic_service::archive_options(), default_options);
+
+    // set archive options by owner will ok, omitted values are unchanged
+    let chain_length = basic_service::token_metrics().chain_length;
+    let res = management_service::set_archive_options(&test_owner, options, None, now);
+    assert!(res.is_ok(), "set_archive_options should be ok");
+    let new_options = basic_service::archive_options();
+    assert_eq!(new_options.trigger_threshold, 3000);
+    assert_eq!(new_options.num_blocks_to_archive, 500);
+    assert_eq!(new_options.max_message_size_bytes, Some(1024 * 1024));
+    assert_eq!(new_options.archive_interval_seconds, Some(30));
+    assert_eq!(new_options.replication_factor, Some(2));
+    assert_eq!(new_options.compress_blocks, Some(false));
+    assert_eq!(
+        new_options.node_max_memory_size_bytes,
+        default_options.node_max_memory_size_bytes
+    );
+    assert_eq!(
+        new_options.cycles_for_archive_creation,
+        default_options.cycles_for_archive_creation
+    );
+    assert_eq!(basic_service::archiving_status().interval_seconds, 30);
+
+    // the change is recorded in a block
+    match basic_service::block_by_height(chain_length.0) {
+        BlockResult::Ok(block) => assert_eq!(
+            block.transaction.operation,
+            Operation::ArchiveOptionsModify {
+                caller: test_owner.into(),
+                new_options: new_options.clone(),
+            }
+        ),
+        res => panic!("unexpected block result {:?}", res),
+    }
+
+    // upgrade args equal to the current options write no block
+    let chain_length = basic_service::token_metrics().chain_length;
+    let res = management_service::set_archive_options_on_upgrade(&test_owner, new_options, now);
+    assert_eq!(res, Ok(false));
+    assert_eq!(basic_service::token_metrics().chain_length, chain_length);
+    let res = management_service::set_archive_options_on_upgrade(
+        &test_owner,
+        ArchiveOptions {
+            archive_interval_seconds: Some(60),
+            ..basic_service::archive_options()
+        },
+        now,
+    );
+    assert_eq!(res, Ok(true));
+    assert_eq!(
+        basic_service::token_metrics().chain_length,
+        chain_length + 1u32
+    );
+}
+
 #[rstest]
 fn test_token_basic_set_desc(test_owner: Principal) {
     test_token_with_0_fee_rate();
@@ -423,6 +530,7 @@ fn test_token_basic_fee_calculation(
     let transfer_val2 = TokenAmount::from(2000u32);
     let transfer_res2 = basic_service::transfer(
         &test_minter,
+        None,
         &minter_holder,
         &to_holder,
         transfer_val2.clone(),
@@ -459,6 +567,85 @@ fn test_token_basic_fee_calculation(
     assert_eq!(total_supply, mint_val.clone());
 }
 
+// amounts which can not be stored are rejected, nothing is written
+#[rstest]
+fn test_token_rejects_amounts_above_256_bits(
+    test_owner: Principal,
+    test_minter: Principal,
+    test_spender: Principal,
+    now: u64,
+) {
+    test_token_with_0_fee_rate();
+    let minter_holder = TokenHolder::new(test_minter, None);
+    let spender_holder = TokenHolder::new(test_spender, None);
+    let max = (TokenAmount::from(1u32) << 256u32) - 1u32;
+    let too_large = max.clone() + 1u32;
+    let _ = dft_mintable::add_minter(&test_owner, test_owner, None, now);
+    dft_mintable::mint(&test_owner, &minter_holder, 10000u32.into(), None, now).unwrap();
+    let chain_length = basic_service::token_metrics().chain_length;
+
+    let res = basic_service::approve(
+        &test_minter,
+        &minter_holder,
+        &spender_holder,
+        too_large.clone(),
+        None,
+        now,
+    );
+    assert_eq!(res, Err(DFTError::AmountTooLarge));
+    basic_service::approve(
+        &test_minter,
+        &minter_holder,
+        &spender_holder,
+        max,
+        None,
+        now,
+    )
+    .unwrap();
+    let res = basic_service::increase_allowance(
+        &test_minter,
+        &minter_holder,
+        &spender_holder,
+        1u32.into(),
+        None,
+        now,
+    );
+    assert_eq!(res, Err(DFTError::AmountTooLarge));
+
+    let res = dft_mintable::mint(&test_owner, &minter_holder, too_large.clone(), None, now);
+    assert_eq!(res, Err(DFTError::AmountTooLarge));
+    let total_supply = basic_service::total_supply();
+    let res = dft_mintable::validate_mints(
+        &test_owner,
+        &[
+            Ok((
+                minter_holder,
+                too_large.clone() - total_supply.clone() - 1u32,
+            )),
+            Ok((spender_holder, 1u32.into())),
+        ],
+        None,
+        now,
+    );
+    assert_eq!(res, vec![Ok(()), Err(DFTError::AmountTooLarge)]);
+
+    let res = basic_service::transfer(
+        &test_minter,
+        None,
+        &minter_holder,
+        &spender_holder,
+        too_large,
+        None,
+        now,
+    );
+    assert_eq!(res, Err(DFTError::AmountTooLarge));
+    assert_eq!(basic_service::total_supply(), total_supply);
+    assert_eq!(
+        basic_service::token_metrics().chain_length,
+        chain_length + 1u32
+    );
+}
+
 //test token approve
 #[rstest]
 #[case(test_token_with_0_fee_rate())]
@@ -537,7 +724,7 @@ fn test_token_basic_approve(
         now,
     );
 
-    let allowances = basic_service::allowances_of(&minter_holder);
+    let allowances = basic_service::allowances_of(&minter_holder, None, usize::MAX);
     assert_eq!(allowances.len(), 2);
 
     for allowance in allowances.clone() {
@@ -547,6 +734,496 @@ fn test_token_basic_approve(
             assert_eq!(allowance.1, approve_val, "{:?}", allowances.clone());
         }
     }
+    assert_eq!(
+        basic_service::allowances_by_spender(&spender_holder, None, usize::MAX),
+        vec![(minter_holder, new_approve_val)]
+    );
+    assert_eq!(
+        basic_service::allowances_of(&minter_holder, None, 1).len(),
+        1
+    );
+}
+
+#[rstest]
+#[case(test_token_with_0_fee_rate())]
+#[case(test_token_with_non_0_fee_rate())]
+fn test_token_basic_increase_decrease_revoke_allowances(
+    #[case] _test_token: (),
+    test_owner: Principal,
+    test_minter: Principal,
+    test_spender: Principal,
+    other_caller: Principal,
+    now: u64,
+) {
+    let minter_holder = TokenHolder::new(test_minter, None);
+    let spender_holder = TokenHolder::new(test_spender, None);
+    let other_holder = TokenHolder::new(other_caller, None);
+
+    let mint_val = TokenAmount::from(10000u32);
+    let _ = dft_mintable::add_minter(&test_owner, test_owner, None, now);
+    dft_mintable::mint(&test_owner, &minter_holder, mint_val.clone(), None, now).unwrap();
+    let approve_fee = basic_service::fee().minimum;
+
+    basic_service::increase_allowance(
+        &test_minter,
+        &minter_holder,
+        &spender_holder,
+        1000u32.into(),
+        None,
+        now,
+    )
+    .unwrap();
+    basic_service::increase_allowance(
+        &test_minter,
+        &minter_holder,
+        &spender_holder,
+        500u32.into(),
+        None,
+        now + 1,
+    )
+    .unwrap();
+    assert_eq!(
+        basic_service::allowance(&minter_holder, &spender_holder),
+        TokenAmount::from(1500u32)
+    );
+
+    basic_service::decrease_allowance(
+        &test_minter,
+        &minter_holder,
+        &spender_holder,
+        200u32.into(),
+        None,
+        now + 2,
+    )
+    .unwrap();
+    assert_eq!(
+        basic_service::allowance(&minter_holder, &spender_holder),
+        TokenAmount::from(1300u32)
+    );
+    // saturates at zero
+    basic_service::decrease_allowance(
+        &test_minter,
+        &minter_holder,
+        &spender_holder,
+        5000u32.into(),
+        None,
+        now + 3,
+    )
+    .unwrap();
+    assert_eq!(
+        basic_service::allowance(&minter_holder, &spender_holder),
+        TokenAmount::from(0u32)
+    );
+
+    basic_service::approve(
+        &test_minter,
+        &minter_holder,
+        &spender_holder,
+        100u32.into(),
+        None,
+        now + 4,
+    )
+    .unwrap();
+    basic_service::approve(
+        &test_minter,
+        &minter_holder,
+        &other_holder,
+        100u32.into(),
+        None,
+        now + 5,
+    )
+    .unwrap();
+    // one spender per call, the owner revokes again for the rest
+    let mut spenders = vec![spender_holder, other_holder];
+    spenders.sort();
+    let ((first_block_height, _, _), has_more) =
+        basic_service::revoke_all_allowances(&test_minter, &minter_holder, 1, None, now + 6)
+            .unwrap();
+    assert!(has_more);
+    assert_eq!(
+        basic_service::allowances_of(&minter_holder, None, usize::MAX).len(),
+        1
+    );
+    let ((block_height, _, _), has_more) =
+        basic_service::revoke_all_allowances(&test_minter, &minter_holder, 1, None, now + 7)
+            .unwrap();
+    assert!(!has_more);
+    assert!(basic_service::allowances_of(&minter_holder, None, usize::MAX).is_empty());
+
+    // nothing left to revoke, no block and no fee
+    let chain_length = basic_service::token_info().chain_length;
+    assert_eq!(
+        basic_service::revoke_all_allowances(&test_minter, &minter_holder, 1, None, now + 8),
+        Err(DFTError::NoAllowancesToRevoke)
+    );
+    assert_eq!(basic_service::token_info().chain_length, chain_length);
+
+    // every change is a block paying the approve fee
+    assert_eq!(
+        basic_service::balance_of(&minter_holder),
+        mint_val - approve_fee.clone() * 8u32
+    );
+    // each block lists the spenders it revoked
+    for (block_height, spender) in [first_block_height, block_height].into_iter().zip(spenders) {
+        match basic_service::block_by_height(block_height) {
+            BlockResult::Ok(block) => assert_eq!(
+                block.transaction.operation,
+                Operation::RevokeAllAllowances {
+                    caller: minter_holder,
+                    owner: minter_holder,
+                    spenders: vec![spender],
+                    fee: approve_fee.clone().into(),
+                }
+            ),
+            _ => panic!("the revoke block should be local"),
+        }
+    }
+}
+
+#[rstest]
+#[case(test_token_with_0_fee_rate())]
+#[case(test_token_with_non_0_fee_rate())]
+fn test_token_basic_operators(
+    #[case] _test_token: (),
+    test_owner: Principal,
+    test_minter: Principal,
+    test_spender: Principal,
+    other_caller: Principal,
+    now: u64,
+) {
+    let minter_holder = TokenHolder::new(test_minter, Some([1u8; 32]));
+    let other_holder = TokenHolder::new(other_caller, None);
+    let mint_val = TokenAmount::from(10000u32);
+    let _ = dft_mintable::add_minter(&test_owner, test_owner, None, now);
+    dft_mintable::mint(&test_owner, &minter_holder, mint_val.clone(), None, now).unwrap();
+
+    // not an operator yet
+    assert_eq!(
+        basic_service::transfer(
+            &test_spender,
+            None,
+            &minter_holder,
+            &other_holder,
+            100u32.into(),
+            None,
+            now,
+        ),
+        Err(DFTError::NotAuthorizedOperator)
+    );
+    assert_eq!(
+        basic_service::authorize_operator(
+            &test_minter,
+            &minter_holder,
+            &test_spender,
+            Some(now),
+            None,
+            now,
+        ),
+        Err(DFTError::InvalidOperatorExpiration)
+    );
+
+    let expires_at = now + 100;
+    basic_service::authorize_operator(
+        &test_minter,
+        &minter_holder,
+        &test_spender,
+        Some(expires_at),
+        None,
+        now,
+    )
+    .unwrap();
+    assert_eq!(
+        basic_service::operators_of(&minter_holder, now),
+        vec![OperatorInfo {
+            operator: test_spender,
+            expires_at: Some(expires_at),
+        }]
+    );
+    let (block_height, _, _) = basic_service::transfer(
+        &test_spender,
+        None,
+        &minter_holder,
+        &other_holder,
+        1000u32.into(),
+        None,
+        now + 1,
+    )
+    .unwrap();
+    assert_eq!(
+        basic_service::balance_of(&other_holder),
+        TokenAmount::from(1000u32)
+    );
+    // the operator is recorded as the caller
+    match basic_service::block_by_height(block_height) {
+        BlockResult::Ok(block) => assert_eq!(
+            block.transaction.operation,
+            Operation::Transfer {
+                caller: test_spender.into(),
+                from: minter_holder,
+                to: other_holder,
+                value: 1000u32.into(),
+                fee: basic_service::calc_transfer_fee(&1000u32.into()).into(),
+            }
+        ),
+        _ => panic!("the transfer block should be local"),
+    }
+    dft_burnable::burn(
+        &test_spender,
+        None,
+        &minter_holder,
+        1000u32.into(),
+        None,
+        now + 2,
+    )
+    .unwrap();
+    // expired
+    assert_eq!(
+        dft_burnable::burn(
+            &test_spender,
+            None,
+            &minter_holder,
+            1000u32.into(),
+            None,
+            expires_at,
+        ),
+        Err(DFTError::NotAuthorizedOperator)
+    );
+    assert!(basic_service::operators_of(&minter_holder, expires_at).is_empty());
+
+    // authorized again without expiration, then revoked
+    basic_service::authorize_operator(
+        &test_minter,
+        &minter_holder,
+        &test_spender,
+        None,
+        None,
+        now + 3,
+    )
+    .unwrap();
+    basic_service::revoke_operator(&test_minter, &minter_holder, &test_spender, None, now + 4)
+        .unwrap();
+    assert_eq!(
+        basic_service::transfer(
+            &test_spender,
+            None,
+            &minter_holder,
+            &other_holder,
+            100u32.into(),
+            None,
+            now + 5,
+        ),
+        Err(DFTError::NotAuthorizedOperator)
+    );
+    assert_eq!(
+        basic_service::balance_of(&minter_holder),
+        mint_val
+            - TokenAmount::from(2000u32)
+            - basic_service::calc_transfer_fee(&1000u32.into())
+            - basic_service::fee().minimum * 3u32
+    );
+}
+
+// permits approving 1000 to the minter account, valid forever,
+// signed with the Ed25519 secret key [1; 32] and the secp256k1 secret key [7; 32]
+fn test_permit(scheme: SignatureScheme, nonce: u64, signature: &str) -> Permit {
+    let public_key = match scheme {
+        SignatureScheme::Ed25519 => {
+            "8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c"
+        }
+        SignatureScheme::Secp256k1 => {
+            "02989c0b76cb563971fdc9bef31ec06c3560f3249d6ee9e5d83c57625596e05f6f"
+        }
+    };
+    Permit {
+        scheme,
+        public_key: serde_bytes::ByteBuf::from(hex::decode(public_key).unwrap()),
+        sub_account: None,
+        spender: TokenHolder::new(test_minter(), None),
+        value: 1000u32.into(),
+        nonce,
+        deadline: u64::MAX,
+        signature: serde_bytes::ByteBuf::from(hex::decode(signature).unwrap()),
+    }
+}
+
+#[rstest]
+#[case(test_token_with_0_fee_rate())]
+#[case(test_token_with_non_0_fee_rate())]
+fn test_token_basic_permit(
+    #[case] _test_token: (),
+    test_owner: Principal,
+    test_minter: Principal,
+    now: u64,
+) {
+    let spender_holder = TokenHolder::new(test_minter, None);
+    let ed25519_permits = [
+        test_permit(SignatureScheme::Ed25519, 0, "35a0538256232b3a40cfcbbf9f4b410b6d79dca2ce01cfe3b531eaf825efe69c5c77bf488e5efce43c51dee5e8280518aab13bd10a83bbef86e81465f443b606"),
+        test_permit(SignatureScheme::Ed25519, 1, "b2376c6d3cac46b6175995766429d4db181304d61d896e07633138c755b46e7060a04f051cf117ab9a9bb88f823a26dfe887a73ad57990798c8ca58dac976600"),
+    ];
+    let secp256k1_permit = test_permit(SignatureScheme::Secp256k1, 0, "c2d210f9e4fd53da2d5c085b50a09455f01b1d41b8431ad5a882fa668ea4d8d70c133ca6b4c7864945fc69d9fff5517e1836db36bc247a768553addfe739e40d");
+    let (_, ed25519_owner) = ed25519_permits[0].verify(&test_token_id()).unwrap();
+    let (_, secp256k1_owner) = secp256k1_permit.verify(&test_token_id()).unwrap();
+    // the owners pay the approve fee
+    let _ = dft_mintable::add_minter(&test_owner, test_owner, None, now);
+    for owner in [ed25519_owner, secp256k1_owner] {
+        dft_mintable::mint(&test_owner, &owner, 100u32.into(), None, now).unwrap();
+    }
+
+    basic_service::permit(&ed25519_permits[0], now).unwrap();
+    assert_eq!(
+        basic_service::allowance(&ed25519_owner, &spender_holder),
+        TokenAmount::from(1000u32)
+    );
+    assert_eq!(basic_service::permit_nonce(&ed25519_owner), 1);
+    // replayed
+    assert_eq!(
+        basic_service::permit(&ed25519_permits[0], now + 1),
+        Err(DFTError::InvalidPermitNonce)
+    );
+    basic_service::permit(&ed25519_permits[1], now + 1).unwrap();
+    assert_eq!(basic_service::permit_nonce(&ed25519_owner), 2);
+
+    // the nonces are per owner
+    basic_service::permit(&secp256k1_permit, now + 2).unwrap();
+    assert_eq!(
+        basic_service::allowance(&secp256k1_owner, &spender_holder),
+        TokenAmount::from(1000u32)
+    );
+
+    let mut forged = secp256k1_permit.clone();
+    forged.nonce = 1;
+    assert!(matches!(
+        basic_service::permit(&forged, now + 3),
+        Err(DFTError::InvalidPermitSignature { .. })
+    ));
+    let mut expired = secp256k1_permit;
+    expired.deadline = now;
+    assert_eq!(
+        basic_service::permit(&expired, now + 3),
+        Err(DFTError::PermitExpired)
+    );
+    assert_eq!(basic_service::permit_nonce(&secp256k1_owner), 1);
+}
+
+#[rstest]
+#[case(test_token_with_0_fee_rate())]
+#[case(test_token_with_non_0_fee_rate())]
+fn test_token_basic_recurring_allowance(
+    #[case] _test_token: (),
+    test_owner: Principal,
+    test_minter: Principal,
+    test_spender: Principal,
+    other_caller: Principal,
+    now: u64,
+) {
+    let minter_holder = TokenHolder::new(test_minter, None);
+    let spender_holder = TokenHolder::new(test_spender, None);
+    let to_holder = TokenHolder::new(other_caller, None);
+    let _ = dft_mintable::add_minter(&test_owner, test_owner, None, now);
+    dft_mintable::mint(&test_owner, &minter_holder, 10000u32.into(), None, now).unwrap();
+    let approve_recurring = |amount_per_period: u32, period: u64, now: u64| {
+        basic_service::approve_recurring(
+            &test_minter,
+            &minter_holder,
+            &spender_holder,
+            amount_per_period.into(),
+            period,
+            None,
+            None,
+            now,
+        )
+    };
+    let transfer_from = |value: u32, now: u64| {
+        basic_service::transfer_from(
+            &test_spender,
+            &minter_holder,
+            &spender_holder,
+            &to_holder,
+            value.into(),
+            None,
+            now,
+        )
+    };
+
+    assert_eq!(
+        approve_recurring(1000, 0, now),
+        Err(DFTError::InvalidRecurringPeriod)
+    );
+    basic_service::approve(
+        &test_minter,
+        &minter_holder,
+        &spender_holder,
+        5000u32.into(),
+        None,
+        now,
+    )
+    .unwrap();
+    let period = 100;
+    approve_recurring(1000, period, now).unwrap();
+    assert_eq!(
+        basic_service::recurring_allowance(&minter_holder, &spender_holder, now),
+        Some(RecurringAllowance {
+            amount_per_period: 1000u32.into(),
+            period,
+            start: now,
+            remaining: 1000u32.into(),
+            next_reset_at: now + period,
+        })
+    );
+
+    // the transfers debit the remainder of the current period
+    transfer_from(400, now + 1).unwrap();
+    let remaining = TokenAmount::from(600u32) - basic_service::calc_transfer_fee(&400u32.into());
+    let recurring =
+        basic_service::recurring_allowance(&minter_holder, &spender_holder, now + 1).unwrap();
+    assert_eq!(recurring.remaining.0, remaining);
+    assert_eq!(
+        transfer_from(700, now + 2),
+        Err(DFTError::InsufficientAllowance)
+    );
+    // the one-shot allowance is left alone
+    assert_eq!(
+        basic_service::allowance(&minter_holder, &spender_holder),
+        TokenAmount::from(5000u32)
+    );
+
+    // the window resets automatically
+    let recurring =
+        basic_service::recurring_allowance(&minter_holder, &spender_holder, now + period).unwrap();
+    assert_eq!(recurring.remaining, 1000u32);
+    assert_eq!(recurring.next_reset_at, now + 2 * period);
+    transfer_from(700, now + period).unwrap();
+    assert_eq!(
+        basic_service::balance_of(&to_holder),
+        TokenAmount::from(1100u32)
+    );
+
+    // a zero amount removes the recurring allowance
+    approve_recurring(0, 0, now + period + 1).unwrap();
+    assert_eq!(
+        basic_service::recurring_allowance(&minter_holder, &spender_holder, now + period + 1),
+        None
+    );
+    transfer_from(2000, now + period + 2).unwrap();
+
+    // revoking all the allowances removes the recurring ones too
+    approve_recurring(1000, period, now + period + 3).unwrap();
+    basic_service::revoke_all_allowances(
+        &test_minter,
+        &minter_holder,
+        MAX_REVOKED_ALLOWANCES_PER_CALL,
+        None,
+        now + period + 4,
+    )
+    .unwrap();
+    assert_eq!(
+        basic_service::recurring_allowance(&minter_holder, &spender_holder, now + period + 4),
+        None
+    );
+    assert_eq!(
+        transfer_from(1, now + period + 5),
+        Err(DFTError::InsufficientAllowance)
+    );
 }
 
 #[rstest]
@@ -669,7 +1346,8 @@ fn test_token_basic_transfer(
     // transfer token from from_holder to to_holder
     let transfer_val = TokenAmount::from(1000u32);
     let transfer_res = basic_service::transfer(
-        &test_owner,
+        &test_minter,
+        None,
         &minter_holder,
         &to_holder,
         transfer_val.clone(),
@@ -704,6 +1382,622 @@ fn test_token_basic_transfer(
     assert_eq!(total_supply, mint_val);
 }
 
+#[rstest]
+#[case(test_token_with_0_fee_rate())]
+#[case(test_token_with_non_0_fee_rate())]
+fn test_token_basic_holds(
+    #[case] _test_token: (),
+    test_owner: Principal,
+    test_minter: Principal,
+    test_spender: Principal,
+    other_caller: Principal,
+    now: u64,
+) {
+    let owner_holder = TokenHolder::new(test_minter, None);
+    let merchant = TokenHolder::new(test_spender, None);
+    let other_holder = TokenHolder::new(other_caller, None);
+    let _ = dft_mintable::add_minter(&test_owner, test_owner, None, now);
+    dft_mintable::mint(&test_owner, &owner_holder, 10000u32.into(), None, now).unwrap();
+    let total_supply = basic_service::total_supply();
+    let second = 1_000_000_000u64;
+
+    assert_eq!(
+        hold_service::hold(
+            &test_minter,
+            &owner_holder,
+            &merchant,
+            100u32.into(),
+            now,
+            None,
+            now
+        ),
+        Err(DFTError::InvalidHoldExpiration)
+    );
+    assert_eq!(
+        hold_service::hold(
+            &test_minter,
+            &owner_holder,
+            &merchant,
+            100u32.into(),
+            now + MAX_HOLD_DURATION + 1,
+            None,
+            now
+        ),
+        Err(DFTError::InvalidHoldExpiration)
+    );
+
+    let amount = TokenAmount::from(6000u32);
+    let fee = basic_service::calc_transfer_fee(&amount);
+    let (block_height, _, _) = hold_service::hold(
+        &test_minter,
+        &owner_holder,
+        &merchant,
+        amount.clone(),
+        now + 60 * second,
+        None,
+        now,
+    )
+    .unwrap();
+    let hold_id = block_height.to_u64().unwrap();
+    let hold = hold_service::hold_by_id(hold_id).unwrap();
+    assert_eq!(hold.owner, owner_holder);
+    assert_eq!(hold.merchant, merchant);
+    assert_eq!(hold.amount, amount);
+
+    // the held amount is not spendable, but still part of the total supply
+    let balance = TokenAmount::from(10000u32) - amount.clone() - fee.clone();
+    assert_eq!(basic_service::balance_of(&owner_holder), balance);
+    assert_eq!(hold_service::held_balance_of(&owner_holder), amount);
+    assert_eq!(basic_service::total_supply(), total_supply);
+    assert_eq!(
+        basic_service::transfer(
+            &test_minter,
+            None,
+            &owner_holder,
+            &other_holder,
+            balance.clone(),
+            None,
+            now,
+        ),
+        Err(DFTError::InsufficientBalance)
+    );
+
+    // only the merchant captures, something but at most what is held
+    assert_eq!(
+        hold_service::capture(
+            &other_caller,
+            &other_holder,
+            hold_id,
+            1u32.into(),
+            None,
+            now
+        ),
+        Err(DFTError::OnlyMerchantAllowCallIt)
+    );
+    assert_eq!(
+        hold_service::capture(&test_spender, &merchant, hold_id, 6001u32.into(), None, now),
+        Err(DFTError::HeldAmountExceeded)
+    );
+    assert_eq!(
+        hold_service::capture(&test_spender, &merchant, hold_id, 0u32.into(), None, now),
+        Err(DFTError::InvalidCaptureAmount)
+    );
+    hold_service::capture(&test_spender, &merchant, hold_id, 2000u32.into(), None, now).unwrap();
+    assert_eq!(
+        basic_service::balance_of(&merchant),
+        TokenAmount::from(2000u32)
+    );
+    assert_eq!(
+        hold_service::held_balance_of(&owner_holder),
+        TokenAmount::from(4000u32)
+    );
+    assert_eq!(
+        hold_service::hold_by_id(hold_id).unwrap().amount,
+        TokenAmount::from(4000u32)
+    );
+
+    // only the merchant releases before the expiration
+    assert_eq!(
+        hold_service::release(&test_minter, &owner_holder, hold_id, None, now),
+        Err(DFTError::OnlyMerchantAllowCallIt)
+    );
+    hold_service::release(&test_spender, &merchant, hold_id, None, now).unwrap();
+    assert_eq!(
+        basic_service::balance_of(&owner_holder),
+        balance.clone() + 4000u32
+    );
+    assert_eq!(
+        hold_service::held_balance_of(&owner_holder),
+        TokenAmount::from(0u32)
+    );
+    assert_eq!(hold_service::hold_by_id(hold_id), None);
+    assert_eq!(
+        hold_service::release(&test_spender, &merchant, hold_id, None, now),
+        Err(DFTError::HoldNotFound)
+    );
+
+    // an expired hold can no longer be captured, and is returned to its owner
+    let (block_height, _, _) = hold_service::hold(
+        &test_minter,
+        &owner_holder,
+        &merchant,
+        1000u32.into(),
+        now + second,
+        None,
+        now,
+    )
+    .unwrap();
+    let expired_hold_id = block_height.to_u64().unwrap();
+    let (block_height, _, _) = hold_service::hold(
+        &test_minter,
+        &owner_holder,
+        &merchant,
+        500u32.into(),
+        now + 60 * second,
+        None,
+        now,
+    )
+    .unwrap();
+    let open_hold_id = block_height.to_u64().unwrap();
+    let later = now + 2 * second;
+    assert_eq!(
+        hold_service::capture(
+            &test_spender,
+            &merchant,
+            expired_hold_id,
+            1u32.into(),
+            None,
+            later
+        ),
+        Err(DFTError::HoldExpired)
+    );
+    let chain_length = basic_service::token_info().chain_length;
+    assert_eq!(
+        hold_service::release_expired_holds(later, 10),
+        vec![Ok(expired_hold_id)]
+    );
+    assert_eq!(
+        basic_service::token_info().chain_length,
+        chain_length + 1u32
+    );
+    assert_eq!(hold_service::hold_by_id(expired_hold_id), None);
+    assert_eq!(
+        hold_service::held_balance_of(&owner_holder),
+        TokenAmount::from(500u32)
+    );
+    assert!(hold_service::hold_by_id(open_hold_id).is_some());
+    assert_eq!(basic_service::total_supply(), total_supply);
+}
+
+#[rstest]
+#[case(test_token_with_0_fee_rate())]
+#[case(test_token_with_non_0_fee_rate())]
+fn test_token_basic_multi_transfer(
+    #[case] _test_token: (),
+    test_owner: Principal,
+    test_minter: Principal,
+    test_spender: Principal,
+    other_caller: Principal,
+    now: u64,
+) {
+    let minter_holder = TokenHolder::new(test_minter, None);
+    let spender_holder = TokenHolder::new(test_spender, None);
+    let other_holder = TokenHolder::new(other_caller, None);
+    let fee_to = basic_service::fee_to();
+    let _ = dft_mintable::add_minter(&test_owner, test_owner, None, now);
+    dft_mintable::mint(&test_owner, &minter_holder, 10000u32.into(), None, now).unwrap();
+    let chain_length = basic_service::token_info().chain_length;
+    let fee_to_balance = basic_service::balance_of(&fee_to);
+
+    assert_eq!(
+        basic_service::multi_transfer(&test_minter, &minter_holder, vec![], None, now),
+        Err(DFTError::InvalidMultiTransferOutputs)
+    );
+    assert_eq!(
+        basic_service::multi_transfer(
+            &test_minter,
+            &minter_holder,
+            vec![(other_holder, 1u32.into()); MAX_MULTI_TRANSFER_OUTPUTS + 1],
+            None,
+            now,
+        ),
+        Err(DFTError::InvalidMultiTransferOutputs)
+    );
+    assert_eq!(
+        basic_service::multi_transfer(
+            &test_minter,
+            &minter_holder,
+            vec![
+                (spender_holder, 5000u32.into()),
+                (other_holder, 5000u32.into())
+            ],
+            None,
+            now,
+        ),
+        Err(DFTError::InsufficientBalance)
+    );
+
+    let outputs = vec![
+        (spender_holder, TokenAmount::from(1000u32)),
+        (other_holder, 2000u32.into()),
+        (spender_holder, 500u32.into()),
+    ];
+    let (block_height, _, _) =
+        basic_service::multi_transfer(&test_minter, &minter_holder, outputs.clone(), None, now)
+            .unwrap();
+    // one block and one fee for all the outputs
+    let transfer_fee = basic_service::calc_transfer_fee(&3500u32.into());
+    assert_eq!(
+        basic_service::token_info().chain_length,
+        chain_length + 1u32
+    );
+    assert_eq!(
+        basic_service::balance_of(&minter_holder),
+        TokenAmount::from(6500u32) - transfer_fee.clone()
+    );
+    assert_eq!(
+        basic_service::balance_of(&spender_holder),
+        TokenAmount::from(1500u32)
+    );
+    assert_eq!(
+        basic_service::balance_of(&other_holder),
+        TokenAmount::from(2000u32)
+    );
+    assert_eq!(
+        basic_service::balance_of(&fee_to),
+        fee_to_balance + transfer_fee.clone()
+    );
+    match basic_service::block_by_height(block_height) {
+        BlockResult::Ok(block) => assert_eq!(
+            block.transaction.operation,
+            Operation::MultiTransfer {
+                caller: minter_holder,
+                from: minter_holder,
+                outputs: outputs
+                    .into_iter()
+                    .map(|(to, value)| (to, value.into()))
+                    .collect(),
+                fee: transfer_fee.into(),
+            }
+        ),
+        _ => panic!("the multi transfer block should be local"),
+    }
+    assert_eq!(basic_service::total_supply(), TokenAmount::from(10000u32));
+}
+
+fn batch_transfer(
+    caller: &Principal,
+    from: &TokenHolder,
+    spender: Option<&TokenHolder>,
+    mode: BatchMode,
+    items: Vec<CommonResult<(TokenHolder, TokenAmount)>>,
+    now: u64,
+) -> Vec<batch_service::BatchItemResult> {
+    batch_service::apply_batch(
+        mode,
+        items,
+        |items| batch_service::validate_transfers(caller, from, spender, items, None, now),
+        |(to, value)| match spender {
+            Some(spender) => {
+                basic_service::transfer_from(caller, from, spender, &to, value, None, now)
+            }
+            None => basic_service::transfer(caller, None, from, &to, value, None, now),
+        },
+    )
+}
+
+#[rstest]
+#[case(test_token_with_0_fee_rate())]
+#[case(test_token_with_non_0_fee_rate())]
+fn test_token_batch_modes(
+    #[case] _test_token: (),
+    test_owner: Principal,
+    test_minter: Principal,
+    test_spender: Principal,
+    other_caller: Principal,
+    now: u64,
+) {
+    let minter_holder = TokenHolder::new(test_minter, None);
+    let spender_holder = TokenHolder::new(test_spender, None);
+    let other_holder = TokenHolder::new(other_caller, None);
+    let _ = dft_mintable::add_minter(&test_owner, test_owner, None, now);
+    dft_mintable::mint(&test_owner, &minter_holder, 10000u32.into(), None, now).unwrap();
+
+    // the best effort mode reports the error of each item and applies the others
+    let results = batch_transfer(
+        &test_minter,
+        &minter_holder,
+        None,
+        BatchMode::BestEffort,
+        vec![
+            Ok((other_holder, 1000u32.into())),
+            Err(DFTError::InvalidArgFormatTo),
+            Ok((other_holder, 20000u32.into())),
+        ],
+        now,
+    );
+    assert!(results[0].is_ok());
+    assert_eq!(
+        results[1..],
+        [
+            Err(DFTError::InvalidArgFormatTo),
+            Err(DFTError::InsufficientBalance),
+        ]
+    );
+    assert_eq!(
+        basic_service::balance_of(&other_holder),
+        TokenAmount::from(1000u32)
+    );
+
+    // the all or nothing mode validates the items against the balances left by the previous ones
+    let chain_length = basic_service::token_info().chain_length;
+    let balance = basic_service::balance_of(&minter_holder);
+    let results = batch_transfer(
+        &test_minter,
+        &minter_holder,
+        None,
+        BatchMode::AllOrNothing,
+        vec![
+            Ok((spender_holder, 4000u32.into())),
+            Ok((other_holder, 4000u32.into())),
+            Ok((spender_holder, 1000u32.into())),
+            Ok((spender_holder, 1000u32.into())),
+        ],
+        now + 1,
+    );
+    assert_eq!(
+        results,
+        vec![
+            Err(DFTError::BatchAborted),
+            Err(DFTError::BatchAborted),
+            Err(DFTError::InsufficientBalance),
+            Err(DFTError::InsufficientBalance),
+        ]
+    );
+    // an item identical to an earlier one is a duplicate
+    let results = batch_transfer(
+        &test_minter,
+        &minter_holder,
+        None,
+        BatchMode::AllOrNothing,
+        vec![
+            Ok((spender_holder, 10u32.into())),
+            Ok((spender_holder, 10u32.into())),
+        ],
+        now + 1,
+    );
+    assert_eq!(
+        results,
+        vec![Err(DFTError::BatchAborted), Err(DFTError::TxDuplicate)]
+    );
+    assert_eq!(basic_service::token_info().chain_length, chain_length);
+    assert_eq!(basic_service::balance_of(&minter_holder), balance);
+
+    let results = batch_transfer(
+        &test_minter,
+        &minter_holder,
+        None,
+        BatchMode::AllOrNothing,
+        vec![
+            Ok((spender_holder, 4000u32.into())),
+            Ok((other_holder, 4000u32.into())),
+        ],
+        now + 1,
+    );
+    assert!(results.iter().all(|result| result.is_ok()));
+    assert_eq!(
+        basic_service::token_info().chain_length,
+        chain_length + 2u32
+    );
+    assert_eq!(
+        basic_service::balance_of(&spender_holder),
+        TokenAmount::from(4000u32)
+    );
+
+    // the allowance is spent item after item
+    basic_service::approve(
+        &test_spender,
+        &spender_holder,
+        &minter_holder,
+        1000u32.into(),
+        None,
+        now + 2,
+    )
+    .unwrap();
+    let items = vec![
+        Ok((other_holder, 400u32.into())),
+        Ok((minter_holder, 400u32.into())),
+    ];
+    let fee = basic_service::calc_transfer_fee(&400u32.into());
+    let results = batch_transfer(
+        &test_minter,
+        &spender_holder,
+        Some(&minter_holder),
+        BatchMode::AllOrNothing,
+        items,
+        now + 3,
+    );
+    if fee > TokenAmount::from(100u32) {
+        assert_eq!(results[1], Err(DFTError::InsufficientAllowance));
+    } else {
+        assert!(results.iter().all(|result| result.is_ok()));
+    }
+
+    let results = batch_transfer(
+        &test_minter,
+        &minter_holder,
+        None,
+        BatchMode::BestEffort,
+        vec![Ok((other_holder, 1u32.into())); MAX_BATCH_ITEMS + 1],
+        now + 4,
+    );
+    assert_eq!(results.len(), MAX_BATCH_ITEMS + 1);
+    assert!(results
+        .iter()
+        .all(|result| *result == Err(DFTError::TooManyBatchItems)));
+
+    // only the minters can mint, no item is applied
+    assert_eq!(
+        dft_mintable::validate_mints(
+            &other_caller,
+            &[
+                Ok((other_holder, 1u32.into())),
+                Err(DFTError::InvalidArgFormatTo)
+            ],
+            None,
+            now + 5,
+        ),
+        vec![
+            Err(DFTError::OnlyMinterAllowCallIt),
+            Err(DFTError::InvalidArgFormatTo),
+        ]
+    );
+    assert_eq!(
+        dft_mintable::validate_mints(
+            &test_owner,
+            &[
+                Ok((other_holder, 1u32.into())),
+                Ok((other_holder, 1u32.into()))
+            ],
+            None,
+            now + 5,
+        ),
+        vec![Ok(()), Err(DFTError::TxDuplicate)]
+    );
+}
+
+#[rstest]
+#[case(test_token_with_0_fee_rate())]
+#[case(test_token_with_non_0_fee_rate())]
+fn test_token_batch_approve_and_burn(
+    #[case] _test_token: (),
+    test_owner: Principal,
+    test_minter: Principal,
+    test_spender: Principal,
+    other_caller: Principal,
+    now: u64,
+) {
+    let minter_holder = TokenHolder::new(test_minter, None);
+    let minter_sub_holder = TokenHolder::new(test_minter, Some([1u8; 32]));
+    let spender_holder = TokenHolder::new(test_spender, None);
+    let other_holder = TokenHolder::new(other_caller, None);
+    let _ = dft_mintable::add_minter(&test_owner, test_owner, None, now);
+    dft_mintable::mint(&test_owner, &minter_holder, 10000u32.into(), None, now).unwrap();
+    dft_mintable::mint(&test_owner, &minter_sub_holder, 100u32.into(), None, now).unwrap();
+
+    let approve = |mode, items: Vec<CommonResult<(TokenHolder, TokenAmount)>>, now| {
+        batch_service::apply_batch(
+            mode,
+            items,
+            |items| {
+                batch_service::validate_approvals(&test_minter, &minter_holder, items, None, now)
+            },
+            |(spender, value)| {
+                basic_service::approve(&test_minter, &minter_holder, &spender, value, None, now)
+            },
+        )
+    };
+    let results = approve(
+        BatchMode::AllOrNothing,
+        vec![
+            Ok((spender_holder, 1000u32.into())),
+            Ok((other_holder, 2000u32.into())),
+            Ok((spender_holder, 1000u32.into())),
+        ],
+        now + 1,
+    );
+    assert_eq!(
+        results,
+        vec![
+            Err(DFTError::BatchAborted),
+            Err(DFTError::BatchAborted),
+            Err(DFTError::TxDuplicate),
+        ]
+    );
+    assert_eq!(
+        basic_service::allowance(&minter_holder, &spender_holder),
+        TokenAmount::from(0u32)
+    );
+
+    let results = approve(
+        BatchMode::BestEffort,
+        vec![
+            Ok((spender_holder, 1000u32.into())),
+            Err(DFTError::InvalidSpender),
+            Ok((other_holder, 2000u32.into())),
+        ],
+        now + 1,
+    );
+    assert!(results[0].is_ok() && results[2].is_ok());
+    assert_eq!(results[1], Err(DFTError::InvalidSpender));
+    assert_eq!(
+        basic_service::allowance(&minter_holder, &spender_holder),
+        TokenAmount::from(1000u32)
+    );
+    assert_eq!(
+        basic_service::allowance(&minter_holder, &other_holder),
+        TokenAmount::from(2000u32)
+    );
+
+    // the burns of a holder add up
+    let burn = |mode, items: Vec<CommonResult<(Option<Subaccount>, TokenAmount)>>, now| {
+        batch_service::apply_batch(
+            mode,
+            items,
+            |items| dft_burnable::validate_burns(&test_minter, items, None, now),
+            |(sub_account, value)| {
+                let owner = TokenHolder::new(test_minter, sub_account);
+                dft_burnable::burn(&test_minter, sub_account, &owner, value, None, now)
+            },
+        )
+    };
+    let chain_length = basic_service::token_info().chain_length;
+    let balance = basic_service::balance_of(&minter_holder);
+    let results = burn(
+        BatchMode::AllOrNothing,
+        vec![
+            Ok((None, 500u32.into())),
+            Ok((Some([1u8; 32]), 60u32.into())),
+            Ok((Some([1u8; 32]), 50u32.into())),
+            Ok((None, 1u32.into())),
+        ],
+        now + 2,
+    );
+    assert_eq!(
+        results,
+        vec![
+            Err(DFTError::BatchAborted),
+            Err(DFTError::BatchAborted),
+            Err(DFTError::InsufficientBalance),
+            Err(DFTError::BurnValueTooSmall),
+        ]
+    );
+    assert_eq!(basic_service::token_info().chain_length, chain_length);
+
+    let results = burn(
+        BatchMode::AllOrNothing,
+        vec![
+            Ok((None, 500u32.into())),
+            Ok((Some([1u8; 32]), 60u32.into())),
+            Ok((Some([1u8; 32]), 40u32.into())),
+        ],
+        now + 2,
+    );
+    assert!(results.iter().all(|result| result.is_ok()));
+    assert_eq!(
+        basic_service::token_info().chain_length,
+        chain_length + 3u32
+    );
+    assert_eq!(
+        basic_service::balance_of(&minter_holder),
+        balance - TokenAmount::from(500u32)
+    );
+    assert_eq!(
+        basic_service::balance_of(&minter_sub_holder),
+        TokenAmount::from(0u32)
+    );
+}
+
 // test token _mint/_burn
 #[rstest]
 #[case(test_token_with_0_fee_rate())]
@@ -761,6 +2055,7 @@ fn test_token_basic_mint_burn(
     let burn_val = TokenAmount::from(1000u32);
     let burn_res = dft_burnable::burn(
         &test_minter,
+        None,
         &minter_holder,
         burn_val.clone(),
         None,
@@ -815,6 +2110,7 @@ fn test_token_basic_mint_burn(
     let burn_val = TokenAmount::from(1u32);
     let burn_res = dft_burnable::burn(
         &test_minter,
+        None,
         &minter_holder,
         burn_val.clone(),
         None,
@@ -883,6 +2179,7 @@ fn test_token_basic_approve_transfer_from_transfer(
     let transfer_val = TokenAmount::from(1000u32);
     let transfer_res = basic_service::transfer(
         &anonymous_caller,
+        None,
         &minter_holder,
         &spender_holder,
         transfer_val.clone(),
@@ -1,21 +1,34 @@
 use crate::service;
-use crate::types::StorageInfo;
+use crate::types::{BlocksByAccountResult, StorageInfo};
 use candid::Principal;
 use candid::{candid_method, Nat};
 use dft_types::*;
 use ic_cdk::api;
 use ic_cdk_macros::*;
 
+// blocks are compressed unless `compress_blocks` is false,
+// `parent_hash` is the hash of the block before `dft_tx_start_index`
 #[init]
 #[candid_method(init)]
-fn canister_init(dft_id: Principal, dft_tx_start_index: Nat) {
-    service::init(dft_id, dft_tx_start_index.0, api::time());
+fn canister_init(
+    dft_id: Principal,
+    dft_tx_start_index: Nat,
+    compress_blocks: Option<bool>,
+    parent_hash: Option<BlockHash>,
+) {
+    service::init(
+        dft_id,
+        dft_tx_start_index.0,
+        compress_blocks.unwrap_or(true),
+        parent_hash,
+        api::time(),
+    );
 }
 
 #[update(name = "batchAppend")]
 #[candid_method(update, rename = "batchAppend")]
-fn batch_append(blocks: Vec<EncodedBlock>) -> BooleanResult {
-    match service::batch_append(&api::caller(), blocks, api::time()) {
+fn batch_append(start_block_height: Nat, blocks: Vec<EncodedBlock>) -> BooleanResult {
+    match service::batch_append(&api::caller(), start_block_height.0, blocks, api::time()) {
         Ok(_) => BooleanResult::Ok(true),
         Err(e) => BooleanResult::Err(e.into()),
     }
@@ -33,6 +46,34 @@ fn blocks(block_height_start: Nat, size: usize) -> BlockListResult {
     service::get_blocks_by_query(block_height_start.0, size)
 }
 
+#[query(name = "encodedBlocksByQuery")]
+#[candid_method(query, rename = "encodedBlocksByQuery")]
+fn encoded_blocks(block_height_start: Nat, size: usize) -> EncodedBlockListResult {
+    service::get_encoded_blocks_by_query(block_height_start.0, size)
+}
+
+#[query(name = "blocksByTimeRange")]
+#[candid_method(query, rename = "blocksByTimeRange")]
+fn blocks_by_time_range(
+    start_ts: u64,
+    end_ts: u64,
+    limit: usize,
+    cursor: Option<Nat>,
+) -> BlocksByTimeRangeResult {
+    service::get_blocks_by_time_range(start_ts, end_ts, limit, cursor.map(|cursor| cursor.0))
+}
+
+#[query(name = "blocksByAccount")]
+#[candid_method(query, rename = "blocksByAccount")]
+fn blocks_by_account(account: String, cursor: Option<Nat>, limit: usize) -> BlocksByAccountResult {
+    match account.parse::<TokenHolder>() {
+        Ok(account) => {
+            service::get_blocks_by_account(&account, cursor.map(|cursor| cursor.0), limit).into()
+        }
+        Err(_) => BlocksByAccountResult::Err(DFTError::InvalidArgFormatAccount.into()),
+    }
+}
+
 #[query(name = "storageInfo")]
 #[candid_method(query, rename = "storageInfo")]
 fn storage_info() -> StorageInfo {
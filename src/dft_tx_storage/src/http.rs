@@ -0,0 +1,525 @@
+use std::fmt::Display;
+
+use candid::{candid_method, Nat};
+use dft_types::constants::MAX_BLOCKS_PER_REQUEST;
+use dft_types::*;
+use ic_cdk_macros::query;
+use num_bigint::BigUint;
+
+use crate::service;
+use crate::types::StorageInfo;
+
+#[cfg_attr(coverage_nightly, no_coverage)]
+#[query]
+#[candid_method(query, rename = "http_request")]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    handle_http_request(&req, ic_cdk::api::canister_balance())
+}
+
+// Routes:
+//   /info                      storage info
+//   /blocks/{height}           the block at `height`
+//   /blocks?start=&limit=      at most `limit` blocks from `start`
+fn handle_http_request(req: &HttpRequest, cycles: u64) -> HttpResponse {
+    let path = req.path().to_lowercase();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["info"] => {
+            let mut info = service::get_storage_info();
+            info.cycles = cycles;
+            json_response(storage_info_json(&info))
+        }
+        ["blocks", height] => match height.parse::<BigUint>() {
+            Ok(height) => match service::get_block_by_height(height.clone()) {
+                BlockResult::Ok(block) => json_response(block_json(&height, &block)),
+                _ => HttpResponse::not_found(),
+            },
+            Err(_) => HttpResponse::bad_request(),
+        },
+        ["blocks"] => {
+            let params = req.params();
+            let start = params.get("start").map(|start| start.parse::<BigUint>());
+            let limit = params
+                .get("limit")
+                .map(|limit| limit.parse::<usize>())
+                .unwrap_or(Ok(MAX_BLOCKS_PER_REQUEST as usize));
+            match (start, limit) {
+                (Some(Ok(start)), Ok(limit)) => {
+                    match service::get_blocks_by_query(start.clone(), limit) {
+                        BlockListResult::Ok(blocks) => json_response(blocks_json(&start, &blocks)),
+                        BlockListResult::Err(_) => HttpResponse::not_found(),
+                    }
+                }
+                _ => HttpResponse::bad_request(),
+            }
+        }
+        _ => HttpResponse::not_found(),
+    }
+}
+
+fn json_response(json: String) -> HttpResponse {
+    HttpResponse::ok(vec![], json.into_bytes())
+}
+
+// Nat values are rendered as strings, they may not fit in a JSON number.
+fn nat_json(value: &Nat) -> String {
+    format!("\"{}\"", value.0)
+}
+
+fn opt_json<T: Display>(value: &Option<T>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "null".to_string(),
+    }
+}
+
+fn storage_info_json(info: &StorageInfo) -> String {
+    format!(
+        "{{\"tokenId\":\"{}\",\"blockHeightOffset\":{},\"totalBlocksCount\":{},\"totalBlockSizeBytes\":{},\"compressedBlockSizeBytes\":{},\"stableMemorySizeBytes\":{},\"lastBlockHash\":{},\"cycles\":{}}}",
+        info.token_id,
+        nat_json(&info.block_height_offset),
+        nat_json(&info.total_blocks_count),
+        info.total_block_size_bytes,
+        info.compressed_block_size_bytes,
+        info.stable_memory_size_bytes,
+        opt_json(&info.last_block_hash.map(|hash| format!("\"{}\"", hex::encode(hash)))),
+        info.cycles,
+    )
+}
+
+fn blocks_json(start: &BigUint, blocks: &[Block]) -> String {
+    let blocks: Vec<String> = blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| block_json(&(start + i), block))
+        .collect();
+    let next = start + blocks.len();
+    format!(
+        "{{\"blocks\":[{}],\"next\":\"{}\"}}",
+        blocks.join(","),
+        next
+    )
+}
+
+fn block_json(height: &BigUint, block: &Block) -> String {
+    format!(
+        "{{\"height\":\"{}\",\"parentHash\":\"{}\",\"timestamp\":{},\"transaction\":{{\"createdAt\":{},\"operation\":{}}}}}",
+        height,
+        hex::encode(block.parent_hash),
+        block.timestamp,
+        block.transaction.created_at,
+        operation_json(&block.transaction.operation),
+    )
+}
+
+fn fee_json(fee: &TokenFee) -> String {
+    format!(
+        "{{\"minimum\":{},\"rate\":{},\"rateDecimals\":{}}}",
+        nat_json(&fee.minimum),
+        fee.rate,
+        fee.rate_decimals
+    )
+}
+
+fn archive_options_json(options: &ArchiveOptions) -> String {
+    format!(
+        "{{\"triggerThreshold\":{},\"numBlocksToArchive\":{},\"nodeMaxMemorySizeBytes\":{},\"maxMessageSizeBytes\":{},\"cyclesForArchiveCreation\":{},\"archiveIntervalSeconds\":{},\"minArchiveCycles\":{},\"archiveCyclesTopUp\":{},\"replicationFactor\":{},\"compressBlocks\":{}}}",
+        options.trigger_threshold,
+        options.num_blocks_to_archive,
+        opt_json(&options.node_max_memory_size_bytes),
+        opt_json(&options.max_message_size_bytes),
+        opt_json(&options.cycles_for_archive_creation),
+        opt_json(&options.archive_interval_seconds),
+        opt_json(&options.min_archive_cycles),
+        opt_json(&options.archive_cycles_top_up),
+        opt_json(&options.replication_factor),
+        opt_json(&options.compress_blocks),
+    )
+}
+
+fn operation_json(operation: &Operation) -> String {
+    match operation {
+        Operation::Approve {
+            caller,
+            owner,
+            spender,
+            value,
+            fee,
+        } => format!(
+            "{{\"type\":\"approve\",\"caller\":\"{}\",\"owner\":\"{}\",\"spender\":\"{}\",\"value\":{},\"fee\":{}}}",
+            caller,
+            owner,
+            spender,
+            nat_json(value),
+            nat_json(fee)
+        ),
+        Operation::Transfer {
+            caller,
+            from,
+            to,
+            value,
+            fee,
+        } => format!(
+            "{{\"type\":\"transfer\",\"caller\":\"{}\",\"from\":\"{}\",\"to\":\"{}\",\"value\":{},\"fee\":{}}}",
+            caller,
+            from,
+            to,
+            nat_json(value),
+            nat_json(fee)
+        ),
+        Operation::FeeModify { caller, new_fee } => format!(
+            "{{\"type\":\"feeModify\",\"caller\":\"{}\",\"newFee\":{}}}",
+            caller,
+            fee_json(new_fee)
+        ),
+        Operation::OwnerModify { caller, new_owner } => format!(
+            "{{\"type\":\"ownerModify\",\"caller\":\"{}\",\"newOwner\":\"{}\"}}",
+            caller, new_owner
+        ),
+        Operation::FeeToModify { caller, new_fee_to } => format!(
+            "{{\"type\":\"feeToModify\",\"caller\":\"{}\",\"newFeeTo\":\"{}\"}}",
+            caller, new_fee_to
+        ),
+        Operation::AddMinter { caller, minter } => format!(
+            "{{\"type\":\"addMinter\",\"caller\":\"{}\",\"minter\":\"{}\"}}",
+            caller, minter
+        ),
+        Operation::RemoveMinter { caller, minter } => format!(
+            "{{\"type\":\"removeMinter\",\"caller\":\"{}\",\"minter\":\"{}\"}}",
+            caller, minter
+        ),
+        Operation::ArchiveOptionsModify {
+            caller,
+            new_options,
+        } => format!(
+            "{{\"type\":\"archiveOptionsModify\",\"caller\":\"{}\",\"newOptions\":{}}}",
+            caller,
+            archive_options_json(new_options)
+        ),
+        Operation::IncreaseAllowance {
+            caller,
+            owner,
+            spender,
+            value,
+            fee,
+        } => format!(
+            "{{\"type\":\"increaseAllowance\",\"caller\":\"{}\",\"owner\":\"{}\",\"spender\":\"{}\",\"value\":{},\"fee\":{}}}",
+            caller,
+            owner,
+            spender,
+            nat_json(value),
+            nat_json(fee)
+        ),
+        Operation::DecreaseAllowance {
+            caller,
+            owner,
+            spender,
+            value,
+            fee,
+        } => format!(
+            "{{\"type\":\"decreaseAllowance\",\"caller\":\"{}\",\"owner\":\"{}\",\"spender\":\"{}\",\"value\":{},\"fee\":{}}}",
+            caller,
+            owner,
+            spender,
+            nat_json(value),
+            nat_json(fee)
+        ),
+        Operation::RevokeAllAllowances {
+            caller,
+            owner,
+            spenders,
+            fee,
+        } => format!(
+            "{{\"type\":\"revokeAllAllowances\",\"caller\":\"{}\",\"owner\":\"{}\",\"spenders\":[{}],\"fee\":{}}}",
+            caller,
+            owner,
+            spenders
+                .iter()
+                .map(|spender| format!("\"{}\"", spender))
+                .collect::<Vec<_>>()
+                .join(","),
+            nat_json(fee)
+        ),
+        Operation::AuthorizeOperator {
+            caller,
+            holder,
+            operator,
+            expires_at,
+            fee,
+        } => format!(
+            "{{\"type\":\"authorizeOperator\",\"caller\":\"{}\",\"holder\":\"{}\",\"operator\":\"{}\",\"expiresAt\":{},\"fee\":{}}}",
+            caller,
+            holder,
+            operator,
+            opt_json(expires_at),
+            nat_json(fee)
+        ),
+        Operation::RevokeOperator {
+            caller,
+            holder,
+            operator,
+            fee,
+        } => format!(
+            "{{\"type\":\"revokeOperator\",\"caller\":\"{}\",\"holder\":\"{}\",\"operator\":\"{}\",\"fee\":{}}}",
+            caller,
+            holder,
+            operator,
+            nat_json(fee)
+        ),
+        Operation::MultiTransfer {
+            caller,
+            from,
+            outputs,
+            fee,
+        } => format!(
+            "{{\"type\":\"multiTransfer\",\"caller\":\"{}\",\"from\":\"{}\",\"outputs\":[{}],\"fee\":{}}}",
+            caller,
+            from,
+            outputs
+                .iter()
+                .map(|(to, value)| format!("{{\"to\":\"{}\",\"value\":{}}}", to, nat_json(value)))
+                .collect::<Vec<_>>()
+                .join(","),
+            nat_json(fee)
+        ),
+        Operation::ApproveRecurring {
+            caller,
+            owner,
+            spender,
+            amount_per_period,
+            period,
+            start,
+            fee,
+        } => format!(
+            "{{\"type\":\"approveRecurring\",\"caller\":\"{}\",\"owner\":\"{}\",\"spender\":\"{}\",\"amountPerPeriod\":{},\"period\":{},\"start\":{},\"fee\":{}}}",
+            caller,
+            owner,
+            spender,
+            nat_json(amount_per_period),
+            period,
+            start,
+            nat_json(fee)
+        ),
+        Operation::Hold {
+            caller,
+            owner,
+            merchant,
+            amount,
+            expires_at,
+            fee,
+        } => format!(
+            "{{\"type\":\"hold\",\"caller\":\"{}\",\"owner\":\"{}\",\"merchant\":\"{}\",\"amount\":{},\"expiresAt\":{},\"fee\":{}}}",
+            caller,
+            owner,
+            merchant,
+            nat_json(amount),
+            expires_at,
+            nat_json(fee)
+        ),
+        Operation::CaptureHold {
+            caller,
+            hold_id,
+            owner,
+            merchant,
+            amount,
+        } => format!(
+            "{{\"type\":\"captureHold\",\"caller\":\"{}\",\"holdId\":{},\"owner\":\"{}\",\"merchant\":\"{}\",\"amount\":{}}}",
+            caller,
+            hold_id,
+            owner,
+            merchant,
+            nat_json(amount)
+        ),
+        Operation::ReleaseHold {
+            caller,
+            hold_id,
+            owner,
+            amount,
+        } => format!(
+            "{{\"type\":\"releaseHold\",\"caller\":\"{}\",\"holdId\":{},\"owner\":\"{}\",\"amount\":{}}}",
+            caller,
+            hold_id,
+            owner,
+            nat_json(amount)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+
+    use super::*;
+
+    fn get(url: &str) -> HttpResponse {
+        let req = HttpRequest {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            headers: vec![],
+            body: Default::default(),
+        };
+        handle_http_request(&req, 100)
+    }
+
+    fn body(res: &HttpResponse) -> String {
+        String::from_utf8(res.body.clone()).unwrap()
+    }
+
+    // 3 transfers archived from the block height 10
+    fn init_storage() -> TokenHolder {
+        let token_id: Principal = "rwlgt-iiaaa-aaaaa-aaaaa-cai".parse().unwrap();
+        let holder = TokenHolder::new(
+            "qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe"
+                .parse()
+                .unwrap(),
+            None,
+        );
+        service::init(token_id, 10u8.into(), true, None, 1);
+
+        let mut blocks = Vec::new();
+        let mut parent_hash = None;
+        for i in 0..3u64 {
+            let block = InnerBlock::new_from_transaction(
+                &token_id,
+                parent_hash,
+                InnerTransaction {
+                    operation: InnerOperation::Transfer {
+                        caller: holder,
+                        from: holder,
+                        to: holder,
+                        value: (1000 + i).into(),
+                        fee: 1u8.into(),
+                    },
+                    created_at: i,
+                },
+                i,
+            )
+            .encode()
+            .unwrap();
+            parent_hash = Some(block.hash_with_token_id(&token_id));
+            blocks.push(block);
+        }
+        service::batch_append(&token_id, 10u8.into(), blocks, 1).unwrap();
+        holder
+    }
+
+    #[test]
+    fn test_get_block() {
+        let holder = init_storage();
+
+        let res = get("/blocks/11");
+        assert_eq!(res.status_code, 200);
+        let json = body(&res);
+        assert!(json.starts_with("{\"height\":\"11\","));
+        assert!(json.contains(&format!(
+            "\"operation\":{{\"type\":\"transfer\",\"caller\":\"{0}\",\"from\":\"{0}\",\"to\":\"{0}\",\"value\":\"1001\",\"fee\":\"1\"}}",
+            holder
+        )));
+
+        assert_eq!(get("/blocks/9").status_code, 404);
+        assert_eq!(get("/blocks/13").status_code, 404);
+        assert_eq!(get("/blocks/abc").status_code, 400);
+    }
+
+    #[test]
+    fn test_multi_transfer_json() {
+        let holder = |text: &str| TokenHolder::new(text.parse().unwrap(), None);
+        let from = holder("qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe");
+        let to = holder("o5y7v-htz2q-vk7fc-cqi4m-bqvwa-eth75-sc2wz-ubuev-curf2-rbipe-tae");
+        let operation = Operation::MultiTransfer {
+            caller: from,
+            from,
+            outputs: vec![(to, 1u8.into()), (from, 2u8.into())],
+            fee: 3u8.into(),
+        };
+        assert_eq!(
+            operation_json(&operation),
+            format!(
+                "{{\"type\":\"multiTransfer\",\"caller\":\"{0}\",\"from\":\"{0}\",\"outputs\":[{{\"to\":\"{1}\",\"value\":\"1\"}},{{\"to\":\"{0}\",\"value\":\"2\"}}],\"fee\":\"3\"}}",
+                from, to
+            )
+        );
+    }
+
+    #[test]
+    fn test_hold_json() {
+        let holder = |text: &str| TokenHolder::new(text.parse().unwrap(), None);
+        let owner = holder("qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe");
+        let merchant = holder("o5y7v-htz2q-vk7fc-cqi4m-bqvwa-eth75-sc2wz-ubuev-curf2-rbipe-tae");
+        let operation = Operation::Hold {
+            caller: owner,
+            owner,
+            merchant,
+            amount: 100u8.into(),
+            expires_at: 5,
+            fee: 1u8.into(),
+        };
+        assert_eq!(
+            operation_json(&operation),
+            format!(
+                "{{\"type\":\"hold\",\"caller\":\"{0}\",\"owner\":\"{0}\",\"merchant\":\"{1}\",\"amount\":\"100\",\"expiresAt\":5,\"fee\":\"1\"}}",
+                owner, merchant
+            )
+        );
+        let operation = Operation::CaptureHold {
+            caller: merchant,
+            hold_id: 7,
+            owner,
+            merchant,
+            amount: 60u8.into(),
+        };
+        assert_eq!(
+            operation_json(&operation),
+            format!(
+                "{{\"type\":\"captureHold\",\"caller\":\"{1}\",\"holdId\":7,\"owner\":\"{0}\",\"merchant\":\"{1}\",\"amount\":\"60\"}}",
+                owner, merchant
+            )
+        );
+        let operation = Operation::ReleaseHold {
+            caller: merchant,
+            hold_id: 7,
+            owner,
+            amount: 40u8.into(),
+        };
+        assert_eq!(
+            operation_json(&operation),
+            format!(
+                "{{\"type\":\"releaseHold\",\"caller\":\"{1}\",\"holdId\":7,\"owner\":\"{0}\",\"amount\":\"40\"}}",
+                owner, merchant
+            )
+        );
+    }
+
+    #[test]
+    fn test_get_blocks() {
+        init_storage();
+
+        let json = body(&get("/blocks?start=10&limit=2"));
+        assert_eq!(json.matches("\"height\"").count(), 2);
+        assert!(json.contains("\"height\":\"11\""));
+        assert!(json.ends_with("],\"next\":\"12\"}"));
+
+        // the limit defaults to the maximum page size
+        let json = body(&get("/blocks?start=11"));
+        assert_eq!(json.matches("\"height\"").count(), 2);
+        assert!(json.ends_with("],\"next\":\"13\"}"));
+
+        assert_eq!(get("/blocks?start=9").status_code, 404);
+        assert_eq!(get("/blocks").status_code, 400);
+        assert_eq!(get("/blocks?start=10&limit=-1").status_code, 400);
+    }
+
+    #[test]
+    fn test_get_info() {
+        init_storage();
+
+        let res = get("/info");
+        assert_eq!(res.status_code, 200);
+        let json = body(&res);
+        assert!(json.starts_with("{\"tokenId\":\"rwlgt-iiaaa-aaaaa-aaaaa-cai\","));
+        assert!(json.contains("\"blockHeightOffset\":\"10\",\"totalBlocksCount\":\"3\""));
+        assert!(json.ends_with("\"cycles\":100}"));
+
+        assert_eq!(get("/unknown").status_code, 404);
+    }
+}
@@ -1,10 +1,12 @@
-use crate::types::StorageInfo;
+use crate::types::{BlocksByAccountResult, StorageInfo};
 use candid::Principal;
 use candid::{candid_method, Nat};
 use dft_types::*;
 use ic_cdk_macros::*;
 
 mod actor;
+mod http;
+mod memory;
 mod service;
 mod state;
 mod types;
@@ -0,0 +1,98 @@
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, Memory as _};
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// small heap state (settings) serialized by pre_upgrade
+const UPGRADES_MEMORY_ID: MemoryId = MemoryId::new(0);
+// stable log of the archived blocks
+const BLOCKS_INDEX_MEMORY_ID: MemoryId = MemoryId::new(1);
+const BLOCKS_DATA_MEMORY_ID: MemoryId = MemoryId::new(2);
+// sparse block timestamp index used by time range queries
+const BLOCK_TIMESTAMP_INDEX_MEMORY_ID: MemoryId = MemoryId::new(3);
+// account -> blocks index and the number of blocks it covers
+const ACCOUNT_INDEX_ENTRIES_MEMORY_ID: MemoryId = MemoryId::new(4);
+const ACCOUNT_INDEX_PROGRESS_MEMORY_ID: MemoryId = MemoryId::new(5);
+// compressed block frames and the table of where each frame ends
+const BLOCK_FRAMES_INDEX_MEMORY_ID: MemoryId = MemoryId::new(6);
+const BLOCK_FRAMES_DATA_MEMORY_ID: MemoryId = MemoryId::new(7);
+const BLOCK_FRAME_ENTRIES_MEMORY_ID: MemoryId = MemoryId::new(8);
+
+const WASM_PAGE_SIZE_BYTES: u64 = 64 * 1024;
+// the first bytes of stable memory once it is managed by the memory manager
+const MEMORY_MANAGER_MAGIC: &[u8; 3] = b"MGR";
+
+thread_local! {
+    static STABLE_MEMORY: DefaultMemoryImpl = DefaultMemoryImpl::default();
+    // initialized lazily, legacy stable memory must be read before the first access
+    static MEMORY_MANAGER: MemoryManager<DefaultMemoryImpl> =
+        MemoryManager::init(STABLE_MEMORY.with(|m| m.clone()));
+}
+
+pub fn upgrades_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.get(UPGRADES_MEMORY_ID))
+}
+
+pub fn blocks_index_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.get(BLOCKS_INDEX_MEMORY_ID))
+}
+
+pub fn blocks_data_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.get(BLOCKS_DATA_MEMORY_ID))
+}
+
+pub fn block_timestamp_index_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.get(BLOCK_TIMESTAMP_INDEX_MEMORY_ID))
+}
+
+pub fn account_index_memories() -> (Memory, Memory) {
+    MEMORY_MANAGER.with(|m| {
+        (
+            m.get(ACCOUNT_INDEX_ENTRIES_MEMORY_ID),
+            m.get(ACCOUNT_INDEX_PROGRESS_MEMORY_ID),
+        )
+    })
+}
+
+pub fn block_frames_memories() -> (Memory, Memory, Memory) {
+    MEMORY_MANAGER.with(|m| {
+        (
+            m.get(BLOCK_FRAMES_INDEX_MEMORY_ID),
+            m.get(BLOCK_FRAMES_DATA_MEMORY_ID),
+            m.get(BLOCK_FRAME_ENTRIES_MEMORY_ID),
+        )
+    })
+}
+
+pub fn stable_memory_size_bytes() -> u64 {
+    STABLE_MEMORY.with(|m| m.size()) * WASM_PAGE_SIZE_BYTES
+}
+
+// Before blocks were kept in stable memory, pre_upgrade wrote the whole
+// bincode-serialized state at the beginning of stable memory.
+// Returns those bytes if stable memory still has that layout.
+pub fn take_legacy_state_bytes() -> Option<Vec<u8>> {
+    STABLE_MEMORY.with(|m| {
+        let size_bytes = m.size() * WASM_PAGE_SIZE_BYTES;
+        if size_bytes == 0 {
+            return None;
+        }
+        let mut magic = [0u8; 3];
+        m.read(0, &mut magic);
+        if &magic == MEMORY_MANAGER_MAGIC {
+            return None;
+        }
+        let mut bytes = vec![0u8; size_bytes as usize];
+        m.read(0, &mut bytes);
+        Some(bytes)
+    })
+}
+
+#[cfg(test)]
+pub fn write_legacy_state_bytes(bytes: &[u8]) {
+    STABLE_MEMORY.with(|m| {
+        let pages = (bytes.len() as u64).div_ceil(WASM_PAGE_SIZE_BYTES);
+        m.grow(pages);
+        m.write(0, bytes);
+    })
+}
@@ -1,28 +1,80 @@
+use std::cell::RefCell;
 use std::convert::TryInto;
+use std::time::Duration;
 
 use candid::Principal;
 use num_bigint::BigUint;
 use num_traits::{CheckedSub, ToPrimitive};
 
-use dft_types::constants::MAX_BLOCKS_PER_REQUEST;
-use dft_types::{Block, BlockListResult, BlockResult, CommonResult, DFTError, EncodedBlock};
-
-use crate::{state::STATE, types::StorageInfo};
+use dft_types::constants::{
+    ACCOUNT_INDEX_BLOCKS_PER_ROUND, ACCOUNT_INDEX_INTERVAL_SECONDS, MAX_BLOCKS_PER_REQUEST,
+    MAX_CANISTER_STORAGE_BYTES,
+};
+use dft_types::{
+    BlockHash, BlockListResult, BlockResult, BlocksByTimeRange, BlocksByTimeRangeResult,
+    CommonResult, DFTError, EncodedBlock, EncodedBlockListResult, TokenHolder,
+};
+use ic_cdk_timers::TimerId;
+
+use crate::{
+    memory,
+    state::STATE,
+    types::{AccountBlock, BlocksByAccount, StorageInfo},
+};
+
+thread_local! {
+    static ACCOUNT_INDEX_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+}
 
-pub fn init(dft_id: Principal, dft_tx_start_index: BigUint, now: u64) {
+pub fn init(
+    dft_id: Principal,
+    dft_tx_start_index: BigUint,
+    compress_blocks: bool,
+    parent_hash: Option<BlockHash>,
+    now: u64,
+) {
     STATE.with(|s| {
         let mut setting = s.storage_setting.borrow_mut();
-        setting.initialize(dft_id, dft_tx_start_index, now);
+        setting.initialize(
+            dft_id,
+            dft_tx_start_index,
+            compress_blocks,
+            parent_hash,
+            now,
+        );
     });
 }
 
-pub fn batch_append(caller: &Principal, blocks: Vec<EncodedBlock>, now: u64) -> CommonResult<()> {
+pub fn batch_append(
+    caller: &Principal,
+    start_block_height: BigUint,
+    blocks: Vec<EncodedBlock>,
+    now: u64,
+) -> CommonResult<()> {
     STATE.with(|s| {
         let setting = s.storage_setting.borrow();
         setting.only_allow_token_canister(caller)?;
 
+        let start_index = start_block_height
+            .checked_sub(setting.block_height_offset())
+            .and_then(|index| index.to_u64())
+            .ok_or(DFTError::InvalidStartBlockHeight)?;
+
+        // stable memory grows with the stored (compressed) blocks,
+        // keep room for the rest of the canister
+        let available_size_bytes =
+            (MAX_CANISTER_STORAGE_BYTES as u64).saturating_sub(memory::stable_memory_size_bytes());
+
         let mut block_archive = s.block_archive.borrow_mut();
-        block_archive.batch_append(blocks, now);
+        block_archive.batch_append(
+            setting.token_id(),
+            start_index,
+            blocks,
+            now,
+            available_size_bytes,
+            *setting.compress_blocks(),
+            *setting.parent_hash(),
+        )?;
         Ok(())
     })
 }
@@ -38,13 +90,13 @@ pub fn get_block_by_height(block_height: BigUint) -> BlockResult {
         {
             BlockResult::Err(DFTError::NonExistentBlockHeight.into())
         } else {
-            let inner_index: usize = block_height
+            let inner_index: u64 = block_height
                 .checked_sub(setting.block_height_offset())
                 .unwrap()
-                .to_usize()
+                .to_u64()
                 .unwrap();
 
-            match block_archive.blocks().get(inner_index) {
+            match block_archive.get_block(inner_index) {
                 Some(block) => match block.decode() {
                     Ok(de_block) => BlockResult::Ok(de_block.into()),
                     Err(e) => BlockResult::Err(e.into()),
@@ -56,6 +108,21 @@ pub fn get_block_by_height(block_height: BigUint) -> BlockResult {
 }
 
 pub fn get_blocks_by_query(start_block_height: BigUint, size: usize) -> BlockListResult {
+    match get_encoded_blocks_by_query(start_block_height, size) {
+        EncodedBlockListResult::Ok(blocks) => BlockListResult::Ok(
+            blocks
+                .iter()
+                .map(|block| block.decode().unwrap().into())
+                .collect(),
+        ),
+        EncodedBlockListResult::Err(e) => BlockListResult::Err(e),
+    }
+}
+
+pub fn get_encoded_blocks_by_query(
+    start_block_height: BigUint,
+    size: usize,
+) -> EncodedBlockListResult {
     let size = MAX_BLOCKS_PER_REQUEST.min(size as u32);
     STATE.with(|s| {
         let setting = s.storage_setting.borrow();
@@ -65,7 +132,7 @@ pub fn get_blocks_by_query(start_block_height: BigUint, size: usize) -> BlockLis
         if start_block_height < *setting.block_height_offset()
             || start_block_height > (setting.block_height_offset() + total_blocks_count)
         {
-            BlockListResult::Err(DFTError::NonExistentBlockHeight.into())
+            EncodedBlockListResult::Err(DFTError::NonExistentBlockHeight.into())
         } else {
             let inner_index_start: u64 = start_block_height
                 .checked_sub(&setting.block_height_offset())
@@ -80,16 +147,107 @@ pub fn get_blocks_by_query(start_block_height: BigUint, size: usize) -> BlockLis
                 inner_index_end
             };
 
-            let mut res: Vec<Block> = Vec::new();
-            for i in inner_index_start..inner_index_end {
-                let block = block_archive.get_block(i).unwrap();
-                res.push(block.decode().unwrap().into());
-            }
-            BlockListResult::Ok(res)
+            EncodedBlockListResult::Ok(
+                (inner_index_start..inner_index_end)
+                    .map(|i| block_archive.get_block(i).unwrap())
+                    .collect(),
+            )
         }
     })
 }
 
+// Blocks whose timestamp is in [start_ts, end_ts), starting from the `cursor` height.
+pub fn get_blocks_by_time_range(
+    start_ts: u64,
+    end_ts: u64,
+    limit: usize,
+    cursor: Option<BigUint>,
+) -> BlocksByTimeRangeResult {
+    if start_ts > end_ts {
+        return BlocksByTimeRangeResult::Err(DFTError::InvalidTimeRange.into());
+    }
+    let limit = MAX_BLOCKS_PER_REQUEST.min(limit as u32) as usize;
+    STATE.with(|s| {
+        let setting = s.storage_setting.borrow();
+        let block_archive = s.block_archive.borrow();
+        let offset = setting.block_height_offset();
+
+        let from = cursor
+            .and_then(|cursor| cursor.checked_sub(offset))
+            .map_or(0, |index| index.to_u64().unwrap_or(u64::MAX));
+        let (blocks, next_index) =
+            block_archive.blocks_by_time_range(start_ts, end_ts, limit, from);
+        BlocksByTimeRangeResult::Ok(BlocksByTimeRange {
+            blocks,
+            archived_blocks: vec![],
+            next_cursor: next_index.map(|index| (offset + index).into()),
+        })
+    })
+}
+
+// The blocks involving `account`, starting from the `cursor` height.
+pub fn get_blocks_by_account(
+    account: &TokenHolder,
+    cursor: Option<BigUint>,
+    limit: usize,
+) -> CommonResult<BlocksByAccount> {
+    let limit = MAX_BLOCKS_PER_REQUEST.min(limit as u32) as usize;
+    STATE.with(|s| {
+        let setting = s.storage_setting.borrow();
+        let block_archive = s.block_archive.borrow();
+        let offset = setting.block_height_offset();
+
+        let from = cursor
+            .and_then(|cursor| cursor.checked_sub(offset))
+            .map_or(0, |index| index.to_u64().unwrap_or(u64::MAX));
+        let (blocks, next_index) = block_archive.blocks_by_account(account, from, limit);
+        let indexed_blocks_count =
+            block_archive.total_blocks_count() - block_archive.pending_account_index_blocks();
+        Ok(BlocksByAccount {
+            blocks: blocks
+                .into_iter()
+                .map(|(index, block)| AccountBlock {
+                    height: (offset + index).into(),
+                    block,
+                })
+                .collect(),
+            next_cursor: next_index.map(|index| (offset + index).into()),
+            indexed_until: (offset + indexed_blocks_count).into(),
+        })
+    })
+}
+
+// Index a round of the blocks stored before the account index existed,
+// returns the number of blocks still pending.
+pub fn index_pending_blocks() -> u64 {
+    STATE.with(|s| {
+        s.block_archive
+            .borrow_mut()
+            .index_pending_blocks(ACCOUNT_INDEX_BLOCKS_PER_ROUND)
+    })
+}
+
+// Started from post_upgrade when the account index is behind the stored
+// blocks, the timer stops once every block is indexed.
+pub fn start_account_indexing_timer() {
+    if STATE.with(|s| s.block_archive.borrow().pending_account_index_blocks()) == 0 {
+        return;
+    }
+    let interval = Duration::from_secs(ACCOUNT_INDEX_INTERVAL_SECONDS);
+    let timer_id = ic_cdk_timers::set_timer_interval(interval, || {
+        if index_pending_blocks() == 0 {
+            if let Some(timer_id) = ACCOUNT_INDEX_TIMER.with(|t| t.borrow_mut().take()) {
+                ic_cdk_timers::clear_timer(timer_id);
+            }
+        }
+    });
+    ACCOUNT_INDEX_TIMER.with(|t| {
+        if let Some(previous) = t.borrow_mut().replace(timer_id) {
+            ic_cdk_timers::clear_timer(previous);
+        }
+    });
+}
+
 pub fn get_storage_info() -> StorageInfo {
     STATE.with(|s| {
         let setting = s.storage_setting.borrow();
@@ -99,7 +257,10 @@ pub fn get_storage_info() -> StorageInfo {
             token_id: *setting.token_id(),
             block_height_offset: setting.block_height_offset().clone().into(),
             total_blocks_count: block_archive.total_blocks_count().into(),
-            total_block_size_bytes: *block_archive.total_block_size_bytes() as u64,
+            total_block_size_bytes: block_archive.total_block_size_bytes(),
+            compressed_block_size_bytes: block_archive.compressed_block_size_bytes(),
+            stable_memory_size_bytes: memory::stable_memory_size_bytes(),
+            last_block_hash: block_archive.last_hash(),
             cycles: 0,
         }
     })
@@ -112,7 +273,8 @@ mod tests {
     use candid::Nat;
 
     use dft_types::{
-        ErrorInfo, InnerBlock, InnerOperation, InnerTransaction, Operation, TokenHolder,
+        stored_blocks_size_bytes, ErrorInfo, InnerBlock, InnerOperation, InnerTransaction,
+        Operation, TokenHolder,
     };
 
     use super::*;
@@ -127,13 +289,14 @@ mod tests {
             .as_nanos()
             .try_into()
             .unwrap();
-        init(test_token_id.clone(), block_height_offset.clone(), now);
+        init(test_token_id, block_height_offset.clone(), true, None, now);
 
         let storage_info = get_storage_info();
         assert_eq!(storage_info.token_id, test_token_id);
         assert_eq!(storage_info.block_height_offset.0, block_height_offset);
         assert_eq!(storage_info.total_blocks_count.0, BigUint::from(0u8));
         assert_eq!(storage_info.total_block_size_bytes, 0);
+        assert_eq!(storage_info.compressed_block_size_bytes, 0);
     }
 
     #[test]
@@ -147,12 +310,8 @@ mod tests {
             .as_nanos()
             .try_into()
             .unwrap();
-        init(
-            test_token_id.clone(),
-            block_height_offset.clone(),
-            now.clone(),
-        );
-        init(test_token_id, block_height_offset, now);
+        init(test_token_id, block_height_offset.clone(), true, None, now);
+        init(test_token_id, block_height_offset, true, None, now);
     }
 
     #[test]
@@ -174,7 +333,7 @@ mod tests {
             .as_nanos()
             .try_into()
             .unwrap();
-        init(test_token_id, block_height_offset.clone(), now);
+        init(test_token_id, block_height_offset.clone(), true, None, now);
 
         let mut blocks = Vec::new();
         let mut pre_block: Option<InnerBlock> = None;
@@ -212,8 +371,29 @@ mod tests {
             blocks.push(block.encode().unwrap());
         }
 
-        let res = batch_append(&test_token_id, blocks, now);
+        let last_block_hash = blocks.last().unwrap().hash_with_token_id(&test_token_id);
+        // a gap before the first stored block height is rejected
+        let res = batch_append(
+            &test_token_id,
+            block_height_offset.clone() + 1u32,
+            blocks.clone(),
+            now,
+        );
+        assert_eq!(res, Err(DFTError::InvalidStartBlockHeight));
+        let res = batch_append(&test_token_id, BigUint::from(0u32), blocks.clone(), now);
+        assert_eq!(res, Err(DFTError::InvalidStartBlockHeight));
+
+        let res = batch_append(
+            &test_token_id,
+            block_height_offset.clone(),
+            blocks.clone(),
+            now,
+        );
         assert_eq!(res.is_ok(), true);
+        // resending the same blocks succeeds without appending them again
+        let res = batch_append(&test_token_id, block_height_offset.clone(), blocks, now);
+        assert!(res.is_ok());
+        assert_eq!(get_storage_info().last_block_hash, Some(last_block_hash));
 
         for i in 0..loop_times {
             let block_height = BigUint::from(i);
@@ -267,7 +447,120 @@ mod tests {
             _ => panic!("unexpected result"),
         }
 
+        // time range queries page with a block height cursor
+        match get_blocks_by_time_range(now + 50, now + 400, 300, None) {
+            BlocksByTimeRangeResult::Ok(res) => {
+                assert_eq!(res.blocks.len(), 100);
+                assert_eq!(res.blocks[0].timestamp, now + 50);
+                assert!(res.archived_blocks.is_empty());
+                assert_eq!(
+                    res.next_cursor,
+                    Some(Nat::from(block_height_offset.clone() + 150u32))
+                );
+            }
+            BlocksByTimeRangeResult::Err(e) => panic!("unexpected result,{:?}", e),
+        }
+        match get_blocks_by_time_range(
+            now + 50,
+            now + 400,
+            300,
+            Some(block_height_offset.clone() + 350u32),
+        ) {
+            BlocksByTimeRangeResult::Ok(res) => {
+                assert_eq!(res.blocks.len(), 50);
+                assert_eq!(res.blocks[0].timestamp, now + 350);
+                assert_eq!(res.next_cursor, None);
+            }
+            BlocksByTimeRangeResult::Err(e) => panic!("unexpected result,{:?}", e),
+        }
+        let res = get_blocks_by_account(&to_holder, None, 300).unwrap();
+        assert_eq!(res.blocks.len(), 100);
+        assert_eq!(res.blocks[1].height.0, block_height_offset.clone() + 1u32);
+        assert_eq!(
+            res.next_cursor,
+            Some(Nat::from(block_height_offset.clone() + 100u32))
+        );
+        assert_eq!(
+            res.indexed_until.0,
+            block_height_offset.clone() + loop_times
+        );
+        let res =
+            get_blocks_by_account(&to_holder, Some(block_height_offset.clone() + 450u32), 300)
+                .unwrap();
+        assert_eq!(res.blocks.len(), 50);
+        assert_eq!(res.next_cursor, None);
+        let other = TokenHolder::new(test_token_id, None);
+        assert!(get_blocks_by_account(&other, None, 300)
+            .unwrap()
+            .blocks
+            .is_empty());
+
+        match get_blocks_by_time_range(now + 2, now + 1, 10, None) {
+            BlocksByTimeRangeResult::Err(e) => {
+                let comp_err: ErrorInfo = DFTError::InvalidTimeRange.into();
+                assert_eq!(e, comp_err);
+            }
+            _ => panic!("unexpected result"),
+        }
+
         let storage_info = get_storage_info();
         assert_eq!(storage_info.total_blocks_count, loop_times as u32);
+        assert!(storage_info.stable_memory_size_bytes >= storage_info.total_block_size_bytes);
+        // the blocks of one batch are stored compressed
+        assert!(storage_info.compressed_block_size_bytes < storage_info.total_block_size_bytes);
+    }
+
+    #[test]
+    fn test_get_block_without_compression() {
+        let test_token_id: Principal = "rwlgt-iiaaa-aaaaa-aaaaa-cai".parse().unwrap();
+        let block_height_offset = BigUint::from(110u8);
+        let holder = TokenHolder::new(test_token_id, None);
+        let now = 1_670_000_000_000_000_000u64;
+        init(test_token_id, block_height_offset.clone(), false, None, now);
+
+        let mut blocks = Vec::new();
+        let mut parent_hash = None;
+        for i in 0..100u64 {
+            let block = InnerBlock::new_from_transaction(
+                &test_token_id,
+                parent_hash,
+                InnerTransaction {
+                    operation: InnerOperation::Transfer {
+                        caller: holder,
+                        from: holder,
+                        to: holder,
+                        value: (1000u64 + i).into(),
+                        fee: 1u32.into(),
+                    },
+                    created_at: now + i,
+                },
+                now + i,
+            )
+            .encode()
+            .unwrap();
+            parent_hash = Some(block.hash_with_token_id(&test_token_id));
+            blocks.push(block);
+        }
+        let stored_size_bytes = stored_blocks_size_bytes(&blocks, false) as u64;
+        batch_append(&test_token_id, block_height_offset.clone(), blocks, now).unwrap();
+
+        match get_block_by_height(block_height_offset.clone() + 42u32) {
+            BlockResult::Ok(b) => match b.transaction.operation {
+                Operation::Transfer { value, .. } => assert_eq!(value, Nat::from(1042u64)),
+                _ => panic!("unexpected operation"),
+            },
+            res => panic!("unexpected result,{:?}", res),
+        }
+        match get_blocks_by_query(block_height_offset.clone() + 90u32, 20) {
+            BlockListResult::Ok(b) => {
+                assert_eq!(b.len(), 10);
+                assert_eq!(b[0].timestamp, now + 90);
+            }
+            BlockListResult::Err(e) => panic!("unexpected result,{:?}", e),
+        }
+
+        let storage_info = get_storage_info();
+        assert_eq!(storage_info.compressed_block_size_bytes, stored_size_bytes);
+        assert!(storage_info.compressed_block_size_bytes > storage_info.total_block_size_bytes);
     }
 }
@@ -1,7 +1,8 @@
+use crate::memory;
 use crate::types::*;
 use dft_types::*;
-use ic_cdk::api::stable::{stable_bytes, StableWriter};
 use ic_cdk_macros::*;
+use ic_stable_structures::StableCell;
 use log::{error, info};
 use std::cell::RefCell;
 
@@ -14,41 +15,103 @@ pub struct State {
     pub block_archive: RefCell<BlockArchive>,
 }
 
+// Layouts of the blob saved on upgrade:
+// v1: settings and blocks serialized into one heap blob at the start of stable memory
+// v2: settings and the archive metadata, in the upgrades memory
+// v3: the settings record whether the blocks are compressed and the parent hash of the first block
+const STATE_SCHEMA: StableStateSchema<State> =
+    StableStateSchema::new(&[State::migrate_v1_to_v2, State::migrate_v2_to_v3]);
+
 impl State {
     pub fn replace(&self, new_state: State) {
         self.storage_setting
-            .replace(new_state.storage_setting.take());
-        self.block_archive.replace(new_state.block_archive.take());
+            .replace(new_state.storage_setting.into_inner());
+        self.block_archive
+            .replace(new_state.block_archive.into_inner());
     }
-}
 
-impl StableState for State {
-    fn encode(&self) -> Vec<u8> {
+    // The blocks are already in stable memory, only the settings and the
+    // archive metadata have to be saved, so upgrades take constant time.
+    fn encode_payload(&self) -> Vec<u8> {
         bincode::serialize(&(
             self.storage_setting.borrow().encode(),
-            self.block_archive.borrow().encode(),
+            self.block_archive.borrow().last_update_timestamp(),
         ))
         .unwrap()
     }
 
-    #[allow(clippy::type_complexity)]
-    fn decode(bytes: Vec<u8>) -> Result<Self, String> {
+    fn restore(&self, payload: Vec<u8>) -> Result<(), String> {
+        let (storage_setting_bytes, last_update_timestamp): (Vec<u8>, u64) =
+            bincode::deserialize(&payload)
+                .map_err(|e| format!("auto-scaling-storage: decode storage state failed, {}", e))?;
+
+        let storage_setting = StorageSetting::decode(storage_setting_bytes)?;
+        self.block_archive
+            .borrow_mut()
+            .restore(storage_setting.token_id(), last_update_timestamp);
+        self.storage_setting.replace(storage_setting);
+        Ok(())
+    }
+
+    // The blocks of the heap blob are moved into the stable block log.
+    fn migrate_v1_to_v2(&mut self, bytes: Vec<u8>) -> Result<Vec<u8>, String> {
         let (storage_setting_bytes, block_archive_bytes): (Vec<u8>, Vec<u8>) =
-            bincode::deserialize(&bytes).unwrap();
+            bincode::deserialize(&bytes).map_err(|e| {
+                format!(
+                    "auto-scaling-storage: decode heap storage state failed, {}",
+                    e
+                )
+            })?;
+
+        let storage_setting = StorageSetting::decode(StorageSetting::migrate_from_v2(
+            storage_setting_bytes.clone(),
+        )?)?;
+        let mut block_archive = self.block_archive.borrow_mut();
+        block_archive.migrate_from_heap_format(storage_setting.token_id(), block_archive_bytes)?;
+        Ok(
+            bincode::serialize(&(storage_setting_bytes, block_archive.last_update_timestamp()))
+                .unwrap(),
+        )
+    }
 
-        Ok(State {
-            storage_setting: RefCell::new(StorageSetting::decode(storage_setting_bytes)?),
-            block_archive: RefCell::new(BlockArchive::decode(block_archive_bytes)?),
-        })
+    fn migrate_v2_to_v3(&mut self, bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+        let (storage_setting_bytes, last_update_timestamp): (Vec<u8>, u64) =
+            bincode::deserialize(&bytes)
+                .map_err(|e| format!("auto-scaling-storage: decode storage state failed, {}", e))?;
+
+        Ok(bincode::serialize(&(
+            StorageSetting::migrate_from_v2(storage_setting_bytes)?,
+            last_update_timestamp,
+        ))
+        .unwrap())
+    }
+}
+
+impl StableState for State {
+    fn encode(&self) -> Vec<u8> {
+        STATE_SCHEMA.encode(self.encode_payload())
+    }
+
+    fn decode(bytes: Vec<u8>) -> Result<Self, String> {
+        let mut state = State::default();
+        let payload = STATE_SCHEMA.migrate(&mut state, bytes)?;
+        state.restore(payload)?;
+        Ok(state)
     }
 }
 
+fn upgrades_cell() -> StableCell<Vec<u8>, memory::Memory> {
+    StableCell::init(memory::upgrades_memory(), Vec::new())
+        .expect("auto-scaling-storage: failed to initialize the upgrades memory")
+}
+
 #[pre_upgrade]
 fn pre_upgrade() {
     STATE.with(|s| {
         let bytes = s.encode();
-        match StableWriter::default().write(bytes.as_slice()) {
-            Ok(size) => {
+        let size = bytes.len();
+        match upgrades_cell().set(bytes) {
+            Ok(_) => {
                 info!(
                     "auto-scaling-storage: after pre_upgrade stable_write size{}",
                     size
@@ -63,12 +126,17 @@ fn pre_upgrade() {
 
 #[post_upgrade]
 fn post_upgrade() {
-    STATE.with(|s| {
-        let bytes = stable_bytes();
-        let restore_state =
-            State::decode(bytes).expect("auto-scaling-storage: Decoding stable memory failed");
-        s.replace(restore_state);
-    })
+    // must be checked before anything touches the memory manager,
+    // which takes over the stable memory when initialized
+    let bytes = memory::take_legacy_state_bytes().unwrap_or_else(|| upgrades_cell().get().clone());
+    match State::decode(bytes) {
+        Ok(restore_state) => STATE.with(|s| s.replace(restore_state)),
+        Err(e) => ic_cdk::trap(&format!(
+            "auto-scaling-storage: Decoding stable memory failed, {}",
+            e
+        )),
+    }
+    crate::service::start_account_indexing_timer();
 }
 
 #[cfg(test)]
@@ -91,8 +159,10 @@ mod tests {
 
         let state = State::default();
         state.storage_setting.borrow_mut().initialize(
-            test_token_id.clone(),
+            test_token_id,
             block_height_offset.clone(),
+            false,
+            Some([1u8; 32]),
             now,
         );
 
@@ -108,5 +178,160 @@ mod tests {
             restore_setting.block_height_offset()
         );
         assert_eq!(setting2.create_at(), restore_setting.create_at());
+        assert!(!restore_setting.compress_blocks());
+        assert_eq!(*restore_setting.parent_hash(), Some([1u8; 32]));
+    }
+
+    fn test_blocks(token_id: &Principal, count: u64) -> Vec<EncodedBlock> {
+        let holder = TokenHolder::new(*token_id, None);
+        let mut parent_hash = None;
+        let mut blocks = vec![];
+        for i in 0..count {
+            let block = InnerBlock::new_from_transaction(
+                token_id,
+                parent_hash,
+                InnerTransaction {
+                    operation: InnerOperation::Transfer {
+                        caller: holder,
+                        from: holder,
+                        to: holder,
+                        value: (1000u64 + i).into(),
+                        fee: 1u32.into(),
+                    },
+                    created_at: i,
+                },
+                i,
+            )
+            .encode()
+            .unwrap();
+            parent_hash = Some(block.hash_with_token_id(token_id));
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_state_upgrade_keeps_blocks_in_stable_memory() {
+        let test_token_id: Principal = "rwlgt-iiaaa-aaaaa-aaaaa-cai".parse().unwrap();
+        let blocks = test_blocks(&test_token_id, 10);
+
+        let state = State::default();
+        state.storage_setting.borrow_mut().initialize(
+            test_token_id,
+            BigUint::from(0u8),
+            true,
+            None,
+            1,
+        );
+        state
+            .block_archive
+            .borrow_mut()
+            .batch_append(&test_token_id, 0, blocks.clone(), 2, u64::MAX, true, None)
+            .unwrap();
+
+        // only the settings and archive metadata are serialized
+        let bytes = state.encode();
+        assert!(bytes.len() < 200);
+        upgrades_cell().set(bytes).unwrap();
+
+        let restore_state = State::decode(upgrades_cell().get().clone()).unwrap();
+        let block_archive = restore_state.block_archive.borrow();
+        assert_eq!(block_archive.total_blocks_count(), 10);
+        assert_eq!(block_archive.last_update_timestamp(), 2);
+        assert_eq!(
+            block_archive.last_hash(),
+            Some(blocks[9].hash_with_token_id(&test_token_id))
+        );
+    }
+
+    #[test]
+    fn test_state_migrate_from_heap_format() {
+        let test_token_id: Principal = "rwlgt-iiaaa-aaaaa-aaaaa-cai".parse().unwrap();
+        let blocks = test_blocks(&test_token_id, 10);
+        let total_block_size_bytes: usize = blocks.iter().map(|b| b.size_bytes()).sum();
+
+        // layouts written by the pre_upgrade of the heap-based archive
+        let storage_setting_bytes =
+            bincode::serialize(&(test_token_id, BigUint::from(100u8), 1u64)).unwrap();
+        let legacy_bytes = bincode::serialize(&(
+            storage_setting_bytes,
+            bincode::serialize(&(&blocks, total_block_size_bytes, 5u64)).unwrap(),
+        ))
+        .unwrap();
+        memory::write_legacy_state_bytes(&legacy_bytes);
+
+        let bytes = memory::take_legacy_state_bytes().unwrap();
+        let restore_state = State::decode(bytes).unwrap();
+        // the memory manager owns the stable memory from now on
+        assert!(memory::take_legacy_state_bytes().is_none());
+
+        let restore_setting = restore_state.storage_setting.borrow();
+        assert_eq!(*restore_setting.token_id(), test_token_id);
+        assert_eq!(*restore_setting.block_height_offset(), BigUint::from(100u8));
+        assert!(restore_setting.compress_blocks());
+        let block_archive = restore_state.block_archive.borrow();
+        assert_eq!(block_archive.total_blocks_count(), 10);
+        assert_eq!(
+            block_archive.total_block_size_bytes(),
+            total_block_size_bytes as u64
+        );
+        assert_eq!(block_archive.last_update_timestamp(), 5);
+        assert_eq!(block_archive.get_block(3), Some(blocks[3].clone()));
+    }
+
+    // the state the fixtures were written from: the storage is initialized for
+    // the token with a block height offset of 100, then 3 chained blocks are appended
+    const STATE_V1_FIXTURE: &[u8] = include_bytes!("../fixtures/state_v1.bin");
+    const STATE_V2_FIXTURE: &[u8] = include_bytes!("../fixtures/state_v2.bin");
+    const FIXTURE_NOW: u64 = 1_670_000_000_000_000_000;
+
+    fn assert_fixture_settings(state: &State) {
+        let setting = state.storage_setting.borrow();
+        assert_eq!(
+            *setting.token_id(),
+            "rwlgt-iiaaa-aaaaa-aaaaa-cai".parse::<Principal>().unwrap()
+        );
+        assert_eq!(*setting.block_height_offset(), BigUint::from(100u8));
+        assert!(setting.compress_blocks());
+        assert_eq!(*setting.parent_hash(), None);
+        assert_eq!(
+            state.block_archive.borrow().last_update_timestamp(),
+            FIXTURE_NOW + 10
+        );
+    }
+
+    #[test]
+    fn test_decode_state_v1_fixture() {
+        memory::write_legacy_state_bytes(STATE_V1_FIXTURE);
+
+        let bytes = memory::take_legacy_state_bytes().unwrap();
+        let state = State::decode(bytes).unwrap();
+
+        assert_fixture_settings(&state);
+        let token_id = *state.storage_setting.borrow().token_id();
+        let block_archive = state.block_archive.borrow();
+        assert_eq!(block_archive.total_blocks_count(), 3);
+        let last_block = block_archive.get_block(2).unwrap();
+        assert_eq!(
+            block_archive.last_hash(),
+            Some(last_block.hash_with_token_id(&token_id))
+        );
+    }
+
+    #[test]
+    fn test_decode_state_v2_fixture() {
+        // v2 only holds the metadata, the block log is empty here
+        let state = State::decode(STATE_V2_FIXTURE.to_vec()).unwrap();
+        assert_fixture_settings(&state);
+    }
+
+    #[test]
+    fn test_decode_state_errors_are_readable() {
+        let err = State::decode(b"not a state".to_vec()).unwrap_err();
+        assert!(
+            err.starts_with("migrate stable state from version 1 to 2 failed"),
+            "{}",
+            err
+        );
     }
 }
@@ -0,0 +1,180 @@
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::fmt;
+
+use dft_types::*;
+use ic_stable_structures::{BoundedStorable, Memory, StableBTreeMap, StableCell, Storable};
+
+// Keyed by (account, block index), so the blocks of an account are adjacent
+// and sorted, and can be listed with a range scan.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct AccountBlockKey {
+    account: TokenHolder,
+    index: u64,
+}
+
+impl Storable for AccountBlockKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned([&self.account.to_bytes()[..], &self.index.to_be_bytes()[..]].concat())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let size = TokenHolder::MAX_SIZE as usize;
+        AccountBlockKey {
+            account: TokenHolder::from_bytes(Cow::Borrowed(&bytes[..size])),
+            index: u64::from_be_bytes(bytes[size..].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for AccountBlockKey {
+    const MAX_SIZE: u32 = TokenHolder::MAX_SIZE + 8;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// account -> inner indexes of the blocks involving the account.
+// The blocks are indexed in order, `indexed_blocks_count` is the number of
+// blocks already indexed, the archives created before the index existed
+// catch up in rounds.
+pub struct AccountIndex<M: Memory> {
+    entries: StableBTreeMap<AccountBlockKey, (), M>,
+    indexed_blocks_count: StableCell<u64, M>,
+}
+
+impl<M: Memory> fmt::Debug for AccountIndex<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccountIndex")
+            .field("entries_count", &self.entries.len())
+            .field("indexed_blocks_count", &self.indexed_blocks_count())
+            .finish()
+    }
+}
+
+impl<M: Memory> AccountIndex<M> {
+    // load the index already stored in the memories, if any
+    pub fn init(entries_memory: M, progress_memory: M) -> Self {
+        AccountIndex {
+            entries: StableBTreeMap::init(entries_memory),
+            indexed_blocks_count: StableCell::init(progress_memory, 0)
+                .expect("auto-scaling-storage: failed to initialize the account index"),
+        }
+    }
+
+    pub fn indexed_blocks_count(&self) -> u64 {
+        *self.indexed_blocks_count.get()
+    }
+
+    // index the next block, its inner index is `indexed_blocks_count`
+    pub fn push_block(&mut self, block: &InnerBlock) {
+        let index = self.indexed_blocks_count();
+        for account in block.transaction.operation.accounts() {
+            self.entries.insert(AccountBlockKey { account, index }, ());
+        }
+        self.indexed_blocks_count
+            .set(index + 1)
+            .expect("auto-scaling-storage: failed to update the account index");
+    }
+
+    // The inner indexes of at most `limit` blocks of `account`, starting from `from`.
+    // Returns the index to continue from when there are more blocks.
+    pub fn blocks_of(
+        &self,
+        account: &TokenHolder,
+        from: u64,
+        limit: usize,
+    ) -> (Vec<u64>, Option<u64>) {
+        let mut indexes: Vec<u64> = self
+            .entries
+            .range(
+                AccountBlockKey {
+                    account: *account,
+                    index: from,
+                }..=AccountBlockKey {
+                    account: *account,
+                    index: u64::MAX,
+                },
+            )
+            .take(limit + 1)
+            .map(|(key, _)| key.index)
+            .collect();
+        let next_index = if indexes.len() > limit {
+            indexes.pop()
+        } else {
+            None
+        };
+        (indexes, next_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+    use ic_stable_structures::VectorMemory;
+
+    use super::*;
+
+    fn transfer_block(from: TokenHolder, to: TokenHolder) -> InnerBlock {
+        let token_id: Principal = "rwlgt-iiaaa-aaaaa-aaaaa-cai".parse().unwrap();
+        InnerBlock::new_from_transaction(
+            &token_id,
+            None,
+            InnerTransaction {
+                operation: InnerOperation::Transfer {
+                    caller: from,
+                    from,
+                    to,
+                    value: 1u32.into(),
+                    fee: 0u32.into(),
+                },
+                created_at: 1,
+            },
+            1,
+        )
+    }
+
+    #[test]
+    fn test_account_index() {
+        let alice = TokenHolder::new(
+            "qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe"
+                .parse()
+                .unwrap(),
+            None,
+        );
+        let bob = TokenHolder::new(
+            "o5y7v-htz2q-vk7fc-cqi4m-bqvwa-eth75-sc2wz-ubuev-curf2-rbipe-tae"
+                .parse()
+                .unwrap(),
+            None,
+        );
+        let carol = TokenHolder::new(
+            "czjfo-ddpvm-6sibl-6zbox-ee5zq-bx3hc-e336t-s6pka-dupmy-wcxqi-fae"
+                .parse()
+                .unwrap(),
+            None,
+        );
+        let entries_memory = VectorMemory::default();
+        let progress_memory = VectorMemory::default();
+        let mut index = AccountIndex::init(entries_memory.clone(), progress_memory.clone());
+        // alice pays bob in every even block, bob pays carol in every odd one
+        for i in 0..10 {
+            if i % 2 == 0 {
+                index.push_block(&transfer_block(alice, bob));
+            } else {
+                index.push_block(&transfer_block(bob, carol));
+            }
+        }
+        assert_eq!(index.indexed_blocks_count(), 10);
+
+        assert_eq!(index.blocks_of(&alice, 0, 100), (vec![0, 2, 4, 6, 8], None));
+        assert_eq!(index.blocks_of(&bob, 0, 100).0.len(), 10);
+        assert_eq!(index.blocks_of(&carol, 0, 2), (vec![1, 3], Some(5)));
+        assert_eq!(index.blocks_of(&carol, 5, 2), (vec![5, 7], Some(9)));
+        assert_eq!(index.blocks_of(&carol, 9, 2), (vec![9], None));
+        assert_eq!(index.blocks_of(&carol, 10, 2), (vec![], None));
+
+        // the index is read back from the memories, as after an upgrade
+        let index = AccountIndex::init(entries_memory, progress_memory);
+        assert_eq!(index.indexed_blocks_count(), 10);
+        assert_eq!(index.blocks_of(&alice, 3, 100), (vec![4, 6, 8], None));
+    }
+}
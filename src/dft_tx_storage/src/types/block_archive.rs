@@ -1,61 +1,405 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::fmt;
+
+use candid::Principal;
+use dft_types::constants::BLOCK_FRAME_SIZE;
 use dft_types::*;
-use getset::{Getters, Setters};
+use ic_stable_structures::{BoundedStorable, Memory, StableLog, StableVec, Storable};
+
+use crate::memory;
+use crate::types::AccountIndex;
+
+// Where the blocks of a frame end, the frame starts where the previous one ends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FrameEntry {
+    // inner index of the block after the last block of the frame
+    blocks_end: u64,
+    // logical (uncompressed) size of the blocks up to the end of the frame
+    logical_bytes_end: u64,
+}
 
-#[derive(Default, Debug, Getters, Setters)]
-#[getset(get = "pub")]
-pub struct BlockArchive {
-    blocks: Vec<EncodedBlock>,
-    total_block_size_bytes: usize,
+impl Storable for FrameEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(
+            [
+                self.blocks_end.to_be_bytes(),
+                self.logical_bytes_end.to_be_bytes(),
+            ]
+            .concat(),
+        )
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        FrameEntry {
+            blocks_end: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            logical_bytes_end: u64::from_be_bytes(bytes[8..].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for FrameEntry {
+    const MAX_SIZE: u32 = 8 + 8;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// Blocks live in stable memory, so upgrades do not have to serialize them.
+// Only the small metadata below is kept on the heap.
+// Blocks are stored in frames of up to BLOCK_FRAME_SIZE blocks, compressed
+// together unless the storage was installed without compression. Archives
+// created before the frames existed keep their first blocks in the raw
+// block log, the blocks appended afterwards go to frames.
+pub struct BlockArchive<M: Memory = memory::Memory> {
+    // raw blocks [0, blocks.len())
+    blocks: StableLog<EncodedBlock, M, M>,
+    // the blocks after the raw ones, one encoded frame per entry
+    frames: StableLog<Vec<u8>, M, M>,
+    frame_entries: StableVec<FrameEntry, M>,
+    // the last decoded frame, consecutive blocks are usually read together
+    frame_cache: RefCell<Option<(u64, Vec<EncodedBlock>)>>,
+    timestamp_index: BlockTimestampIndex<M>,
+    account_index: AccountIndex<M>,
     last_update_timestamp: u64,
+    // hash of the last stored block, the next appended block must link to it
+    last_hash: Option<BlockHash>,
+}
+
+impl Default for BlockArchive {
+    fn default() -> Self {
+        Self::init(
+            memory::blocks_index_memory(),
+            memory::blocks_data_memory(),
+            memory::block_timestamp_index_memory(),
+            memory::account_index_memories(),
+            memory::block_frames_memories(),
+        )
+    }
 }
 
-impl BlockArchive {
-    #[allow(dead_code)]
-    pub fn append(&mut self, block: EncodedBlock, now: u64) {
-        self.total_block_size_bytes += block.size_bytes();
-        self.blocks.push(block);
-        self.last_update_timestamp = now;
+impl<M: Memory> fmt::Debug for BlockArchive<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockArchive")
+            .field("total_blocks_count", &self.total_blocks_count())
+            .field("total_block_size_bytes", &self.total_block_size_bytes())
+            .field(
+                "compressed_block_size_bytes",
+                &self.compressed_block_size_bytes(),
+            )
+            .field("frames_count", &self.frame_entries.len())
+            .field("timestamp_index", &self.timestamp_index)
+            .field("account_index", &self.account_index)
+            .field("last_update_timestamp", &self.last_update_timestamp)
+            .field("last_hash", &self.last_hash)
+            .finish()
     }
+}
 
-    pub fn batch_append(&mut self, blocks: Vec<EncodedBlock>, now: u64) {
-        // update total block_size_bytes
-        for block in &blocks {
-            self.total_block_size_bytes += block.size_bytes();
+impl<M: Memory> BlockArchive<M> {
+    // load the blocks already stored in the memories, if any
+    pub fn init(
+        index_memory: M,
+        data_memory: M,
+        timestamp_index_memory: M,
+        account_index_memories: (M, M),
+        frames_memories: (M, M, M),
+    ) -> Self {
+        let blocks = StableLog::init(index_memory, data_memory)
+            .expect("auto-scaling-storage: failed to initialize the block log");
+        let (frames_index_memory, frames_data_memory, frame_entries_memory) = frames_memories;
+        Self {
+            blocks,
+            frames: StableLog::init(frames_index_memory, frames_data_memory)
+                .expect("auto-scaling-storage: failed to initialize the block frame log"),
+            frame_entries: StableVec::init(frame_entries_memory)
+                .expect("auto-scaling-storage: failed to initialize the block frame table"),
+            frame_cache: RefCell::new(None),
+            timestamp_index: BlockTimestampIndex::init(timestamp_index_memory),
+            account_index: AccountIndex::init(account_index_memories.0, account_index_memories.1),
+            last_update_timestamp: 0,
+            last_hash: None,
         }
-        self.blocks.extend(blocks);
-        self.last_update_timestamp = now;
+    }
+
+    pub fn last_update_timestamp(&self) -> u64 {
+        self.last_update_timestamp
+    }
+
+    pub fn last_hash(&self) -> Option<BlockHash> {
+        self.last_hash
+    }
+
+    fn last_frame_entry(&self) -> Option<FrameEntry> {
+        self.frame_entries
+            .len()
+            .checked_sub(1)
+            .and_then(|index| self.frame_entries.get(index))
+    }
+
+    // logical size of the stored blocks, as if they were not compressed
+    pub fn total_block_size_bytes(&self) -> u64 {
+        self.blocks.log_size_bytes()
+            + self
+                .last_frame_entry()
+                .map_or(0, |entry| entry.logical_bytes_end)
+    }
+
+    // size the stored blocks actually take
+    pub fn compressed_block_size_bytes(&self) -> u64 {
+        self.blocks.log_size_bytes() + self.frames.log_size_bytes()
+    }
+
+    // Append `blocks` starting at the inner index `start_index`.
+    // Blocks which are already stored (a retry after a lost reply) are skipped,
+    // they must be identical to the stored ones. A gap is rejected.
+    // The new blocks are rejected if their frames take more than `available_size_bytes`.
+    // The frames are compressed if `compress` is set, a frame is always decoded
+    // with the codec it was written with.
+    // The first block of the archive must link to `parent_hash`, when it is known.
+    // Returns the number of blocks actually appended.
+    #[allow(clippy::too_many_arguments)]
+    pub fn batch_append(
+        &mut self,
+        token_id: &Principal,
+        start_index: u64,
+        blocks: Vec<EncodedBlock>,
+        now: u64,
+        available_size_bytes: u64,
+        compress: bool,
+        parent_hash: Option<BlockHash>,
+    ) -> CommonResult<usize> {
+        let total_blocks_count = self.total_blocks_count();
+        if start_index > total_blocks_count {
+            return Err(DFTError::InvalidStartBlockHeight);
+        }
+
+        let num_already_stored =
+            (total_blocks_count - start_index).min(blocks.len() as u64) as usize;
+        for (i, block) in blocks[..num_already_stored].iter().enumerate() {
+            if self.get_block(start_index + i as u64).as_ref() != Some(block) {
+                return Err(DFTError::ResentBlocksDoNotMatch);
+            }
+        }
+
+        let new_blocks = &blocks[num_already_stored..];
+        // verify the hash chain of the whole batch before appending anything
+        let mut last_hash = if total_blocks_count == 0 {
+            parent_hash
+        } else {
+            self.last_hash
+        };
+        let mut hashes: Vec<Option<BlockHash>> = Vec::with_capacity(new_blocks.len());
+        let mut decoded_blocks = Vec::with_capacity(new_blocks.len());
+        for block in new_blocks {
+            let decoded_block = block.decode()?;
+            if last_hash.is_some() && last_hash.unwrap() != decoded_block.parent_hash {
+                return Err(DFTError::ApplyBlockFailedByParentHashDoesNotMatch);
+            }
+            last_hash = Some(block.hash_with_token_id(token_id));
+            hashes.push(last_hash);
+            decoded_blocks.push(decoded_block);
+        }
+
+        if new_blocks.is_empty() {
+            return Ok(0);
+        }
+
+        let frames: Vec<Vec<u8>> = new_blocks
+            .chunks(BLOCK_FRAME_SIZE)
+            .map(|frame_blocks| encode_block_frame(frame_blocks, compress))
+            .collect();
+        let frames_size_bytes: usize = frames.iter().map(|frame| frame.len()).sum();
+        if frames_size_bytes as u64 > available_size_bytes {
+            return Err(DFTError::InsufficientStorageCapacity);
+        }
+
+        let mut index = total_blocks_count;
+        let mut logical_bytes_end = self.total_block_size_bytes() - self.blocks.log_size_bytes();
+        let mut decoded_blocks = decoded_blocks.into_iter();
+        // each stored frame moves the last hash and the indexes along with it, a frame
+        // failing to grow the memory keeps the frames before it and the retry
+        // resends them with the rest
+        for ((frame, frame_blocks), frame_hashes) in frames
+            .iter()
+            .zip(new_blocks.chunks(BLOCK_FRAME_SIZE))
+            .zip(hashes.chunks(BLOCK_FRAME_SIZE))
+        {
+            self.frames
+                .append(frame)
+                .map_err(|_| DFTError::InsufficientStorageCapacity)?;
+            logical_bytes_end += frame_blocks
+                .iter()
+                .map(|block| block.size_bytes() as u64)
+                .sum::<u64>();
+            // the frame is already in the log, the table must follow it
+            self.frame_entries
+                .push(&FrameEntry {
+                    blocks_end: index + frame_blocks.len() as u64,
+                    logical_bytes_end,
+                })
+                .expect("auto-scaling-storage: failed to update the block frame table");
+            for decoded_block in decoded_blocks.by_ref().take(frame_blocks.len()) {
+                self.timestamp_index.record(index, decoded_block.timestamp);
+                // an index still catching up indexes the block in a later round
+                if self.account_index.indexed_blocks_count() == index {
+                    self.account_index.push_block(&decoded_block);
+                }
+                index += 1;
+            }
+            self.last_hash = *frame_hashes.last().unwrap();
+            self.last_update_timestamp = now;
+        }
+        Ok(new_blocks.len())
+    }
+
+    // restore the heap metadata after an upgrade
+    pub fn restore(&mut self, token_id: &Principal, last_update_timestamp: u64) {
+        self.last_update_timestamp = last_update_timestamp;
+        self.last_hash = self
+            .total_blocks_count()
+            .checked_sub(1)
+            .and_then(|index| self.get_block(index))
+            .map(|block| block.hash_with_token_id(token_id));
+        // archives created before the index existed sample their blocks once,
+        // they were all stored before the frames existed
+        let blocks = &self.blocks;
+        self.timestamp_index.backfill(0..blocks.len(), |index| {
+            blocks
+                .get(index)
+                .unwrap()
+                .decode()
+                .expect("auto-scaling-storage: failed to decode stored block")
+                .timestamp
+        });
+    }
+
+    // Move the blocks of an archive saved in the heap format
+    // (bincode of the whole block vector) into the stable log.
+    pub fn migrate_from_heap_format(
+        &mut self,
+        token_id: &Principal,
+        bytes: Vec<u8>,
+    ) -> Result<(), String> {
+        assert!(self.blocks.is_empty() && self.frame_entries.is_empty());
+        let (blocks, _, last_update_timestamp): (Vec<EncodedBlock>, usize, u64) =
+            bincode::deserialize(&bytes).map_err(|e| {
+                format!(
+                    "auto-scaling-storage: decode heap block archive failed, {}",
+                    e
+                )
+            })?;
+        for block in blocks.iter() {
+            self.blocks
+                .append(block)
+                .map_err(|e| format!("auto-scaling-storage: migrate block failed, {:?}", e))?;
+        }
+        self.restore(token_id, last_update_timestamp);
+        Ok(())
     }
 
     pub fn total_blocks_count(&self) -> u64 {
-        self.blocks.len() as u64
+        self.last_frame_entry()
+            .map_or(self.blocks.len(), |entry| entry.blocks_end)
     }
 
     pub fn get_block(&self, index: u64) -> Option<EncodedBlock> {
-        self.blocks.get(index as usize).cloned()
+        if index < self.blocks.len() {
+            return self.blocks.get(index);
+        }
+        if index >= self.total_blocks_count() {
+            return None;
+        }
+        // the first frame which ends after the block
+        let (mut low, mut high) = (0, self.frame_entries.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.frame_entries.get(mid).unwrap().blocks_end <= index {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let frame_index = low;
+        let frame_start = match frame_index.checked_sub(1) {
+            Some(previous) => self.frame_entries.get(previous).unwrap().blocks_end,
+            None => self.blocks.len(),
+        };
+
+        let mut frame_cache = self.frame_cache.borrow_mut();
+        if !matches!(frame_cache.as_ref(), Some((cached, _)) if *cached == frame_index) {
+            let frame = self
+                .frames
+                .get(frame_index)
+                .expect("bug: block frame out of range");
+            let frame_blocks = decode_block_frame(&frame)
+                .unwrap_or_else(|e| panic!("auto-scaling-storage: {}", e));
+            *frame_cache = Some((frame_index, frame_blocks));
+        }
+        frame_cache
+            .as_ref()
+            .and_then(|(_, frame_blocks)| frame_blocks.get((index - frame_start) as usize))
+            .cloned()
+    }
+
+    pub fn pending_account_index_blocks(&self) -> u64 {
+        self.total_blocks_count() - self.account_index.indexed_blocks_count()
     }
-}
 
-impl StableState for BlockArchive {
-    fn encode(&self) -> Vec<u8> {
-        bincode::serialize(&(
-            &self.blocks,
-            &self.total_block_size_bytes,
-            &self.last_update_timestamp,
-        ))
-        .unwrap()
+    // Index at most `max_blocks` blocks not in the account index yet,
+    // returns the number of blocks still pending.
+    pub fn index_pending_blocks(&mut self, max_blocks: u64) -> u64 {
+        let start = self.account_index.indexed_blocks_count();
+        let end = self.total_blocks_count().min(start + max_blocks);
+        for index in start..end {
+            let block = self
+                .get_block(index)
+                .unwrap()
+                .decode()
+                .expect("auto-scaling-storage: failed to decode stored block");
+            self.account_index.push_block(&block);
+        }
+        self.pending_account_index_blocks()
     }
 
-    fn decode(bytes: Vec<u8>) -> Result<Self, String> {
-        let (blocks, total_block_size_bytes, last_update_timestamp): (
-            Vec<EncodedBlock>,
-            usize,
-            u64,
-        ) = bincode::deserialize(&bytes).unwrap();
+    // The blocks of `account` (with their inner index), scanned from the inner index `from`.
+    // Returns the inner index to continue from when the limit is reached.
+    pub fn blocks_by_account(
+        &self,
+        account: &TokenHolder,
+        from: u64,
+        limit: usize,
+    ) -> (Vec<(u64, Block)>, Option<u64>) {
+        let (indexes, next_index) = self.account_index.blocks_of(account, from, limit);
+        let blocks = indexes
+            .into_iter()
+            .map(|index| {
+                let block = self
+                    .get_block(index)
+                    .expect("bug: indexed block out of range")
+                    .decode()
+                    .expect("auto-scaling-storage: failed to decode stored block");
+                (index, block.into())
+            })
+            .collect();
+        (blocks, next_index)
+    }
 
-        Ok(BlockArchive {
-            blocks,
-            total_block_size_bytes,
-            last_update_timestamp,
+    // Blocks whose timestamp is in [start_ts, end_ts), scanned from the inner index `from`.
+    // Returns the inner index to continue from when the limit is reached.
+    pub fn blocks_by_time_range(
+        &self,
+        start_ts: u64,
+        end_ts: u64,
+        limit: usize,
+        from: u64,
+    ) -> (Vec<Block>, Option<u64>) {
+        let start = self.timestamp_index.scan_start(start_ts).max(from);
+        let end = self
+            .timestamp_index
+            .scan_end(end_ts)
+            .unwrap_or_else(|| self.total_blocks_count());
+        collect_blocks_in_time_range(start..end, start_ts, end_ts, limit, |index| {
+            self.get_block(index)
         })
     }
 }
@@ -63,69 +407,645 @@ impl StableState for BlockArchive {
 #[cfg(test)]
 mod tests {
     use candid::Principal;
+    use ic_stable_structures::VectorMemory;
+    use std::cell::Cell;
     use std::convert::TryInto;
+    use std::rc::Rc;
 
     use super::*;
 
+    // the memories holding the blocks of an archive
+    #[derive(Clone, Default)]
+    struct BlockMemories {
+        index: VectorMemory,
+        data: VectorMemory,
+        frames: (VectorMemory, VectorMemory, VectorMemory),
+    }
+
+    // the indexes are not kept, as for an archive created before they existed
+    fn block_archive_from(memories: &BlockMemories) -> BlockArchive<VectorMemory> {
+        BlockArchive::init(
+            memories.index.clone(),
+            memories.data.clone(),
+            VectorMemory::default(),
+            Default::default(),
+            memories.frames.clone(),
+        )
+    }
+
+    fn test_block_archive() -> (BlockArchive<VectorMemory>, BlockMemories) {
+        let memories = BlockMemories::default();
+        (block_archive_from(&memories), memories)
+    }
+
+    fn chained_blocks(token_id: &Principal, count: u64, now: u64) -> Vec<EncodedBlock> {
+        let holder = TokenHolder::new(*token_id, None);
+        let mut parent_hash = None;
+        let mut blocks = vec![];
+        for i in 0..count {
+            let block = InnerBlock::new_from_transaction(
+                token_id,
+                parent_hash,
+                InnerTransaction {
+                    operation: InnerOperation::Transfer {
+                        caller: holder,
+                        from: holder,
+                        to: holder,
+                        value: (1000u64 + i).into(),
+                        fee: 1u32.into(),
+                    },
+                    created_at: now + i,
+                },
+                now + i,
+            )
+            .encode()
+            .unwrap();
+            parent_hash = Some(block.hash_with_token_id(token_id));
+            blocks.push(block);
+        }
+        blocks
+    }
+
     #[test]
-    fn test_block_archive() {
-        let mut block_archive = BlockArchive::default();
+    fn test_block_archive_survives_reinit() {
         let test_token_id: Principal = "rwlgt-iiaaa-aaaaa-aaaaa-cai".parse().unwrap();
-        let from: Principal = "o5y7v-htz2q-vk7fc-cqi4m-bqvwa-eth75-sc2wz-ubuev-curf2-rbipe-tae"
-            .parse()
-            .unwrap();
-        let to: Principal = "qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe"
-            .parse()
-            .unwrap();
-        let from_holder = TokenHolder::new(from.clone(), None);
-        let to_holder = TokenHolder::new(to.clone(), None);
-        let genesis_block_hash = dft_utils::sha256::compute_hash(test_token_id.as_slice());
         let now: u64 = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_nanos()
             .try_into()
             .unwrap();
+        let blocks = chained_blocks(&test_token_id, 5, now);
+        let total_byte_size: usize = blocks.iter().map(|b| b.size_bytes()).sum();
+        let last_hash = blocks[4].hash_with_token_id(&test_token_id);
 
-        let mut total_byte_size: usize = 0;
-        for i in 0..5u64 {
-            let block = InnerBlock::new_from_transaction(
+        let (mut block_archive, memories) = test_block_archive();
+        let res = block_archive.batch_append(
+            &test_token_id,
+            0,
+            blocks.clone(),
+            now + 5,
+            u64::MAX,
+            true,
+            None,
+        );
+        assert_eq!(res, Ok(5));
+        assert_eq!(block_archive.total_blocks_count(), 5);
+        assert_eq!(
+            block_archive.total_block_size_bytes(),
+            total_byte_size as u64
+        );
+        assert_eq!(block_archive.last_update_timestamp(), now + 5);
+        assert_eq!(block_archive.last_hash(), Some(last_hash));
+
+        // the blocks are read back from the memories, as after an upgrade
+        let mut block_archive_2 = block_archive_from(&memories);
+        block_archive_2.restore(&test_token_id, now + 5);
+        assert_eq!(block_archive_2.total_blocks_count(), 5);
+        assert_eq!(
+            block_archive_2.total_block_size_bytes(),
+            total_byte_size as u64
+        );
+        assert_eq!(block_archive_2.last_update_timestamp(), now + 5);
+        assert_eq!(block_archive_2.last_hash(), Some(last_hash));
+        for (i, block) in blocks.iter().enumerate() {
+            assert_eq!(block_archive_2.get_block(i as u64).as_ref(), Some(block));
+        }
+        assert_eq!(block_archive_2.get_block(5), None);
+    }
+
+    #[test]
+    fn test_block_archive_batch_append_is_idempotent() {
+        let test_token_id: Principal = "rwlgt-iiaaa-aaaaa-aaaaa-cai".parse().unwrap();
+        let blocks = chained_blocks(&test_token_id, 10, 1);
+        let (mut block_archive, _) = test_block_archive();
+
+        let res = block_archive.batch_append(
+            &test_token_id,
+            0,
+            blocks[0..5].to_vec(),
+            2,
+            u64::MAX,
+            true,
+            None,
+        );
+        assert_eq!(res, Ok(5));
+        let total_block_size_bytes = block_archive.total_block_size_bytes();
+
+        // resend the same range
+        let res = block_archive.batch_append(
+            &test_token_id,
+            0,
+            blocks[0..5].to_vec(),
+            3,
+            u64::MAX,
+            true,
+            None,
+        );
+        assert_eq!(res, Ok(0));
+        assert_eq!(block_archive.total_blocks_count(), 5);
+        assert_eq!(
+            block_archive.total_block_size_bytes(),
+            total_block_size_bytes
+        );
+        assert_eq!(block_archive.last_update_timestamp(), 2);
+
+        // partially overlapping range only appends the new blocks
+        let res = block_archive.batch_append(
+            &test_token_id,
+            3,
+            blocks[3..8].to_vec(),
+            4,
+            u64::MAX,
+            true,
+            None,
+        );
+        assert_eq!(res, Ok(3));
+        assert_eq!(block_archive.total_blocks_count(), 8);
+        assert_eq!(
+            block_archive.last_hash(),
+            Some(blocks[7].hash_with_token_id(&test_token_id))
+        );
+    }
+
+    #[test]
+    fn test_block_archive_batch_append_rejects_invalid_batches() {
+        let test_token_id: Principal = "rwlgt-iiaaa-aaaaa-aaaaa-cai".parse().unwrap();
+        let blocks = chained_blocks(&test_token_id, 10, 1);
+        let (mut block_archive, _) = test_block_archive();
+        block_archive
+            .batch_append(
                 &test_token_id,
-                Some(genesis_block_hash),
-                InnerTransaction {
-                    operation: InnerOperation::Transfer {
-                        caller: from_holder.clone(),
-                        from: from_holder,
-                        to: to_holder,
-                        value: 1000u32.into(),
-                        fee: 1u32.into(),
-                    },
-                    created_at: now.clone() + i,
-                },
-                now.clone() + i,
+                0,
+                blocks[0..5].to_vec(),
+                2,
+                u64::MAX,
+                true,
+                None,
+            )
+            .unwrap();
+
+        // gap
+        let res = block_archive.batch_append(
+            &test_token_id,
+            6,
+            blocks[6..8].to_vec(),
+            3,
+            u64::MAX,
+            true,
+            None,
+        );
+        assert_eq!(res, Err(DFTError::InvalidStartBlockHeight));
+
+        // resent range differs from the stored one
+        let res = block_archive.batch_append(
+            &test_token_id,
+            1,
+            blocks[0..5].to_vec(),
+            3,
+            u64::MAX,
+            true,
+            None,
+        );
+        assert_eq!(res, Err(DFTError::ResentBlocksDoNotMatch));
+
+        // block does not link to the last stored block
+        let res = block_archive.batch_append(
+            &test_token_id,
+            5,
+            blocks[6..8].to_vec(),
+            3,
+            u64::MAX,
+            true,
+            None,
+        );
+        assert_eq!(res, Err(DFTError::ApplyBlockFailedByParentHashDoesNotMatch));
+
+        // nothing was appended by the rejected calls
+        assert_eq!(block_archive.total_blocks_count(), 5);
+        assert_eq!(
+            block_archive.last_hash(),
+            Some(blocks[4].hash_with_token_id(&test_token_id))
+        );
+    }
+
+    #[test]
+    fn test_block_archive_checks_the_first_block_against_the_parent_hash() {
+        let test_token_id: Principal = "rwlgt-iiaaa-aaaaa-aaaaa-cai".parse().unwrap();
+        let blocks = chained_blocks(&test_token_id, 10, 1);
+        let parent_hash = Some(blocks[4].hash_with_token_id(&test_token_id));
+
+        // an archive starting at block 5 must be given the blocks after block 4
+        let (mut block_archive, _) = test_block_archive();
+        let res = block_archive.batch_append(
+            &test_token_id,
+            0,
+            blocks[6..].to_vec(),
+            2,
+            u64::MAX,
+            true,
+            parent_hash,
+        );
+        assert_eq!(res, Err(DFTError::ApplyBlockFailedByParentHashDoesNotMatch));
+        assert_eq!(block_archive.total_blocks_count(), 0);
+
+        let res = block_archive.batch_append(
+            &test_token_id,
+            0,
+            blocks[5..].to_vec(),
+            2,
+            u64::MAX,
+            true,
+            parent_hash,
+        );
+        assert_eq!(res, Ok(5));
+        assert_eq!(
+            block_archive.last_hash(),
+            Some(blocks[9].hash_with_token_id(&test_token_id))
+        );
+    }
+
+    #[test]
+    fn test_block_archive_migrate_from_heap_format() {
+        let test_token_id: Principal = "rwlgt-iiaaa-aaaaa-aaaaa-cai".parse().unwrap();
+        let blocks = chained_blocks(&test_token_id, 3, 1);
+        let total_byte_size: usize = blocks.iter().map(|b| b.size_bytes()).sum();
+        let bytes = bincode::serialize(&(&blocks, total_byte_size, 10u64)).unwrap();
+
+        let (mut block_archive, _) = test_block_archive();
+        block_archive
+            .migrate_from_heap_format(&test_token_id, bytes)
+            .unwrap();
+        assert_eq!(block_archive.total_blocks_count(), 3);
+        assert_eq!(
+            block_archive.total_block_size_bytes(),
+            total_byte_size as u64
+        );
+        assert_eq!(block_archive.last_update_timestamp(), 10);
+        assert_eq!(
+            block_archive.last_hash(),
+            Some(blocks[2].hash_with_token_id(&test_token_id))
+        );
+
+        let (mut block_archive, _) = test_block_archive();
+        assert!(block_archive
+            .migrate_from_heap_format(&test_token_id, vec![1, 2, 3])
+            .is_err());
+    }
+
+    #[test]
+    fn test_block_archive_compresses_blocks_in_frames() {
+        let test_token_id: Principal = "rwlgt-iiaaa-aaaaa-aaaaa-cai".parse().unwrap();
+        let count = BLOCK_FRAME_SIZE as u64 * 3 + 5;
+        let blocks = chained_blocks(&test_token_id, count, 1);
+        let total_byte_size: usize = blocks.iter().map(|b| b.size_bytes()).sum();
+
+        let (mut block_archive, memories) = test_block_archive();
+        block_archive
+            .batch_append(
+                &test_token_id,
+                0,
+                blocks[..100].to_vec(),
+                2,
+                u64::MAX,
+                true,
+                None,
+            )
+            .unwrap();
+        block_archive
+            .batch_append(
+                &test_token_id,
+                100,
+                blocks[100..].to_vec(),
+                3,
+                u64::MAX,
+                true,
+                None,
+            )
+            .unwrap();
+        assert_eq!(block_archive.frame_entries.len(), 4);
+        assert_eq!(block_archive.total_blocks_count(), count);
+        assert_eq!(
+            block_archive.total_block_size_bytes(),
+            total_byte_size as u64
+        );
+        assert!(block_archive.compressed_block_size_bytes() < total_byte_size as u64);
+
+        // read back in any order, from the memories as after an upgrade
+        let mut block_archive = block_archive_from(&memories);
+        block_archive.restore(&test_token_id, 3);
+        assert_eq!(block_archive.total_blocks_count(), count);
+        assert_eq!(
+            block_archive.last_hash(),
+            blocks.last().map(|b| b.hash_with_token_id(&test_token_id))
+        );
+        for i in (0..count).rev().chain(0..count) {
+            assert_eq!(
+                block_archive.get_block(i).as_ref(),
+                Some(&blocks[i as usize])
             );
-            let encoded_block = block.encode().unwrap();
-            total_byte_size += encoded_block.size_bytes();
-            block_archive.append(encoded_block, now.clone() + i);
         }
-        assert_eq!(block_archive.total_blocks_count(), 5);
-        assert_eq!(block_archive.total_block_size_bytes, total_byte_size);
-        assert_eq!(block_archive.last_update_timestamp, now.clone() + 4);
+        assert_eq!(block_archive.get_block(count), None);
+    }
 
-        let encoded_block_archive = block_archive.encode();
-        let decoded_block_archive = BlockArchive::decode(encoded_block_archive).unwrap();
-        assert_eq!(decoded_block_archive.blocks.len(), 5);
+    #[test]
+    fn test_block_archive_appends_frames_after_raw_blocks() {
+        let test_token_id: Principal = "rwlgt-iiaaa-aaaaa-aaaaa-cai".parse().unwrap();
+        let blocks = chained_blocks(&test_token_id, 100, 1);
+        let total_byte_size: usize = blocks.iter().map(|b| b.size_bytes()).sum();
+        let raw_byte_size: usize = blocks[..30].iter().map(|b| b.size_bytes()).sum();
+        let bytes = bincode::serialize(&(&blocks[..30], raw_byte_size, 10u64)).unwrap();
+
+        // the raw blocks of an archive stored before the frames existed
+        let (mut block_archive, memories) = test_block_archive();
+        block_archive
+            .migrate_from_heap_format(&test_token_id, bytes)
+            .unwrap();
+        assert_eq!(block_archive.total_blocks_count(), 30);
         assert_eq!(
-            decoded_block_archive.total_block_size_bytes,
-            total_byte_size
+            block_archive.compressed_block_size_bytes(),
+            block_archive.total_block_size_bytes()
         );
-        assert_eq!(decoded_block_archive.last_update_timestamp, now.clone() + 4);
 
-        let mut block_archive_2 = BlockArchive::default();
-        block_archive_2.batch_append(decoded_block_archive.blocks, now.clone() + 5);
+        block_archive
+            .batch_append(
+                &test_token_id,
+                30,
+                blocks[30..].to_vec(),
+                11,
+                u64::MAX,
+                true,
+                None,
+            )
+            .unwrap();
+        assert_eq!(block_archive.blocks.len(), 30);
+        assert_eq!(block_archive.total_blocks_count(), 100);
+        assert_eq!(
+            block_archive.total_block_size_bytes(),
+            total_byte_size as u64
+        );
 
-        assert_eq!(block_archive_2.total_blocks_count(), 5);
-        assert_eq!(block_archive_2.total_block_size_bytes, total_byte_size);
-        assert_eq!(block_archive_2.last_update_timestamp, now.clone() + 5);
+        let block_archive = block_archive_from(&memories);
+        for (i, block) in blocks.iter().enumerate() {
+            assert_eq!(block_archive.get_block(i as u64).as_ref(), Some(block));
+        }
+    }
+
+    #[test]
+    fn test_block_archive_checks_the_compressed_size() {
+        let test_token_id: Principal = "rwlgt-iiaaa-aaaaa-aaaaa-cai".parse().unwrap();
+        let blocks = chained_blocks(&test_token_id, 100, 1);
+        let compressed_size = stored_blocks_size_bytes(&blocks, true) as u64;
+        let total_byte_size: usize = blocks.iter().map(|b| b.size_bytes()).sum();
+        assert!(compressed_size < total_byte_size as u64);
+
+        let (mut block_archive, _) = test_block_archive();
+        let res = block_archive.batch_append(
+            &test_token_id,
+            0,
+            blocks.clone(),
+            2,
+            compressed_size - 1,
+            true,
+            None,
+        );
+        assert_eq!(res, Err(DFTError::InsufficientStorageCapacity));
+        assert_eq!(block_archive.total_blocks_count(), 0);
+
+        // fits although the logical size does not
+        let res =
+            block_archive.batch_append(&test_token_id, 0, blocks, 2, compressed_size, true, None);
+        assert_eq!(res, Ok(100));
+        assert_eq!(block_archive.compressed_block_size_bytes(), compressed_size);
+    }
+
+    // a memory refusing to grow past `max_pages`, as a full stable memory does
+    #[derive(Clone)]
+    struct LimitedMemory {
+        memory: VectorMemory,
+        max_pages: Rc<Cell<u64>>,
+    }
+
+    impl Default for LimitedMemory {
+        fn default() -> Self {
+            Self {
+                memory: VectorMemory::default(),
+                max_pages: Rc::new(Cell::new(u64::MAX)),
+            }
+        }
+    }
+
+    impl Memory for LimitedMemory {
+        fn size(&self) -> u64 {
+            self.memory.size()
+        }
+
+        fn grow(&self, pages: u64) -> i64 {
+            if self.memory.size() + pages > self.max_pages.get() {
+                return -1;
+            }
+            self.memory.grow(pages)
+        }
+
+        fn read(&self, offset: u64, dst: &mut [u8]) {
+            self.memory.read(offset, dst)
+        }
+
+        fn write(&self, offset: u64, src: &[u8]) {
+            self.memory.write(offset, src)
+        }
+    }
+
+    #[test]
+    fn test_block_archive_retries_a_batch_stored_in_part() {
+        let test_token_id: Principal = "rwlgt-iiaaa-aaaaa-aaaaa-cai".parse().unwrap();
+        let count = BLOCK_FRAME_SIZE as u64 * 10;
+        let blocks = chained_blocks(&test_token_id, count, 1);
+        // the raw frames take more than the one page the frame log may grow to
+        assert!(stored_blocks_size_bytes(&blocks, false) > 64 * 1024);
+
+        let frames_data_memory = LimitedMemory::default();
+        frames_data_memory.max_pages.set(1);
+        let mut block_archive = BlockArchive::init(
+            LimitedMemory::default(),
+            LimitedMemory::default(),
+            LimitedMemory::default(),
+            Default::default(),
+            (
+                LimitedMemory::default(),
+                frames_data_memory.clone(),
+                LimitedMemory::default(),
+            ),
+        );
+        let res =
+            block_archive.batch_append(&test_token_id, 0, blocks.clone(), 2, u64::MAX, false, None);
+        assert_eq!(res, Err(DFTError::InsufficientStorageCapacity));
+        // the frames stored before the failing one stay readable and chained
+        let stored_count = block_archive.total_blocks_count();
+        assert!(stored_count > 0 && stored_count < count);
+        assert_eq!(stored_count % BLOCK_FRAME_SIZE as u64, 0);
+        assert_eq!(
+            block_archive.last_hash(),
+            Some(blocks[stored_count as usize - 1].hash_with_token_id(&test_token_id))
+        );
+        assert_eq!(block_archive.pending_account_index_blocks(), 0);
+
+        // the retry resends the whole batch once the memory grows again
+        frames_data_memory.max_pages.set(u64::MAX);
+        let res =
+            block_archive.batch_append(&test_token_id, 0, blocks.clone(), 3, u64::MAX, false, None);
+        assert_eq!(res, Ok((count - stored_count) as usize));
+        assert_eq!(block_archive.total_blocks_count(), count);
+        assert_eq!(
+            block_archive.last_hash(),
+            blocks.last().map(|b| b.hash_with_token_id(&test_token_id))
+        );
+        assert_eq!(block_archive.pending_account_index_blocks(), 0);
+        for i in 0..count {
+            assert_eq!(
+                block_archive.get_block(i).as_ref(),
+                Some(&blocks[i as usize])
+            );
+        }
+    }
+
+    #[test]
+    fn test_block_archive_without_compression() {
+        let test_token_id: Principal = "rwlgt-iiaaa-aaaaa-aaaaa-cai".parse().unwrap();
+        let blocks = chained_blocks(&test_token_id, 100, 1);
+        let (mut block_archive, memories) = test_block_archive();
+        block_archive
+            .batch_append(
+                &test_token_id,
+                0,
+                blocks[..50].to_vec(),
+                2,
+                u64::MAX,
+                false,
+                None,
+            )
+            .unwrap();
+        let raw_size = stored_blocks_size_bytes(&blocks[..50], false) as u64;
+        assert_eq!(block_archive.compressed_block_size_bytes(), raw_size);
+        assert!(raw_size > stored_blocks_size_bytes(&blocks[..50], true) as u64);
+
+        // frames written either way are read back the same
+        block_archive
+            .batch_append(
+                &test_token_id,
+                50,
+                blocks[50..].to_vec(),
+                3,
+                u64::MAX,
+                true,
+                None,
+            )
+            .unwrap();
+        let block_archive = block_archive_from(&memories);
+        for (i, block) in blocks.iter().enumerate() {
+            assert_eq!(block_archive.get_block(i as u64).as_ref(), Some(block));
+        }
+    }
+
+    #[test]
+    fn test_block_archive_blocks_by_time_range() {
+        let test_token_id: Principal = "rwlgt-iiaaa-aaaaa-aaaaa-cai".parse().unwrap();
+        // block i has the timestamp 1000 + i
+        let blocks = chained_blocks(&test_token_id, 200, 1000);
+        let (mut block_archive, _) = test_block_archive();
+        block_archive
+            .batch_append(
+                &test_token_id,
+                0,
+                blocks[0..70].to_vec(),
+                2,
+                u64::MAX,
+                true,
+                None,
+            )
+            .unwrap();
+        block_archive
+            .batch_append(
+                &test_token_id,
+                70,
+                blocks[70..].to_vec(),
+                2,
+                u64::MAX,
+                true,
+                None,
+            )
+            .unwrap();
+        assert_eq!(block_archive.timestamp_index.len(), 4);
+
+        let (res, next_index) = block_archive.blocks_by_time_range(1050, 1150, 100, 0);
+        assert_eq!(res.len(), 100);
+        assert_eq!(res[0].timestamp, 1050);
+        assert_eq!(res[99].timestamp, 1149);
+        assert_eq!(next_index, None);
+
+        let (res, next_index) = block_archive.blocks_by_time_range(1050, 1150, 30, 0);
+        assert_eq!(res.len(), 30);
+        assert_eq!(next_index, Some(80));
+        let (res, next_index) = block_archive.blocks_by_time_range(1050, 1150, 100, 80);
+        assert_eq!(res.len(), 70);
+        assert_eq!(res[0].timestamp, 1080);
+        assert_eq!(next_index, None);
+
+        let (res, _) = block_archive.blocks_by_time_range(0, 1000, 100, 0);
+        assert!(res.is_empty());
+        let (res, _) = block_archive.blocks_by_time_range(1190, u64::MAX, 100, 0);
+        assert_eq!(res.len(), 10);
+    }
+
+    #[test]
+    fn test_block_archive_account_index_catches_up() {
+        let test_token_id: Principal = "rwlgt-iiaaa-aaaaa-aaaaa-cai".parse().unwrap();
+        let holder = TokenHolder::new(test_token_id, None);
+        let blocks = chained_blocks(&test_token_id, 10, 1);
+        let (mut block_archive, memories) = test_block_archive();
+        block_archive
+            .batch_append(
+                &test_token_id,
+                0,
+                blocks[0..5].to_vec(),
+                2,
+                u64::MAX,
+                true,
+                None,
+            )
+            .unwrap();
+        assert_eq!(block_archive.pending_account_index_blocks(), 0);
+        let (res, next_index) = block_archive.blocks_by_account(&holder, 1, 2);
+        assert_eq!(res.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(res[0].1, blocks[1].decode().unwrap().into());
+        assert_eq!(next_index, Some(3));
+
+        // the blocks were stored before the account index existed
+        let mut block_archive = block_archive_from(&memories);
+        block_archive.restore(&test_token_id, 2);
+        assert_eq!(block_archive.pending_account_index_blocks(), 5);
+        assert_eq!(
+            block_archive.blocks_by_account(&holder, 0, 10),
+            (vec![], None)
+        );
+
+        // appended blocks wait for the index to catch up
+        block_archive
+            .batch_append(
+                &test_token_id,
+                5,
+                blocks[5..].to_vec(),
+                3,
+                u64::MAX,
+                true,
+                None,
+            )
+            .unwrap();
+        assert_eq!(block_archive.pending_account_index_blocks(), 10);
+        assert_eq!(block_archive.index_pending_blocks(4), 6);
+        assert_eq!(block_archive.index_pending_blocks(100), 0);
+        let (res, next_index) = block_archive.blocks_by_account(&holder, 0, 10);
+        assert_eq!(res.len(), 10);
+        assert_eq!(next_index, None);
     }
 }
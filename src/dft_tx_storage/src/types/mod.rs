@@ -1,9 +1,12 @@
 use candid::{CandidType, Nat, Principal};
+use dft_types::{Block, BlockHash, CommonResult, ErrorInfo};
 use serde::Deserialize;
 
+mod account_index;
 mod block_archive;
 mod storage_setting;
 
+pub use account_index::AccountIndex;
 pub use block_archive::BlockArchive;
 pub use storage_setting::StorageSetting;
 
@@ -15,7 +18,48 @@ pub struct StorageInfo {
     pub block_height_offset: Nat,
     #[serde(rename = "totalBlocksCount")]
     pub total_blocks_count: Nat,
+    // logical size of the blocks, as if they were not compressed
     #[serde(rename = "totalBlockSizeBytes")]
     pub total_block_size_bytes: u64,
+    // size the blocks take in stable memory
+    #[serde(rename = "compressedBlockSizeBytes")]
+    pub compressed_block_size_bytes: u64,
+    #[serde(rename = "stableMemorySizeBytes")]
+    pub stable_memory_size_bytes: u64,
+    #[serde(rename = "lastBlockHash")]
+    pub last_block_hash: Option<BlockHash>,
     pub cycles: u64,
 }
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct AccountBlock {
+    pub height: Nat,
+    pub block: Block,
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct BlocksByAccount {
+    pub blocks: Vec<AccountBlock>,
+    // The block height to pass as cursor to get the next blocks, if the limit was reached
+    #[serde(rename = "nextCursor")]
+    pub next_cursor: Option<Nat>,
+    // Blocks below this height are indexed, the archives created before the
+    // account index existed index their blocks in the background.
+    #[serde(rename = "indexedUntil")]
+    pub indexed_until: Nat,
+}
+
+#[derive(CandidType, Debug)]
+pub enum BlocksByAccountResult {
+    Ok(BlocksByAccount),
+    Err(ErrorInfo),
+}
+
+impl From<CommonResult<BlocksByAccount>> for BlocksByAccountResult {
+    fn from(result: CommonResult<BlocksByAccount>) -> Self {
+        match result {
+            Ok(value) => BlocksByAccountResult::Ok(value),
+            Err(error) => BlocksByAccountResult::Err(error.into()),
+        }
+    }
+}
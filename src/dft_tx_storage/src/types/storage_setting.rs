@@ -1,5 +1,5 @@
 use candid::{Deserialize, Principal};
-use dft_types::{CommonResult, DFTError, StableState};
+use dft_types::{BlockHash, CommonResult, DFTError, StableState};
 use getset::{Getters, Setters};
 use num_bigint::BigUint;
 use serde::Serialize;
@@ -10,6 +10,11 @@ pub struct StorageSetting {
     token_id: Principal,
     block_height_offset: BigUint,
     create_at: u64,
+    // whether the appended blocks are stored compressed
+    compress_blocks: bool,
+    // hash of the block before the first one of the storage, the first appended
+    // block must link to it, unknown for storages installed before it was passed
+    parent_hash: Option<BlockHash>,
 }
 
 impl Default for StorageSetting {
@@ -18,17 +23,45 @@ impl Default for StorageSetting {
             token_id: Principal::anonymous(),
             block_height_offset: 0u8.into(),
             create_at: 0,
+            compress_blocks: true,
+            parent_hash: None,
         }
     }
 }
 
 impl StorageSetting {
-    pub fn initialize(&mut self, token_id: Principal, block_height_offset: BigUint, now: u64) {
+    pub fn initialize(
+        &mut self,
+        token_id: Principal,
+        block_height_offset: BigUint,
+        compress_blocks: bool,
+        parent_hash: Option<BlockHash>,
+        now: u64,
+    ) {
         assert!(self.token_id == Principal::anonymous() && self.create_at == 0);
         self.token_id = token_id;
         self.block_height_offset = block_height_offset;
+        self.compress_blocks = compress_blocks;
+        self.parent_hash = parent_hash;
         self.create_at = now;
     }
+
+    // Convert settings saved by the version 2 state, whose archives always
+    // compressed the blocks and were not given the parent hash.
+    pub fn migrate_from_v2(bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+        let (token_id, block_height_offset, create_at): (Principal, BigUint, u64) =
+            bincode::deserialize(&bytes).map_err(|e| {
+                format!(
+                    "auto-scaling-storage: decode storage setting v2 failed, {}",
+                    e
+                )
+            })?;
+        let parent_hash: Option<BlockHash> = None;
+        Ok(
+            bincode::serialize(&(token_id, block_height_offset, create_at, true, parent_hash))
+                .unwrap(),
+        )
+    }
     // fn only allow token canister
     pub fn only_allow_token_canister(&self, caller: &Principal) -> CommonResult<()> {
         if &self.token_id != caller {
@@ -44,18 +77,28 @@ impl StableState for StorageSetting {
             self.token_id,
             self.block_height_offset.clone(),
             self.create_at,
+            self.compress_blocks,
+            self.parent_hash,
         ))
         .unwrap()
     }
 
     fn decode(bytes: Vec<u8>) -> Result<Self, String> {
-        let (token_id, block_height_offset, create_at): (Principal, BigUint, u64) =
-            bincode::deserialize(&bytes).unwrap();
+        let (token_id, block_height_offset, create_at, compress_blocks, parent_hash): (
+            Principal,
+            BigUint,
+            u64,
+            bool,
+            Option<BlockHash>,
+        ) = bincode::deserialize(&bytes)
+            .map_err(|e| format!("auto-scaling-storage: decode storage setting failed, {}", e))?;
 
         Ok(StorageSetting {
             token_id,
             block_height_offset,
             create_at,
+            compress_blocks,
+            parent_hash,
         })
     }
 }
@@ -78,7 +121,13 @@ mod tests {
             .unwrap();
 
         let mut storage_setting = StorageSetting::default();
-        storage_setting.initialize(test_token_id.clone(), block_height_offset.clone(), now);
+        storage_setting.initialize(
+            test_token_id,
+            block_height_offset.clone(),
+            false,
+            Some([1u8; 32]),
+            now,
+        );
         let encoded = storage_setting.encode();
         let decoded = StorageSetting::decode(encoded).unwrap();
 
@@ -88,6 +137,8 @@ mod tests {
             decoded.block_height_offset
         );
         assert_eq!(storage_setting.create_at, decoded.create_at);
+        assert!(!decoded.compress_blocks);
+        assert_eq!(decoded.parent_hash, Some([1u8; 32]));
     }
 
     #[test]
@@ -109,6 +160,8 @@ mod tests {
             token_id: test_token_id,
             block_height_offset,
             create_at: now,
+            compress_blocks: true,
+            parent_hash: None,
         };
 
         assert!(storage_setting
@@ -1,8 +1,10 @@
 use candid::{CandidType, Deserialize, Principal};
 
+use ic_stable_structures::{BoundedStorable, Storable};
 use serde::{de, de::Error, Serialize};
 use sha2::{Digest, Sha224};
 use std::{
+    borrow::Cow,
     convert::TryInto,
     fmt::{Display, Formatter},
     str::FromStr,
@@ -168,6 +170,24 @@ impl CandidType for AccountIdentifier {
     }
 }
 
+// stable structures key on the raw hash, the checksum can be recomputed
+impl Storable for AccountIdentifier {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.hash)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let mut hash = [0; 28];
+        hash.copy_from_slice(&bytes);
+        AccountIdentifier { hash }
+    }
+}
+
+impl BoundedStorable for AccountIdentifier {
+    const MAX_SIZE: u32 = 28;
+    const IS_FIXED_SIZE: bool = true;
+}
+
 pub type Subaccount = [u8; 32];
 
 // test
@@ -1,6 +1,7 @@
 use std::borrow::Cow;
 
 use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::Storable;
 use serde::Serialize;
 
 use crate::{BlockHash, CommonResult, DFTError, InnerTransaction, Transaction};
@@ -110,6 +111,17 @@ impl EncodedBlock {
     }
 }
 
+// blocks are stored as they are, without any extra encoding
+impl Storable for EncodedBlock {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_ref())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self::from_vec(bytes.into_owned())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::convert::TryInto;
@@ -123,7 +135,7 @@ mod tests {
     #[test]
     fn test_block_size() {
         let block_size = std::mem::size_of::<InnerBlock>();
-        let should_be_size = 184;
+        let should_be_size = 200;
         assert_eq!(should_be_size, block_size);
     }
 
@@ -0,0 +1,111 @@
+use crate::EncodedBlock;
+
+// Frame codecs, the first byte of every frame.
+const FRAME_CODEC_RAW: u8 = 0;
+const FRAME_CODEC_LZ4: u8 = 1;
+
+// Encode a batch of blocks into one frame.
+// With `compress` the blocks are compressed together, since consecutive blocks
+// share most of their accounts and amounts. Without it, or when compression
+// does not save any space, the bincode bytes are stored as they are.
+pub fn encode_block_frame(blocks: &[EncodedBlock], compress: bool) -> Vec<u8> {
+    let raw = bincode::serialize(blocks).expect("bug: failed to encode block frame");
+    let compressed = if compress {
+        Some(lz4_flex::compress_prepend_size(&raw))
+    } else {
+        None
+    };
+    let (codec, payload) = match compressed {
+        Some(compressed) if compressed.len() < raw.len() => (FRAME_CODEC_LZ4, compressed),
+        _ => (FRAME_CODEC_RAW, raw),
+    };
+    let mut frame = Vec::with_capacity(payload.len() + 1);
+    frame.push(codec);
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+pub fn decode_block_frame(frame: &[u8]) -> Result<Vec<EncodedBlock>, String> {
+    let raw = match frame.split_first() {
+        Some((&FRAME_CODEC_RAW, payload)) => payload.to_vec(),
+        Some((&FRAME_CODEC_LZ4, payload)) => lz4_flex::decompress_size_prepended(payload)
+            .map_err(|e| format!("block frame decompress failed,{}", e))?,
+        Some((codec, _)) => return Err(format!("unknown block frame codec {}", codec)),
+        None => return Err("empty block frame".to_string()),
+    };
+    bincode::deserialize(&raw).map_err(|e| format!("block frame decode failed,{}", e))
+}
+
+// The size of `blocks` once stored in frames of BLOCK_FRAME_SIZE blocks,
+// as an archive stores them.
+pub fn stored_blocks_size_bytes(blocks: &[EncodedBlock], compress: bool) -> usize {
+    blocks
+        .chunks(crate::constants::BLOCK_FRAME_SIZE)
+        .map(|frame_blocks| encode_block_frame(frame_blocks, compress).len())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::BLOCK_FRAME_SIZE;
+
+    // similar blocks, as the blocks of a token are
+    fn test_blocks(count: usize) -> Vec<EncodedBlock> {
+        (0..count)
+            .map(|i| {
+                let mut bytes = vec![7u8; 120];
+                bytes.extend_from_slice(&(i as u64).to_le_bytes());
+                EncodedBlock::from(bytes)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_block_frame_round_trip() {
+        let blocks = test_blocks(BLOCK_FRAME_SIZE);
+        let frame = encode_block_frame(&blocks, true);
+        assert_eq!(frame[0], FRAME_CODEC_LZ4);
+        let raw_size: usize = blocks.iter().map(|b| b.0.len()).sum();
+        assert!(frame.len() < raw_size);
+        assert_eq!(decode_block_frame(&frame).unwrap(), blocks);
+    }
+
+    #[test]
+    fn test_block_frame_falls_back_to_raw() {
+        // a single short block does not compress
+        let blocks = vec![EncodedBlock::from(vec![1u8, 2, 3])];
+        let frame = encode_block_frame(&blocks, true);
+        assert_eq!(frame[0], FRAME_CODEC_RAW);
+        assert_eq!(decode_block_frame(&frame).unwrap(), blocks);
+    }
+
+    #[test]
+    fn test_block_frame_without_compression() {
+        let blocks = test_blocks(BLOCK_FRAME_SIZE);
+        let frame = encode_block_frame(&blocks, false);
+        assert_eq!(frame[0], FRAME_CODEC_RAW);
+        assert_eq!(decode_block_frame(&frame).unwrap(), blocks);
+    }
+
+    #[test]
+    fn test_block_frame_decode_errors() {
+        assert!(decode_block_frame(&[]).is_err());
+        assert!(decode_block_frame(&[9, 1, 2]).is_err());
+        assert!(decode_block_frame(&[FRAME_CODEC_LZ4, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_stored_blocks_size_bytes() {
+        let blocks = test_blocks(BLOCK_FRAME_SIZE * 2 + 1);
+        for compress in [true, false] {
+            let expected: usize = blocks
+                .chunks(BLOCK_FRAME_SIZE)
+                .map(|chunk| encode_block_frame(chunk, compress).len())
+                .sum();
+            assert_eq!(stored_blocks_size_bytes(&blocks, compress), expected);
+            assert_eq!(stored_blocks_size_bytes(&[], compress), 0);
+        }
+        assert!(stored_blocks_size_bytes(&blocks, true) < stored_blocks_size_bytes(&blocks, false));
+    }
+}
@@ -0,0 +1,248 @@
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::fmt;
+use std::ops::Range;
+
+use ic_stable_structures::{BoundedStorable, Memory, StableVec, Storable, VectorMemory};
+
+use crate::constants::BLOCK_TIMESTAMP_INDEX_INTERVAL;
+use crate::*;
+
+// The timestamp of the block stored at `position`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct TimestampSample {
+    position: u64,
+    timestamp: u64,
+}
+
+impl Storable for TimestampSample {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned([self.position.to_be_bytes(), self.timestamp.to_be_bytes()].concat())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        TimestampSample {
+            position: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            timestamp: u64::from_be_bytes(bytes[8..].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for TimestampSample {
+    const MAX_SIZE: u32 = 8 + 8;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// Sparse timestamp -> block position index, it keeps the timestamp of one
+// block every BLOCK_TIMESTAMP_INDEX_INTERVAL blocks.
+// Block timestamps are monotonic, so a time range is resolved by a binary
+// search over the samples and a scan of at most one interval of blocks.
+pub struct BlockTimestampIndex<M: Memory> {
+    samples: StableVec<TimestampSample, M>,
+}
+
+impl BlockTimestampIndex<VectorMemory> {
+    pub fn new() -> Self {
+        Self::init(VectorMemory::default())
+    }
+}
+
+impl Default for BlockTimestampIndex<VectorMemory> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Memory> fmt::Debug for BlockTimestampIndex<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockTimestampIndex")
+            .field("samples_count", &self.len())
+            .finish()
+    }
+}
+
+impl<M: Memory> BlockTimestampIndex<M> {
+    // load the samples already stored in the memory, if any
+    pub fn init(memory: M) -> Self {
+        BlockTimestampIndex {
+            samples: StableVec::init(memory)
+                .expect("failed to initialize the block timestamp index"),
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    // Called for every appended block, only one block per interval is sampled.
+    // Traps if the memory can not grow, so the block append is rolled back.
+    pub fn record(&mut self, position: u64, timestamp: u64) {
+        let needs_sample = match self.len().checked_sub(1) {
+            Some(last) => {
+                position
+                    >= self.samples.get(last).unwrap().position + BLOCK_TIMESTAMP_INDEX_INTERVAL
+            }
+            None => true,
+        };
+        if needs_sample {
+            self.samples
+                .push(&TimestampSample {
+                    position,
+                    timestamp,
+                })
+                .expect("failed to record the block timestamp");
+        }
+    }
+
+    // Sample the blocks stored before the index existed.
+    // Only one block per interval has to be read.
+    pub fn backfill(&mut self, positions: Range<u64>, timestamp_at: impl Fn(u64) -> u64) {
+        if !self.is_empty() {
+            return;
+        }
+        for position in positions.step_by(BLOCK_TIMESTAMP_INDEX_INTERVAL as usize) {
+            self.record(position, timestamp_at(position));
+        }
+    }
+
+    // number of samples older than `timestamp`
+    fn count_older_samples(&self, timestamp: u64) -> u64 {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.samples.get(mid).unwrap().timestamp < timestamp {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+
+    // The position to scan from to find the blocks not older than `timestamp`,
+    // every block before it is older.
+    pub fn scan_start(&self, timestamp: u64) -> u64 {
+        match self.count_older_samples(timestamp).checked_sub(1) {
+            Some(index) => self.samples.get(index).unwrap().position,
+            None => 0,
+        }
+    }
+
+    // A position from which every block is at least `timestamp`, if one is known.
+    pub fn scan_end(&self, timestamp: u64) -> Option<u64> {
+        self.samples
+            .get(self.count_older_samples(timestamp))
+            .map(|sample| sample.position)
+    }
+}
+
+// Collect at most `limit` blocks of `positions` whose timestamp is in
+// [start_ts, end_ts). The position of the next matching block is returned
+// when the limit is reached, to continue from.
+pub fn collect_blocks_in_time_range(
+    positions: Range<u64>,
+    start_ts: u64,
+    end_ts: u64,
+    limit: usize,
+    get_block: impl Fn(u64) -> Option<EncodedBlock>,
+) -> (Vec<Block>, Option<u64>) {
+    let mut blocks = Vec::new();
+    for position in positions {
+        let block = get_block(position)
+            .expect("bug: block out of range")
+            .decode()
+            .expect("bug: failed to decode encoded block");
+        if block.timestamp >= end_ts {
+            break;
+        }
+        if block.timestamp < start_ts {
+            continue;
+        }
+        if blocks.len() >= limit {
+            return (blocks, Some(position));
+        }
+        blocks.push(block.into());
+    }
+    (blocks, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // one sample per interval, the block timestamps are 10 * position
+    fn test_index(positions: Range<u64>) -> BlockTimestampIndex<VectorMemory> {
+        let mut index = BlockTimestampIndex::new();
+        for position in positions {
+            index.record(position, position * 10);
+        }
+        index
+    }
+
+    #[test]
+    fn test_record_is_sparse() {
+        let index = test_index(0..BLOCK_TIMESTAMP_INDEX_INTERVAL * 3 + 1);
+        assert_eq!(index.len(), 4);
+
+        let index = test_index(5..BLOCK_TIMESTAMP_INDEX_INTERVAL + 5);
+        assert_eq!(index.len(), 1);
+    }
+
+    #[test]
+    fn test_scan_bounds() {
+        let interval = BLOCK_TIMESTAMP_INDEX_INTERVAL;
+        let index = test_index(0..interval * 3);
+
+        // before the first sample, scan from the beginning
+        assert_eq!(index.scan_start(0), 0);
+        assert_eq!(index.scan_end(0), Some(0));
+        // the samples are at 0, interval and 2 * interval
+        assert_eq!(index.scan_start(interval * 10), 0);
+        assert_eq!(index.scan_start(interval * 10 + 1), interval);
+        assert_eq!(index.scan_end(interval * 10), Some(interval));
+        assert_eq!(index.scan_end(interval * 10 + 1), Some(interval * 2));
+        // after the last sample, the end is unknown
+        assert_eq!(index.scan_start(u64::MAX), interval * 2);
+        assert_eq!(index.scan_end(u64::MAX), None);
+
+        let empty_index = BlockTimestampIndex::new();
+        assert_eq!(empty_index.scan_start(100), 0);
+        assert_eq!(empty_index.scan_end(100), None);
+    }
+
+    #[test]
+    fn test_backfill() {
+        let interval = BLOCK_TIMESTAMP_INDEX_INTERVAL;
+        let mut index = BlockTimestampIndex::new();
+        index.backfill(3..interval * 2 + 4, |position| position * 10);
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.scan_start(u64::MAX), interval * 2 + 3);
+
+        // blocks appended afterwards keep the same interval
+        index.record(interval * 3 + 2, (interval * 3 + 2) * 10);
+        assert_eq!(index.len(), 3);
+        index.record(interval * 3 + 3, (interval * 3 + 3) * 10);
+        assert_eq!(index.len(), 4);
+
+        // an index which is not empty is left unchanged
+        index.backfill(0..interval * 10, |_| 0);
+        assert_eq!(index.len(), 4);
+    }
+
+    #[test]
+    fn test_index_survives_reinit() {
+        let memory = VectorMemory::default();
+        let mut index = BlockTimestampIndex::init(memory.clone());
+        index.record(0, 100);
+        index.record(BLOCK_TIMESTAMP_INDEX_INTERVAL, 200);
+
+        let index = BlockTimestampIndex::init(memory);
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.scan_start(150), 0);
+        assert_eq!(index.scan_end(150), Some(BLOCK_TIMESTAMP_INDEX_INTERVAL));
+    }
+}
@@ -1,37 +1,145 @@
 use std::collections::VecDeque;
 use std::convert::TryInto;
-use std::ops::Sub;
+use std::fmt;
 
-use candid::{Deserialize, Principal};
-use num_traits::CheckedSub;
-use serde::Serialize;
+use candid::Principal;
+use ic_stable_structures::{Memory, StableLog, VectorMemory};
+use num_traits::{CheckedSub, ToPrimitive};
 
+use crate::token_archive::{ArchiveV1, ArchiveV2, ArchiveV3, ArchiveV4};
+use crate::token_transaction_window::HeapTransactionWindow;
 use crate::*;
 
-#[derive(Clone, Deserialize, Serialize, Debug, Ord, PartialOrd, Eq, PartialEq)]
-pub struct Blockchain {
-    pub blocks: Vec<EncodedBlock>,
-    pub tx_window: TokenTransactionWindow,
+// The local (not yet archived) blocks, kept in one of two stable logs.
+// A log can only grow, so removing the archived blocks copies the remaining
+// ones into the other log, which costs at most the archive trigger threshold.
+pub struct LocalBlocks<M: Memory + Clone> {
+    memories: [(M, M); 2],
+    active: usize,
+    log: StableLog<EncodedBlock, M, M>,
+}
+
+impl<M: Memory + Clone> LocalBlocks<M> {
+    // load the blocks already stored in the first log, if any
+    pub fn init(memories: [(M, M); 2]) -> Self {
+        let (index_memory, data_memory) = memories[0].clone();
+        let log = StableLog::init(index_memory, data_memory)
+            .expect("failed to initialize the local block log");
+        LocalBlocks {
+            memories,
+            active: 0,
+            log,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.log.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.log.is_empty()
+    }
+
+    pub fn get(&self, index: u64) -> Option<EncodedBlock> {
+        self.log.get(index)
+    }
+
+    fn push(&mut self, block: &EncodedBlock) -> CommonResult<()> {
+        self.log
+            .append(block)
+            .map(|_| ())
+            .map_err(|e| DFTError::Unknown {
+                detail: format!("append local block failed, {:?}", e),
+            })
+    }
+
+    // switch to the log the blocks were in before an upgrade
+    fn restore(&mut self, active: usize) {
+        let (index_memory, data_memory) = self.memories[active].clone();
+        self.log = StableLog::init(index_memory, data_memory)
+            .expect("failed to restore the local block log");
+        self.active = active;
+    }
+
+    // drop the first `len` blocks
+    fn remove_first(&mut self, len: u64) {
+        let next = 1 - self.active;
+        let (index_memory, data_memory) = self.memories[next].clone();
+        let log = StableLog::new(index_memory, data_memory);
+        for index in len..self.log.len() {
+            log.append(&self.log.get(index).unwrap())
+                .expect("failed to copy the local blocks");
+        }
+        self.log = log;
+        self.active = next;
+    }
+}
+
+pub struct Blockchain<M: Memory + Clone> {
+    pub blocks: LocalBlocks<M>,
+    pub tx_window: TokenTransactionWindow<M>,
+    pub timestamp_index: BlockTimestampIndex<M>,
     pub last_hash: Option<BlockHash>,
     pub last_timestamp: u64,
     pub archive: Archive,
     pub num_archived_blocks: BlockHeight,
+    // set once the blocks stored before the timestamp index existed are sampled,
+    // so later upgrades do not read any block
+    pub timestamp_index_backfilled: bool,
+}
+
+impl Blockchain<VectorMemory> {
+    pub fn new() -> Self {
+        Self::init(
+            LocalBlocks::init(Default::default()),
+            TokenTransactionWindow::new(),
+            BlockTimestampIndex::init(VectorMemory::default()),
+        )
+    }
 }
 
-impl Default for Blockchain {
+impl Default for Blockchain<VectorMemory> {
     fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Memory + Clone> fmt::Debug for Blockchain<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Blockchain")
+            .field("local_block_count", &self.blocks.len())
+            .field("tx_window", &self.tx_window)
+            .field("timestamp_index", &self.timestamp_index)
+            .field("last_hash", &self.last_hash)
+            .field("last_timestamp", &self.last_timestamp)
+            .field("archive", &self.archive)
+            .field("num_archived_blocks", &self.num_archived_blocks)
+            .field(
+                "timestamp_index_backfilled",
+                &self.timestamp_index_backfilled,
+            )
+            .finish()
+    }
+}
+
+impl<M: Memory + Clone> Blockchain<M> {
+    pub fn init(
+        blocks: LocalBlocks<M>,
+        tx_window: TokenTransactionWindow<M>,
+        timestamp_index: BlockTimestampIndex<M>,
+    ) -> Self {
         Blockchain {
-            blocks: Vec::new(),
-            tx_window: TokenTransactionWindow::new(),
+            blocks,
+            tx_window,
+            timestamp_index,
             last_hash: None,
             last_timestamp: 0,
             archive: Archive::default(),
             num_archived_blocks: 0u32.into(),
+            timestamp_index_backfilled: true,
         }
     }
-}
 
-impl Blockchain {
     pub fn add_tx_to_block(
         &mut self,
         token_id: &Principal,
@@ -76,17 +184,20 @@ impl Blockchain {
         if block.timestamp < self.last_timestamp {
             return Err(DFTError::ApplyBlockFailedByInvalidTimestamp);
         }
+        self.blocks.push(&encoded_block)?;
         self.last_hash = Some(encoded_block.hash_with_token_id(token_id));
         self.last_timestamp = block.timestamp;
-        self.blocks.push(encoded_block);
-        Ok(self.chain_length().checked_sub(&1u32.into()).unwrap())
+        let height = self.chain_length().checked_sub(&1u32.into()).unwrap();
+        self.timestamp_index
+            .record(height.to_u64().unwrap(), block.timestamp);
+        Ok(height)
     }
 
-    pub fn get(&self, height: BlockHeight) -> Option<&EncodedBlock> {
+    pub fn get(&self, height: BlockHeight) -> Option<EncodedBlock> {
         if height < self.num_archived_blocks() {
             None
         } else {
-            let index: usize = (height - self.num_archived_blocks()).try_into().unwrap();
+            let index: u64 = (height - self.num_archived_blocks()).try_into().unwrap();
             self.blocks.get(index)
         }
     }
@@ -96,7 +207,7 @@ impl Blockchain {
     }
 
     pub fn num_unarchived_blocks(&self) -> u64 {
-        self.blocks.len() as u64
+        self.blocks.len()
     }
 
     pub fn local_block_range(&self) -> std::ops::Range<BlockHeight> {
@@ -109,65 +220,197 @@ impl Blockchain {
     }
 
     pub fn remove_archived_blocks(&mut self, len: usize) {
-        if len > self.blocks.len() {
+        if len as u64 > self.blocks.len() {
             panic!(
                 "Asked to remove more blocks than present. Present: {}, to remove: {}",
                 self.blocks.len(),
                 len
             );
         }
-        self.blocks = self.blocks.split_off(len);
+        self.blocks.remove_first(len as u64);
         self.num_archived_blocks += len;
     }
 
+    // The oldest blocks, at most `num_blocks_to_archive` of them and
+    // at most `max_size_bytes` in total, unless the first block alone is larger.
     pub fn get_blocks_for_archiving(
         &self,
         trigger_threshold: usize,
         num_blocks_to_archive: usize,
+        max_size_bytes: usize,
     ) -> VecDeque<EncodedBlock> {
         let num_blocks_unarchived = self.num_unarchived_blocks() as usize;
         if num_blocks_unarchived < trigger_threshold {
             return VecDeque::new();
         }
 
-        let blocks_to_archive: VecDeque<EncodedBlock> = VecDeque::from(
-            self.blocks[0..num_blocks_to_archive.min(num_blocks_unarchived)].to_vec(),
-        );
-        blocks_to_archive
+        let mut size_bytes = 0;
+        let mut blocks = VecDeque::new();
+        for index in 0..num_blocks_to_archive.min(num_blocks_unarchived) as u64 {
+            let block = self.blocks.get(index).unwrap();
+            size_bytes += block.size_bytes();
+            if size_bytes > max_size_bytes && !blocks.is_empty() {
+                break;
+            }
+            blocks.push_back(block);
+        }
+        blocks
     }
-}
 
-impl StableState for Blockchain {
-    fn encode(&self) -> Vec<u8> {
+    // The heap metadata saved on upgrade,
+    // the blocks and the transaction window are already in stable memory.
+    pub fn encode_metadata(&self) -> Vec<u8> {
         bincode::serialize(&(
-            &self.blocks,
-            &self.tx_window,
             &self.last_hash,
             &self.last_timestamp,
             &self.archive,
             &self.num_archived_blocks,
+            &self.blocks.active,
+            &self.timestamp_index_backfilled,
         ))
         .unwrap()
     }
 
-    fn decode(bytes: Vec<u8>) -> Result<Self, String> {
+    // restore the heap metadata after an upgrade
+    pub fn restore(&mut self, metadata: Vec<u8>) -> Result<(), String> {
+        let (
+            last_hash,
+            last_timestamp,
+            archive,
+            num_archived_blocks,
+            active,
+            timestamp_index_backfilled,
+        ): (Option<BlockHash>, u64, Archive, BlockHeight, usize, bool) =
+            bincode::deserialize(&metadata)
+                .map_err(|e| format!("decode blockchain metadata failed, {}", e))?;
+
+        self.blocks.restore(active);
+        self.last_hash = last_hash;
+        self.last_timestamp = last_timestamp;
+        self.archive = archive;
+        self.num_archived_blocks = num_archived_blocks;
+        self.timestamp_index_backfilled = timestamp_index_backfilled;
+        if !self.timestamp_index_backfilled {
+            self.backfill_timestamp_index();
+            self.timestamp_index_backfilled = true;
+        }
+        Ok(())
+    }
+
+    // The index did not exist before, sample the local blocks.
+    // Archived blocks can not be sampled, time range queries fall back to
+    // every archived block before the first sample.
+    fn backfill_timestamp_index(&mut self) {
+        let start = self.num_archived_blocks.to_u64().unwrap();
+        let blocks = &self.blocks;
+        self.timestamp_index
+            .backfill(start..start + blocks.len(), |position| {
+                blocks
+                    .get(position - start)
+                    .unwrap()
+                    .decode()
+                    .expect("bug: failed to decode encoded block")
+                    .timestamp
+            });
+    }
+
+    // Convert metadata saved by the version 2 state, whose archive did not
+    // monitor the cycles of the archive canisters yet, to the version 3 layout.
+    pub fn migrate_metadata_from_v2(metadata: Vec<u8>) -> Result<Vec<u8>, String> {
+        let (last_hash, last_timestamp, archive, num_archived_blocks, active): (
+            Option<BlockHash>,
+            u64,
+            ArchiveV2,
+            BlockHeight,
+            usize,
+        ) = bincode::deserialize(&metadata)
+            .map_err(|e| format!("decode blockchain metadata v2 failed, {}", e))?;
+
+        Ok(bincode::serialize(&(
+            last_hash,
+            last_timestamp,
+            ArchiveV3::from(archive),
+            num_archived_blocks,
+            active,
+        ))
+        .unwrap())
+    }
+
+    // Convert metadata saved by the version 3 state, whose archive did not
+    // mirror the archived ranges yet, to the version 4 layout.
+    pub fn migrate_metadata_from_v3(metadata: Vec<u8>) -> Result<Vec<u8>, String> {
+        let (last_hash, last_timestamp, archive, num_archived_blocks, active): (
+            Option<BlockHash>,
+            u64,
+            ArchiveV3,
+            BlockHeight,
+            usize,
+        ) = bincode::deserialize(&metadata)
+            .map_err(|e| format!("decode blockchain metadata v3 failed, {}", e))?;
+
+        Ok(bincode::serialize(&(
+            last_hash,
+            last_timestamp,
+            ArchiveV4::from(archive),
+            num_archived_blocks,
+            active,
+        ))
+        .unwrap())
+    }
+
+    // Convert metadata saved by the version 4 state, whose archive always
+    // compressed the archived blocks, to the current layout.
+    // The timestamp index may not have existed yet, it is backfilled on restore.
+    pub fn migrate_metadata_from_v4(metadata: Vec<u8>) -> Result<Vec<u8>, String> {
+        let (last_hash, last_timestamp, archive, num_archived_blocks, active): (
+            Option<BlockHash>,
+            u64,
+            ArchiveV4,
+            BlockHeight,
+            usize,
+        ) = bincode::deserialize(&metadata)
+            .map_err(|e| format!("decode blockchain metadata v4 failed, {}", e))?;
+
+        Ok(bincode::serialize(&(
+            last_hash,
+            last_timestamp,
+            Archive::from(archive),
+            num_archived_blocks,
+            active,
+            false,
+        ))
+        .unwrap())
+    }
+
+    // Move the blocks and the transaction window of a blockchain saved in the
+    // version 1 heap format into the stable structures, returns the metadata
+    // in the version 2 layout.
+    pub fn migrate_from_heap_format(&mut self, bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+        assert!(self.blocks.is_empty());
         let (blocks, tx_window, last_hash, last_timestamp, archive, num_archived_blocks): (
             Vec<EncodedBlock>,
-            TokenTransactionWindow,
+            HeapTransactionWindow,
             Option<BlockHash>,
             u64,
-            Archive,
+            ArchiveV1,
             BlockHeight,
-        ) = bincode::deserialize(&bytes).unwrap();
+        ) = bincode::deserialize(&bytes)
+            .map_err(|e| format!("decode heap blockchain failed, {}", e))?;
 
-        Ok(Blockchain {
-            blocks,
-            tx_window,
+        for block in blocks.iter() {
+            self.blocks
+                .push(block)
+                .map_err(|e| format!("migrate local block failed, {}", e))?;
+        }
+        self.tx_window.migrate_from_heap_format(tx_window);
+        Ok(bincode::serialize(&(
             last_hash,
             last_timestamp,
-            archive,
+            ArchiveV2::from(archive),
             num_archived_blocks,
-        })
+            self.blocks.active,
+        ))
+        .unwrap())
     }
 }
 
@@ -175,16 +418,23 @@ impl StableState for Blockchain {
 mod tests {
     use std::time::SystemTime;
 
-    use num_traits::ToPrimitive;
-
     use dft_utils::range_utils::make_range;
 
     use super::*;
 
-    #[test]
-    fn test_blockchain_encode_decode() {
-        let mut blockchain = Blockchain::default();
-        let token_id: Principal = "rkp4c-7iaaa-aaaaa-aaaca-cai".parse().unwrap();
+    fn init_blockchain(
+        block_memories: &[(VectorMemory, VectorMemory); 2],
+        window_memories: &(VectorMemory, VectorMemory),
+        index_memory: &VectorMemory,
+    ) -> Blockchain<VectorMemory> {
+        Blockchain::init(
+            LocalBlocks::init(block_memories.clone()),
+            TokenTransactionWindow::init(window_memories.0.clone(), window_memories.1.clone()),
+            BlockTimestampIndex::init(index_memory.clone()),
+        )
+    }
+
+    fn owner_modify_tx(created_at: u64) -> InnerTransaction {
         let caller: Principal = "qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe"
             .parse()
             .unwrap();
@@ -192,6 +442,22 @@ mod tests {
             "o5y7v-htz2q-vk7fc-cqi4m-bqvwa-eth75-sc2wz-ubuev-curf2-rbipe-tae"
                 .parse()
                 .unwrap();
+        InnerTransaction {
+            operation: InnerOperation::OwnerModify {
+                caller: caller.into(),
+                new_owner: new_owner.into(),
+            },
+            created_at,
+        }
+    }
+
+    #[test]
+    fn test_blockchain_restore() {
+        let block_memories: [(VectorMemory, VectorMemory); 2] = Default::default();
+        let window_memories: (VectorMemory, VectorMemory) = Default::default();
+        let index_memory = VectorMemory::default();
+        let mut blockchain = init_blockchain(&block_memories, &window_memories, &index_memory);
+        let token_id: Principal = "rkp4c-7iaaa-aaaaa-aaaca-cai".parse().unwrap();
         let now: u64 = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
@@ -199,27 +465,169 @@ mod tests {
             .try_into()
             .unwrap();
 
-        let timestamp = now.clone();
-        let transaction = InnerTransaction {
-            operation: InnerOperation::OwnerModify {
-                caller: caller.clone().into(),
-                new_owner: new_owner.clone().into(),
-            },
-            created_at: timestamp.clone(),
-        };
-        let _ = blockchain.add_tx_to_block(&token_id, transaction.clone(), timestamp.clone());
+        for i in 0..3u64 {
+            blockchain
+                .add_tx_to_block(&token_id, owner_modify_tx(now + i), now + i)
+                .unwrap();
+        }
+        // the remaining blocks are moved to the other log
+        blockchain.remove_archived_blocks(1);
 
-        // Encode and decode the blockchain
-        let encoded_blockchain = blockchain.clone().encode();
-        let decoded_blockchain = Blockchain::decode(encoded_blockchain).unwrap();
+        // only the metadata is encoded, the blocks and the window are reloaded from the memories
+        let metadata = blockchain.encode_metadata();
+        assert!(metadata.len() < 200);
+        let mut restored = init_blockchain(&block_memories, &window_memories, &index_memory);
+        restored.restore(metadata).unwrap();
+
+        assert_eq!(restored.num_archived_blocks(), BigUint::from(1u32));
+        assert_eq!(restored.num_unarchived_blocks(), 2);
+        assert_eq!(restored.chain_length(), BigUint::from(3u32));
+        assert_eq!(restored.last_hash, blockchain.last_hash);
+        assert_eq!(restored.last_timestamp, now + 2);
+        assert_eq!(restored.get(0u32.into()), None);
+        assert_eq!(restored.get(2u32.into()), blockchain.get(2u32.into()));
+        assert_eq!(
+            restored.tx_window.transactions_count_in_window(),
+            blockchain.tx_window.transactions_count_in_window()
+        );
+        assert_eq!(restored.timestamp_index.len(), 1);
+
+        let res = restored.add_tx_to_block(&token_id, owner_modify_tx(now), now + 3);
+        assert_eq!(res.unwrap_err(), DFTError::TxDuplicate);
+        let (height, _, _) = restored
+            .add_tx_to_block(&token_id, owner_modify_tx(now + 3), now + 3)
+            .unwrap();
+        assert_eq!(height, BigUint::from(3u32));
+    }
 
-        // Check that the decoded blockchain is the same as the original
-        assert_eq!(blockchain, decoded_blockchain);
+    #[test]
+    fn test_blockchain_restore_backfills_timestamp_index() {
+        let block_memories: [(VectorMemory, VectorMemory); 2] = Default::default();
+        let window_memories: (VectorMemory, VectorMemory) = Default::default();
+        let token_id: Principal = "rkp4c-7iaaa-aaaaa-aaaca-cai".parse().unwrap();
+        let interval = constants::BLOCK_TIMESTAMP_INDEX_INTERVAL;
+        let mut blockchain =
+            init_blockchain(&block_memories, &window_memories, &VectorMemory::default());
+        for i in 0..interval * 2 + 11 {
+            blockchain
+                .add_tx_to_block(&token_id, owner_modify_tx(1000 + i), 1000 + i)
+                .unwrap();
+        }
+        assert_eq!(blockchain.timestamp_index.len(), 3);
+        blockchain.remove_archived_blocks(10);
 
-        let res = blockchain.add_tx_to_block(&token_id, transaction.clone(), timestamp.clone());
+        // a backfilled index is not sampled again
+        let mut restored =
+            init_blockchain(&block_memories, &window_memories, &VectorMemory::default());
+        restored.restore(blockchain.encode_metadata()).unwrap();
+        assert!(restored.timestamp_index.is_empty());
 
-        assert!(res.is_err());
-        assert_eq!(res.unwrap_err(), DFTError::TxDuplicate)
+        // saved before the index existed, only the local blocks are sampled
+        blockchain.timestamp_index_backfilled = false;
+        let mut restored =
+            init_blockchain(&block_memories, &window_memories, &VectorMemory::default());
+        restored.restore(blockchain.encode_metadata()).unwrap();
+        assert!(restored.timestamp_index_backfilled);
+        assert_eq!(restored.timestamp_index.len(), 3);
+        assert_eq!(restored.timestamp_index.scan_start(1000), 0);
+        assert_eq!(restored.timestamp_index.scan_start(1011), 10);
+        assert_eq!(
+            restored.timestamp_index.scan_end(1011 + interval),
+            Some(10 + interval * 2)
+        );
+    }
+
+    #[test]
+    fn test_blockchain_migrate_from_heap_format() {
+        let token_id: Principal = "rkp4c-7iaaa-aaaaa-aaaca-cai".parse().unwrap();
+        let mut source = Blockchain::default();
+        for i in 0..5u64 {
+            source
+                .add_tx_to_block(&token_id, owner_modify_tx(1000 + i), 1000 + i)
+                .unwrap();
+        }
+        let blocks: Vec<EncodedBlock> = (0..5u32)
+            .map(|height| source.get(height.into()).unwrap())
+            .collect();
+        let transactions: VecDeque<TransactionInfo> = blocks
+            .iter()
+            .map(|block| {
+                let block = block.decode().unwrap();
+                TransactionInfo {
+                    block_timestamp: block.timestamp,
+                    tx_hash: block.transaction.hash_with_token_id(&token_id),
+                }
+            })
+            .collect();
+        let transactions_by_hash: std::collections::BTreeMap<TransactionHash, BlockHeight> =
+            transactions
+                .iter()
+                .enumerate()
+                .map(|(height, tx)| (tx.tx_hash, BigUint::from(height)))
+                .collect();
+
+        // version 1 archive layout
+        let archive_v1 = (
+            Vec::<Principal>::new(),
+            Option::<Principal>::None,
+            Vec::<(BlockHeight, BlockHeight)>::new(),
+            1024u32,
+            2048u32,
+            3000u32,
+            500u32,
+            1_000_000u64,
+        );
+        // layout written by the pre_upgrade of the heap-based blockchain
+        let bytes = bincode::serialize(&(
+            &blocks,
+            &(
+                1_000_000usize,
+                constants::DEFAULT_TRANSACTION_WINDOW,
+                &transactions_by_hash,
+                &transactions,
+            ),
+            &source.last_hash,
+            &source.last_timestamp,
+            &archive_v1,
+            &source.num_archived_blocks,
+        ))
+        .unwrap();
+
+        let mut blockchain = Blockchain::default();
+        let metadata_v2 = blockchain.migrate_from_heap_format(bytes).unwrap();
+        let metadata_v3 =
+            Blockchain::<VectorMemory>::migrate_metadata_from_v2(metadata_v2).unwrap();
+        let metadata_v4 =
+            Blockchain::<VectorMemory>::migrate_metadata_from_v3(metadata_v3).unwrap();
+        blockchain
+            .restore(Blockchain::<VectorMemory>::migrate_metadata_from_v4(metadata_v4).unwrap())
+            .unwrap();
+        assert_eq!(blockchain.chain_length(), BigUint::from(5u32));
+        assert_eq!(blockchain.last_hash, source.last_hash);
+        assert_eq!(blockchain.get(4u32.into()), Some(blocks[4].clone()));
+        assert_eq!(blockchain.tx_window.transactions_count_in_window(), 5);
+        // the index is rebuilt from the local blocks
+        assert_eq!(blockchain.timestamp_index.len(), 1);
+        assert_eq!(blockchain.timestamp_index.scan_end(1000), Some(0));
+        assert_eq!(blockchain.archive.trigger_threshold, 3000);
+        assert_eq!(blockchain.archive.num_blocks_to_archive, 500);
+        assert_eq!(blockchain.archive.cycles_for_archive_creation, 1_000_000);
+        assert_eq!(
+            blockchain.archive.archive_interval_seconds,
+            constants::DEFAULT_ARCHIVE_INTERVAL_SECONDS
+        );
+        assert_eq!(
+            blockchain.archive.min_archive_cycles,
+            constants::DEFAULT_MIN_ARCHIVE_CYCLES
+        );
+        assert!(blockchain.archive.compress_blocks());
+
+        let res = blockchain.add_tx_to_block(&token_id, owner_modify_tx(1000), 1005);
+        assert_eq!(res.unwrap_err(), DFTError::TxDuplicate);
+        blockchain
+            .add_tx_to_block(&token_id, owner_modify_tx(1005), 1005)
+            .unwrap();
+        assert_eq!(blockchain.chain_length(), BigUint::from(6u32));
     }
 
     #[test]
@@ -258,6 +666,7 @@ mod tests {
                 let blocks = blockchain.get_blocks_for_archiving(
                     blockchain.archive.trigger_threshold as usize,
                     blockchain.archive.num_blocks_to_archive as usize,
+                    blockchain.archive.max_message_size_bytes() as usize,
                 );
                 assert_eq!(blocks.len(), 0);
                 assert_eq!(blockchain.num_archived_blocks(), BigUint::from(0u64));
@@ -274,6 +683,7 @@ mod tests {
                 let blocks = blockchain.get_blocks_for_archiving(
                     blockchain.archive.trigger_threshold as usize,
                     blockchain.archive.num_blocks_to_archive as usize,
+                    blockchain.archive.max_message_size_bytes() as usize,
                 );
                 assert_eq!(blocks.len(), 1000);
                 blockchain.remove_archived_blocks(blocks.len());
@@ -298,6 +708,7 @@ mod tests {
                 let blocks = blockchain.get_blocks_for_archiving(
                     blockchain.archive.trigger_threshold as usize,
                     blockchain.archive.num_blocks_to_archive as usize,
+                    blockchain.archive.max_message_size_bytes() as usize,
                 );
                 assert_eq!(blocks.len(), 1000);
                 blockchain.remove_archived_blocks(blocks.len());
@@ -310,5 +721,12 @@ mod tests {
                 );
             }
         }
+
+        // the batch is cut at the message size, but never empty
+        let block_size = blockchain.get(3000u32.into()).unwrap().size_bytes();
+        let blocks = blockchain.get_blocks_for_archiving(0, 1000, 3 * block_size);
+        assert_eq!(blocks.len(), 3);
+        let blocks = blockchain.get_blocks_for_archiving(0, 1000, 0);
+        assert_eq!(blocks.len(), 1);
     }
 }
@@ -0,0 +1,778 @@
+use std::borrow::Cow;
+use std::fmt;
+
+use candid::{CandidType, Deserialize, Nat};
+use ic_stable_structures::{Memory, StableCell, Storable, VectorMemory};
+use serde::Serialize;
+
+use crate::constants::MAX_CHAIN_VERIFICATION_MISMATCHES;
+use crate::*;
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainVerificationState {
+    NotStarted,
+    // the blocks are replayed from genesis
+    Replaying,
+    // every block is replayed, the replayed balances are compared to the live ones
+    ComparingBalances,
+    Completed,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InnerChainMismatch {
+    pub block_height: Option<BlockHeight>,
+    pub account: Option<TokenHolder>,
+    pub reason: String,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChainMismatch {
+    #[serde(rename = "blockHeight")]
+    pub block_height: Option<Nat>,
+    pub account: Option<String>,
+    pub reason: String,
+}
+
+impl From<InnerChainMismatch> for ChainMismatch {
+    fn from(mismatch: InnerChainMismatch) -> Self {
+        ChainMismatch {
+            block_height: mismatch.block_height.map(|height| height.into()),
+            account: mismatch.account.map(|account| account.to_hex()),
+            reason: mismatch.reason,
+        }
+    }
+}
+
+// The progress of a verification, saved after every round so it survives upgrades.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InnerChainVerification {
+    pub state: ChainVerificationState,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    // the next block to replay
+    pub next_block_height: BlockHeight,
+    pub last_block_hash: Option<BlockHash>,
+    // the fee recipient at `next_block_height`
+    pub fee_to: TokenHolder,
+    pub replayed_total_supply: TokenAmount,
+    // the last replayed holder compared to the live balances
+    pub compared_holders_cursor: Option<TokenHolder>,
+    pub compared_holders: u64,
+    // the first mismatches found, at most MAX_CHAIN_VERIFICATION_MISMATCHES
+    pub mismatches: Vec<InnerChainMismatch>,
+    // the error of the last round, the round is retried
+    pub last_error: Option<String>,
+}
+
+impl Default for InnerChainVerification {
+    fn default() -> Self {
+        InnerChainVerification {
+            state: ChainVerificationState::NotStarted,
+            started_at: None,
+            finished_at: None,
+            next_block_height: BlockHeight::default(),
+            last_block_hash: None,
+            fee_to: TokenHolder::empty(),
+            replayed_total_supply: TokenAmount::default(),
+            compared_holders_cursor: None,
+            compared_holders: 0,
+            mismatches: Vec::new(),
+            last_error: None,
+        }
+    }
+}
+
+impl Storable for InnerChainVerification {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(bincode::serialize(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        bincode::deserialize(&bytes).expect("failed to decode the chain verification")
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChainVerification {
+    pub state: ChainVerificationState,
+    #[serde(rename = "startedAt")]
+    pub started_at: Option<u64>,
+    #[serde(rename = "finishedAt")]
+    pub finished_at: Option<u64>,
+    #[serde(rename = "verifiedBlocks")]
+    pub verified_blocks: Nat,
+    #[serde(rename = "comparedHolders")]
+    pub compared_holders: u64,
+    pub mismatches: Vec<ChainMismatch>,
+    #[serde(rename = "lastError")]
+    pub last_error: Option<String>,
+}
+
+impl From<InnerChainVerification> for ChainVerification {
+    fn from(verification: InnerChainVerification) -> Self {
+        ChainVerification {
+            state: verification.state,
+            started_at: verification.started_at,
+            finished_at: verification.finished_at,
+            verified_blocks: verification.next_block_height.into(),
+            compared_holders: verification.compared_holders,
+            mismatches: verification
+                .mismatches
+                .into_iter()
+                .map(|mismatch| mismatch.into())
+                .collect(),
+            last_error: verification.last_error,
+        }
+    }
+}
+
+// Replays the blocks from genesis into its own balances, with the same
+// debits and credits as the token services, then compares them to the live state.
+// The blocks are fed in order, in as many rounds as needed.
+pub struct ChainVerifier<M: Memory> {
+    progress: StableCell<InnerChainVerification, M>,
+    balances: Option<TokenBalances<M>>,
+}
+
+impl ChainVerifier<VectorMemory> {
+    pub fn new() -> Self {
+        Self::init(
+            VectorMemory::default(),
+            VectorMemory::default(),
+            VectorMemory::default(),
+        )
+    }
+}
+
+impl Default for ChainVerifier<VectorMemory> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Memory> fmt::Debug for ChainVerifier<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChainVerifier")
+            .field("progress", self.progress.get())
+            .finish()
+    }
+}
+
+impl<M: Memory> ChainVerifier<M> {
+    // load the verification already stored in the memories, if any
+    pub fn init(progress_memory: M, balances_memory: M, held_memory: M) -> Self {
+        let progress = StableCell::init(progress_memory, InnerChainVerification::default())
+            .expect("failed to initialize the chain verification");
+        let mut balances = TokenBalances::init(balances_memory, held_memory);
+        balances.restore(progress.get().replayed_total_supply.clone());
+        ChainVerifier {
+            progress,
+            balances: Some(balances),
+        }
+    }
+
+    pub fn progress(&self) -> &InnerChainVerification {
+        self.progress.get()
+    }
+
+    pub fn is_running(&self) -> bool {
+        matches!(
+            self.progress().state,
+            ChainVerificationState::Replaying | ChainVerificationState::ComparingBalances
+        )
+    }
+
+    fn balances(&self) -> &TokenBalances<M> {
+        self.balances.as_ref().unwrap()
+    }
+
+    fn update(&mut self, f: impl FnOnce(&mut InnerChainVerification)) {
+        let mut progress = self.progress.get().clone();
+        f(&mut progress);
+        progress.replayed_total_supply = self.balances().total_supply();
+        self.progress
+            .set(progress)
+            .expect("failed to save the chain verification");
+    }
+
+    // Start over from genesis, the replayed balances are dropped.
+    // `fee_to` is the fee recipient the token was initialized with, the
+    // FeeToModify blocks change it as they are replayed.
+    pub fn start(&mut self, fee_to: TokenHolder, now: u64) {
+        self.balances = self.balances.take().map(|balances| balances.clear());
+        self.update(|progress| {
+            *progress = InnerChainVerification {
+                state: ChainVerificationState::Replaying,
+                started_at: Some(now),
+                fee_to,
+                ..Default::default()
+            }
+        });
+    }
+
+    pub fn record_error(&mut self, error: Option<String>) {
+        self.update(|progress| progress.last_error = error);
+    }
+
+    fn fail(&mut self, mismatch: InnerChainMismatch, now: u64) {
+        self.update(|progress| {
+            progress.mismatches.push(mismatch);
+            progress.state = ChainVerificationState::Failed;
+            progress.finished_at = Some(now);
+        });
+    }
+
+    // Replay `blocks`, starting at the next block height.
+    // The verification fails at the first block which is not chained to the
+    // previous one or can not be applied to the replayed balances.
+    pub fn replay_blocks(&mut self, token_id: &Principal, blocks: &[EncodedBlock], now: u64) {
+        let mut progress = self.progress().clone();
+        let balances = self.balances.as_mut().unwrap();
+        let mut failure = None;
+        for encoded_block in blocks {
+            let height = progress.next_block_height.clone();
+            let expected_parent_hash = progress
+                .last_block_hash
+                .unwrap_or_else(|| dft_utils::sha256::compute_hash(token_id.as_slice()));
+            let result = encoded_block
+                .decode()
+                .map_err(|e| e.to_string())
+                .and_then(|block| {
+                    if block.parent_hash != expected_parent_hash {
+                        return Err("parent hash mismatch".to_string());
+                    }
+                    apply_operation(balances, &mut progress.fee_to, &block.transaction.operation)
+                });
+            if let Err(reason) = result {
+                failure = Some(InnerChainMismatch {
+                    block_height: Some(height),
+                    account: None,
+                    reason,
+                });
+                break;
+            }
+            progress.last_block_hash = Some(encoded_block.hash_with_token_id(token_id));
+            progress.next_block_height += 1u32;
+        }
+        self.update(|saved| {
+            saved.next_block_height = progress.next_block_height;
+            saved.last_block_hash = progress.last_block_hash;
+            saved.fee_to = progress.fee_to;
+            saved.last_error = None;
+        });
+        if let Some(mismatch) = failure {
+            self.fail(mismatch, now);
+        }
+    }
+
+    // Every block is replayed, the last one must be the live chain tip.
+    pub fn finish_replay(&mut self, last_block_hash: Option<BlockHash>, now: u64) {
+        if self.progress().last_block_hash != last_block_hash {
+            let height = self.progress().next_block_height.clone();
+            self.fail(
+                InnerChainMismatch {
+                    block_height: Some(height),
+                    account: None,
+                    reason: "last block hash mismatch".to_string(),
+                },
+                now,
+            );
+            return;
+        }
+        self.update(|progress| progress.state = ChainVerificationState::ComparingBalances);
+    }
+
+    // Compare at most `limit` replayed balances to the live ones.
+    // Once every replayed holder is compared, the holder count and the total
+    // supply are checked, so a live holder missing from the replay is found too.
+    pub fn compare_balances<L: Memory>(
+        &mut self,
+        live_balances: &TokenBalances<L>,
+        limit: usize,
+        now: u64,
+    ) {
+        let mut progress = self.progress().clone();
+        let balances = self.balances();
+        let replayed = balances.balances_after(progress.compared_holders_cursor.as_ref(), limit);
+        for (holder, balance) in &replayed {
+            let live_balance = live_balances.balance_of(holder);
+            if live_balance != *balance {
+                push_mismatch(
+                    &mut progress.mismatches,
+                    InnerChainMismatch {
+                        block_height: None,
+                        account: Some(*holder),
+                        reason: format!("balance {} != replayed {}", live_balance, balance),
+                    },
+                );
+            }
+            let (live_held, held) = (
+                live_balances.held_balance_of(holder),
+                balances.held_balance_of(holder),
+            );
+            if live_held != held {
+                push_mismatch(
+                    &mut progress.mismatches,
+                    InnerChainMismatch {
+                        block_height: None,
+                        account: Some(*holder),
+                        reason: format!("held amount {} != replayed {}", live_held, held),
+                    },
+                );
+            }
+        }
+        progress.compared_holders += replayed.len() as u64;
+        progress.compared_holders_cursor = replayed.last().map(|(holder, _)| *holder);
+
+        if replayed.len() < limit {
+            if live_balances.holder_count() != balances.holder_count() {
+                push_mismatch(
+                    &mut progress.mismatches,
+                    InnerChainMismatch {
+                        block_height: None,
+                        account: None,
+                        reason: format!(
+                            "holder count {} != replayed {}",
+                            live_balances.holder_count(),
+                            balances.holder_count()
+                        ),
+                    },
+                );
+            }
+            if live_balances.held_holder_count() != balances.held_holder_count() {
+                push_mismatch(
+                    &mut progress.mismatches,
+                    InnerChainMismatch {
+                        block_height: None,
+                        account: None,
+                        reason: format!(
+                            "held holder count {} != replayed {}",
+                            live_balances.held_holder_count(),
+                            balances.held_holder_count()
+                        ),
+                    },
+                );
+            }
+            if live_balances.total_supply() != balances.total_supply() {
+                push_mismatch(
+                    &mut progress.mismatches,
+                    InnerChainMismatch {
+                        block_height: None,
+                        account: None,
+                        reason: format!(
+                            "total supply {} != replayed {}",
+                            live_balances.total_supply(),
+                            balances.total_supply()
+                        ),
+                    },
+                );
+            }
+            progress.state = if progress.mismatches.is_empty() {
+                ChainVerificationState::Completed
+            } else {
+                ChainVerificationState::Failed
+            };
+            progress.finished_at = Some(now);
+        }
+        progress.last_error = None;
+        self.update(|saved| *saved = progress);
+    }
+}
+
+fn push_mismatch(mismatches: &mut Vec<InnerChainMismatch>, mismatch: InnerChainMismatch) {
+    if mismatches.len() < MAX_CHAIN_VERIFICATION_MISMATCHES {
+        mismatches.push(mismatch);
+    }
+}
+
+// The balance changes of an operation, as applied by the token services.
+fn apply_operation<M: Memory>(
+    balances: &mut TokenBalances<M>,
+    fee_to: &mut TokenHolder,
+    operation: &InnerOperation,
+) -> Result<(), String> {
+    let zero = TokenAmount::default();
+    match operation {
+        InnerOperation::Approve { owner, fee, .. }
+        | InnerOperation::IncreaseAllowance { owner, fee, .. }
+        | InnerOperation::DecreaseAllowance { owner, fee, .. }
+        | InnerOperation::RevokeAllAllowances { owner, fee, .. }
+        | InnerOperation::AuthorizeOperator {
+            holder: owner, fee, ..
+        }
+        | InnerOperation::RevokeOperator {
+            holder: owner, fee, ..
+        }
+        | InnerOperation::ApproveRecurring { owner, fee, .. } => {
+            if *fee > zero {
+                balances
+                    .debit_balance(owner, fee.clone())
+                    .map_err(|e| e.to_string())?;
+                balances.credit_balance(fee_to, fee.clone());
+            }
+        }
+        InnerOperation::Transfer {
+            from,
+            to,
+            value,
+            fee,
+            ..
+        } => {
+            // mint
+            if *from == TokenHolder::empty() {
+                balances.credit_balance(to, value.clone());
+                return Ok(());
+            }
+            balances
+                .debit_balance(from, value.clone())
+                .map_err(|e| e.to_string())?;
+            // burn
+            if *to == TokenHolder::empty() {
+                return Ok(());
+            }
+            balances.credit_balance(to, value.clone());
+            if *fee > zero {
+                balances
+                    .debit_balance(from, fee.clone())
+                    .map_err(|e| e.to_string())?;
+                balances.credit_balance(fee_to, fee.clone());
+            }
+        }
+        InnerOperation::MultiTransfer {
+            from, outputs, fee, ..
+        } => {
+            for (to, value) in outputs {
+                balances
+                    .debit_balance(from, value.clone())
+                    .map_err(|e| e.to_string())?;
+                balances.credit_balance(to, value.clone());
+            }
+            if *fee > zero {
+                balances
+                    .debit_balance(from, fee.clone())
+                    .map_err(|e| e.to_string())?;
+                balances.credit_balance(fee_to, fee.clone());
+            }
+        }
+        InnerOperation::Hold {
+            owner, amount, fee, ..
+        } => {
+            balances
+                .hold_balance(owner, amount.clone())
+                .map_err(|e| e.to_string())?;
+            if *fee > zero {
+                balances
+                    .debit_balance(owner, fee.clone())
+                    .map_err(|e| e.to_string())?;
+                balances.credit_balance(fee_to, fee.clone());
+            }
+        }
+        InnerOperation::CaptureHold {
+            owner,
+            merchant,
+            amount,
+            ..
+        } => {
+            balances
+                .debit_held(owner, amount.clone())
+                .map_err(|e| e.to_string())?;
+            balances.credit_balance(merchant, amount.clone());
+        }
+        InnerOperation::ReleaseHold { owner, amount, .. } => balances
+            .release_held(owner, amount.clone())
+            .map_err(|e| e.to_string())?,
+        InnerOperation::FeeToModify { new_fee_to, .. } => *fee_to = *new_fee_to,
+        InnerOperation::FeeModify { .. }
+        | InnerOperation::OwnerModify { .. }
+        | InnerOperation::AddMinter { .. }
+        | InnerOperation::RemoveMinter { .. }
+        | InnerOperation::ArchiveOptionsModify { .. } => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn holder(text: &str) -> TokenHolder {
+        TokenHolder::new(text.parse().unwrap(), None)
+    }
+
+    fn token_id() -> Principal {
+        "rwlgt-iiaaa-aaaaa-aaaaa-cai".parse().unwrap()
+    }
+
+    fn alice() -> TokenHolder {
+        holder("qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe")
+    }
+
+    // mint 1000 to alice, alice pays bob 100 with a fee of 2 and bob burns 50
+    fn test_chain() -> (Vec<EncodedBlock>, TokenBalances<VectorMemory>) {
+        let alice = alice();
+        let bob = holder("o5y7v-htz2q-vk7fc-cqi4m-bqvwa-eth75-sc2wz-ubuev-curf2-rbipe-tae");
+        let operations = vec![
+            InnerOperation::Transfer {
+                caller: alice,
+                from: TokenHolder::empty(),
+                to: alice,
+                value: 1000u32.into(),
+                fee: 0u32.into(),
+            },
+            InnerOperation::Transfer {
+                caller: alice,
+                from: alice,
+                to: bob,
+                value: 100u32.into(),
+                fee: 2u32.into(),
+            },
+            InnerOperation::Transfer {
+                caller: bob,
+                from: bob,
+                to: TokenHolder::empty(),
+                value: 50u32.into(),
+                fee: 0u32.into(),
+            },
+        ];
+        let blocks = encode_chain(operations);
+
+        // the fees go to alice, the fee recipient
+        let mut live_balances = TokenBalances::new();
+        live_balances.credit_balance(&alice, 900u32.into());
+        live_balances.credit_balance(&bob, 50u32.into());
+        (blocks, live_balances)
+    }
+
+    fn encode_chain(operations: Vec<InnerOperation>) -> Vec<EncodedBlock> {
+        let mut blocks: Vec<EncodedBlock> = Vec::new();
+        for (i, operation) in operations.into_iter().enumerate() {
+            let parent_hash = blocks
+                .last()
+                .map(|block| block.hash_with_token_id(&token_id()));
+            let block = InnerBlock::new_from_transaction(
+                &token_id(),
+                parent_hash,
+                InnerTransaction {
+                    operation,
+                    created_at: i as u64,
+                },
+                i as u64,
+            );
+            blocks.push(block.encode().unwrap());
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_verify_consistent_chain() {
+        let (blocks, live_balances) = test_chain();
+        let mut verifier = ChainVerifier::new();
+        verifier.start(alice(), 1);
+        assert!(verifier.is_running());
+
+        verifier.replay_blocks(&token_id(), &blocks[..2], 2);
+        verifier.replay_blocks(&token_id(), &blocks[2..], 3);
+        assert_eq!(
+            verifier.progress().next_block_height,
+            BlockHeight::from(3u32)
+        );
+        verifier.finish_replay(Some(blocks[2].hash_with_token_id(&token_id())), 4);
+        assert_eq!(
+            verifier.progress().state,
+            ChainVerificationState::ComparingBalances
+        );
+
+        verifier.compare_balances(&live_balances, 1, 5);
+        assert_eq!(
+            verifier.progress().state,
+            ChainVerificationState::ComparingBalances
+        );
+        verifier.compare_balances(&live_balances, 1, 6);
+        verifier.compare_balances(&live_balances, 1, 7);
+        let progress = verifier.progress();
+        assert_eq!(progress.state, ChainVerificationState::Completed);
+        assert_eq!(progress.compared_holders, 2);
+        assert_eq!(progress.finished_at, Some(7));
+        assert!(progress.mismatches.is_empty());
+    }
+
+    #[test]
+    fn test_verify_multi_transfer() {
+        let alice = holder("qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe");
+        let bob = holder("o5y7v-htz2q-vk7fc-cqi4m-bqvwa-eth75-sc2wz-ubuev-curf2-rbipe-tae");
+        let carol = holder("czjfo-ddpvm-6sibl-6zbox-ee5zq-bx3hc-e336t-s6pka-dupmy-wcxqi-fae");
+        let blocks = encode_chain(vec![
+            InnerOperation::Transfer {
+                caller: alice,
+                from: TokenHolder::empty(),
+                to: alice,
+                value: 1000u32.into(),
+                fee: 0u32.into(),
+            },
+            InnerOperation::FeeToModify {
+                caller: alice,
+                new_fee_to: carol,
+            },
+            InnerOperation::MultiTransfer {
+                caller: alice,
+                from: alice,
+                outputs: vec![(bob, 100u32.into()), (carol, 200u32.into())],
+                fee: 3u32.into(),
+            },
+        ]);
+        let mut live_balances = TokenBalances::new();
+        live_balances.credit_balance(&alice, 697u32.into());
+        live_balances.credit_balance(&bob, 100u32.into());
+        live_balances.credit_balance(&carol, 203u32.into());
+
+        let mut verifier = ChainVerifier::new();
+        verifier.start(alice, 1);
+        verifier.replay_blocks(&token_id(), &blocks, 2);
+        verifier.finish_replay(Some(blocks[2].hash_with_token_id(&token_id())), 3);
+        verifier.compare_balances(&live_balances, 100, 4);
+        let progress = verifier.progress();
+        assert_eq!(progress.state, ChainVerificationState::Completed);
+        assert!(progress.mismatches.is_empty(), "{:?}", progress.mismatches);
+    }
+
+    #[test]
+    fn test_verify_holds() {
+        let alice = holder("qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe");
+        let merchant = holder("o5y7v-htz2q-vk7fc-cqi4m-bqvwa-eth75-sc2wz-ubuev-curf2-rbipe-tae");
+        let carol = holder("czjfo-ddpvm-6sibl-6zbox-ee5zq-bx3hc-e336t-s6pka-dupmy-wcxqi-fae");
+        let hold = |expires_at| InnerOperation::Hold {
+            caller: alice,
+            owner: alice,
+            merchant,
+            amount: 300u32.into(),
+            expires_at,
+            fee: 3u32.into(),
+        };
+        let blocks = encode_chain(vec![
+            InnerOperation::Transfer {
+                caller: alice,
+                from: TokenHolder::empty(),
+                to: alice,
+                value: 1000u32.into(),
+                fee: 0u32.into(),
+            },
+            InnerOperation::FeeToModify {
+                caller: alice,
+                new_fee_to: carol,
+            },
+            hold(10),
+            InnerOperation::CaptureHold {
+                caller: merchant,
+                hold_id: 2,
+                owner: alice,
+                merchant,
+                amount: 100u32.into(),
+            },
+            InnerOperation::ReleaseHold {
+                caller: merchant,
+                hold_id: 2,
+                owner: alice,
+                amount: 200u32.into(),
+            },
+            // still held
+            hold(20),
+        ]);
+        let mut live_balances = TokenBalances::new();
+        live_balances.credit_balance(&alice, 894u32.into());
+        live_balances.hold_balance(&alice, 300u32.into()).unwrap();
+        live_balances.credit_balance(&merchant, 100u32.into());
+        live_balances.credit_balance(&carol, 6u32.into());
+
+        let mut verifier = ChainVerifier::new();
+        verifier.start(alice, 1);
+        verifier.replay_blocks(&token_id(), &blocks, 2);
+        verifier.finish_replay(Some(blocks[5].hash_with_token_id(&token_id())), 3);
+        verifier.compare_balances(&live_balances, 100, 4);
+        let progress = verifier.progress();
+        assert_eq!(progress.state, ChainVerificationState::Completed);
+        assert!(progress.mismatches.is_empty(), "{:?}", progress.mismatches);
+
+        // the held amount of alice is missing from the live balances
+        let mut live_balances = TokenBalances::new();
+        live_balances.credit_balance(&alice, 1194u32.into());
+        live_balances.credit_balance(&merchant, 100u32.into());
+        live_balances.credit_balance(&carol, 6u32.into());
+        verifier.start(alice, 5);
+        verifier.replay_blocks(&token_id(), &blocks, 6);
+        verifier.finish_replay(Some(blocks[5].hash_with_token_id(&token_id())), 7);
+        verifier.compare_balances(&live_balances, 100, 8);
+        let progress = verifier.progress();
+        assert_eq!(progress.state, ChainVerificationState::Failed);
+        assert!(progress
+            .mismatches
+            .iter()
+            .any(|mismatch| mismatch.reason == "held amount 0 != replayed 300"));
+    }
+
+    #[test]
+    fn test_verify_broken_chain() {
+        let (mut blocks, _) = test_chain();
+        blocks.swap(1, 2);
+        let mut verifier = ChainVerifier::new();
+        verifier.start(alice(), 1);
+        verifier.replay_blocks(&token_id(), &blocks, 2);
+
+        let progress = verifier.progress();
+        assert_eq!(progress.state, ChainVerificationState::Failed);
+        assert_eq!(progress.next_block_height, BlockHeight::from(1u32));
+        assert_eq!(
+            progress.mismatches,
+            vec![InnerChainMismatch {
+                block_height: Some(1u32.into()),
+                account: None,
+                reason: "parent hash mismatch".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_verify_balance_mismatch() {
+        let (blocks, mut live_balances) = test_chain();
+        let bob = holder("o5y7v-htz2q-vk7fc-cqi4m-bqvwa-eth75-sc2wz-ubuev-curf2-rbipe-tae");
+        live_balances.credit_balance(&bob, 1u32.into());
+
+        let progress_memory = VectorMemory::default();
+        let balances_memory = VectorMemory::default();
+        let held_memory = VectorMemory::default();
+        let mut verifier = ChainVerifier::init(
+            progress_memory.clone(),
+            balances_memory.clone(),
+            held_memory.clone(),
+        );
+        verifier.start(alice(), 1);
+        verifier.replay_blocks(&token_id(), &blocks, 2);
+
+        // the verification is resumed from the memories, as after an upgrade
+        let mut verifier = ChainVerifier::init(progress_memory, balances_memory, held_memory);
+        assert_eq!(
+            verifier.progress().next_block_height,
+            BlockHeight::from(3u32)
+        );
+        verifier.finish_replay(Some(blocks[2].hash_with_token_id(&token_id())), 3);
+        verifier.compare_balances(&live_balances, 100, 4);
+
+        let progress = verifier.progress();
+        assert_eq!(progress.state, ChainVerificationState::Failed);
+        assert_eq!(progress.mismatches.len(), 2);
+        assert_eq!(progress.mismatches[0].account, Some(bob));
+        assert_eq!(progress.mismatches[0].reason, "balance 51 != replayed 50");
+        assert_eq!(
+            progress.mismatches[1].reason,
+            "total supply 951 != replayed 950"
+        );
+
+        // a new verification starts over
+        verifier.start(alice(), 5);
+        assert!(verifier.progress().mismatches.is_empty());
+        assert_eq!(
+            verifier.progress().next_block_height,
+            BlockHeight::default()
+        );
+    }
+}
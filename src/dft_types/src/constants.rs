@@ -1,11 +1,57 @@
 pub const BLOCK_ARCHIVE_TRIGGER_THRESHOLD: u32 = 2000;
 pub const BLOCK_ARCHIVE_SIZE: u32 = 1000;
+// interval of the archiving timer (seconds)
+pub const DEFAULT_ARCHIVE_INTERVAL_SECONDS: u64 = 60;
+// upper bound of the delay between two failed archiving attempts (seconds)
+pub const MAX_ARCHIVE_RETRY_BACKOFF_SECONDS: u64 = 24 * 60 * 60;
 pub const MIN_CANISTER_STORAGE_BYTES: u32 = 100 * 1024 * 1024;
 // 3.9GB
 pub const MAX_CANISTER_STORAGE_BYTES: u32 = 4294967295u32 - MIN_CANISTER_STORAGE_BYTES;
 // 2T
 pub const CYCLES_PER_AUTO_SCALING: u64 = 2_000_000_000_000;
+// number of archive canisters storing each archived block range, the first one included
+pub const DEFAULT_ARCHIVE_REPLICATION_FACTOR: u8 = 1;
+pub const MAX_ARCHIVE_REPLICATION_FACTOR: u8 = 3;
+// archive canisters with less cycles are topped up (0.5T)
+pub const DEFAULT_MIN_ARCHIVE_CYCLES: u64 = 500_000_000_000;
+// cycles deposited into an archive canister on each top-up (1T)
+pub const DEFAULT_ARCHIVE_CYCLES_TOP_UP: u64 = 1_000_000_000_000;
+// the token does not top up archives below this balance,
+// so it can still create a new archive canister
+pub const MIN_TOKEN_CYCLES_AFTER_TOP_UP: u64 = CYCLES_PER_AUTO_SCALING;
+// interval of the archive cycles monitoring timer (seconds)
+pub const ARCHIVE_CYCLES_CHECK_INTERVAL_SECONDS: u64 = 6 * 60 * 60;
 pub const MAX_BLOCKS_PER_REQUEST: u32 = 100;
+pub const MAX_ALLOWANCES_PER_REQUEST: usize = 1_000;
+// keeps a revocation block, which lists the revoked spenders, as small as a multi transfer,
+// the owner calls again for the rest
+pub const MAX_REVOKED_ALLOWANCES_PER_CALL: usize = 100;
+// keeps a multi transfer block small enough for MAX_BLOCKS_PER_REQUEST of them in one reply
+pub const MAX_MULTI_TRANSFER_OUTPUTS: usize = 100;
+pub const MAX_BATCH_ITEMS: usize = 500;
+// a hold expires at most 30 days after it is created (nanos)
+pub const MAX_HOLD_DURATION: u64 = 30 * 24 * 60 * 60 * (10u64.pow(9));
+// interval between two releases of the expired holds (seconds)
+pub const HOLD_EXPIRATION_CHECK_INTERVAL_SECONDS: u64 = 60;
+// expired holds released per check, the others wait for the next one
+pub const MAX_EXPIRED_HOLDS_PER_CHECK: usize = 200;
+// the block timestamp index keeps the timestamp of one block out of this many
+pub const BLOCK_TIMESTAMP_INDEX_INTERVAL: u64 = 64;
+// number of blocks compressed together in one archive block frame
+pub const BLOCK_FRAME_SIZE: usize = 64;
+// blocks an archive created before its account index existed indexes per timer round
+pub const ACCOUNT_INDEX_BLOCKS_PER_ROUND: u64 = 5_000;
+// interval of the archive account index catch up timer (seconds)
+pub const ACCOUNT_INDEX_INTERVAL_SECONDS: u64 = 1;
+// blocks replayed per chain verification round, the archived ones are
+// fetched MAX_BLOCKS_PER_REQUEST at a time
+pub const CHAIN_VERIFICATION_BLOCKS_PER_ROUND: u64 = 2_000;
+// balances compared per chain verification round
+pub const CHAIN_VERIFICATION_HOLDERS_PER_ROUND: usize = 2_000;
+// only the first mismatches found by a chain verification are kept
+pub const MAX_CHAIN_VERIFICATION_MISMATCHES: usize = 100;
+// delay before retrying a chain verification round which failed (seconds)
+pub const CHAIN_VERIFICATION_RETRY_SECONDS: u64 = 60;
 pub const MAX_MESSAGE_SIZE_BYTES: u32 = 1024 * 1024 + 8 * 1024 * 1024 / 10;
 pub const DEFAULT_FEE_RATE_DECIMALS: u8 = 8;
 /// The maximum number of transactions that we attempt to purge in one go.
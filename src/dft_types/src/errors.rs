@@ -61,6 +61,54 @@ pub enum DFTError {
     TxIdNotBelongToCurrentDft,
     #[error("DFT_TX: only allow token canister call this function")]
     OnlyAllowTokenCanisterCallThisFunction,
+    #[error("DFT_TX: blocks must be appended from the next block height")]
+    InvalidStartBlockHeight,
+    #[error("DFT_TX: resent blocks do not match the stored blocks")]
+    ResentBlocksDoNotMatch,
+    #[error("DFT_TX: insufficient storage capacity")]
+    InsufficientStorageCapacity,
+    #[error("DFT: archiving is in progress, please retry later")]
+    ArchivingInProgress,
+    #[error("DFT: invalid archive options, {detail}")]
+    InvalidArchiveOptions { detail: String },
+    #[error("DFT: invalid time range, the start timestamp is after the end timestamp")]
+    InvalidTimeRange,
+    #[error("DFT: invalid arg format [account]")]
+    InvalidArgFormatAccount,
+    #[error("DFT: caller is not an authorized operator of the holder")]
+    NotAuthorizedOperator,
+    #[error("DFT: operator expiration must be later than now")]
+    InvalidOperatorExpiration,
+    #[error("DFT: the permit deadline has passed")]
+    PermitExpired,
+    #[error("DFT: invalid permit nonce")]
+    InvalidPermitNonce,
+    #[error("DFT: invalid permit signature, {detail}")]
+    InvalidPermitSignature { detail: String },
+    #[error("DFT: the period of a recurring allowance must be greater than zero")]
+    InvalidRecurringPeriod,
+    #[error("DFT: a multi transfer must have between 1 and 100 outputs")]
+    InvalidMultiTransferOutputs,
+    #[error("DFT: a batch must have at most 500 items")]
+    TooManyBatchItems,
+    #[error("DFT: the batch was not applied because another item is invalid")]
+    BatchAborted,
+    #[error("DFT: hold not found")]
+    HoldNotFound,
+    #[error("DFT: the hold has expired")]
+    HoldExpired,
+    #[error("DFT: only the merchant of the hold is allowed to call it before it expires")]
+    OnlyMerchantAllowCallIt,
+    #[error("DFT: a hold must expire later than now and within 30 days")]
+    InvalidHoldExpiration,
+    #[error("DFT: the amount exceeds the amount still held")]
+    HeldAmountExceeded,
+    #[error("DFT: the amount exceeds 256 bits")]
+    AmountTooLarge,
+    #[error("DFT: the captured amount must be greater than zero")]
+    InvalidCaptureAmount,
+    #[error("DFT: the owner has no allowances to revoke")]
+    NoAllowancesToRevoke,
 
     #[error("Unknown error, detail: {detail:?}")]
     Unknown { detail: String },
@@ -98,6 +146,30 @@ impl DFTError {
             DFTError::InvalidTxId => 27,
             DFTError::TxIdNotBelongToCurrentDft => 28,
             DFTError::OnlyAllowTokenCanisterCallThisFunction => 29,
+            DFTError::InvalidStartBlockHeight => 30,
+            DFTError::ResentBlocksDoNotMatch => 31,
+            DFTError::InsufficientStorageCapacity => 32,
+            DFTError::ArchivingInProgress => 33,
+            DFTError::InvalidArchiveOptions { .. } => 34,
+            DFTError::InvalidTimeRange => 35,
+            DFTError::InvalidArgFormatAccount => 36,
+            DFTError::NotAuthorizedOperator => 37,
+            DFTError::InvalidOperatorExpiration => 38,
+            DFTError::PermitExpired => 39,
+            DFTError::InvalidPermitNonce => 40,
+            DFTError::InvalidPermitSignature { .. } => 41,
+            DFTError::InvalidRecurringPeriod => 42,
+            DFTError::InvalidMultiTransferOutputs => 43,
+            DFTError::TooManyBatchItems => 44,
+            DFTError::BatchAborted => 45,
+            DFTError::HoldNotFound => 46,
+            DFTError::HoldExpired => 47,
+            DFTError::OnlyMerchantAllowCallIt => 48,
+            DFTError::InvalidHoldExpiration => 49,
+            DFTError::HeldAmountExceeded => 50,
+            DFTError::AmountTooLarge => 51,
+            DFTError::InvalidCaptureAmount => 52,
+            DFTError::NoAllowancesToRevoke => 53,
             DFTError::Unknown { .. } => 10000,
         }
     }
@@ -146,6 +218,34 @@ impl From<ErrorInfo> for DFTError {
             27 => DFTError::InvalidTxId,
             28 => DFTError::TxIdNotBelongToCurrentDft,
             29 => DFTError::OnlyAllowTokenCanisterCallThisFunction,
+            30 => DFTError::InvalidStartBlockHeight,
+            31 => DFTError::ResentBlocksDoNotMatch,
+            32 => DFTError::InsufficientStorageCapacity,
+            33 => DFTError::ArchivingInProgress,
+            34 => DFTError::InvalidArchiveOptions {
+                detail: error.message,
+            },
+            35 => DFTError::InvalidTimeRange,
+            36 => DFTError::InvalidArgFormatAccount,
+            37 => DFTError::NotAuthorizedOperator,
+            38 => DFTError::InvalidOperatorExpiration,
+            39 => DFTError::PermitExpired,
+            40 => DFTError::InvalidPermitNonce,
+            41 => DFTError::InvalidPermitSignature {
+                detail: error.message,
+            },
+            42 => DFTError::InvalidRecurringPeriod,
+            43 => DFTError::InvalidMultiTransferOutputs,
+            44 => DFTError::TooManyBatchItems,
+            45 => DFTError::BatchAborted,
+            46 => DFTError::HoldNotFound,
+            47 => DFTError::HoldExpired,
+            48 => DFTError::OnlyMerchantAllowCallIt,
+            49 => DFTError::InvalidHoldExpiration,
+            50 => DFTError::HeldAmountExceeded,
+            51 => DFTError::AmountTooLarge,
+            52 => DFTError::InvalidCaptureAmount,
+            53 => DFTError::NoAllowancesToRevoke,
             _ => DFTError::Unknown {
                 detail: error.message,
             },
@@ -227,6 +327,42 @@ mod tests {
         assert_eq!(DFTError::InvalidTxId.code(), 27);
         assert_eq!(DFTError::TxIdNotBelongToCurrentDft.code(), 28);
         assert_eq!(DFTError::OnlyAllowTokenCanisterCallThisFunction.code(), 29);
+        assert_eq!(DFTError::InvalidStartBlockHeight.code(), 30);
+        assert_eq!(DFTError::ResentBlocksDoNotMatch.code(), 31);
+        assert_eq!(DFTError::InsufficientStorageCapacity.code(), 32);
+        assert_eq!(DFTError::ArchivingInProgress.code(), 33);
+        assert_eq!(
+            DFTError::InvalidArchiveOptions {
+                detail: "test".to_owned()
+            }
+            .code(),
+            34
+        );
+        assert_eq!(DFTError::InvalidTimeRange.code(), 35);
+        assert_eq!(DFTError::InvalidArgFormatAccount.code(), 36);
+        assert_eq!(DFTError::NotAuthorizedOperator.code(), 37);
+        assert_eq!(DFTError::InvalidOperatorExpiration.code(), 38);
+        assert_eq!(DFTError::PermitExpired.code(), 39);
+        assert_eq!(DFTError::InvalidPermitNonce.code(), 40);
+        assert_eq!(
+            DFTError::InvalidPermitSignature {
+                detail: "test".to_owned()
+            }
+            .code(),
+            41
+        );
+        assert_eq!(DFTError::InvalidRecurringPeriod.code(), 42);
+        assert_eq!(DFTError::InvalidMultiTransferOutputs.code(), 43);
+        assert_eq!(DFTError::TooManyBatchItems.code(), 44);
+        assert_eq!(DFTError::BatchAborted.code(), 45);
+        assert_eq!(DFTError::HoldNotFound.code(), 46);
+        assert_eq!(DFTError::HoldExpired.code(), 47);
+        assert_eq!(DFTError::OnlyMerchantAllowCallIt.code(), 48);
+        assert_eq!(DFTError::InvalidHoldExpiration.code(), 49);
+        assert_eq!(DFTError::HeldAmountExceeded.code(), 50);
+        assert_eq!(DFTError::AmountTooLarge.code(), 51);
+        assert_eq!(DFTError::InvalidCaptureAmount.code(), 52);
+        assert_eq!(DFTError::NoAllowancesToRevoke.code(), 53);
         assert_eq!(
             DFTError::Unknown {
                 detail: "test".to_owned()
@@ -342,6 +478,105 @@ mod tests {
             DFTError::OnlyAllowTokenCanisterCallThisFunction.to_string(),
             "DFT_TX: only allow token canister call this function"
         );
+        assert_eq!(
+            DFTError::InvalidStartBlockHeight.to_string(),
+            "DFT_TX: blocks must be appended from the next block height"
+        );
+        assert_eq!(
+            DFTError::ResentBlocksDoNotMatch.to_string(),
+            "DFT_TX: resent blocks do not match the stored blocks"
+        );
+        assert_eq!(
+            DFTError::InsufficientStorageCapacity.to_string(),
+            "DFT_TX: insufficient storage capacity"
+        );
+        assert_eq!(
+            DFTError::ArchivingInProgress.to_string(),
+            "DFT: archiving is in progress, please retry later"
+        );
+        assert_eq!(
+            DFTError::InvalidArchiveOptions {
+                detail: "test".to_owned()
+            }
+            .to_string(),
+            "DFT: invalid archive options, test"
+        );
+        assert_eq!(
+            DFTError::InvalidTimeRange.to_string(),
+            "DFT: invalid time range, the start timestamp is after the end timestamp"
+        );
+        assert_eq!(
+            DFTError::InvalidArgFormatAccount.to_string(),
+            "DFT: invalid arg format [account]"
+        );
+        assert_eq!(
+            DFTError::NotAuthorizedOperator.to_string(),
+            "DFT: caller is not an authorized operator of the holder"
+        );
+        assert_eq!(
+            DFTError::InvalidOperatorExpiration.to_string(),
+            "DFT: operator expiration must be later than now"
+        );
+        assert_eq!(
+            DFTError::PermitExpired.to_string(),
+            "DFT: the permit deadline has passed"
+        );
+        assert_eq!(
+            DFTError::InvalidPermitNonce.to_string(),
+            "DFT: invalid permit nonce"
+        );
+        assert_eq!(
+            DFTError::InvalidPermitSignature {
+                detail: "test".to_owned()
+            }
+            .to_string(),
+            "DFT: invalid permit signature, test"
+        );
+        assert_eq!(
+            DFTError::InvalidRecurringPeriod.to_string(),
+            "DFT: the period of a recurring allowance must be greater than zero"
+        );
+        assert_eq!(
+            DFTError::InvalidMultiTransferOutputs.to_string(),
+            "DFT: a multi transfer must have between 1 and 100 outputs"
+        );
+        assert_eq!(
+            DFTError::TooManyBatchItems.to_string(),
+            "DFT: a batch must have at most 500 items"
+        );
+        assert_eq!(
+            DFTError::BatchAborted.to_string(),
+            "DFT: the batch was not applied because another item is invalid"
+        );
+        assert_eq!(DFTError::HoldNotFound.to_string(), "DFT: hold not found");
+        assert_eq!(
+            DFTError::HoldExpired.to_string(),
+            "DFT: the hold has expired"
+        );
+        assert_eq!(
+            DFTError::OnlyMerchantAllowCallIt.to_string(),
+            "DFT: only the merchant of the hold is allowed to call it before it expires"
+        );
+        assert_eq!(
+            DFTError::InvalidHoldExpiration.to_string(),
+            "DFT: a hold must expire later than now and within 30 days"
+        );
+        assert_eq!(
+            DFTError::HeldAmountExceeded.to_string(),
+            "DFT: the amount exceeds the amount still held"
+        );
+        assert_eq!(
+            DFTError::AmountTooLarge.to_string(),
+            "DFT: the amount exceeds 256 bits"
+        );
+        assert_eq!(
+            DFTError::InvalidCaptureAmount.to_string(),
+            "DFT: the captured amount must be greater than zero"
+        );
+        assert_eq!(
+            DFTError::NoAllowancesToRevoke.to_string(),
+            "DFT: the owner has no allowances to revoke"
+        );
         assert_eq!(
             DFTError::Unknown {
                 detail: "test".to_owned()
@@ -360,7 +595,7 @@ mod tests {
             };
             let dft_error: DFTError = error_info.into();
 
-            if i > 29 {
+            if i > 53 {
                 assert_eq!(dft_error.code(), 10000u32);
             } else {
                 assert_eq!(dft_error.code(), i);
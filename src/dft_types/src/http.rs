@@ -22,17 +22,15 @@ impl HttpRequest {
         let parts: Vec<&str> = url.split('?').collect();
         parts[0].to_string()
     }
-    // get params
+    // get params, the params without a value are skipped
     pub fn params(&self) -> HashMap<String, String> {
-        let url = &self.url;
-        let parts: Vec<&str> = url.split('?').collect();
-        let params: Vec<&str> = parts[1].split('&').collect();
         let mut result = HashMap::new();
-        for param in params {
-            let parts: Vec<&str> = param.split('=').collect();
-            let key = parts[0].to_string();
-            let value = parts[1].to_string();
-            result.insert(key, value);
+        if let Some((_, query)) = self.url.split_once('?') {
+            for param in query.split('&') {
+                if let Some((key, value)) = param.split_once('=') {
+                    result.insert(key.to_string(), value.to_string());
+                }
+            }
         }
         result
     }
@@ -209,4 +207,25 @@ mod tests {
         assert_eq!(result.len(), 1);
         assert_eq!(result["test"], "123");
     }
+
+    #[test]
+    fn test_req_params_without_query() {
+        let req = HttpRequest {
+            method: "".to_string(),
+            url: "/test/path".to_string(),
+            headers: vec![],
+            body: Default::default(),
+        };
+        assert!(req.params().is_empty());
+
+        let req = HttpRequest {
+            method: "".to_string(),
+            url: "/test/path?flag&start=10&".to_string(),
+            headers: vec![],
+            body: Default::default(),
+        };
+        let result = req.params();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result["start"], "10");
+    }
 }
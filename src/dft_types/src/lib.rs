@@ -1,19 +1,26 @@
 #![cfg_attr(coverage_nightly, feature(no_coverage))]
 mod account_identifier;
 mod block;
+mod block_compression;
+mod block_timestamp_index;
 mod blockchain;
+mod chain_verification;
 pub mod constants;
 mod errors;
 mod http;
+mod permit;
+mod recurring_allowance;
 mod stable_state;
 mod token_allowances;
 mod token_archive;
 mod token_balances;
 mod token_description;
 mod token_fee;
+mod token_holds;
 mod token_info;
 mod token_metadata;
 mod token_metrics;
+mod token_operators;
 mod token_response;
 mod token_setting;
 mod token_transaction;
@@ -21,23 +28,30 @@ mod token_transaction_window;
 
 pub use account_identifier::*;
 pub use block::*;
+pub use block_compression::*;
+pub use block_timestamp_index::*;
 pub use blockchain::*;
 use candid::Nat;
 use candid::Principal;
+pub use chain_verification::*;
 pub use errors::*;
 pub use http::*;
 use num_bigint::BigUint;
+pub use permit::*;
+pub use recurring_allowance::*;
 pub use stable_state::*;
 use std::collections::HashMap;
 use std::string::String;
 pub use token_allowances::TokenAllowances;
 pub use token_archive::*;
-pub use token_balances::TokenBalances;
+pub use token_balances::{check_storable_amount, TokenBalances};
 pub use token_description::TokenDescription;
 pub use token_fee::*;
+pub use token_holds::*;
 pub use token_info::TokenInfo;
 pub use token_metadata::*;
 pub use token_metrics::TokenMetrics;
+pub use token_operators::{OperatorInfo, TokenOperators};
 pub use token_response::*;
 pub use token_setting::*;
 pub use token_transaction::*;
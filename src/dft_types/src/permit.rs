@@ -0,0 +1,242 @@
+use std::fmt;
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_stable_structures::{Memory, StableBTreeMap, VectorMemory};
+use serde_bytes::ByteBuf;
+
+use crate::{check_storable_amount, CommonResult, DFTError, Subaccount, TokenHolder};
+
+const PERMIT_DOMAIN: &[u8] = b"dft-permit";
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureScheme {
+    Ed25519,
+    Secp256k1,
+}
+
+// An approval signed off-chain by the key of the owner.
+// The owner is the account of the self-authenticating principal of the key.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Permit {
+    pub scheme: SignatureScheme,
+    // 32 bytes for Ed25519, SEC1 encoded for secp256k1
+    #[serde(rename = "publicKey")]
+    pub public_key: ByteBuf,
+    #[serde(rename = "subAccount")]
+    pub sub_account: Option<Subaccount>,
+    pub spender: TokenHolder,
+    pub value: Nat,
+    pub nonce: u64,
+    // the permit can be applied until this time (nanoseconds)
+    pub deadline: u64,
+    pub signature: ByteBuf,
+}
+
+impl Permit {
+    // The signed message:
+    // "dft-permit" | token id length (1 byte) | token id | sub account (32 bytes, zeros if none)
+    // | spender (28 bytes) | value length (1 byte) | value (big endian) | nonce (8 bytes, big endian)
+    // | deadline (8 bytes, big endian)
+    // The key and the sub account identify the owner.
+    pub fn message(&self, token_id: &Principal) -> Vec<u8> {
+        let value = self.value.0.to_bytes_be();
+        [
+            PERMIT_DOMAIN,
+            &[token_id.as_slice().len() as u8],
+            token_id.as_slice(),
+            &self.sub_account.unwrap_or([0u8; 32]),
+            &self.spender.hash,
+            &[value.len() as u8],
+            &value,
+            &self.nonce.to_be_bytes(),
+            &self.deadline.to_be_bytes(),
+        ]
+        .concat()
+    }
+
+    // Check the signature, returns the signer and the owner account.
+    pub fn verify(&self, token_id: &Principal) -> CommonResult<(Principal, TokenHolder)> {
+        // the value length takes one byte of the message
+        check_storable_amount(&self.value.0)?;
+        let message = self.message(token_id);
+        let signer = match self.scheme {
+            SignatureScheme::Ed25519 => {
+                dft_utils::signature::verify_ed25519(&self.public_key, &message, &self.signature)
+            }
+            SignatureScheme::Secp256k1 => {
+                dft_utils::signature::verify_secp256k1(&self.public_key, &message, &self.signature)
+            }
+        }
+        .map_err(|detail| DFTError::InvalidPermitSignature { detail })?;
+        Ok((signer, TokenHolder::new(signer, self.sub_account)))
+    }
+}
+
+// The next permit nonce of each owner, a permit is only valid with the next nonce.
+pub struct PermitNonces<M: Memory>(StableBTreeMap<TokenHolder, u64, M>);
+
+impl PermitNonces<VectorMemory> {
+    pub fn new() -> Self {
+        Self::init(VectorMemory::default())
+    }
+}
+
+impl Default for PermitNonces<VectorMemory> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Memory> fmt::Debug for PermitNonces<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PermitNonces")
+            .field("owner_size", &self.0.len())
+            .finish()
+    }
+}
+
+impl<M: Memory> PermitNonces<M> {
+    // load the nonces already stored in the memory, if any
+    pub fn init(memory: M) -> Self {
+        PermitNonces(StableBTreeMap::init(memory))
+    }
+
+    pub fn nonce(&self, owner: &TokenHolder) -> u64 {
+        self.0.get(owner).unwrap_or_default()
+    }
+
+    pub fn check(&self, owner: &TokenHolder, nonce: u64) -> CommonResult<()> {
+        if self.nonce(owner) == nonce {
+            Ok(())
+        } else {
+            Err(DFTError::InvalidPermitNonce)
+        }
+    }
+
+    // the permit with the nonce was applied, the next one must use the next nonce
+    pub fn consume(&mut self, owner: &TokenHolder, nonce: u64) -> CommonResult<()> {
+        self.check(owner, nonce)?;
+        self.0.insert(*owner, nonce + 1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token_id() -> Principal {
+        "rrkah-fqaaa-aaaaa-aaaaq-cai".parse().unwrap()
+    }
+
+    fn spender() -> TokenHolder {
+        TokenHolder::new(
+            "o5y7v-htz2q-vk7fc-cqi4m-bqvwa-eth75-sc2wz-ubuev-curf2-rbipe-tae"
+                .parse()
+                .unwrap(),
+            None,
+        )
+    }
+
+    fn permit(scheme: SignatureScheme, public_key: &str, signature: &str) -> Permit {
+        Permit {
+            scheme,
+            public_key: ByteBuf::from(hex::decode(public_key).unwrap()),
+            sub_account: None,
+            spender: spender(),
+            value: 1000u32.into(),
+            nonce: 0,
+            deadline: 1_700_000_000_000_000_000,
+            signature: ByteBuf::from(hex::decode(signature).unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_permit_message() {
+        let permit = permit(SignatureScheme::Ed25519, "", "");
+        assert_eq!(
+            hex::encode(permit.message(&token_id())),
+            format!(
+                "{}0a{}{}{}{}{}{}{}",
+                hex::encode(PERMIT_DOMAIN),
+                "00000000000000010101",
+                "00".repeat(32),
+                hex::encode(spender().hash),
+                "02",
+                "03e8",
+                "0000000000000000",
+                "17979cfe362a0000"
+            )
+        );
+    }
+
+    // signed with the Ed25519 secret key [1; 32] and the secp256k1 secret key [7; 32]
+    const ED25519_PUBLIC_KEY: &str =
+        "8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c";
+    const ED25519_SIGNATURE: &str = "838f755edc2cb4d24aa101e9f755bf6f6fbc75e035e86d5aa23c8958a3816f070e63814d120c952389eb3902d72c27a986d0aa06a5efb9a15b1d78687c11f101";
+    const SECP256K1_PUBLIC_KEY: &str =
+        "02989c0b76cb563971fdc9bef31ec06c3560f3249d6ee9e5d83c57625596e05f6f";
+    const SECP256K1_SIGNATURE: &str = "b72923e4c263c65ca558c6e6890fc8fae689fa79fc7224eee32dc5ab64aea5433ab0cc9d0ba1302bad3601551c5421756b7ebf4ea7d5aea9b435b58696503175";
+
+    #[test]
+    fn test_verify_permit() {
+        for (scheme, public_key, signature) in [
+            (
+                SignatureScheme::Ed25519,
+                ED25519_PUBLIC_KEY,
+                ED25519_SIGNATURE,
+            ),
+            (
+                SignatureScheme::Secp256k1,
+                SECP256K1_PUBLIC_KEY,
+                SECP256K1_SIGNATURE,
+            ),
+        ] {
+            let permit = permit(scheme, public_key, signature);
+            let (signer, owner) = permit.verify(&token_id()).unwrap();
+            assert_eq!(signer.as_slice().len(), 29);
+            assert_eq!(owner, TokenHolder::new(signer, None));
+
+            // any change of the signed fields invalidates the signature
+            let mut changed = permit.clone();
+            changed.value = 1001u32.into();
+            assert!(matches!(
+                changed.verify(&token_id()),
+                Err(DFTError::InvalidPermitSignature { .. })
+            ));
+            let mut changed = permit.clone();
+            changed.sub_account = Some([1u8; 32]);
+            assert!(changed.verify(&token_id()).is_err());
+            let mut changed = permit.clone();
+            changed.nonce = 1;
+            assert!(changed.verify(&token_id()).is_err());
+            let mut changed = permit.clone();
+            changed.scheme = match scheme {
+                SignatureScheme::Ed25519 => SignatureScheme::Secp256k1,
+                SignatureScheme::Secp256k1 => SignatureScheme::Ed25519,
+            };
+            assert!(changed.verify(&token_id()).is_err());
+            // signed for another token
+            assert!(permit
+                .verify(&"ryjl3-tyaaa-aaaaa-aaaba-cai".parse().unwrap())
+                .is_err());
+            // a value longer than a balance is rejected before the signature
+            let mut changed = permit.clone();
+            changed.value = Nat(crate::TokenAmount::from(1u32) << (8 * 256));
+            assert_eq!(changed.verify(&token_id()), Err(DFTError::AmountTooLarge));
+        }
+    }
+
+    #[test]
+    fn test_permit_nonces() {
+        let mut nonces = PermitNonces::new();
+        let owner = spender();
+        assert_eq!(nonces.nonce(&owner), 0);
+        assert_eq!(nonces.consume(&owner, 1), Err(DFTError::InvalidPermitNonce));
+        nonces.consume(&owner, 0).unwrap();
+        assert_eq!(nonces.nonce(&owner), 1);
+        // a permit cannot be replayed
+        assert_eq!(nonces.consume(&owner, 0), Err(DFTError::InvalidPermitNonce));
+        nonces.check(&owner, 1).unwrap();
+    }
+}
@@ -0,0 +1,164 @@
+use std::borrow::Cow;
+
+use candid::{CandidType, Deserialize, Nat};
+use ic_stable_structures::{BoundedStorable, Storable};
+use serde::Serialize;
+
+use crate::{CommonResult, DFTError, TokenAmount};
+
+// An allowance of at most `amount_per_period` per period, the periods start at `start`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct InnerRecurringAllowance {
+    pub amount_per_period: TokenAmount,
+    // nanoseconds
+    pub period: u64,
+    pub start: u64,
+    // the amount spent in the period `spent_period`
+    spent: TokenAmount,
+    spent_period: u64,
+}
+
+impl InnerRecurringAllowance {
+    pub fn new(amount_per_period: TokenAmount, period: u64, start: u64) -> Self {
+        assert!(period > 0);
+        InnerRecurringAllowance {
+            amount_per_period,
+            period,
+            start,
+            spent: TokenAmount::default(),
+            spent_period: 0,
+        }
+    }
+
+    // the index of the period at `now`, none before the start
+    fn current_period(&self, now: u64) -> Option<u64> {
+        now.checked_sub(self.start)
+            .map(|elapsed| elapsed / self.period)
+    }
+
+    pub fn remaining(&self, now: u64) -> TokenAmount {
+        match self.current_period(now) {
+            None => TokenAmount::default(),
+            Some(period) if period == self.spent_period => {
+                if self.spent < self.amount_per_period {
+                    self.amount_per_period.clone() - self.spent.clone()
+                } else {
+                    TokenAmount::default()
+                }
+            }
+            Some(_) => self.amount_per_period.clone(),
+        }
+    }
+
+    // when the remaining amount is reset to `amount_per_period`
+    pub fn next_reset_at(&self, now: u64) -> u64 {
+        match self.current_period(now) {
+            None => self.start,
+            Some(period) => self
+                .start
+                .saturating_add((period + 1).saturating_mul(self.period)),
+        }
+    }
+
+    pub fn spend(&mut self, value: TokenAmount, now: u64) -> CommonResult<()> {
+        if self.remaining(now) < value {
+            return Err(DFTError::InsufficientAllowance);
+        }
+        // remaining is zero before the start
+        let period = self.current_period(now).unwrap_or_default();
+        if period != self.spent_period {
+            self.spent = TokenAmount::default();
+            self.spent_period = period;
+        }
+        self.spent += value;
+        Ok(())
+    }
+
+    pub fn to_candid(&self, now: u64) -> RecurringAllowance {
+        RecurringAllowance {
+            amount_per_period: self.amount_per_period.clone().into(),
+            period: self.period,
+            start: self.start,
+            remaining: self.remaining(now).into(),
+            next_reset_at: self.next_reset_at(now),
+        }
+    }
+}
+
+impl Storable for InnerRecurringAllowance {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(bincode::serialize(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        bincode::deserialize(&bytes).unwrap()
+    }
+}
+
+// two amounts of at most 256 bits (8 bytes length + 8 digits of 4 bytes) and three u64
+impl BoundedStorable for InnerRecurringAllowance {
+    const MAX_SIZE: u32 = 2 * (8 + 8 * 4) + 3 * 8;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct RecurringAllowance {
+    #[serde(rename = "amountPerPeriod")]
+    pub amount_per_period: Nat,
+    pub period: u64,
+    pub start: u64,
+    // what can still be spent in the current period
+    pub remaining: Nat,
+    #[serde(rename = "nextResetAt")]
+    pub next_reset_at: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recurring_allowance_periods() {
+        let mut allowance = InnerRecurringAllowance::new(100u32.into(), 30, 1000);
+        // not started yet
+        assert_eq!(allowance.remaining(999), 0u32.into());
+        assert_eq!(allowance.next_reset_at(999), 1000);
+        assert_eq!(
+            allowance.spend(1u32.into(), 999),
+            Err(DFTError::InsufficientAllowance)
+        );
+
+        allowance.spend(60u32.into(), 1000).unwrap();
+        allowance.spend(40u32.into(), 1029).unwrap();
+        assert_eq!(allowance.remaining(1029), 0u32.into());
+        assert_eq!(allowance.next_reset_at(1029), 1030);
+        assert_eq!(
+            allowance.spend(1u32.into(), 1029),
+            Err(DFTError::InsufficientAllowance)
+        );
+
+        // the window resets, unspent amounts do not roll over
+        assert_eq!(allowance.remaining(1030), 100u32.into());
+        allowance.spend(10u32.into(), 1095).unwrap();
+        assert_eq!(allowance.remaining(1095), 90u32.into());
+        assert_eq!(allowance.next_reset_at(1095), 1120);
+        assert_eq!(allowance.remaining(1120), 100u32.into());
+
+        let candid = allowance.to_candid(1100);
+        assert_eq!(candid.remaining, 90u32);
+        assert_eq!(candid.next_reset_at, 1120);
+    }
+
+    #[test]
+    fn test_recurring_allowance_storable() {
+        let mut allowance = InnerRecurringAllowance::new(
+            TokenAmount::from_bytes_le(&[u8::MAX; 32]),
+            u64::MAX,
+            u64::MAX,
+        );
+        allowance.spend(1u32.into(), u64::MAX).unwrap();
+        let bytes = allowance.to_bytes();
+        assert!(bytes.len() <= InnerRecurringAllowance::MAX_SIZE as usize);
+        assert_eq!(InnerRecurringAllowance::from_bytes(bytes), allowance);
+    }
+}
@@ -1,4 +1,138 @@
+use std::convert::TryInto;
+
 pub trait StableState: Sized {
     fn encode(&self) -> Vec<u8>;
     fn decode(bytes: Vec<u8>) -> Result<Self, String>;
 }
+
+// Blobs saved on upgrade start with this magic followed by the version of
+// their layout (u32, little endian). Blobs written before the layout was
+// versioned have no header, they are version 1.
+const VERSION_HEADER_MAGIC: &[u8; 4] = b"DFTV";
+const VERSION_HEADER_SIZE: usize = 8;
+
+/// Upgrades the payload of a stable blob from one version to the next one.
+/// Migrations receive the state being restored, so that data which no longer
+/// belongs to the blob (e.g. moved to stable structures) can be handed over.
+pub type StableStateMigration<S> = fn(&mut S, Vec<u8>) -> Result<Vec<u8>, String>;
+
+/// The versions of a stable blob layout and how to migrate between them.
+pub struct StableStateSchema<S: 'static> {
+    // migrations[i] upgrades version i + 1 to version i + 2,
+    // the current version is the one produced by the last migration
+    migrations: &'static [StableStateMigration<S>],
+}
+
+impl<S> StableStateSchema<S> {
+    pub const fn new(migrations: &'static [StableStateMigration<S>]) -> Self {
+        StableStateSchema { migrations }
+    }
+
+    pub fn current_version(&self) -> u32 {
+        self.migrations.len() as u32 + 1
+    }
+
+    // prefix a payload of the current version with the version header
+    pub fn encode(&self, payload: Vec<u8>) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(VERSION_HEADER_SIZE + payload.len());
+        bytes.extend_from_slice(VERSION_HEADER_MAGIC);
+        bytes.extend_from_slice(&self.current_version().to_le_bytes());
+        bytes.extend(payload);
+        bytes
+    }
+
+    // the version of a blob and its payload
+    pub fn split_version(bytes: Vec<u8>) -> (u32, Vec<u8>) {
+        if bytes.len() >= VERSION_HEADER_SIZE && &bytes[..4] == VERSION_HEADER_MAGIC {
+            let version = u32::from_le_bytes(bytes[4..VERSION_HEADER_SIZE].try_into().unwrap());
+            (version, bytes[VERSION_HEADER_SIZE..].to_vec())
+        } else {
+            (1, bytes)
+        }
+    }
+
+    // Run the migrations from the version of the blob up to the current one,
+    // returns the payload in the current layout.
+    pub fn migrate(&self, state: &mut S, bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+        let (version, mut payload) = Self::split_version(bytes);
+        let current_version = self.current_version();
+        if version == 0 || version > current_version {
+            return Err(format!(
+                "unsupported stable state version {}, the supported versions are 1 to {}",
+                version, current_version
+            ));
+        }
+        for (i, migration) in self.migrations[(version - 1) as usize..].iter().enumerate() {
+            let from_version = version + i as u32;
+            payload = migration(state, payload).map_err(|e| {
+                format!(
+                    "migrate stable state from version {} to {} failed, {}",
+                    from_version,
+                    from_version + 1,
+                    e
+                )
+            })?;
+        }
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the state records the versions it was migrated through
+    fn v1_to_v2(state: &mut Vec<u32>, payload: Vec<u8>) -> Result<Vec<u8>, String> {
+        state.push(1);
+        Ok([payload, b"-v2".to_vec()].concat())
+    }
+
+    fn v2_to_v3(state: &mut Vec<u32>, payload: Vec<u8>) -> Result<Vec<u8>, String> {
+        state.push(2);
+        if payload.is_empty() {
+            return Err("empty payload".to_string());
+        }
+        Ok([payload, b"-v3".to_vec()].concat())
+    }
+
+    const SCHEMA: StableStateSchema<Vec<u32>> = StableStateSchema::new(&[v1_to_v2, v2_to_v3]);
+
+    #[test]
+    fn test_blob_without_header_is_version_1() {
+        let mut state = vec![];
+        let payload = SCHEMA.migrate(&mut state, b"v1".to_vec()).unwrap();
+        assert_eq!(payload, b"v1-v2-v3".to_vec());
+        assert_eq!(state, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_current_version_is_not_migrated() {
+        assert_eq!(SCHEMA.current_version(), 3);
+        let bytes = SCHEMA.encode(b"v3".to_vec());
+        assert_eq!(
+            StableStateSchema::<Vec<u32>>::split_version(bytes.clone()),
+            (3, b"v3".to_vec())
+        );
+
+        let mut state = vec![];
+        assert_eq!(SCHEMA.migrate(&mut state, bytes).unwrap(), b"v3".to_vec());
+        assert!(state.is_empty());
+    }
+
+    #[test]
+    fn test_migrate_errors_are_readable() {
+        let newer = [&VERSION_HEADER_MAGIC[..], &4u32.to_le_bytes()[..], b"v4"].concat();
+        assert_eq!(
+            SCHEMA.migrate(&mut vec![], newer),
+            Err(
+                "unsupported stable state version 4, the supported versions are 1 to 3".to_string()
+            )
+        );
+
+        let empty_v2 = [&VERSION_HEADER_MAGIC[..], &2u32.to_le_bytes()[..]].concat();
+        assert_eq!(
+            SCHEMA.migrate(&mut vec![], empty_v2),
+            Err("migrate stable state from version 2 to 3 failed, empty payload".to_string())
+        );
+    }
+}
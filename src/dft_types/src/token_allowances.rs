@@ -1,43 +1,173 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::ops::Bound;
 
-use candid::Deserialize;
+use ic_stable_structures::{BoundedStorable, Memory, StableBTreeMap, Storable, VectorMemory};
 use num_traits::CheckedSub;
-use serde::Serialize;
 
-use crate::{CommonResult, DFTError, StableState, TokenAmount, TokenHolder};
+use crate::recurring_allowance::InnerRecurringAllowance;
+use crate::token_balances::StableTokenAmount;
+use crate::{AccountIdentifier, CommonResult, DFTError, TokenAmount, TokenHolder};
 
-#[derive(Clone, Default, Debug, Deserialize, Serialize)]
-pub struct TokenAllowances(HashMap<TokenHolder, HashMap<TokenHolder, TokenAmount>>);
+// Allowances are keyed by (owner, spender), so all the allowances
+// of an owner are adjacent and can be listed with a range scan.
+// The spender index uses the same key with the two accounts swapped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct AllowanceKey {
+    owner: TokenHolder,
+    spender: TokenHolder,
+}
 
-impl TokenAllowances {
-    pub fn new() -> Self {
-        TokenAllowances(HashMap::new())
+impl AllowanceKey {
+    fn first_of(owner: &TokenHolder) -> Self {
+        AllowanceKey {
+            owner: *owner,
+            spender: AccountIdentifier { hash: [0; 28] },
+        }
     }
 
-    pub fn allowance_size(&self) -> usize {
-        match self.0.len() {
-            0 => 0,
-            _ => self.0.values().map(|v| v.len()).sum(),
+    fn last_of(owner: &TokenHolder) -> Self {
+        AllowanceKey {
+            owner: *owner,
+            spender: AccountIdentifier {
+                hash: [u8::MAX; 28],
+            },
         }
     }
 
-    pub fn allowance(&self, owner: &TokenHolder, spender: &TokenHolder) -> TokenAmount {
-        if let Some(allowances) = self.0.get(owner) {
-            if let Some(amount) = allowances.get(spender) {
-                return amount.clone();
-            }
+    fn swapped(&self) -> Self {
+        AllowanceKey {
+            owner: self.spender,
+            spender: self.owner,
         }
-        TokenAmount::from(0u32)
     }
+}
+
+impl Storable for AllowanceKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned([self.owner.to_bytes(), self.spender.to_bytes()].concat())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let size = TokenHolder::MAX_SIZE as usize;
+        AllowanceKey {
+            owner: TokenHolder::from_bytes(Cow::Borrowed(&bytes[..size])),
+            spender: TokenHolder::from_bytes(Cow::Borrowed(&bytes[size..])),
+        }
+    }
+}
 
-    pub fn allowances_of(&self, owner: &TokenHolder) -> Vec<(TokenHolder, TokenAmount)> {
-        let mut vec = Vec::new();
-        if let Some(allowances) = self.0.get(owner) {
-            for (spender, amount) in allowances {
-                vec.push((*spender, amount.clone()));
+impl BoundedStorable for AllowanceKey {
+    const MAX_SIZE: u32 = 2 * TokenHolder::MAX_SIZE;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+pub struct TokenAllowances<M: Memory> {
+    allowances: StableBTreeMap<AllowanceKey, StableTokenAmount, M>,
+    // (spender, owner) of every allowance
+    by_spender: StableBTreeMap<AllowanceKey, (), M>,
+    // allowances of at most an amount per period, which take precedence over
+    // the one-shot allowance of the same (owner, spender)
+    recurring: StableBTreeMap<AllowanceKey, InnerRecurringAllowance, M>,
+}
+
+impl TokenAllowances<VectorMemory> {
+    pub fn new() -> Self {
+        Self::init(
+            VectorMemory::default(),
+            VectorMemory::default(),
+            VectorMemory::default(),
+        )
+    }
+}
+
+impl Default for TokenAllowances<VectorMemory> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Memory> fmt::Debug for TokenAllowances<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenAllowances")
+            .field("allowance_size", &self.allowance_size())
+            .finish()
+    }
+}
+
+impl<M: Memory> TokenAllowances<M> {
+    // Load the allowances already stored in the memories, if any.
+    // The spender index is built on the first load after it was added.
+    pub fn init(memory: M, by_spender_memory: M, recurring_memory: M) -> Self {
+        let mut allowances = TokenAllowances {
+            allowances: StableBTreeMap::init(memory),
+            by_spender: StableBTreeMap::init(by_spender_memory),
+            recurring: StableBTreeMap::init(recurring_memory),
+        };
+        if allowances.by_spender.is_empty() {
+            for (key, _) in allowances.allowances.iter() {
+                allowances.by_spender.insert(key.swapped(), ());
             }
         }
-        vec
+        allowances
+    }
+
+    pub fn allowance_size(&self) -> usize {
+        self.allowances.len() as usize
+    }
+
+    pub fn allowance(&self, owner: &TokenHolder, spender: &TokenHolder) -> TokenAmount {
+        let key = AllowanceKey {
+            owner: *owner,
+            spender: *spender,
+        };
+        match self.allowances.get(&key) {
+            Some(amount) => amount.0,
+            None => TokenAmount::from(0u32),
+        }
+    }
+
+    // the allowances given by the owner, the first `limit` spenders after the cursor
+    pub fn allowances_of(
+        &self,
+        owner: &TokenHolder,
+        cursor: Option<&TokenHolder>,
+        limit: usize,
+    ) -> Vec<(TokenHolder, TokenAmount)> {
+        self.allowances
+            .range(Self::range_after(owner, cursor))
+            .take(limit)
+            .map(|(key, amount)| (key.spender, amount.0))
+            .collect()
+    }
+
+    // the allowances given to the spender, the first `limit` owners after the cursor
+    pub fn allowances_by_spender(
+        &self,
+        spender: &TokenHolder,
+        cursor: Option<&TokenHolder>,
+        limit: usize,
+    ) -> Vec<(TokenHolder, TokenAmount)> {
+        self.by_spender
+            .range(Self::range_after(spender, cursor))
+            .take(limit)
+            .map(|(key, _)| (key.spender, self.allowance(&key.spender, &key.owner)))
+            .collect()
+    }
+
+    fn range_after(
+        account: &TokenHolder,
+        cursor: Option<&TokenHolder>,
+    ) -> (Bound<AllowanceKey>, Bound<AllowanceKey>) {
+        let start = match cursor {
+            Some(cursor) => Bound::Excluded(AllowanceKey {
+                owner: *account,
+                spender: *cursor,
+            }),
+            None => Bound::Included(AllowanceKey::first_of(account)),
+        };
+        (start, Bound::Included(AllowanceKey::last_of(account)))
     }
 
     //debit token holder's allowance
@@ -54,59 +184,151 @@ impl TokenAllowances {
             return Err(DFTError::InsufficientAllowance);
         }
         let new_spender_allowance = spender_allowance.checked_sub(&value).unwrap();
-        if let Some(inner) = self.0.get(owner) {
-            let mut temp = inner.clone();
-            if new_spender_allowance == TokenAmount::from(0u32) {
-                temp.remove(spender);
-                if temp.is_empty() {
-                    self.0.remove(owner);
-                } else {
-                    self.0.insert(*owner, temp);
-                }
-            } else {
-                temp.insert(*spender, new_spender_allowance);
-                self.0.insert(*owner, temp);
-            }
-        };
+        self.credit(owner, spender, new_spender_allowance);
         Ok(())
     }
 
     // credit token spender's allowance
+    // The amount is updated in place, the spender index is only written
+    // when the allowance is created or removed.
     pub fn credit(&mut self, owner: &TokenHolder, spender: &TokenHolder, value: TokenAmount) {
-        match self.0.get(owner) {
-            Some(inner) => {
-                let mut temp = inner.clone();
-                if value == TokenAmount::from(0u32) {
-                    temp.remove(spender);
-                    if temp.is_empty() {
-                        self.0.remove(owner);
-                    } else {
-                        self.0.insert(*owner, temp);
-                    }
-                } else {
-                    temp.insert(*spender, value);
-                    self.0.insert(*owner, temp);
-                }
+        let key = AllowanceKey {
+            owner: *owner,
+            spender: *spender,
+        };
+        if value == TokenAmount::from(0u32) {
+            if self.allowances.remove(&key).is_some() {
+                self.by_spender.remove(&key.swapped());
             }
-            None => {
-                if value > TokenAmount::from(0u32) {
-                    let mut inner = HashMap::new();
-                    inner.insert(*spender, value);
-                    self.0.insert(*owner, inner);
-                }
+        } else if self
+            .allowances
+            .insert(key, StableTokenAmount(value))
+            .is_none()
+        {
+            self.by_spender.insert(key.swapped(), ());
+        }
+    }
+
+    pub fn increase(&mut self, owner: &TokenHolder, spender: &TokenHolder, value: TokenAmount) {
+        let allowance = self.allowance(owner, spender);
+        self.credit(owner, spender, allowance + value);
+    }
+
+    // decrease the allowance, saturating at zero
+    pub fn decrease(&mut self, owner: &TokenHolder, spender: &TokenHolder, value: TokenAmount) {
+        let allowance = self.allowance(owner, spender);
+        self.credit(
+            owner,
+            spender,
+            allowance.checked_sub(&value).unwrap_or_default(),
+        );
+    }
+
+    // The first `limit` spenders of the owner, with a one-shot or a recurring
+    // allowance, and whether the owner has more.
+    pub fn spenders_of(&self, owner: &TokenHolder, limit: usize) -> (Vec<TokenHolder>, bool) {
+        let range = AllowanceKey::first_of(owner)..=AllowanceKey::last_of(owner);
+        // the first spenders of the union are among the first ones of each map
+        let keys: BTreeSet<AllowanceKey> = self
+            .allowances
+            .range(range.clone())
+            .take(limit.saturating_add(1))
+            .map(|(key, _)| key)
+            .chain(
+                self.recurring
+                    .range(range)
+                    .take(limit.saturating_add(1))
+                    .map(|(key, _)| key),
+            )
+            .collect();
+        let has_more = keys.len() > limit;
+        let spenders = keys
+            .into_iter()
+            .take(limit)
+            .map(|key| key.spender)
+            .collect();
+        (spenders, has_more)
+    }
+
+    // remove the one-shot and recurring allowances of the spenders
+    pub fn revoke(&mut self, owner: &TokenHolder, spenders: &[TokenHolder]) {
+        for spender in spenders {
+            let key = AllowanceKey {
+                owner: *owner,
+                spender: *spender,
+            };
+            if self.allowances.remove(&key).is_some() {
+                self.by_spender.remove(&key.swapped());
             }
+            self.recurring.remove(&key);
+        }
+    }
+
+    pub fn recurring_allowance(
+        &self,
+        owner: &TokenHolder,
+        spender: &TokenHolder,
+    ) -> Option<InnerRecurringAllowance> {
+        self.recurring.get(&AllowanceKey {
+            owner: *owner,
+            spender: *spender,
+        })
+    }
+
+    // set the recurring allowance of the spender, none removes it
+    pub fn set_recurring(
+        &mut self,
+        owner: &TokenHolder,
+        spender: &TokenHolder,
+        allowance: Option<InnerRecurringAllowance>,
+    ) {
+        let key = AllowanceKey {
+            owner: *owner,
+            spender: *spender,
+        };
+        match allowance {
+            Some(allowance) => self.recurring.insert(key, allowance),
+            None => self.recurring.remove(&key),
         };
     }
 
+    // what the spender can spend now: the remainder of the current period
+    // if it has a recurring allowance, the one-shot allowance otherwise
+    pub fn spendable(&self, owner: &TokenHolder, spender: &TokenHolder, now: u64) -> TokenAmount {
+        match self.recurring_allowance(owner, spender) {
+            Some(recurring) => recurring.remaining(now),
+            None => self.allowance(owner, spender),
+        }
+    }
+
+    // debit the allowance used by `spendable`
+    pub fn spend(
+        &mut self,
+        owner: &TokenHolder,
+        spender: &TokenHolder,
+        value: TokenAmount,
+        now: u64,
+    ) -> CommonResult<()> {
+        match self.recurring_allowance(owner, spender) {
+            Some(mut recurring) => {
+                recurring.spend(value, now)?;
+                self.set_recurring(owner, spender, Some(recurring));
+                Ok(())
+            }
+            None => self.debit(owner, spender, value),
+        }
+    }
+
     // to vec
     pub fn to_vec(&self) -> Vec<(TokenHolder, Vec<(TokenHolder, TokenAmount)>)> {
-        let mut allowances = Vec::new();
-        for (th, v) in self.0.iter() {
-            let mut allow_item = Vec::new();
-            for (sp, val) in v.iter() {
-                allow_item.push((*sp, val.clone()));
+        let mut allowances: Vec<(TokenHolder, Vec<(TokenHolder, TokenAmount)>)> = Vec::new();
+        for (key, amount) in self.allowances.iter() {
+            match allowances.last_mut() {
+                Some((owner, allow_item)) if *owner == key.owner => {
+                    allow_item.push((key.spender, amount.0))
+                }
+                _ => allowances.push((key.owner, vec![(key.spender, amount.0)])),
             }
-            allowances.push((*th, allow_item));
         }
         allowances
     }
@@ -116,35 +338,146 @@ impl TokenAllowances {
         &mut self,
         allowances: Vec<(TokenHolder, Vec<(TokenHolder, TokenAmount)>)>,
     ) {
-        for (th, v) in allowances.iter() {
-            let mut allow_item = HashMap::new();
-            for (sp, val) in v.iter() {
-                allow_item.insert(*sp, val.clone());
+        for (th, v) in allowances.into_iter() {
+            for (sp, val) in v.into_iter() {
+                self.credit(&th, &sp, val);
             }
-            self.0.insert(*th, allow_item);
         }
     }
-}
-
-impl StableState for TokenAllowances {
-    fn encode(&self) -> Vec<u8> {
-        bincode::serialize(&self.0).unwrap()
-    }
 
-    fn decode(bytes: Vec<u8>) -> Result<Self, String> {
+    // Move the allowances saved in the heap format
+    // (bincode of the nested owner -> spender map) into the stable map.
+    pub fn migrate_from_heap_format(&mut self, bytes: Vec<u8>) -> Result<(), String> {
+        assert!(self.allowances.is_empty());
         let allowances: HashMap<TokenHolder, HashMap<TokenHolder, TokenAmount>> =
-            bincode::deserialize(&bytes).unwrap();
-
-        Ok(TokenAllowances(allowances))
+            bincode::deserialize(&bytes)
+                .map_err(|e| format!("decode heap allowances failed, {}", e))?;
+        for (owner, spenders) in allowances {
+            for (spender, value) in spenders {
+                self.credit(&owner, &spender, value);
+            }
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
     use crate::{TokenAmount, TokenHolder};
 
     use super::*;
 
+    // counts the bytes read from and written to the stable memory
+    #[derive(Clone, Default)]
+    struct CountingMemory {
+        memory: VectorMemory,
+        bytes: Rc<Cell<u64>>,
+    }
+
+    impl Memory for CountingMemory {
+        fn size(&self) -> u64 {
+            self.memory.size()
+        }
+
+        fn grow(&self, pages: u64) -> i64 {
+            self.memory.grow(pages)
+        }
+
+        fn read(&self, offset: u64, dst: &mut [u8]) {
+            self.bytes.set(self.bytes.get() + dst.len() as u64);
+            self.memory.read(offset, dst)
+        }
+
+        fn write(&self, offset: u64, src: &[u8]) {
+            self.bytes.set(self.bytes.get() + src.len() as u64);
+            self.memory.write(offset, src)
+        }
+    }
+
+    fn nth_holder(n: u32) -> TokenHolder {
+        let mut hash = [0u8; 28];
+        hash[..4].copy_from_slice(&n.to_be_bytes());
+        AccountIdentifier { hash }
+    }
+
+    // bytes accessed by a debit, a credit of an existing allowance and
+    // a credit of a new one, for an owner with `num_spenders` spenders
+    fn allowance_update_traffic(num_spenders: u32) -> (u64, u64, u64, u64) {
+        let memory = CountingMemory::default();
+        let by_spender_memory = CountingMemory::default();
+        let mut allowances = TokenAllowances::init(
+            memory.clone(),
+            by_spender_memory.clone(),
+            CountingMemory::default(),
+        );
+        let owner = nth_holder(u32::MAX);
+        for n in 0..num_spenders {
+            allowances.credit(&owner, &nth_holder(n), 1_000u32.into());
+        }
+        let spender = nth_holder(num_spenders / 2);
+        let reset = || {
+            memory.bytes.set(0);
+            by_spender_memory.bytes.set(0);
+        };
+
+        reset();
+        allowances.debit(&owner, &spender, 1u32.into()).unwrap();
+        let debit = memory.bytes.get() + by_spender_memory.bytes.get();
+        reset();
+        allowances.credit(&owner, &spender, 10u32.into());
+        let update = memory.bytes.get();
+        let update_index = by_spender_memory.bytes.get();
+        reset();
+        allowances.credit(&owner, &nth_holder(num_spenders), 10u32.into());
+        let create = memory.bytes.get() + by_spender_memory.bytes.get();
+        (debit, update, update_index, create)
+    }
+
+    #[test]
+    fn test_allowance_update_memory_traffic_does_not_grow_with_spenders() {
+        let (small_debit, small_update, _, small_create) = allowance_update_traffic(500);
+        let (large_debit, large_update, large_update_index, large_create) =
+            allowance_update_traffic(5_000);
+        // only the path from the root to the entry is read, so 10 times
+        // more spenders cost far less than 10 times more
+        assert!(large_debit < 2 * small_debit);
+        assert!(large_update < 2 * small_update);
+        assert!(large_create < 2 * small_create);
+        // updating an existing allowance leaves the spender index alone
+        assert_eq!(large_update_index, 0);
+    }
+
+    #[test]
+    fn test_to_vec_keeps_the_stable_format() {
+        let owner = nth_holder(1);
+        let spender = nth_holder(2);
+        let mut allowances = TokenAllowances::new();
+        allowances.credit(&owner, &spender, 100u32.into());
+        allowances.credit(&owner, &owner, 1u32.into());
+        allowances.credit(&spender, &owner, 5u32.into());
+
+        // the layout of the upgrade snapshots: owner -> [(spender, amount)]
+        let snapshot: Vec<(TokenHolder, Vec<(TokenHolder, TokenAmount)>)> = vec![
+            (owner, vec![(owner, 1u32.into()), (spender, 100u32.into())]),
+            (spender, vec![(owner, 5u32.into())]),
+        ];
+        assert_eq!(
+            bincode::serialize(&allowances.to_vec()).unwrap(),
+            bincode::serialize(&snapshot).unwrap()
+        );
+
+        let mut restored = TokenAllowances::new();
+        restored.restore_from(snapshot);
+        assert_eq!(restored.to_vec(), allowances.to_vec());
+        assert_eq!(
+            restored.allowances_by_spender(&owner, None, usize::MAX),
+            vec![(owner, 1u32.into()), (spender, 5u32.into())]
+        );
+    }
+
     #[test]
     fn test_token_allowances() {
         let mut allowances = TokenAllowances::new();
@@ -165,7 +498,7 @@ mod tests {
         assert_eq!(allowances.allowance(&owner, &spender), value);
         assert_eq!(allowances.allowance_size(), 1);
         assert_eq!(
-            allowances.allowances_of(&owner),
+            allowances.allowances_of(&owner, None, usize::MAX),
             vec![(spender, value.clone())]
         );
 
@@ -175,7 +508,7 @@ mod tests {
         assert_eq!(allowances.allowance_size(), 1);
         assert_eq!(allowances.to_vec().len(), 1);
         assert_eq!(
-            allowances.allowances_of(&owner),
+            allowances.allowances_of(&owner, None, usize::MAX),
             vec![(spender, value.clone())]
         );
 
@@ -185,11 +518,183 @@ mod tests {
             TokenAmount::from(0u32)
         );
         assert_eq!(allowances.allowance_size(), 0, "{:?}", allowances.to_vec());
-        assert_eq!(allowances.allowances_of(&owner), vec![]);
+        assert_eq!(allowances.allowances_of(&owner, None, usize::MAX), vec![]);
         assert_eq!(allowances.to_vec().len(), 0);
     }
     #[test]
-    fn test_decode_encode() {
+    fn test_increase_decrease_revoke_all() {
+        let mut allowances = TokenAllowances::new();
+        let owner = TokenHolder::new(
+            "qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe"
+                .parse()
+                .unwrap(),
+            None,
+        );
+        let spender = TokenHolder::new(
+            "o5y7v-htz2q-vk7fc-cqi4m-bqvwa-eth75-sc2wz-ubuev-curf2-rbipe-tae"
+                .parse()
+                .unwrap(),
+            None,
+        );
+        let other_owner = TokenHolder::new(
+            "czjfo-ddpvm-6sibl-6zbox-ee5zq-bx3hc-e336t-s6pka-dupmy-wcxqi-fae"
+                .parse()
+                .unwrap(),
+            None,
+        );
+        allowances.increase(&owner, &spender, 100u32.into());
+        allowances.increase(&owner, &spender, 50u32.into());
+        assert_eq!(allowances.allowance(&owner, &spender), 150u32.into());
+
+        allowances.decrease(&owner, &spender, 30u32.into());
+        assert_eq!(allowances.allowance(&owner, &spender), 120u32.into());
+        allowances.decrease(&owner, &spender, 1000u32.into());
+        assert_eq!(allowances.allowance(&owner, &spender), 0u32.into());
+        assert_eq!(allowances.allowance_size(), 0);
+
+        allowances.credit(&owner, &spender, 10u32.into());
+        allowances.credit(&owner, &other_owner, 20u32.into());
+        allowances.credit(&other_owner, &owner, 30u32.into());
+        let recurring = InnerRecurringAllowance::new(40u32.into(), 1000, 0);
+        allowances.set_recurring(&owner, &spender, Some(recurring.clone()));
+        allowances.set_recurring(&other_owner, &spender, Some(recurring));
+        assert_eq!(allowances.spendable(&owner, &spender, 0), 40u32.into());
+        // a spender counts once, whatever allowances it has
+        let mut spenders = vec![spender, other_owner];
+        spenders.sort();
+        assert_eq!(
+            allowances.spenders_of(&owner, 1),
+            (spenders[..1].to_vec(), true)
+        );
+        assert_eq!(allowances.spenders_of(&owner, 2), (spenders.clone(), false));
+        allowances.revoke(&owner, &spenders[..1]);
+        assert_eq!(
+            allowances.spenders_of(&owner, 2),
+            (spenders[1..].to_vec(), false)
+        );
+        allowances.revoke(&owner, &spenders[1..]);
+        assert_eq!(allowances.allowances_of(&owner, None, usize::MAX), vec![]);
+        assert_eq!(allowances.recurring_allowance(&owner, &spender), None);
+        assert_eq!(allowances.spendable(&owner, &spender, 0), 0u32.into());
+        assert_eq!(allowances.allowance(&other_owner, &owner), 30u32.into());
+        assert_eq!(
+            allowances.spendable(&other_owner, &spender, 0),
+            40u32.into()
+        );
+        assert_eq!(allowances.spenders_of(&owner, 1), (vec![], false));
+
+        // an owner with only recurring allowances
+        let recurring = InnerRecurringAllowance::new(40u32.into(), 1000, 0);
+        allowances.set_recurring(&owner, &other_owner, Some(recurring));
+        assert_eq!(
+            allowances.spenders_of(&owner, 1),
+            (vec![other_owner], false)
+        );
+        allowances.revoke(&owner, &[other_owner]);
+        assert_eq!(allowances.spendable(&owner, &other_owner, 0), 0u32.into());
+    }
+
+    #[test]
+    fn test_allowances_pagination_and_spender_index() {
+        let holder = |text: &str| TokenHolder::new(text.parse().unwrap(), None);
+        let owner = holder("qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe");
+        let spender = holder("o5y7v-htz2q-vk7fc-cqi4m-bqvwa-eth75-sc2wz-ubuev-curf2-rbipe-tae");
+        let other = holder("czjfo-ddpvm-6sibl-6zbox-ee5zq-bx3hc-e336t-s6pka-dupmy-wcxqi-fae");
+        let memory = VectorMemory::default();
+        let mut allowances = TokenAllowances::init(
+            memory.clone(),
+            VectorMemory::default(),
+            VectorMemory::default(),
+        );
+        allowances.credit(&owner, &spender, 10u32.into());
+        allowances.credit(&owner, &other, 20u32.into());
+        allowances.credit(&other, &spender, 30u32.into());
+        allowances.credit(&spender, &owner, 40u32.into());
+
+        let all = allowances.allowances_of(&owner, None, usize::MAX);
+        assert_eq!(all.len(), 2);
+        let first_page = allowances.allowances_of(&owner, None, 1);
+        assert_eq!(first_page, all[..1]);
+        let second_page = allowances.allowances_of(&owner, Some(&first_page[0].0), 1);
+        assert_eq!(second_page, all[1..]);
+        assert_eq!(
+            allowances.allowances_of(&owner, Some(&second_page[0].0), 1),
+            vec![]
+        );
+
+        let mut expected = vec![(owner, TokenAmount::from(10u32)), (other, 30u32.into())];
+        expected.sort();
+        assert_eq!(
+            allowances.allowances_by_spender(&spender, None, usize::MAX),
+            expected
+        );
+        assert_eq!(
+            allowances.allowances_by_spender(&spender, Some(&expected[0].0), 10),
+            expected[1..]
+        );
+
+        // both indexes follow the debits and the revocations
+        allowances.debit(&owner, &spender, 10u32.into()).unwrap();
+        assert_eq!(
+            allowances.allowances_by_spender(&spender, None, usize::MAX),
+            vec![(other, 30u32.into())]
+        );
+        allowances.revoke(&other, &[spender]);
+        assert_eq!(
+            allowances.allowances_by_spender(&spender, None, usize::MAX),
+            vec![]
+        );
+        assert_eq!(
+            allowances.allowances_by_spender(&other, None, usize::MAX),
+            vec![(owner, 20u32.into())]
+        );
+
+        // the index is rebuilt from allowances stored without it
+        let reloaded =
+            TokenAllowances::init(memory, VectorMemory::default(), VectorMemory::default());
+        assert_eq!(
+            reloaded.allowances_by_spender(&owner, None, usize::MAX),
+            vec![(spender, 40u32.into())]
+        );
+        assert_eq!(
+            reloaded.allowances_by_spender(&other, None, usize::MAX),
+            vec![(owner, 20u32.into())]
+        );
+    }
+
+    #[test]
+    fn test_recurring_allowances() {
+        let owner = nth_holder(1);
+        let spender = nth_holder(2);
+        let mut allowances = TokenAllowances::new();
+        allowances.credit(&owner, &spender, 1_000u32.into());
+        assert_eq!(allowances.spendable(&owner, &spender, 0), 1_000u32.into());
+
+        // the recurring allowance takes precedence over the one-shot allowance
+        allowances.set_recurring(
+            &owner,
+            &spender,
+            Some(InnerRecurringAllowance::new(100u32.into(), 10, 0)),
+        );
+        assert_eq!(allowances.spendable(&owner, &spender, 5), 100u32.into());
+        assert_eq!(
+            allowances.spend(&owner, &spender, 101u32.into(), 5),
+            Err(DFTError::InsufficientAllowance)
+        );
+        allowances.spend(&owner, &spender, 70u32.into(), 5).unwrap();
+        assert_eq!(allowances.spendable(&owner, &spender, 9), 30u32.into());
+        assert_eq!(allowances.spendable(&owner, &spender, 10), 100u32.into());
+        assert_eq!(allowances.allowance(&owner, &spender), 1_000u32.into());
+        assert_eq!(allowances.spendable(&spender, &owner, 5), 0u32.into());
+
+        allowances.set_recurring(&owner, &spender, None);
+        assert_eq!(allowances.recurring_allowance(&owner, &spender), None);
+        allowances.spend(&owner, &spender, 70u32.into(), 5).unwrap();
+        assert_eq!(allowances.allowance(&owner, &spender), 930u32.into());
+    }
+
+    #[test]
+    fn test_migrate_from_heap_format() {
         let mut allowances = TokenAllowances::new();
         let owner = TokenHolder::new(
             "qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe"
@@ -204,10 +709,24 @@ mod tests {
             None,
         );
         let value = TokenAmount::from(100u32);
-        allowances.credit(&owner, &spender, value.clone());
-        let encoded = allowances.encode();
-        let decoded = TokenAllowances::decode(encoded).unwrap();
-        assert_eq!(decoded.allowance(&owner, &spender), value);
+        // layout written by the pre_upgrade of the heap-based allowances
+        let mut spenders = HashMap::new();
+        spenders.insert(spender, value.clone());
+        spenders.insert(owner, TokenAmount::from(1u32));
+        let mut heap_allowances = HashMap::new();
+        heap_allowances.insert(owner, spenders);
+        heap_allowances.insert(spender, HashMap::from([(owner, value.clone())]));
+        let bytes = bincode::serialize(&heap_allowances).unwrap();
+
+        allowances.migrate_from_heap_format(bytes).unwrap();
+        assert_eq!(allowances.allowance(&owner, &spender), value);
+        assert_eq!(allowances.allowance(&spender, &owner), value);
+        assert_eq!(allowances.allowance_size(), 3);
+        assert_eq!(allowances.allowances_of(&owner, None, usize::MAX).len(), 2);
+        assert_eq!(
+            allowances.allowances_of(&spender, None, usize::MAX),
+            vec![(owner, value)]
+        );
     }
 
     #[test]
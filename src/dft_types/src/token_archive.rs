@@ -1,5 +1,7 @@
 use std::ops::Add;
 
+use std::collections::{BTreeMap, BTreeSet};
+
 use candid::{CandidType, Nat, Principal};
 use num_traits::CheckedSub;
 use serde::{Deserialize, Serialize};
@@ -7,12 +9,17 @@ use serde::{Deserialize, Serialize};
 use crate::{
     constants::{
         BLOCK_ARCHIVE_SIZE, BLOCK_ARCHIVE_TRIGGER_THRESHOLD, CYCLES_PER_AUTO_SCALING,
-        MAX_CANISTER_STORAGE_BYTES,
+        DEFAULT_ARCHIVE_CYCLES_TOP_UP, DEFAULT_ARCHIVE_INTERVAL_SECONDS,
+        DEFAULT_ARCHIVE_REPLICATION_FACTOR, DEFAULT_MIN_ARCHIVE_CYCLES,
+        MAX_ARCHIVE_REPLICATION_FACTOR, MAX_ARCHIVE_RETRY_BACKOFF_SECONDS,
+        MAX_CANISTER_STORAGE_BYTES, MAX_MESSAGE_SIZE_BYTES, MIN_CANISTER_STORAGE_BYTES,
     },
-    BlockHeight,
+    BlockHeight, CommonResult, DFTError,
 };
 
-#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+#[derive(
+    Serialize, Deserialize, CandidType, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord,
+)]
 pub struct ArchiveOptions {
     /// The number of blocks which, when exceeded, will trigger an archiving
     /// operation
@@ -22,6 +29,20 @@ pub struct ArchiveOptions {
     pub node_max_memory_size_bytes: Option<u32>,
     pub max_message_size_bytes: Option<u32>,
     pub cycles_for_archive_creation: Option<u64>,
+    /// How often the archiving timer checks whether the trigger threshold
+    /// has been exceeded
+    pub archive_interval_seconds: Option<u64>,
+    /// Archive canisters with fewer cycles are topped up by the token
+    pub min_archive_cycles: Option<u64>,
+    /// The cycles deposited to an archive canister when it is topped up
+    pub archive_cycles_top_up: Option<u64>,
+    /// The number of archive canisters storing each archived block range.
+    /// Only the archive canisters created afterwards get mirrors, the ranges
+    /// already stored keep the canisters they were archived to
+    pub replication_factor: Option<u8>,
+    /// Whether the archive canisters compress the blocks they store, lz4 by default.
+    /// Only the archive canisters created afterwards are affected
+    pub compress_blocks: Option<bool>,
 }
 
 #[derive(CandidType, Deserialize, Debug, Clone)]
@@ -34,6 +55,89 @@ pub struct ArchiveInfo {
     end_block_height: Nat,
     #[serde(rename = "numBlocks")]
     num_blocks: Nat,
+    // cycle balance at the last check of the archive canister
+    cycles: Option<Nat>,
+    #[serde(rename = "cyclesCheckedAt")]
+    cycles_checked_at: Option<u64>,
+    #[serde(rename = "toppedUpCycles")]
+    topped_up_cycles: Nat,
+    #[serde(rename = "lastTopUpAt")]
+    last_top_up_at: Option<u64>,
+    #[serde(rename = "cyclesError")]
+    cycles_error: Option<String>,
+    // the other canisters storing a copy of the range
+    #[serde(rename = "mirrorCanisterIds")]
+    mirror_canister_ids: Vec<Principal>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, Ord, PartialOrd, Eq, PartialEq)]
+pub struct ArchiveCyclesStats {
+    // balance reported by the last successful check, including the top-up
+    cycles: Option<u128>,
+    checked_at: Option<u64>,
+    // total cycles deposited by the token
+    topped_up_cycles: u128,
+    last_top_up_at: Option<u64>,
+    // why the last check or top-up failed
+    last_error: Option<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Ord, PartialOrd, Eq, PartialEq)]
+pub enum ArchivingState {
+    Idle,
+    CreatingCanister,
+    Installing,
+    Appending,
+    Failed { reason: String, attempts: u32 },
+    UpgradingArchives,
+}
+
+impl ArchivingState {
+    pub fn is_in_progress(&self) -> bool {
+        matches!(
+            self,
+            ArchivingState::CreatingCanister
+                | ArchivingState::Installing
+                | ArchivingState::Appending
+                | ArchivingState::UpgradingArchives
+        )
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArchivingStatus {
+    pub state: ArchivingState,
+    #[serde(rename = "intervalSeconds")]
+    pub interval_seconds: u64,
+    #[serde(rename = "triggerThreshold")]
+    pub trigger_threshold: u32,
+    #[serde(rename = "numBlocksToArchive")]
+    pub num_blocks_to_archive: u32,
+    #[serde(rename = "lastRunAt")]
+    pub last_run_at: Option<u64>,
+    #[serde(rename = "lastSuccessAt")]
+    pub last_success_at: Option<u64>,
+    #[serde(rename = "lastError")]
+    pub last_error: Option<String>,
+    #[serde(rename = "nextRetryAt")]
+    pub next_retry_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ArchiveUpgradeStatus {
+    Upgraded,
+    // the canister already runs the embedded wasm
+    UpToDate,
+    Failed { reason: String },
+    // not attempted because the upgrade of a previous canister failed
+    Skipped,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveUpgradeInfo {
+    #[serde(rename = "canisterId")]
+    pub canister_id: Principal,
+    pub status: ArchiveUpgradeStatus,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, Ord, PartialOrd, Eq, PartialEq)]
@@ -50,8 +154,28 @@ pub struct Archive {
     pub num_blocks_to_archive: u32,
     // cycles to use for the call to create a new canister and to install the archive3
     pub cycles_for_archive_creation: u64,
-    #[serde(skip)]
-    archiving_in_progress: bool,
+    pub archive_interval_seconds: u64,
+    last_run_at: Option<u64>,
+    last_success_at: Option<u64>,
+    last_error: Option<String>,
+    state: ArchivingState,
+    // failed attempts since the last successful archiving
+    failed_attempts: u32,
+    // while in the failed state, the timer does not retry before this time
+    next_retry_at: Option<u64>,
+    pub min_archive_cycles: u64,
+    pub archive_cycles_top_up: u64,
+    storage_canisters_cycles: BTreeMap<Principal, ArchiveCyclesStats>,
+    replication_factor: u8,
+    // the mirrors of each storage canister, they store the same block range
+    storage_canister_mirrors: Vec<Vec<Principal>>,
+    // newly created mirror canister, not installed yet
+    latest_mirror_canister: Option<Principal>,
+    // archive canisters whose status could not be read at the last cycles check,
+    // reads are routed to another replica of their range
+    unreachable_canisters: BTreeSet<Principal>,
+    // passed to the archive canisters when they are installed
+    compress_blocks: bool,
 }
 
 impl Default for Archive {
@@ -61,29 +185,325 @@ impl Default for Archive {
             latest_storage_canister: None,
             storage_canisters_block_ranges: Vec::new(),
             node_max_memory_size_bytes: MAX_CANISTER_STORAGE_BYTES,
-            max_message_size_bytes: 0,
+            max_message_size_bytes: MAX_MESSAGE_SIZE_BYTES,
             trigger_threshold: BLOCK_ARCHIVE_TRIGGER_THRESHOLD,
             num_blocks_to_archive: BLOCK_ARCHIVE_SIZE,
             cycles_for_archive_creation: CYCLES_PER_AUTO_SCALING,
-            archiving_in_progress: false,
+            archive_interval_seconds: DEFAULT_ARCHIVE_INTERVAL_SECONDS,
+            last_run_at: None,
+            last_success_at: None,
+            last_error: None,
+            state: ArchivingState::Idle,
+            failed_attempts: 0,
+            next_retry_at: None,
+            min_archive_cycles: DEFAULT_MIN_ARCHIVE_CYCLES,
+            archive_cycles_top_up: DEFAULT_ARCHIVE_CYCLES_TOP_UP,
+            storage_canisters_cycles: BTreeMap::new(),
+            replication_factor: DEFAULT_ARCHIVE_REPLICATION_FACTOR,
+            storage_canister_mirrors: Vec::new(),
+            latest_mirror_canister: None,
+            unreachable_canisters: BTreeSet::new(),
+            compress_blocks: true,
+        }
+    }
+}
+
+// Frozen layout of `Archive` in the version 1 stable state,
+// before the archiving timer and state machine fields were added.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct ArchiveV1 {
+    storage_canisters: Vec<Principal>,
+    latest_storage_canister: Option<Principal>,
+    storage_canisters_block_ranges: Vec<(BlockHeight, BlockHeight)>,
+    node_max_memory_size_bytes: u32,
+    max_message_size_bytes: u32,
+    trigger_threshold: u32,
+    num_blocks_to_archive: u32,
+    cycles_for_archive_creation: u64,
+}
+
+// Frozen layout of `Archive` in the version 2 stable state,
+// before the cycles of the archive canisters were monitored.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct ArchiveV2 {
+    storage_canisters: Vec<Principal>,
+    latest_storage_canister: Option<Principal>,
+    storage_canisters_block_ranges: Vec<(BlockHeight, BlockHeight)>,
+    node_max_memory_size_bytes: u32,
+    max_message_size_bytes: u32,
+    trigger_threshold: u32,
+    num_blocks_to_archive: u32,
+    cycles_for_archive_creation: u64,
+    archive_interval_seconds: u64,
+    last_run_at: Option<u64>,
+    last_success_at: Option<u64>,
+    last_error: Option<String>,
+    state: ArchivingState,
+    failed_attempts: u32,
+    next_retry_at: Option<u64>,
+}
+
+// Frozen layout of `Archive` in the version 3 stable state,
+// before the archived block ranges could be mirrored.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct ArchiveV3 {
+    storage_canisters: Vec<Principal>,
+    latest_storage_canister: Option<Principal>,
+    storage_canisters_block_ranges: Vec<(BlockHeight, BlockHeight)>,
+    node_max_memory_size_bytes: u32,
+    max_message_size_bytes: u32,
+    trigger_threshold: u32,
+    num_blocks_to_archive: u32,
+    cycles_for_archive_creation: u64,
+    archive_interval_seconds: u64,
+    last_run_at: Option<u64>,
+    last_success_at: Option<u64>,
+    last_error: Option<String>,
+    state: ArchivingState,
+    failed_attempts: u32,
+    next_retry_at: Option<u64>,
+    min_archive_cycles: u64,
+    archive_cycles_top_up: u64,
+    storage_canisters_cycles: BTreeMap<Principal, ArchiveCyclesStats>,
+}
+
+// Frozen layout of `Archive` in the version 4 stable state,
+// before the compression of the archived blocks could be turned off.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct ArchiveV4 {
+    storage_canisters: Vec<Principal>,
+    latest_storage_canister: Option<Principal>,
+    storage_canisters_block_ranges: Vec<(BlockHeight, BlockHeight)>,
+    node_max_memory_size_bytes: u32,
+    max_message_size_bytes: u32,
+    trigger_threshold: u32,
+    num_blocks_to_archive: u32,
+    cycles_for_archive_creation: u64,
+    archive_interval_seconds: u64,
+    last_run_at: Option<u64>,
+    last_success_at: Option<u64>,
+    last_error: Option<String>,
+    state: ArchivingState,
+    failed_attempts: u32,
+    next_retry_at: Option<u64>,
+    min_archive_cycles: u64,
+    archive_cycles_top_up: u64,
+    storage_canisters_cycles: BTreeMap<Principal, ArchiveCyclesStats>,
+    replication_factor: u8,
+    storage_canister_mirrors: Vec<Vec<Principal>>,
+    latest_mirror_canister: Option<Principal>,
+    unreachable_canisters: BTreeSet<Principal>,
+}
+
+impl From<ArchiveV4> for Archive {
+    fn from(archive: ArchiveV4) -> Self {
+        Archive {
+            storage_canisters: archive.storage_canisters,
+            latest_storage_canister: archive.latest_storage_canister,
+            storage_canisters_block_ranges: archive.storage_canisters_block_ranges,
+            node_max_memory_size_bytes: archive.node_max_memory_size_bytes,
+            max_message_size_bytes: archive.max_message_size_bytes,
+            trigger_threshold: archive.trigger_threshold,
+            num_blocks_to_archive: archive.num_blocks_to_archive,
+            cycles_for_archive_creation: archive.cycles_for_archive_creation,
+            archive_interval_seconds: archive.archive_interval_seconds,
+            last_run_at: archive.last_run_at,
+            last_success_at: archive.last_success_at,
+            last_error: archive.last_error,
+            state: archive.state,
+            failed_attempts: archive.failed_attempts,
+            next_retry_at: archive.next_retry_at,
+            min_archive_cycles: archive.min_archive_cycles,
+            archive_cycles_top_up: archive.archive_cycles_top_up,
+            storage_canisters_cycles: archive.storage_canisters_cycles,
+            replication_factor: archive.replication_factor,
+            storage_canister_mirrors: archive.storage_canister_mirrors,
+            latest_mirror_canister: archive.latest_mirror_canister,
+            unreachable_canisters: archive.unreachable_canisters,
+            ..Archive::default()
+        }
+    }
+}
+
+impl From<ArchiveV3> for ArchiveV4 {
+    fn from(archive: ArchiveV3) -> Self {
+        ArchiveV4 {
+            storage_canister_mirrors: vec![Vec::new(); archive.storage_canisters.len()],
+            storage_canisters: archive.storage_canisters,
+            latest_storage_canister: archive.latest_storage_canister,
+            storage_canisters_block_ranges: archive.storage_canisters_block_ranges,
+            node_max_memory_size_bytes: archive.node_max_memory_size_bytes,
+            max_message_size_bytes: archive.max_message_size_bytes,
+            trigger_threshold: archive.trigger_threshold,
+            num_blocks_to_archive: archive.num_blocks_to_archive,
+            cycles_for_archive_creation: archive.cycles_for_archive_creation,
+            archive_interval_seconds: archive.archive_interval_seconds,
+            last_run_at: archive.last_run_at,
+            last_success_at: archive.last_success_at,
+            last_error: archive.last_error,
+            state: archive.state,
+            failed_attempts: archive.failed_attempts,
+            next_retry_at: archive.next_retry_at,
+            min_archive_cycles: archive.min_archive_cycles,
+            archive_cycles_top_up: archive.archive_cycles_top_up,
+            storage_canisters_cycles: archive.storage_canisters_cycles,
+            replication_factor: DEFAULT_ARCHIVE_REPLICATION_FACTOR,
+            latest_mirror_canister: None,
+            unreachable_canisters: BTreeSet::new(),
+        }
+    }
+}
+
+impl From<ArchiveV2> for ArchiveV3 {
+    fn from(archive: ArchiveV2) -> Self {
+        ArchiveV3 {
+            storage_canisters: archive.storage_canisters,
+            latest_storage_canister: archive.latest_storage_canister,
+            storage_canisters_block_ranges: archive.storage_canisters_block_ranges,
+            node_max_memory_size_bytes: archive.node_max_memory_size_bytes,
+            max_message_size_bytes: archive.max_message_size_bytes,
+            trigger_threshold: archive.trigger_threshold,
+            num_blocks_to_archive: archive.num_blocks_to_archive,
+            cycles_for_archive_creation: archive.cycles_for_archive_creation,
+            archive_interval_seconds: archive.archive_interval_seconds,
+            last_run_at: archive.last_run_at,
+            last_success_at: archive.last_success_at,
+            last_error: archive.last_error,
+            state: archive.state,
+            failed_attempts: archive.failed_attempts,
+            next_retry_at: archive.next_retry_at,
+            min_archive_cycles: DEFAULT_MIN_ARCHIVE_CYCLES,
+            archive_cycles_top_up: DEFAULT_ARCHIVE_CYCLES_TOP_UP,
+            storage_canisters_cycles: BTreeMap::new(),
+        }
+    }
+}
+
+impl From<ArchiveV1> for ArchiveV2 {
+    fn from(archive: ArchiveV1) -> Self {
+        ArchiveV2 {
+            storage_canisters: archive.storage_canisters,
+            latest_storage_canister: archive.latest_storage_canister,
+            storage_canisters_block_ranges: archive.storage_canisters_block_ranges,
+            node_max_memory_size_bytes: archive.node_max_memory_size_bytes,
+            max_message_size_bytes: archive.max_message_size_bytes,
+            trigger_threshold: archive.trigger_threshold,
+            num_blocks_to_archive: archive.num_blocks_to_archive,
+            cycles_for_archive_creation: archive.cycles_for_archive_creation,
+            archive_interval_seconds: DEFAULT_ARCHIVE_INTERVAL_SECONDS,
+            last_run_at: None,
+            last_success_at: None,
+            last_error: None,
+            state: ArchivingState::Idle,
+            failed_attempts: 0,
+            next_retry_at: None,
         }
     }
 }
 
 impl Archive {
-    pub fn new(options: ArchiveOptions) -> Self {
-        Self {
-            storage_canisters: vec![],
-            latest_storage_canister: None,
-            storage_canisters_block_ranges: vec![],
-            node_max_memory_size_bytes: options
-                .node_max_memory_size_bytes
-                .unwrap_or(1024 * 1024 * 1024),
-            max_message_size_bytes: options.max_message_size_bytes.unwrap_or(2 * 1024 * 1024),
-            trigger_threshold: options.trigger_threshold,
-            num_blocks_to_archive: options.num_blocks_to_archive,
-            cycles_for_archive_creation: options.cycles_for_archive_creation.unwrap_or(0),
-            archiving_in_progress: false,
+    pub fn new(options: ArchiveOptions) -> CommonResult<Self> {
+        let mut archive = Archive::default();
+        archive.set_options(options)?;
+        Ok(archive)
+    }
+
+    pub fn options(&self) -> ArchiveOptions {
+        ArchiveOptions {
+            trigger_threshold: self.trigger_threshold,
+            num_blocks_to_archive: self.num_blocks_to_archive,
+            node_max_memory_size_bytes: Some(self.node_max_memory_size_bytes()),
+            max_message_size_bytes: Some(self.max_message_size_bytes()),
+            cycles_for_archive_creation: Some(self.cycles_for_archive_creation),
+            archive_interval_seconds: Some(self.archive_interval_seconds),
+            min_archive_cycles: Some(self.min_archive_cycles),
+            archive_cycles_top_up: Some(self.archive_cycles_top_up),
+            replication_factor: Some(self.replication_factor),
+            compress_blocks: Some(self.compress_blocks),
+        }
+    }
+
+    // Validate and apply new options, the omitted optional values are left unchanged.
+    pub fn set_options(&mut self, options: ArchiveOptions) -> CommonResult<()> {
+        let node_max_memory_size_bytes = options
+            .node_max_memory_size_bytes
+            .unwrap_or_else(|| self.node_max_memory_size_bytes());
+        let max_message_size_bytes = options
+            .max_message_size_bytes
+            .unwrap_or_else(|| self.max_message_size_bytes());
+        let archive_interval_seconds = options
+            .archive_interval_seconds
+            .unwrap_or(self.archive_interval_seconds);
+
+        let invalid = |detail: &str| {
+            Err(DFTError::InvalidArchiveOptions {
+                detail: detail.to_string(),
+            })
+        };
+        if options.num_blocks_to_archive == 0 {
+            return invalid("num_blocks_to_archive must be greater than 0");
+        }
+        if options.trigger_threshold < options.num_blocks_to_archive {
+            return invalid("trigger_threshold must not be less than num_blocks_to_archive");
+        }
+        if max_message_size_bytes == 0 || max_message_size_bytes > MAX_MESSAGE_SIZE_BYTES {
+            return invalid(&format!(
+                "max_message_size_bytes must be between 1 and {}",
+                MAX_MESSAGE_SIZE_BYTES
+            ));
+        }
+        if !(MIN_CANISTER_STORAGE_BYTES..=MAX_CANISTER_STORAGE_BYTES)
+            .contains(&node_max_memory_size_bytes)
+        {
+            return invalid(&format!(
+                "node_max_memory_size_bytes must be between {} and {}",
+                MIN_CANISTER_STORAGE_BYTES, MAX_CANISTER_STORAGE_BYTES
+            ));
+        }
+        if archive_interval_seconds == 0 {
+            return invalid("archive_interval_seconds must be greater than 0");
+        }
+        let replication_factor = options
+            .replication_factor
+            .unwrap_or(self.replication_factor);
+        if !(1..=MAX_ARCHIVE_REPLICATION_FACTOR).contains(&replication_factor) {
+            return invalid(&format!(
+                "replication_factor must be between 1 and {}",
+                MAX_ARCHIVE_REPLICATION_FACTOR
+            ));
+        }
+
+        self.trigger_threshold = options.trigger_threshold;
+        self.num_blocks_to_archive = options.num_blocks_to_archive;
+        self.node_max_memory_size_bytes = node_max_memory_size_bytes;
+        self.max_message_size_bytes = max_message_size_bytes;
+        self.archive_interval_seconds = archive_interval_seconds;
+        self.replication_factor = replication_factor;
+        if let Some(cycles) = options.cycles_for_archive_creation {
+            self.cycles_for_archive_creation = cycles;
+        }
+        if let Some(cycles) = options.min_archive_cycles {
+            self.min_archive_cycles = cycles;
+        }
+        if let Some(cycles) = options.archive_cycles_top_up {
+            self.archive_cycles_top_up = cycles;
+        }
+        if let Some(compress_blocks) = options.compress_blocks {
+            self.compress_blocks = compress_blocks;
+        }
+        Ok(())
+    }
+
+    pub fn node_max_memory_size_bytes(&self) -> u32 {
+        self.node_max_memory_size_bytes
+    }
+
+    // archives created without options used to store 0, which means the protocol limit
+    pub fn max_message_size_bytes(&self) -> u32 {
+        if self.max_message_size_bytes == 0 {
+            MAX_MESSAGE_SIZE_BYTES
+        } else {
+            self.max_message_size_bytes
         }
     }
 
@@ -102,28 +522,169 @@ impl Archive {
         self.storage_canisters_block_ranges.last().cloned()
     }
 
-    pub fn index(&self) -> Vec<((BlockHeight, BlockHeight), Principal)> {
+    pub fn replication_factor(&self) -> u8 {
+        self.replication_factor
+    }
+
+    pub fn compress_blocks(&self) -> bool {
+        self.compress_blocks
+    }
+
+    // The block ranges and the canisters storing them, the first replica of
+    // a range is the canister the blocks were archived to first.
+    pub fn index(&self) -> Vec<((BlockHeight, BlockHeight), Vec<Principal>)> {
         self.storage_canisters_block_ranges
             .iter()
             .cloned()
-            .zip(self.storage_canisters.clone())
+            .zip(
+                self.storage_canisters
+                    .iter()
+                    .zip(&self.storage_canister_mirrors),
+            )
+            .map(|(range, (canister_id, mirrors))| {
+                let mut replicas = vec![*canister_id];
+                replicas.extend(mirrors);
+                (range, replicas)
+            })
             .collect()
     }
 
+    // The replica to read a range from, the first one which was reachable at
+    // the last cycles check, or the first replica when none was.
+    pub fn readable_replica(&self, replicas: &[Principal]) -> Principal {
+        replicas
+            .iter()
+            .find(|canister_id| !self.unreachable_canisters.contains(canister_id))
+            .unwrap_or(&replicas[0])
+            .to_owned()
+    }
+
+    // the storage canisters followed by their mirrors, range by range
+    pub fn all_storage_canisters(&self) -> Vec<Principal> {
+        self.storage_canisters
+            .iter()
+            .zip(&self.storage_canister_mirrors)
+            .flat_map(|(canister_id, mirrors)| std::iter::once(canister_id).chain(mirrors))
+            .copied()
+            .collect()
+    }
+
+    pub fn mirror_canisters(&self, storage_index: usize) -> Vec<Principal> {
+        self.storage_canister_mirrors
+            .get(storage_index)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // Mirrors can only be added to a storage canister which has no block yet,
+    // a mirror must store the whole range of its storage canister.
+    pub fn missing_mirrors_count(&self, storage_index: usize) -> usize {
+        if storage_index < self.storage_canisters_block_ranges.len() {
+            return 0;
+        }
+        (self.replication_factor as usize - 1).saturating_sub(
+            self.storage_canister_mirrors
+                .get(storage_index)
+                .map_or(0, |mirrors| mirrors.len()),
+        )
+    }
+
+    pub fn latest_mirror_canister(&self) -> Option<Principal> {
+        self.latest_mirror_canister
+    }
+
+    pub fn pre_append_mirror_canister(&mut self, canister_id: Principal) {
+        assert!(self.state.is_in_progress());
+        assert!(self.latest_mirror_canister.is_none());
+        self.latest_mirror_canister = Some(canister_id);
+    }
+
+    pub fn append_mirror_canister(&mut self, storage_index: usize, canister_id: Principal) {
+        assert!(self.state.is_in_progress());
+        assert_eq!(Some(canister_id), self.latest_mirror_canister);
+        assert!(self.missing_mirrors_count(storage_index) > 0);
+        self.storage_canister_mirrors[storage_index].push(canister_id);
+        self.latest_mirror_canister = None;
+    }
+
     pub fn archives(&self) -> Vec<ArchiveInfo> {
         self.storage_canisters_block_ranges
             .iter()
             .cloned()
             .zip(self.storage_canisters.clone())
-            .map(|((start, end), id)| ArchiveInfo {
-                canister_id: id,
-                start_block_height: start.clone().into(),
-                end_block_height: end.clone().into(),
-                num_blocks: (end.checked_sub(&start).unwrap().add(1u32)).into(),
+            .zip(self.storage_canister_mirrors.clone())
+            .map(|(((start, end), id), mirror_canister_ids)| {
+                let cycles_stats = self
+                    .storage_canisters_cycles
+                    .get(&id)
+                    .cloned()
+                    .unwrap_or_default();
+                ArchiveInfo {
+                    canister_id: id,
+                    start_block_height: start.clone().into(),
+                    end_block_height: end.clone().into(),
+                    num_blocks: (end.checked_sub(&start).unwrap().add(1u32)).into(),
+                    cycles: cycles_stats.cycles.map(Nat::from),
+                    cycles_checked_at: cycles_stats.checked_at,
+                    topped_up_cycles: cycles_stats.topped_up_cycles.into(),
+                    last_top_up_at: cycles_stats.last_top_up_at,
+                    cycles_error: cycles_stats.last_error,
+                    mirror_canister_ids,
+                }
             })
             .collect()
     }
 
+    // Record the outcome of a cycles check of an archive canister,
+    // `cycles` is None when its status could not be read.
+    pub fn record_archive_cycles(
+        &mut self,
+        canister_id: Principal,
+        now: u64,
+        cycles: Option<u128>,
+        topped_up_cycles: u64,
+        error: Option<String>,
+    ) {
+        let stats = self
+            .storage_canisters_cycles
+            .entry(canister_id)
+            .or_default();
+        if let Some(cycles) = cycles {
+            stats.cycles = Some(cycles.saturating_add(topped_up_cycles.into()));
+            stats.checked_at = Some(now);
+            self.unreachable_canisters.remove(&canister_id);
+        } else {
+            self.unreachable_canisters.insert(canister_id);
+        }
+        if topped_up_cycles > 0 {
+            stats.topped_up_cycles = stats
+                .topped_up_cycles
+                .saturating_add(topped_up_cycles.into());
+            stats.last_top_up_at = Some(now);
+        }
+        stats.last_error = error;
+    }
+
+    // archives still below the cycles threshold after the last check
+    pub fn archives_low_on_cycles(&self) -> usize {
+        self.storage_canisters_cycles
+            .values()
+            .filter(|stats| {
+                stats
+                    .cycles
+                    .is_some_and(|cycles| cycles < self.min_archive_cycles as u128)
+            })
+            .count()
+    }
+
+    // archives whose last cycles check or top-up failed
+    pub fn archives_with_cycles_errors(&self) -> usize {
+        self.storage_canisters_cycles
+            .values()
+            .filter(|stats| stats.last_error.is_some())
+            .count()
+    }
+
     pub fn scaling_storage_block_height_offset(&self) -> BlockHeight {
         self.storage_canisters_block_ranges
             .last()
@@ -140,17 +701,18 @@ impl Archive {
     }
 
     pub fn pre_append_storage_canister(&mut self, canister_id: Principal) {
-        assert!(self.archiving_in_progress);
+        assert!(self.state.is_in_progress());
         assert!(self.latest_storage_canister.is_none());
         self.latest_storage_canister = Some(canister_id);
     }
 
     pub fn append_scaling_storage_canister(&mut self, canister_id: Principal) {
-        assert!(self.archiving_in_progress);
+        assert!(self.state.is_in_progress());
         assert_eq!(canister_id, self.latest_storage_canister.unwrap());
-        if self.archiving_in_progress {
+        if self.state.is_in_progress() {
             assert!(!self.storage_canisters.contains(&canister_id));
             self.storage_canisters.push(canister_id);
+            self.storage_canister_mirrors.push(Vec::new());
             self.latest_storage_canister = None;
         }
     }
@@ -160,7 +722,7 @@ impl Archive {
         storage_index: usize,
         end_block_height: BlockHeight,
     ) {
-        assert!(self.archiving_in_progress);
+        assert!(self.state.is_in_progress());
 
         let last_range: Option<(BlockHeight, BlockHeight)> = self
             .last_storage_canister_range()
@@ -194,18 +756,118 @@ impl Archive {
         }
     }
 
-    pub fn lock_for_archiving(&mut self) -> bool {
-        if self.archiving_in_progress {
-            false
-        } else {
-            self.archiving_in_progress = true;
-            true
+    // start an archiving run, it is refused while another run is in progress
+    // or while the backoff delay of the last failure has not elapsed yet
+    pub fn lock_for_archiving(&mut self, now: u64) -> bool {
+        if self.state.is_in_progress() {
+            return false;
+        }
+        if let Some(next_retry_at) = self.next_retry_at {
+            if now < next_retry_at {
+                return false;
+            }
         }
+        self.state = ArchivingState::Appending;
+        true
     }
 
+    // make sure the archive is not left in an in-progress state
     pub fn unlock_after_archiving(&mut self) {
-        if self.archiving_in_progress {
-            self.archiving_in_progress = false;
+        if self.state.is_in_progress() {
+            self.state = ArchivingState::Idle;
+        }
+    }
+
+    // archive canisters are not upgraded while blocks are being appended to them,
+    // and the archiving timer does not run while they are upgraded
+    pub fn lock_for_upgrading_archives(&mut self) -> bool {
+        if self.state.is_in_progress() {
+            return false;
+        }
+        self.state = ArchivingState::UpgradingArchives;
+        true
+    }
+
+    // go back to the state the archiving was in before the upgrade
+    pub fn unlock_after_upgrading_archives(&mut self) {
+        if self.state != ArchivingState::UpgradingArchives {
+            return;
+        }
+        self.state = match &self.last_error {
+            Some(reason) if self.failed_attempts > 0 => ArchivingState::Failed {
+                reason: reason.clone(),
+                attempts: self.failed_attempts,
+            },
+            _ => ArchivingState::Idle,
+        };
+    }
+
+    pub fn archiving_state(&self) -> &ArchivingState {
+        &self.state
+    }
+
+    pub fn set_archiving_state(&mut self, state: ArchivingState) {
+        assert!(self.state.is_in_progress());
+        assert!(state.is_in_progress());
+        self.state = state;
+    }
+
+    // record the outcome of an archiving run triggered by the archiving timer
+    pub fn record_archiving_result(&mut self, now: u64, error: Option<String>) {
+        self.last_run_at = Some(now);
+        match error {
+            Some(reason) => {
+                self.failed_attempts = self.failed_attempts.saturating_add(1);
+                self.next_retry_at = Some(now.saturating_add(self.retry_backoff_nanos()));
+                self.last_error = Some(reason.clone());
+                self.state = ArchivingState::Failed {
+                    reason,
+                    attempts: self.failed_attempts,
+                };
+            }
+            None => {
+                self.last_success_at = Some(now);
+                self.last_error = None;
+                self.failed_attempts = 0;
+                self.next_retry_at = None;
+                self.state = ArchivingState::Idle;
+            }
+        }
+    }
+
+    // the delay doubles with each consecutive failure, starting from the archive interval
+    fn retry_backoff_nanos(&self) -> u64 {
+        let exponent = self.failed_attempts.saturating_sub(1).min(32);
+        let backoff_seconds = self
+            .archive_interval_seconds
+            .saturating_mul(1u64 << exponent)
+            .min(MAX_ARCHIVE_RETRY_BACKOFF_SECONDS);
+        backoff_seconds.saturating_mul(1_000_000_000)
+    }
+
+    // allow the next archiving run to start immediately, keeping the failure count
+    pub fn clear_retry_backoff(&mut self) {
+        self.next_retry_at = None;
+    }
+
+    // forget about failures and any run that was interrupted by a trap
+    pub fn reset_archiving(&mut self) {
+        self.state = ArchivingState::Idle;
+        self.failed_attempts = 0;
+        self.next_retry_at = None;
+        self.last_error = None;
+    }
+
+    pub fn archiving_status(&self) -> ArchivingStatus {
+        ArchivingStatus {
+            state: self.state.clone(),
+            interval_seconds: self.archive_interval_seconds,
+            trigger_threshold: self.trigger_threshold,
+            num_blocks_to_archive: self.num_blocks_to_archive,
+            last_run_at: self.last_run_at,
+            last_success_at: self.last_success_at,
+            last_error: self.last_error.clone(),
+            next_retry_at: self.next_retry_at,
         }
     }
 }
@@ -234,21 +896,267 @@ mod tests {
     fn test_lock_for_archiving() {
         let mut archive = Archive::default();
 
-        let lock_res = archive.lock_for_archiving();
+        let lock_res = archive.lock_for_archiving(0);
         assert!(lock_res);
-        let lock_res = archive.lock_for_archiving();
+        let lock_res = archive.lock_for_archiving(0);
         assert!(!lock_res);
     }
 
     #[test]
     fn test_unlock_after_archiving() {
         let mut archive = Archive::default();
-        archive.lock_for_archiving();
+        archive.lock_for_archiving(0);
         archive.unlock_after_archiving();
-        let res = archive.lock_for_archiving();
+        let res = archive.lock_for_archiving(0);
         assert!(res);
     }
 
+    #[test]
+    fn test_lock_for_upgrading_archives() {
+        let mut archive = Archive::default();
+        assert!(archive.lock_for_archiving(0));
+        assert!(!archive.lock_for_upgrading_archives());
+        archive.unlock_after_archiving();
+
+        assert!(archive.lock_for_upgrading_archives());
+        assert!(!archive.lock_for_archiving(0));
+        assert!(!archive.lock_for_upgrading_archives());
+        archive.unlock_after_upgrading_archives();
+        assert_eq!(*archive.archiving_state(), ArchivingState::Idle);
+
+        // a failed archiving is still reported as failed after the upgrade
+        assert!(archive.lock_for_archiving(1));
+        archive.record_archiving_result(1, Some("append failed".to_string()));
+        assert!(archive.lock_for_upgrading_archives());
+        archive.unlock_after_upgrading_archives();
+        assert_eq!(
+            *archive.archiving_state(),
+            ArchivingState::Failed {
+                reason: "append failed".to_string(),
+                attempts: 1
+            }
+        );
+    }
+
+    fn test_archive_options() -> ArchiveOptions {
+        ArchiveOptions {
+            trigger_threshold: 2000,
+            num_blocks_to_archive: 1000,
+            node_max_memory_size_bytes: None,
+            max_message_size_bytes: None,
+            cycles_for_archive_creation: None,
+            archive_interval_seconds: None,
+            min_archive_cycles: None,
+            archive_cycles_top_up: None,
+            replication_factor: None,
+            compress_blocks: None,
+        }
+    }
+
+    #[test]
+    fn test_new_archive_defaults() {
+        let archive = Archive::new(test_archive_options()).unwrap();
+        let options = archive.options();
+        assert_eq!(
+            options.node_max_memory_size_bytes,
+            Some(MAX_CANISTER_STORAGE_BYTES)
+        );
+        assert_eq!(options.max_message_size_bytes, Some(MAX_MESSAGE_SIZE_BYTES));
+        assert_eq!(
+            options.cycles_for_archive_creation,
+            Some(CYCLES_PER_AUTO_SCALING)
+        );
+        assert_eq!(
+            options.archive_interval_seconds,
+            Some(DEFAULT_ARCHIVE_INTERVAL_SECONDS)
+        );
+        assert_eq!(
+            options.replication_factor,
+            Some(DEFAULT_ARCHIVE_REPLICATION_FACTOR)
+        );
+        assert_eq!(options.compress_blocks, Some(true));
+        assert_eq!(Archive::new(options.clone()).unwrap().options(), options);
+    }
+
+    #[test]
+    fn test_set_archive_options_validation() {
+        let mut archive = Archive::default();
+        let invalid_options = vec![
+            ArchiveOptions {
+                num_blocks_to_archive: 0,
+                ..test_archive_options()
+            },
+            ArchiveOptions {
+                trigger_threshold: 999,
+                ..test_archive_options()
+            },
+            ArchiveOptions {
+                max_message_size_bytes: Some(MAX_MESSAGE_SIZE_BYTES + 1),
+                ..test_archive_options()
+            },
+            ArchiveOptions {
+                node_max_memory_size_bytes: Some(MIN_CANISTER_STORAGE_BYTES - 1),
+                ..test_archive_options()
+            },
+            ArchiveOptions {
+                archive_interval_seconds: Some(0),
+                ..test_archive_options()
+            },
+            ArchiveOptions {
+                replication_factor: Some(0),
+                ..test_archive_options()
+            },
+            ArchiveOptions {
+                replication_factor: Some(MAX_ARCHIVE_REPLICATION_FACTOR + 1),
+                ..test_archive_options()
+            },
+        ];
+        for options in invalid_options {
+            let res = archive.set_options(options.clone());
+            assert!(
+                matches!(res, Err(DFTError::InvalidArchiveOptions { .. })),
+                "{:?} should be rejected",
+                options
+            );
+        }
+        assert_eq!(archive.options(), Archive::default().options());
+
+        // the omitted values are left unchanged
+        archive
+            .set_options(ArchiveOptions {
+                min_archive_cycles: Some(1),
+                ..test_archive_options()
+            })
+            .unwrap();
+        archive.set_options(test_archive_options()).unwrap();
+        assert_eq!(archive.options().min_archive_cycles, Some(1));
+    }
+
+    #[test]
+    fn test_record_archive_cycles() {
+        let mut archive = Archive::default();
+        let storage_canister_id: Principal = "rno2w-sqaaa-aaaaa-aaacq-cai".parse().unwrap();
+        archive.lock_for_archiving(0);
+        archive.pre_append_storage_canister(storage_canister_id);
+        archive.append_scaling_storage_canister(storage_canister_id);
+        archive.update_scaling_storage_blocks_range(0, BigUint::from(99u32));
+        archive.unlock_after_archiving();
+
+        let archives = archive.archives();
+        assert_eq!(archives[0].cycles, None);
+        assert_eq!(archives[0].topped_up_cycles, Nat::from(0u32));
+
+        // a low archive which is topped up is no longer low
+        let low_cycles = archive.min_archive_cycles as u128 - 1;
+        archive.record_archive_cycles(storage_canister_id, 1, Some(low_cycles), 100, None);
+        let archives = archive.archives();
+        assert_eq!(archives[0].cycles, Some(Nat::from(low_cycles + 100)));
+        assert_eq!(archives[0].cycles_checked_at, Some(1));
+        assert_eq!(archives[0].topped_up_cycles, Nat::from(100u32));
+        assert_eq!(archives[0].last_top_up_at, Some(1));
+        assert_eq!(archive.archives_low_on_cycles(), 0);
+
+        // a failed check keeps the last known balance
+        archive.record_archive_cycles(storage_canister_id, 2, None, 0, Some("failed".to_string()));
+        let archives = archive.archives();
+        assert_eq!(archives[0].cycles_checked_at, Some(1));
+        assert_eq!(archives[0].cycles_error, Some("failed".to_string()));
+        assert_eq!(archive.archives_with_cycles_errors(), 1);
+
+        archive.record_archive_cycles(
+            storage_canister_id,
+            3,
+            Some(low_cycles),
+            0,
+            Some("insufficient token cycles".to_string()),
+        );
+        assert_eq!(archive.archives_low_on_cycles(), 1);
+        assert_eq!(archive.archives()[0].topped_up_cycles, Nat::from(100u32));
+    }
+
+    #[test]
+    fn test_record_archiving_result() {
+        let mut archive = Archive::default();
+        let status = archive.archiving_status();
+        assert_eq!(status.state, ArchivingState::Idle);
+        assert_eq!(status.interval_seconds, DEFAULT_ARCHIVE_INTERVAL_SECONDS);
+        assert!(status.last_run_at.is_none());
+        assert!(status.last_success_at.is_none());
+
+        assert!(archive.lock_for_archiving(1));
+        assert!(archive.archiving_state().is_in_progress());
+        archive.set_archiving_state(ArchivingState::Installing);
+        archive.record_archiving_result(1, Some("install failed".to_string()));
+        archive.unlock_after_archiving();
+        let status = archive.archiving_status();
+        assert_eq!(
+            status.state,
+            ArchivingState::Failed {
+                reason: "install failed".to_string(),
+                attempts: 1
+            }
+        );
+        assert_eq!(status.last_run_at, Some(1));
+        assert_eq!(status.last_success_at, None);
+        assert_eq!(status.last_error, Some("install failed".to_string()));
+
+        assert!(archive.lock_for_archiving(status.next_retry_at.unwrap()));
+        archive.record_archiving_result(2, None);
+        let status = archive.archiving_status();
+        assert_eq!(status.state, ArchivingState::Idle);
+        assert_eq!(status.last_run_at, Some(2));
+        assert_eq!(status.last_success_at, Some(2));
+        assert_eq!(status.last_error, None);
+        assert_eq!(status.next_retry_at, None);
+    }
+
+    #[test]
+    fn test_archiving_retry_backoff() {
+        let mut archive = Archive::default();
+        let interval_nanos = DEFAULT_ARCHIVE_INTERVAL_SECONDS * 1_000_000_000;
+        let mut now = 0u64;
+        let mut expected_delays = vec![];
+        for attempts in 1..=3u32 {
+            assert!(archive.lock_for_archiving(now));
+            archive.record_archiving_result(now, Some("failed".to_string()));
+            let next_retry_at = archive.archiving_status().next_retry_at.unwrap();
+            expected_delays.push(next_retry_at - now);
+            // refused before the backoff delay has elapsed
+            assert!(!archive.lock_for_archiving(next_retry_at - 1));
+            assert_eq!(
+                archive.archiving_state(),
+                &ArchivingState::Failed {
+                    reason: "failed".to_string(),
+                    attempts
+                }
+            );
+            now = next_retry_at;
+        }
+        assert_eq!(
+            expected_delays,
+            vec![interval_nanos, interval_nanos * 2, interval_nanos * 4]
+        );
+
+        // the delay is capped
+        for _ in 0..40 {
+            archive.record_archiving_result(now, Some("failed".to_string()));
+        }
+        assert_eq!(
+            archive.archiving_status().next_retry_at.unwrap() - now,
+            MAX_ARCHIVE_RETRY_BACKOFF_SECONDS * 1_000_000_000
+        );
+
+        archive.clear_retry_backoff();
+        assert!(archive.lock_for_archiving(now));
+        assert!(!archive.lock_for_archiving(now));
+
+        archive.reset_archiving();
+        let status = archive.archiving_status();
+        assert_eq!(status.state, ArchivingState::Idle);
+        assert_eq!(status.last_error, None);
+        assert!(archive.lock_for_archiving(now));
+    }
+
     #[test]
     #[should_panic]
     fn test_prepend_storage_canister_without_lock_should_panic() {
@@ -260,7 +1168,7 @@ mod tests {
     #[test]
     fn test_prepend_storage_canister() {
         let mut archive = Archive::default();
-        archive.lock_for_archiving();
+        archive.lock_for_archiving(0);
         let storage_canister_id: Principal = "rno2w-sqaaa-aaaaa-aaacq-cai".parse().unwrap();
         archive.pre_append_storage_canister(storage_canister_id);
         assert_eq!(archive.storage_canisters().len(), 0);
@@ -271,7 +1179,7 @@ mod tests {
     #[should_panic]
     fn test_append_storage_canister_without_lock_should_panic() {
         let mut archive = Archive::default();
-        archive.lock_for_archiving();
+        archive.lock_for_archiving(0);
         let storage_canister_id: Principal = "rno2w-sqaaa-aaaaa-aaacq-cai".parse().unwrap();
         archive.pre_append_storage_canister(storage_canister_id);
         archive.unlock_after_archiving();
@@ -282,7 +1190,7 @@ mod tests {
     #[should_panic]
     fn test_append_storage_canister_without_pre_append_should_panic() {
         let mut archive = Archive::default();
-        archive.lock_for_archiving();
+        archive.lock_for_archiving(0);
         let storage_canister_id: Principal = "rno2w-sqaaa-aaaaa-aaacq-cai".parse().unwrap();
         archive.append_scaling_storage_canister(storage_canister_id);
     }
@@ -290,7 +1198,7 @@ mod tests {
     #[test]
     fn test_append_storage_canister() {
         let mut archive = Archive::default();
-        archive.lock_for_archiving();
+        archive.lock_for_archiving(0);
         let storage_canister_id: Principal = "rno2w-sqaaa-aaaaa-aaacq-cai".parse().unwrap();
         archive.pre_append_storage_canister(storage_canister_id);
         archive.append_scaling_storage_canister(storage_canister_id);
@@ -302,7 +1210,7 @@ mod tests {
     #[should_panic]
     fn test_append_storage_canister_with_same_id_should_panic() {
         let mut archive = Archive::default();
-        archive.lock_for_archiving();
+        archive.lock_for_archiving(0);
         let storage_canister_id: Principal = "rno2w-sqaaa-aaaaa-aaacq-cai".parse().unwrap();
         archive.pre_append_storage_canister(storage_canister_id);
         archive.append_scaling_storage_canister(storage_canister_id);
@@ -312,14 +1220,13 @@ mod tests {
     #[test]
     fn test_update_storage_canister_block_range() {
         let archive_options = ArchiveOptions {
-            trigger_threshold: 2000,
-            num_blocks_to_archive: 1000,
             node_max_memory_size_bytes: Option::from(MAX_CANISTER_STORAGE_BYTES),
             max_message_size_bytes: Option::from(MAX_MESSAGE_SIZE_BYTES),
             cycles_for_archive_creation: Option::from(CYCLES_PER_AUTO_SCALING),
+            ..test_archive_options()
         };
-        let mut archive = Archive::new(archive_options);
-        archive.lock_for_archiving();
+        let mut archive = Archive::new(archive_options).unwrap();
+        archive.lock_for_archiving(0);
         let storage_canister_id: Principal = "rno2w-sqaaa-aaaaa-aaacq-cai".parse().unwrap();
         archive.pre_append_storage_canister(storage_canister_id);
         archive.append_scaling_storage_canister(storage_canister_id);
@@ -366,7 +1273,7 @@ mod tests {
 
         let new_storage_canister_id: Principal = "r7inp-6aaaa-aaaaa-aaabq-cai".parse().unwrap();
 
-        archive.lock_for_archiving();
+        archive.lock_for_archiving(0);
         archive.pre_append_storage_canister(new_storage_canister_id);
         archive.append_scaling_storage_canister(new_storage_canister_id);
         let last_storage_index = archive.last_storage_canister_index();
@@ -385,8 +1292,8 @@ mod tests {
 
         assert_eq!(indexes.len(), 2);
         assert_eq!(indexes[0].0, (BigUint::from(0u32), BigUint::from(200u32)));
-        assert_eq!(indexes[0].1, storage_canister_id);
-        assert_eq!(indexes[1].1, new_storage_canister_id);
+        assert_eq!(indexes[0].1, vec![storage_canister_id]);
+        assert_eq!(indexes[1].1, vec![new_storage_canister_id]);
 
         let archives = archive.archives();
 
@@ -409,4 +1316,59 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn test_mirror_canisters() {
+        let mut archive = Archive::new(ArchiveOptions {
+            replication_factor: Some(2),
+            ..test_archive_options()
+        })
+        .unwrap();
+        let storage_canister_id: Principal = "rno2w-sqaaa-aaaaa-aaacq-cai".parse().unwrap();
+        let mirror_canister_id: Principal = "r7inp-6aaaa-aaaaa-aaabq-cai".parse().unwrap();
+        archive.lock_for_archiving(0);
+        archive.pre_append_storage_canister(storage_canister_id);
+        archive.append_scaling_storage_canister(storage_canister_id);
+        assert_eq!(archive.missing_mirrors_count(0), 1);
+        archive.pre_append_mirror_canister(mirror_canister_id);
+        assert_eq!(archive.latest_mirror_canister(), Some(mirror_canister_id));
+        archive.append_mirror_canister(0, mirror_canister_id);
+        assert_eq!(archive.latest_mirror_canister(), None);
+        assert_eq!(archive.missing_mirrors_count(0), 0);
+        archive.update_scaling_storage_blocks_range(0, BigUint::from(99u32));
+        archive.unlock_after_archiving();
+
+        let replicas = vec![storage_canister_id, mirror_canister_id];
+        assert_eq!(
+            archive.index(),
+            vec![(
+                (BigUint::from(0u32), BigUint::from(99u32)),
+                replicas.clone()
+            )]
+        );
+        assert_eq!(archive.all_storage_canisters(), replicas);
+        assert_eq!(
+            archive.archives()[0].mirror_canister_ids,
+            vec![mirror_canister_id]
+        );
+
+        // reads go to the first replica reachable at the last check
+        assert_eq!(archive.readable_replica(&replicas), storage_canister_id);
+        archive.record_archive_cycles(storage_canister_id, 1, None, 0, Some("failed".to_string()));
+        assert_eq!(archive.readable_replica(&replicas), mirror_canister_id);
+        archive.record_archive_cycles(mirror_canister_id, 1, None, 0, Some("failed".to_string()));
+        assert_eq!(archive.readable_replica(&replicas), storage_canister_id);
+        archive.record_archive_cycles(mirror_canister_id, 2, Some(1), 0, None);
+        assert_eq!(archive.readable_replica(&replicas), mirror_canister_id);
+
+        // a range which already has blocks does not get new mirrors
+        archive
+            .set_options(ArchiveOptions {
+                replication_factor: Some(3),
+                ..test_archive_options()
+            })
+            .unwrap();
+        assert_eq!(archive.missing_mirrors_count(0), 0);
+        assert_eq!(archive.missing_mirrors_count(1), 2);
+    }
 }
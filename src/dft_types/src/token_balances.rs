@@ -1,28 +1,85 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt;
+use std::ops::Bound;
 
-use candid::Deserialize;
+use ic_stable_structures::{BoundedStorable, Memory, StableBTreeMap, Storable, VectorMemory};
 use num_traits::CheckedSub;
-use serde::Serialize;
 
-use crate::{CommonResult, DFTError, StableState, TokenAmount, TokenHolder};
+use crate::{CommonResult, DFTError, TokenAmount, TokenHolder};
 
-#[derive(Clone, Default, Debug, Deserialize, Serialize)]
-pub struct TokenBalances {
-    balances: HashMap<TokenHolder, TokenAmount>,
+// Token amounts as stored in stable structures, little endian bytes of the amount.
+// Amounts are bounded to 256 bits, far above any supply a token can mint.
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub(crate) struct StableTokenAmount(pub TokenAmount);
+
+impl Storable for StableTokenAmount {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.0.to_bytes_le())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StableTokenAmount(TokenAmount::from_bytes_le(&bytes))
+    }
+}
+
+impl BoundedStorable for StableTokenAmount {
+    const MAX_SIZE: u32 = 32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Larger amounts can not be stored, they are rejected before anything is written.
+pub fn check_storable_amount(value: &TokenAmount) -> CommonResult<()> {
+    if value.bits() > 8 * StableTokenAmount::MAX_SIZE as u64 {
+        return Err(DFTError::AmountTooLarge);
+    }
+    Ok(())
+}
+
+// Balances live in a stable BTreeMap and are written in place,
+// only the total supply has to be saved on upgrade.
+// The amounts held for payments are kept apart from the spendable balances,
+// they still count in the total supply.
+pub struct TokenBalances<M: Memory> {
+    balances: StableBTreeMap<TokenHolder, StableTokenAmount, M>,
+    held: StableBTreeMap<TokenHolder, StableTokenAmount, M>,
     total_supply: TokenAmount,
 }
 
-impl TokenBalances {
+impl TokenBalances<VectorMemory> {
     pub fn new() -> Self {
+        Self::init(VectorMemory::default(), VectorMemory::default())
+    }
+}
+
+impl Default for TokenBalances<VectorMemory> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Memory> fmt::Debug for TokenBalances<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenBalances")
+            .field("holder_count", &self.holder_count())
+            .field("total_supply", &self.total_supply)
+            .finish()
+    }
+}
+
+impl<M: Memory> TokenBalances<M> {
+    // load the balances already stored in the memory, if any
+    pub fn init(memory: M, held_memory: M) -> Self {
         TokenBalances {
-            balances: HashMap::new(),
+            balances: StableBTreeMap::init(memory),
+            held: StableBTreeMap::init(held_memory),
             total_supply: TokenAmount::default(),
         }
     }
 
     // holder count
     pub fn holder_count(&self) -> usize {
-        self.balances.len()
+        self.balances.len() as usize
     }
 
     // total supply
@@ -32,7 +89,7 @@ impl TokenBalances {
 
     pub fn balance_of(&self, holder: &TokenHolder) -> TokenAmount {
         if let Some(balance) = self.balances.get(holder) {
-            balance.clone()
+            balance.0
         } else {
             TokenAmount::default()
         }
@@ -40,14 +97,16 @@ impl TokenBalances {
 
     // debit token holder's balance
     pub fn debit_balance(&mut self, holder: &TokenHolder, value: TokenAmount) -> CommonResult<()> {
-        if self.balance_of(holder) < value {
+        let balance = self.balance_of(holder);
+        if balance < value {
             Err(DFTError::InsufficientBalance)
         } else {
             // calc new balance
-            let new_balance = self.balance_of(holder).checked_sub(&value).unwrap();
+            let new_balance = balance.checked_sub(&value).unwrap();
 
             if new_balance > TokenAmount::from(0u32) {
-                self.balances.insert(*holder, new_balance);
+                self.balances
+                    .insert(*holder, StableTokenAmount(new_balance));
             } else {
                 self.balances.remove(holder);
             }
@@ -60,33 +119,113 @@ impl TokenBalances {
     // credit token holder's balance
     pub fn credit_balance(&mut self, holder: &TokenHolder, value: TokenAmount) {
         let new_balance = self.balance_of(holder) + value.clone();
-        self.balances.insert(*holder, new_balance);
+        self.balances
+            .insert(*holder, StableTokenAmount(new_balance));
+        self.total_supply = self.total_supply.clone() + value;
+    }
+
+    // the amount held for the payments of the holder, not spendable
+    pub fn held_balance_of(&self, holder: &TokenHolder) -> TokenAmount {
+        if let Some(held) = self.held.get(holder) {
+            held.0
+        } else {
+            TokenAmount::default()
+        }
+    }
+
+    // holders with an amount held
+    pub fn held_holder_count(&self) -> usize {
+        self.held.len() as usize
+    }
+
+    // move `value` from the spendable balance of the holder to its held amount
+    pub fn hold_balance(&mut self, holder: &TokenHolder, value: TokenAmount) -> CommonResult<()> {
+        self.debit_balance(holder, value.clone())?;
+        self.credit_held(holder, value);
+        Ok(())
+    }
+
+    // move `value` from the held amount of the holder back to its spendable balance
+    pub fn release_held(&mut self, holder: &TokenHolder, value: TokenAmount) -> CommonResult<()> {
+        self.debit_held(holder, value.clone())?;
+        self.credit_balance(holder, value);
+        Ok(())
+    }
+
+    // debit the held amount of the holder, the value leaves the total supply
+    // until it is credited to someone
+    pub fn debit_held(&mut self, holder: &TokenHolder, value: TokenAmount) -> CommonResult<()> {
+        let held = self.held_balance_of(holder);
+        let new_held = held
+            .checked_sub(&value)
+            .ok_or(DFTError::HeldAmountExceeded)?;
+        if new_held > TokenAmount::from(0u32) {
+            self.held.insert(*holder, StableTokenAmount(new_held));
+        } else {
+            self.held.remove(holder);
+        }
+        self.total_supply = self.total_supply.clone().checked_sub(&value).unwrap();
+        Ok(())
+    }
+
+    fn credit_held(&mut self, holder: &TokenHolder, value: TokenAmount) {
+        let new_held = self.held_balance_of(holder) + value.clone();
+        self.held.insert(*holder, StableTokenAmount(new_held));
         self.total_supply = self.total_supply.clone() + value;
     }
 
+    // at most `limit` balances, in holder order, after the `cursor` holder
+    pub fn balances_after(
+        &self,
+        cursor: Option<&TokenHolder>,
+        limit: usize,
+    ) -> Vec<(TokenHolder, TokenAmount)> {
+        let start = match cursor {
+            Some(holder) => Bound::Excluded(*holder),
+            None => Bound::Unbounded,
+        };
+        self.balances
+            .range((start, Bound::Unbounded))
+            .take(limit)
+            .map(|(holder, balance)| (holder, balance.0))
+            .collect()
+    }
+
+    // drop every balance, the memory is reused from its start
+    pub fn clear(self) -> Self {
+        TokenBalances {
+            balances: self.balances.clear(),
+            held: self.held.clear(),
+            total_supply: TokenAmount::default(),
+        }
+    }
+
     // to vec
     pub fn to_vec(&self) -> Vec<(TokenHolder, TokenAmount)> {
         let mut vec = Vec::new();
         for (holder, balance) in self.balances.iter() {
-            vec.push((*holder, balance.clone()));
+            vec.push((holder, balance.0));
         }
         vec
     }
-}
 
-impl StableState for TokenBalances {
-    fn encode(&self) -> Vec<u8> {
-        bincode::serialize(&(&self.balances, &self.total_supply)).unwrap()
+    // restore the heap metadata after an upgrade
+    pub fn restore(&mut self, total_supply: TokenAmount) {
+        self.total_supply = total_supply;
     }
 
-    fn decode(bytes: Vec<u8>) -> Result<Self, String> {
+    // Move the balances saved in the heap format
+    // (bincode of the balance map and the total supply) into the stable map.
+    pub fn migrate_from_heap_format(&mut self, bytes: Vec<u8>) -> Result<(), String> {
+        assert!(self.balances.is_empty());
         let (balances, total_supply): (HashMap<TokenHolder, TokenAmount>, TokenAmount) =
-            bincode::deserialize(&bytes).unwrap();
-
-        Ok(TokenBalances {
-            balances,
-            total_supply,
-        })
+            bincode::deserialize(&bytes)
+                .map_err(|e| format!("decode heap balances failed, {}", e))?;
+        for (holder, balance) in balances {
+            self.balances.insert(holder, StableTokenAmount(balance));
+        }
+        self.total_supply = total_supply;
+        Ok(())
     }
 }
 
@@ -96,6 +235,19 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_check_storable_amount() {
+        let max = (TokenAmount::from(1u32) << 256u32) - 1u32;
+        assert_eq!(check_storable_amount(&max), Ok(()));
+        assert!(
+            StableTokenAmount(max.clone()).to_bytes().len() <= StableTokenAmount::MAX_SIZE as usize
+        );
+        assert_eq!(
+            check_storable_amount(&(max + 1u32)),
+            Err(DFTError::AmountTooLarge)
+        );
+    }
+
     #[test]
     fn test_token_balances() {
         let mut balances = TokenBalances::new();
@@ -135,6 +287,50 @@ mod tests {
         assert_eq!(balances.holder_count(), 0);
     }
 
+    #[test]
+    fn test_token_balances_held() {
+        let mut balances = TokenBalances::new();
+        let holder = TokenHolder::new(
+            "qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe"
+                .parse()
+                .unwrap(),
+            None,
+        );
+        let merchant = TokenHolder::new(
+            "o5y7v-htz2q-vk7fc-cqi4m-bqvwa-eth75-sc2wz-ubuev-curf2-rbipe-tae"
+                .parse()
+                .unwrap(),
+            None,
+        );
+        balances.credit_balance(&holder, 100u32.into());
+        assert_eq!(
+            balances.hold_balance(&holder, 101u32.into()),
+            Err(DFTError::InsufficientBalance)
+        );
+
+        // held amounts are not spendable but count in the total supply
+        balances.hold_balance(&holder, 100u32.into()).unwrap();
+        assert_eq!(balances.balance_of(&holder), 0u32.into());
+        assert_eq!(balances.held_balance_of(&holder), 100u32.into());
+        assert_eq!(balances.total_supply(), 100u32.into());
+        assert_eq!(balances.holder_count(), 0);
+        assert_eq!(balances.held_holder_count(), 1);
+
+        // a capture moves the held amount to the merchant
+        balances.debit_held(&holder, 30u32.into()).unwrap();
+        balances.credit_balance(&merchant, 30u32.into());
+        assert_eq!(
+            balances.release_held(&holder, 71u32.into()),
+            Err(DFTError::HeldAmountExceeded)
+        );
+        balances.release_held(&holder, 70u32.into()).unwrap();
+        assert_eq!(balances.balance_of(&holder), 70u32.into());
+        assert_eq!(balances.balance_of(&merchant), 30u32.into());
+        assert_eq!(balances.held_balance_of(&holder), 0u32.into());
+        assert_eq!(balances.total_supply(), 100u32.into());
+        assert_eq!(balances.held_holder_count(), 0);
+    }
+
     #[test]
     fn test_token_balances_to_vec() {
         let mut balances = TokenBalances::new();
@@ -158,4 +354,46 @@ mod tests {
         let vec = balances.to_vec();
         assert_eq!(vec.len(), 2);
     }
+
+    #[test]
+    fn test_token_balances_reloaded_from_memory() {
+        let memory = VectorMemory::default();
+        let held_memory = VectorMemory::default();
+        let mut balances = TokenBalances::init(memory.clone(), held_memory.clone());
+        let holder = TokenHolder::new(
+            "qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe"
+                .parse()
+                .unwrap(),
+            None,
+        );
+        let value = TokenAmount::from(u128::MAX) * 1000u32;
+        balances.credit_balance(&holder, value.clone());
+
+        // balances are written in place, only the total supply is restored
+        let mut reloaded = TokenBalances::init(memory, held_memory);
+        reloaded.restore(balances.total_supply());
+        assert_eq!(reloaded.balance_of(&holder), value);
+        assert_eq!(reloaded.total_supply(), value);
+        assert_eq!(reloaded.holder_count(), 1);
+    }
+
+    #[test]
+    fn test_migrate_from_heap_format() {
+        let holder = TokenHolder::new(
+            "qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe"
+                .parse()
+                .unwrap(),
+            None,
+        );
+        let value = TokenAmount::from(100u32);
+        // layout written by the pre_upgrade of the heap-based balances
+        let bytes =
+            bincode::serialize(&(HashMap::from([(holder, value.clone())]), value.clone())).unwrap();
+
+        let mut balances = TokenBalances::new();
+        balances.migrate_from_heap_format(bytes).unwrap();
+        assert_eq!(balances.balance_of(&holder), value);
+        assert_eq!(balances.total_supply(), value);
+        assert_eq!(balances.holder_count(), 1);
+    }
 }
@@ -96,7 +96,8 @@ impl StableState for TokenDescription {
     }
 
     fn decode(bytes: Vec<u8>) -> Result<Self, String> {
-        let desc: HashMap<String, String> = bincode::deserialize(&bytes).unwrap();
+        let desc: HashMap<String, String> = bincode::deserialize(&bytes)
+            .map_err(|e| format!("decode token description failed, {}", e))?;
 
         Ok(TokenDescription { desc })
     }
@@ -0,0 +1,238 @@
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::fmt;
+
+use candid::{CandidType, Deserialize, Nat};
+use ic_stable_structures::{BoundedStorable, Memory, StableBTreeMap, Storable, VectorMemory};
+
+use crate::{TokenAmount, TokenHolder};
+
+// Funds of `owner` reserved for `merchant`, the amount is what is still held.
+// A hold is identified by the height of the block which created it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InnerHold {
+    pub owner: TokenHolder,
+    pub merchant: TokenHolder,
+    pub amount: TokenAmount,
+    pub expires_at: u64,
+}
+
+impl InnerHold {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at <= now
+    }
+}
+
+impl Storable for InnerHold {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(
+            [
+                &self.owner.to_bytes()[..],
+                &self.merchant.to_bytes()[..],
+                &self.expires_at.to_be_bytes()[..],
+                &self.amount.to_bytes_le()[..],
+            ]
+            .concat(),
+        )
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let size = TokenHolder::MAX_SIZE as usize;
+        InnerHold {
+            owner: TokenHolder::from_bytes(Cow::Borrowed(&bytes[..size])),
+            merchant: TokenHolder::from_bytes(Cow::Borrowed(&bytes[size..2 * size])),
+            expires_at: u64::from_be_bytes(bytes[2 * size..2 * size + 8].try_into().unwrap()),
+            amount: TokenAmount::from_bytes_le(&bytes[2 * size + 8..]),
+        }
+    }
+}
+
+// two holders, the expiration and an amount of at most 256 bits
+impl BoundedStorable for InnerHold {
+    const MAX_SIZE: u32 = 2 * TokenHolder::MAX_SIZE + 8 + 32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct Hold {
+    pub owner: TokenHolder,
+    pub merchant: TokenHolder,
+    pub amount: Nat,
+    #[serde(rename = "expiresAt")]
+    pub expires_at: u64,
+}
+
+impl From<InnerHold> for Hold {
+    fn from(hold: InnerHold) -> Self {
+        Hold {
+            owner: hold.owner,
+            merchant: hold.merchant,
+            amount: hold.amount.into(),
+            expires_at: hold.expires_at,
+        }
+    }
+}
+
+// big endian, so the holds are ordered by expiration
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct HoldExpiration {
+    expires_at: u64,
+    hold_id: u64,
+}
+
+impl Storable for HoldExpiration {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned([self.expires_at.to_be_bytes(), self.hold_id.to_be_bytes()].concat())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        HoldExpiration {
+            expires_at: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            hold_id: u64::from_be_bytes(bytes[8..].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for HoldExpiration {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// The open holds, the held amounts themselves are in the balances.
+pub struct TokenHolds<M: Memory> {
+    holds: StableBTreeMap<u64, InnerHold, M>,
+    expirations: StableBTreeMap<HoldExpiration, (), M>,
+}
+
+impl TokenHolds<VectorMemory> {
+    pub fn new() -> Self {
+        Self::init(VectorMemory::default(), VectorMemory::default())
+    }
+}
+
+impl Default for TokenHolds<VectorMemory> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Memory> fmt::Debug for TokenHolds<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenHolds")
+            .field("hold_count", &self.hold_count())
+            .finish()
+    }
+}
+
+impl<M: Memory> TokenHolds<M> {
+    // load the holds already stored in the memories, if any
+    pub fn init(holds_memory: M, expirations_memory: M) -> Self {
+        TokenHolds {
+            holds: StableBTreeMap::init(holds_memory),
+            expirations: StableBTreeMap::init(expirations_memory),
+        }
+    }
+
+    pub fn hold_count(&self) -> usize {
+        self.holds.len() as usize
+    }
+
+    pub fn get(&self, hold_id: u64) -> Option<InnerHold> {
+        self.holds.get(&hold_id)
+    }
+
+    pub fn insert(&mut self, hold_id: u64, hold: InnerHold) {
+        self.expirations.insert(
+            HoldExpiration {
+                expires_at: hold.expires_at,
+                hold_id,
+            },
+            (),
+        );
+        self.holds.insert(hold_id, hold);
+    }
+
+    // what is still held after a capture, the hold is removed once nothing is left
+    pub fn set_amount(&mut self, hold_id: u64, amount: TokenAmount) {
+        if amount == TokenAmount::from(0u32) {
+            self.remove(hold_id);
+        } else if let Some(mut hold) = self.holds.get(&hold_id) {
+            hold.amount = amount;
+            self.holds.insert(hold_id, hold);
+        }
+    }
+
+    pub fn remove(&mut self, hold_id: u64) -> Option<InnerHold> {
+        let hold = self.holds.remove(&hold_id)?;
+        self.expirations.remove(&HoldExpiration {
+            expires_at: hold.expires_at,
+            hold_id,
+        });
+        Some(hold)
+    }
+
+    // at most `limit` holds expired at `now`, the earliest first
+    pub fn expired(&self, now: u64, limit: usize) -> Vec<u64> {
+        self.expirations
+            .iter()
+            .take_while(|(expiration, _)| expiration.expires_at <= now)
+            .take(limit)
+            .map(|(expiration, _)| expiration.hold_id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn holder(text: &str) -> TokenHolder {
+        TokenHolder::new(text.parse().unwrap(), None)
+    }
+
+    #[test]
+    fn test_hold_storable() {
+        let hold = InnerHold {
+            owner: holder("qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe"),
+            merchant: holder("o5y7v-htz2q-vk7fc-cqi4m-bqvwa-eth75-sc2wz-ubuev-curf2-rbipe-tae"),
+            amount: TokenAmount::from(u128::MAX) * TokenAmount::from(u128::MAX),
+            expires_at: 1000,
+        };
+        let bytes = hold.to_bytes();
+        assert!(bytes.len() <= InnerHold::MAX_SIZE as usize);
+        assert_eq!(InnerHold::from_bytes(bytes), hold);
+    }
+
+    #[test]
+    fn test_token_holds() {
+        let mut holds = TokenHolds::new();
+        let owner = holder("qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe");
+        let merchant = holder("o5y7v-htz2q-vk7fc-cqi4m-bqvwa-eth75-sc2wz-ubuev-curf2-rbipe-tae");
+        let hold = |expires_at| InnerHold {
+            owner,
+            merchant,
+            amount: 100u32.into(),
+            expires_at,
+        };
+        holds.insert(1, hold(3000));
+        holds.insert(2, hold(1000));
+        holds.insert(3, hold(2000));
+        assert_eq!(holds.hold_count(), 3);
+        assert!(holds.get(2).unwrap().is_expired(1000));
+        assert!(!holds.get(1).unwrap().is_expired(1000));
+
+        assert_eq!(holds.expired(999, 10), Vec::<u64>::new());
+        assert_eq!(holds.expired(2000, 10), vec![2, 3]);
+        assert_eq!(holds.expired(5000, 2), vec![2, 3]);
+
+        holds.set_amount(3, 40u32.into());
+        assert_eq!(holds.get(3).unwrap().amount, 40u32.into());
+        // nothing left, the hold is closed
+        holds.set_amount(3, 0u32.into());
+        assert_eq!(holds.get(3), None);
+        assert_eq!(holds.remove(2), Some(hold(1000)));
+        assert_eq!(holds.remove(2), None);
+        assert_eq!(holds.expired(5000, 10), vec![1]);
+        assert_eq!(holds.hold_count(), 1);
+    }
+}
@@ -11,5 +11,11 @@ pub struct TokenMetrics {
     pub local_block_count: Nat,
     #[serde(rename = "cyclesBalance")]
     pub cycles_balance: Nat,
+    // archives still below the cycles threshold after the last check
+    #[serde(rename = "archivesLowOnCycles")]
+    pub archives_low_on_cycles: usize,
+    // archives whose last cycles check or top-up failed
+    #[serde(rename = "archivesWithCyclesErrors")]
+    pub archives_with_cycles_errors: usize,
     pub certificate: Option<serde_bytes::ByteBuf>,
 }
@@ -0,0 +1,201 @@
+use std::borrow::Cow;
+use std::fmt;
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{BoundedStorable, Memory, StableBTreeMap, Storable, VectorMemory};
+
+use crate::{AccountIdentifier, TokenHolder};
+
+// stored for the operators authorized without an expiration
+const NEVER_EXPIRES: u64 = u64::MAX;
+// a length byte followed by the principal bytes, 29 at most
+const OPERATOR_SIZE: usize = 1 + 29;
+
+// Operators are keyed by (holder, operator), so all the operators
+// of a holder are adjacent and can be listed with a range scan.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct OperatorKey {
+    holder: TokenHolder,
+    // the principal bytes padded to the maximum principal size
+    operator: [u8; OPERATOR_SIZE],
+}
+
+impl OperatorKey {
+    fn new(holder: &TokenHolder, operator: &Principal) -> Self {
+        let bytes = operator.as_slice();
+        let mut encoded = [0u8; OPERATOR_SIZE];
+        encoded[0] = bytes.len() as u8;
+        encoded[1..=bytes.len()].copy_from_slice(bytes);
+        OperatorKey {
+            holder: *holder,
+            operator: encoded,
+        }
+    }
+
+    fn first_of(holder: &TokenHolder) -> Self {
+        OperatorKey {
+            holder: *holder,
+            operator: [0; OPERATOR_SIZE],
+        }
+    }
+
+    fn last_of(holder: &TokenHolder) -> Self {
+        OperatorKey {
+            holder: *holder,
+            operator: [u8::MAX; OPERATOR_SIZE],
+        }
+    }
+
+    fn operator(&self) -> Principal {
+        let len = self.operator[0] as usize;
+        Principal::from_slice(&self.operator[1..=len])
+    }
+}
+
+impl Storable for OperatorKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned([&self.holder.to_bytes()[..], &self.operator[..]].concat())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let size = TokenHolder::MAX_SIZE as usize;
+        OperatorKey {
+            holder: AccountIdentifier::from_bytes(Cow::Borrowed(&bytes[..size])),
+            operator: bytes[size..].try_into().unwrap(),
+        }
+    }
+}
+
+impl BoundedStorable for OperatorKey {
+    const MAX_SIZE: u32 = TokenHolder::MAX_SIZE + OPERATOR_SIZE as u32;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+#[derive(CandidType, Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct OperatorInfo {
+    pub operator: Principal,
+    #[serde(rename = "expiresAt")]
+    pub expires_at: Option<u64>,
+}
+
+// The operators allowed to move all the funds of a holder, until they expire or are revoked.
+pub struct TokenOperators<M: Memory>(StableBTreeMap<OperatorKey, u64, M>);
+
+impl TokenOperators<VectorMemory> {
+    pub fn new() -> Self {
+        Self::init(VectorMemory::default())
+    }
+}
+
+impl Default for TokenOperators<VectorMemory> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Memory> fmt::Debug for TokenOperators<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenOperators")
+            .field("operator_size", &self.0.len())
+            .finish()
+    }
+}
+
+impl<M: Memory> TokenOperators<M> {
+    // load the operators already stored in the memory, if any
+    pub fn init(memory: M) -> Self {
+        TokenOperators(StableBTreeMap::init(memory))
+    }
+
+    // authorize the operator, or change the expiration of an authorized one
+    pub fn authorize(
+        &mut self,
+        holder: &TokenHolder,
+        operator: &Principal,
+        expires_at: Option<u64>,
+    ) {
+        self.0.insert(
+            OperatorKey::new(holder, operator),
+            expires_at.unwrap_or(NEVER_EXPIRES),
+        );
+    }
+
+    // returns whether the operator was authorized
+    pub fn revoke(&mut self, holder: &TokenHolder, operator: &Principal) -> bool {
+        self.0.remove(&OperatorKey::new(holder, operator)).is_some()
+    }
+
+    pub fn is_operator(&self, holder: &TokenHolder, operator: &Principal, now: u64) -> bool {
+        match self.0.get(&OperatorKey::new(holder, operator)) {
+            Some(expires_at) => now < expires_at,
+            None => false,
+        }
+    }
+
+    // the operators of the holder which have not expired yet
+    pub fn operators_of(&self, holder: &TokenHolder, now: u64) -> Vec<OperatorInfo> {
+        self.0
+            .range(OperatorKey::first_of(holder)..=OperatorKey::last_of(holder))
+            .filter(|(_, expires_at)| now < *expires_at)
+            .map(|(key, expires_at)| OperatorInfo {
+                operator: key.operator(),
+                expires_at: if expires_at == NEVER_EXPIRES {
+                    None
+                } else {
+                    Some(expires_at)
+                },
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_operators() {
+        let mut operators = TokenOperators::new();
+        let holder = TokenHolder::new(
+            "qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe"
+                .parse()
+                .unwrap(),
+            None,
+        );
+        let hot_wallet: Principal =
+            "o5y7v-htz2q-vk7fc-cqi4m-bqvwa-eth75-sc2wz-ubuev-curf2-rbipe-tae"
+                .parse()
+                .unwrap();
+        let canister: Principal = "rrkah-fqaaa-aaaaa-aaaaq-cai".parse().unwrap();
+        let other_holder = TokenHolder::new(hot_wallet, None);
+
+        operators.authorize(&holder, &hot_wallet, None);
+        operators.authorize(&holder, &canister, Some(100));
+        assert!(operators.is_operator(&holder, &hot_wallet, u64::MAX - 1));
+        assert!(operators.is_operator(&holder, &canister, 99));
+        assert!(!operators.is_operator(&holder, &canister, 100));
+        assert!(!operators.is_operator(&other_holder, &hot_wallet, 0));
+
+        let mut listed = operators.operators_of(&holder, 0);
+        listed.sort_by_key(|info| info.operator);
+        let mut expected = vec![
+            OperatorInfo {
+                operator: hot_wallet,
+                expires_at: None,
+            },
+            OperatorInfo {
+                operator: canister,
+                expires_at: Some(100),
+            },
+        ];
+        expected.sort_by_key(|info| info.operator);
+        assert_eq!(listed, expected);
+        // the expired operators are not listed
+        assert_eq!(operators.operators_of(&holder, 100).len(), 1);
+        assert!(operators.operators_of(&other_holder, 0).is_empty());
+
+        assert!(operators.revoke(&holder, &hot_wallet));
+        assert!(!operators.revoke(&holder, &hot_wallet));
+        assert!(!operators.is_operator(&holder, &hot_wallet, 0));
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
-    ActorResult, Block, BlockHash, BlockHeight, CommonResult, ErrorInfo, InnerTransaction,
-    Transaction, TransactionHash, TransactionId,
+    ActorResult, ArchiveUpgradeInfo, Block, BlockHash, BlockHeight, CommonResult, EncodedBlock,
+    ErrorInfo, InnerTransaction, Transaction, TransactionHash, TransactionId,
 };
 use candid::{CandidType, Deserialize, Nat, Principal};
 
@@ -28,6 +28,16 @@ impl From<ActorResult<bool>> for BooleanResult {
     }
 }
 
+// How the items of a batch are applied
+#[derive(CandidType, Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BatchMode {
+    // each item is applied on its own, the invalid ones are skipped
+    #[default]
+    BestEffort,
+    // all the items are validated first, none is applied if one is invalid
+    AllOrNothing,
+}
+
 #[derive(CandidType, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 pub enum OperationResult {
     Ok {
@@ -51,6 +61,20 @@ impl From<CommonResult<(BlockHeight, BlockHash, TransactionHash)>> for Operation
     }
 }
 
+// `hasMore` is set when the owner still has allowances, revoking again removes them
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq)]
+pub enum RevokeAllAllowancesResult {
+    Ok {
+        #[serde(rename = "txId")]
+        tx_id: TransactionId,
+        #[serde(rename = "blockHeight")]
+        block_height: Nat,
+        #[serde(rename = "hasMore")]
+        has_more: bool,
+    },
+    Err(ErrorInfo),
+}
+
 #[derive(CandidType, Debug, Clone, PartialEq, Eq)]
 pub enum BlockResult {
     // Return tx record if exist in the DFT cache txs
@@ -69,6 +93,29 @@ pub enum BlockListResult {
     Err(ErrorInfo),
 }
 
+// The blocks as stored, to check their hashes
+#[derive(CandidType, Debug, Deserialize, Clone)]
+pub enum EncodedBlockListResult {
+    Ok(Vec<EncodedBlock>),
+    Err(ErrorInfo),
+}
+
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+pub enum UpgradeArchivesResult {
+    // The upgrade status of each archive canister, in the order they were upgraded
+    Ok(Vec<ArchiveUpgradeInfo>),
+    Err(ErrorInfo),
+}
+
+impl From<CommonResult<Vec<ArchiveUpgradeInfo>>> for UpgradeArchivesResult {
+    fn from(result: CommonResult<Vec<ArchiveUpgradeInfo>>) -> Self {
+        match result {
+            Ok(value) => UpgradeArchivesResult::Ok(value),
+            Err(error) => UpgradeArchivesResult::Err(error.into()),
+        }
+    }
+}
+
 #[derive(Debug, CandidType, Deserialize, Ord, PartialOrd, Eq, PartialEq)]
 pub struct ArchivedBlocksRange {
     pub start: Nat,
@@ -89,6 +136,33 @@ pub struct QueryBlocksResult {
     pub archived_blocks: Vec<ArchivedBlocksRange>,
 }
 
+#[derive(Debug, CandidType, Deserialize)]
+pub struct BlocksByTimeRange {
+    pub blocks: Vec<Block>,
+    // Block ranges of the archive canisters which may hold blocks of the time range,
+    // query them with the same time range. Always empty on an archive canister.
+    #[serde(rename = "archivedBlocks")]
+    pub archived_blocks: Vec<ArchivedBlocksRange>,
+    // The block height to pass as cursor to get the next blocks, if the limit was reached
+    #[serde(rename = "nextCursor")]
+    pub next_cursor: Option<Nat>,
+}
+
+#[derive(CandidType, Debug)]
+pub enum BlocksByTimeRangeResult {
+    Ok(BlocksByTimeRange),
+    Err(ErrorInfo),
+}
+
+impl From<CommonResult<BlocksByTimeRange>> for BlocksByTimeRangeResult {
+    fn from(result: CommonResult<BlocksByTimeRange>) -> Self {
+        match result {
+            Ok(value) => BlocksByTimeRangeResult::Ok(value),
+            Err(error) => BlocksByTimeRangeResult::Err(error.into()),
+        }
+    }
+}
+
 pub type TransactionList = Vec<InnerTransaction>;
 pub type CandidTransactionList = Vec<Transaction>;
 
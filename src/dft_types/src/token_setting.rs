@@ -31,9 +31,31 @@ impl TokenSetting {
                 minters: Vec::new(),
                 fee,
                 fee_to,
+                genesis_fee_to: fee_to,
             },
         }
     }
+
+    // Convert settings saved by the version 4 state, which did not record the
+    // fee recipient the token was initialized with. The current one is assumed,
+    // which is exact unless the fee recipient was changed before the upgrade.
+    pub fn migrate_from_v4(bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+        let inner: TokenSettingInnerV4 = bincode::deserialize(&bytes)
+            .map_err(|e| format!("decode token setting v4 failed, {}", e))?;
+        Ok(bincode::serialize(&TokenSettingInner {
+            token_id: inner.token_id,
+            logo: inner.logo,
+            name: inner.name,
+            symbol: inner.symbol,
+            decimals: inner.decimals,
+            owner: inner.owner,
+            minters: inner.minters,
+            fee: inner.fee,
+            fee_to: inner.fee_to,
+            genesis_fee_to: inner.fee_to,
+        })
+        .unwrap())
+    }
     // check if the caller is anonymous
     pub fn not_allow_anonymous(&self, caller: &Principal) -> CommonResult<()> {
         if caller == &Principal::anonymous() {
@@ -125,6 +147,10 @@ impl TokenSetting {
     pub fn set_fee_to(&mut self, fee_to: TokenHolder) {
         self.inner.fee_to = fee_to;
     }
+    // the fee recipient before any FeeToModify block, chain verifications start from it
+    pub fn genesis_fee_to(&self) -> TokenHolder {
+        self.inner.genesis_fee_to
+    }
     pub fn metadata(&self) -> InnerTokenMetadata {
         InnerTokenMetadata::new(self.name(), self.symbol(), self.decimals(), self.fee())
     }
@@ -141,6 +167,20 @@ struct TokenSettingInner {
     minters: Vec<Principal>,
     fee: InnerTokenFee,
     fee_to: TokenHolder,
+    genesis_fee_to: TokenHolder,
+}
+
+#[derive(Deserialize)]
+struct TokenSettingInnerV4 {
+    token_id: Principal,
+    logo: Option<Vec<u8>>,
+    name: String,
+    symbol: String,
+    decimals: u8,
+    owner: Principal,
+    minters: Vec<Principal>,
+    fee: InnerTokenFee,
+    fee_to: TokenHolder,
 }
 
 impl Default for TokenSettingInner {
@@ -155,6 +195,7 @@ impl Default for TokenSettingInner {
             minters: Vec::new(),
             fee: InnerTokenFee::default(),
             fee_to: TokenHolder::empty(),
+            genesis_fee_to: TokenHolder::empty(),
         }
     }
 }
@@ -165,7 +206,8 @@ impl StableState for TokenSetting {
     }
 
     fn decode(bytes: Vec<u8>) -> Result<Self, String> {
-        let token_setting_inner: TokenSettingInner = bincode::deserialize(&bytes).unwrap();
+        let token_setting_inner: TokenSettingInner = bincode::deserialize(&bytes)
+            .map_err(|e| format!("decode token setting failed, {}", e))?;
 
         Ok(TokenSetting {
             inner: token_setting_inner,
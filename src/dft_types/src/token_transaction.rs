@@ -1,6 +1,12 @@
-use crate::{InnerTokenFee, TokenAmount, TokenFee, TokenHolder, TokenReceiver, TransactionHash};
+use crate::{
+    ArchiveOptions, InnerTokenFee, TokenAmount, TokenFee, TokenHolder, TokenReceiver,
+    TransactionHash,
+};
 use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_stable_structures::{BoundedStorable, Storable};
 use serde::Serialize;
+use std::borrow::Cow;
+use std::convert::TryInto;
 
 #[derive(Deserialize, Serialize, Clone, Hash, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum InnerOperation {
@@ -41,6 +47,179 @@ pub enum InnerOperation {
         caller: TokenHolder,
         minter: TokenHolder,
     },
+    ArchiveOptionsModify {
+        caller: TokenHolder,
+        #[serde(rename = "newOptions")]
+        new_options: ArchiveOptions,
+    },
+    IncreaseAllowance {
+        caller: TokenHolder,
+        owner: TokenHolder,
+        spender: TokenHolder,
+        value: TokenAmount,
+        fee: TokenAmount,
+    },
+    // the requested decrease, the allowance saturates at zero
+    DecreaseAllowance {
+        caller: TokenHolder,
+        owner: TokenHolder,
+        spender: TokenHolder,
+        value: TokenAmount,
+        fee: TokenAmount,
+    },
+    // the spenders whose allowances were revoked, a call revokes a bounded number of them
+    RevokeAllAllowances {
+        caller: TokenHolder,
+        owner: TokenHolder,
+        spenders: Vec<TokenHolder>,
+        fee: TokenAmount,
+    },
+    AuthorizeOperator {
+        caller: TokenHolder,
+        holder: TokenHolder,
+        operator: Principal,
+        #[serde(rename = "expiresAt")]
+        expires_at: Option<u64>,
+        fee: TokenAmount,
+    },
+    RevokeOperator {
+        caller: TokenHolder,
+        holder: TokenHolder,
+        operator: Principal,
+        fee: TokenAmount,
+    },
+    // a zero amount per period removes the recurring allowance
+    ApproveRecurring {
+        caller: TokenHolder,
+        owner: TokenHolder,
+        spender: TokenHolder,
+        #[serde(rename = "amountPerPeriod")]
+        amount_per_period: TokenAmount,
+        period: u64,
+        start: u64,
+        fee: TokenAmount,
+    },
+    // one transfer to several recipients, the fee is computed on the total value
+    MultiTransfer {
+        caller: TokenHolder,
+        from: TokenHolder,
+        outputs: Vec<(TokenHolder, TokenAmount)>,
+        fee: TokenAmount,
+    },
+    // moves `amount` of the owner to its held amount, the fee is the one of a transfer
+    Hold {
+        caller: TokenHolder,
+        owner: TokenHolder,
+        merchant: TokenHolder,
+        amount: TokenAmount,
+        #[serde(rename = "expiresAt")]
+        expires_at: u64,
+        fee: TokenAmount,
+    },
+    // the hold is the height of the block of its Hold operation
+    CaptureHold {
+        caller: TokenHolder,
+        #[serde(rename = "holdId")]
+        hold_id: u64,
+        owner: TokenHolder,
+        merchant: TokenHolder,
+        amount: TokenAmount,
+    },
+    // returns the amount still held to the owner, on request or once expired
+    ReleaseHold {
+        caller: TokenHolder,
+        #[serde(rename = "holdId")]
+        hold_id: u64,
+        owner: TokenHolder,
+        amount: TokenAmount,
+    },
+}
+
+impl InnerOperation {
+    // the accounts involved in the operation, sorted and without duplicates
+    pub fn accounts(&self) -> Vec<TokenHolder> {
+        let mut accounts = match self {
+            InnerOperation::Approve {
+                caller,
+                owner,
+                spender,
+                ..
+            }
+            | InnerOperation::IncreaseAllowance {
+                caller,
+                owner,
+                spender,
+                ..
+            }
+            | InnerOperation::DecreaseAllowance {
+                caller,
+                owner,
+                spender,
+                ..
+            }
+            | InnerOperation::ApproveRecurring {
+                caller,
+                owner,
+                spender,
+                ..
+            } => vec![*caller, *owner, *spender],
+            InnerOperation::RevokeAllAllowances {
+                caller,
+                owner,
+                spenders,
+                ..
+            } => [*caller, *owner]
+                .into_iter()
+                .chain(spenders.iter().copied())
+                .collect(),
+            InnerOperation::AuthorizeOperator {
+                caller,
+                holder,
+                operator,
+                ..
+            }
+            | InnerOperation::RevokeOperator {
+                caller,
+                holder,
+                operator,
+                ..
+            } => vec![*caller, *holder, (*operator).into()],
+            InnerOperation::Transfer {
+                caller, from, to, ..
+            } => vec![*caller, *from, *to],
+            InnerOperation::MultiTransfer {
+                caller,
+                from,
+                outputs,
+                ..
+            } => [*caller, *from]
+                .into_iter()
+                .chain(outputs.iter().map(|(to, _)| *to))
+                .collect(),
+            InnerOperation::Hold {
+                caller,
+                owner,
+                merchant,
+                ..
+            }
+            | InnerOperation::CaptureHold {
+                caller,
+                owner,
+                merchant,
+                ..
+            } => vec![*caller, *owner, *merchant],
+            InnerOperation::ReleaseHold { caller, owner, .. } => vec![*caller, *owner],
+            InnerOperation::FeeModify { caller, .. } => vec![*caller],
+            InnerOperation::OwnerModify { caller, new_owner } => vec![*caller, *new_owner],
+            InnerOperation::FeeToModify { caller, new_fee_to } => vec![*caller, *new_fee_to],
+            InnerOperation::AddMinter { caller, minter }
+            | InnerOperation::RemoveMinter { caller, minter } => vec![*caller, *minter],
+            InnerOperation::ArchiveOptionsModify { caller, .. } => vec![*caller],
+        };
+        accounts.sort();
+        accounts.dedup();
+        accounts
+    }
 }
 
 #[derive(CandidType, Deserialize, Clone, Hash, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -82,6 +261,85 @@ pub enum Operation {
         caller: TokenHolder,
         minter: TokenHolder,
     },
+    ArchiveOptionsModify {
+        caller: TokenHolder,
+        #[serde(rename = "newOptions")]
+        new_options: ArchiveOptions,
+    },
+    IncreaseAllowance {
+        caller: TokenHolder,
+        owner: TokenHolder,
+        spender: TokenHolder,
+        value: Nat,
+        fee: Nat,
+    },
+    DecreaseAllowance {
+        caller: TokenHolder,
+        owner: TokenHolder,
+        spender: TokenHolder,
+        value: Nat,
+        fee: Nat,
+    },
+    RevokeAllAllowances {
+        caller: TokenHolder,
+        owner: TokenHolder,
+        spenders: Vec<TokenHolder>,
+        fee: Nat,
+    },
+    AuthorizeOperator {
+        caller: TokenHolder,
+        holder: TokenHolder,
+        operator: Principal,
+        #[serde(rename = "expiresAt")]
+        expires_at: Option<u64>,
+        fee: Nat,
+    },
+    RevokeOperator {
+        caller: TokenHolder,
+        holder: TokenHolder,
+        operator: Principal,
+        fee: Nat,
+    },
+    ApproveRecurring {
+        caller: TokenHolder,
+        owner: TokenHolder,
+        spender: TokenHolder,
+        #[serde(rename = "amountPerPeriod")]
+        amount_per_period: Nat,
+        period: u64,
+        start: u64,
+        fee: Nat,
+    },
+    MultiTransfer {
+        caller: TokenHolder,
+        from: TokenHolder,
+        outputs: Vec<(TokenHolder, Nat)>,
+        fee: Nat,
+    },
+    Hold {
+        caller: TokenHolder,
+        owner: TokenHolder,
+        merchant: TokenHolder,
+        amount: Nat,
+        #[serde(rename = "expiresAt")]
+        expires_at: u64,
+        fee: Nat,
+    },
+    CaptureHold {
+        caller: TokenHolder,
+        #[serde(rename = "holdId")]
+        hold_id: u64,
+        owner: TokenHolder,
+        merchant: TokenHolder,
+        amount: Nat,
+    },
+    ReleaseHold {
+        caller: TokenHolder,
+        #[serde(rename = "holdId")]
+        hold_id: u64,
+        owner: TokenHolder,
+        amount: Nat,
+    },
 }
 
 impl From<InnerOperation> for Operation {
@@ -127,6 +385,144 @@ impl From<InnerOperation> for Operation {
             InnerOperation::RemoveMinter { caller, minter } => {
                 Operation::RemoveMinter { caller, minter }
             }
+            InnerOperation::ArchiveOptionsModify {
+                caller,
+                new_options,
+            } => Operation::ArchiveOptionsModify {
+                caller,
+                new_options,
+            },
+            InnerOperation::IncreaseAllowance {
+                caller,
+                owner,
+                spender,
+                value,
+                fee,
+            } => Operation::IncreaseAllowance {
+                caller,
+                owner,
+                spender,
+                value: value.into(),
+                fee: fee.into(),
+            },
+            InnerOperation::DecreaseAllowance {
+                caller,
+                owner,
+                spender,
+                value,
+                fee,
+            } => Operation::DecreaseAllowance {
+                caller,
+                owner,
+                spender,
+                value: value.into(),
+                fee: fee.into(),
+            },
+            InnerOperation::RevokeAllAllowances {
+                caller,
+                owner,
+                spenders,
+                fee,
+            } => Operation::RevokeAllAllowances {
+                caller,
+                owner,
+                spenders,
+                fee: fee.into(),
+            },
+            InnerOperation::AuthorizeOperator {
+                caller,
+                holder,
+                operator,
+                expires_at,
+                fee,
+            } => Operation::AuthorizeOperator {
+                caller,
+                holder,
+                operator,
+                expires_at,
+                fee: fee.into(),
+            },
+            InnerOperation::RevokeOperator {
+                caller,
+                holder,
+                operator,
+                fee,
+            } => Operation::RevokeOperator {
+                caller,
+                holder,
+                operator,
+                fee: fee.into(),
+            },
+            InnerOperation::ApproveRecurring {
+                caller,
+                owner,
+                spender,
+                amount_per_period,
+                period,
+                start,
+                fee,
+            } => Operation::ApproveRecurring {
+                caller,
+                owner,
+                spender,
+                amount_per_period: amount_per_period.into(),
+                period,
+                start,
+                fee: fee.into(),
+            },
+            InnerOperation::MultiTransfer {
+                caller,
+                from,
+                outputs,
+                fee,
+            } => Operation::MultiTransfer {
+                caller,
+                from,
+                outputs: outputs
+                    .into_iter()
+                    .map(|(to, value)| (to, value.into()))
+                    .collect(),
+                fee: fee.into(),
+            },
+            InnerOperation::Hold {
+                caller,
+                owner,
+                merchant,
+                amount,
+                expires_at,
+                fee,
+            } => Operation::Hold {
+                caller,
+                owner,
+                merchant,
+                amount: amount.into(),
+                expires_at,
+                fee: fee.into(),
+            },
+            InnerOperation::CaptureHold {
+                caller,
+                hold_id,
+                owner,
+                merchant,
+                amount,
+            } => Operation::CaptureHold {
+                caller,
+                hold_id,
+                owner,
+                merchant,
+                amount: amount.into(),
+            },
+            InnerOperation::ReleaseHold {
+                caller,
+                hold_id,
+                owner,
+                amount,
+            } => Operation::ReleaseHold {
+                caller,
+                hold_id,
+                owner,
+                amount: amount.into(),
+            },
         }
     }
 }
@@ -137,6 +533,24 @@ pub struct TransactionInfo {
     pub tx_hash: TransactionHash,
 }
 
+impl Storable for TransactionInfo {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned([&self.block_timestamp.to_be_bytes()[..], &self.tx_hash[..]].concat())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        TransactionInfo {
+            block_timestamp: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            tx_hash: bytes[8..].try_into().unwrap(),
+        }
+    }
+}
+
+impl BoundedStorable for TransactionInfo {
+    const MAX_SIZE: u32 = 8 + 32;
+    const IS_FIXED_SIZE: bool = true;
+}
+
 #[derive(Deserialize, Serialize, Clone, Hash, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct InnerTransaction {
     pub operation: InnerOperation,
@@ -199,6 +613,188 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_operation_accounts() {
+        let owner: TokenHolder = "qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe"
+            .parse()
+            .unwrap();
+        let spender: TokenHolder =
+            "o5y7v-htz2q-vk7fc-cqi4m-bqvwa-eth75-sc2wz-ubuev-curf2-rbipe-tae"
+                .parse()
+                .unwrap();
+        let approve = InnerOperation::Approve {
+            caller: owner,
+            owner,
+            spender,
+            value: 1u32.into(),
+            fee: 1u32.into(),
+        };
+        let mut expected = vec![owner, spender];
+        expected.sort();
+        assert_eq!(approve.accounts(), expected);
+
+        let fee_modify = InnerOperation::FeeModify {
+            caller: owner,
+            new_fee: InnerTokenFee {
+                minimum: 1u32.into(),
+                rate: 0,
+                rate_decimals: 8,
+            },
+        };
+        assert_eq!(fee_modify.accounts(), vec![owner]);
+    }
+
+    #[test]
+    fn test_allowance_operations_are_appended() {
+        let owner: TokenHolder = "qupnt-ohzy3-npshw-oba2m-sttkq-tyawc-vufye-u5fbz-zb6yu-conr3-tqe"
+            .parse()
+            .unwrap();
+        let spender: TokenHolder =
+            "o5y7v-htz2q-vk7fc-cqi4m-bqvwa-eth75-sc2wz-ubuev-curf2-rbipe-tae"
+                .parse()
+                .unwrap();
+        let operations = [
+            InnerOperation::IncreaseAllowance {
+                caller: owner,
+                owner,
+                spender,
+                value: 1u32.into(),
+                fee: 1u32.into(),
+            },
+            InnerOperation::DecreaseAllowance {
+                caller: owner,
+                owner,
+                spender,
+                value: 1u32.into(),
+                fee: 1u32.into(),
+            },
+            InnerOperation::RevokeAllAllowances {
+                caller: owner,
+                owner,
+                spenders: vec![spender],
+                fee: 1u32.into(),
+            },
+        ];
+        for (index, operation) in operations.into_iter().enumerate() {
+            let bytes = bincode::serialize(&operation).unwrap();
+            assert_eq!(bytes[..4], (8 + index as u32).to_le_bytes());
+            assert_eq!(
+                bincode::deserialize::<InnerOperation>(&bytes).unwrap(),
+                operation
+            );
+        }
+
+        let operator: Principal = "rrkah-fqaaa-aaaaa-aaaaq-cai".parse().unwrap();
+        let authorize_operator = InnerOperation::AuthorizeOperator {
+            caller: owner,
+            holder: owner,
+            operator,
+            expires_at: Some(1),
+            fee: 1u32.into(),
+        };
+        let bytes = bincode::serialize(&authorize_operator).unwrap();
+        assert_eq!(bytes[..4], 11u32.to_le_bytes());
+        let mut expected = vec![owner, operator.into()];
+        expected.sort();
+        assert_eq!(authorize_operator.accounts(), expected);
+
+        let approve_recurring = InnerOperation::ApproveRecurring {
+            caller: owner,
+            owner,
+            spender,
+            amount_per_period: 100u32.into(),
+            period: 30,
+            start: 1,
+            fee: 1u32.into(),
+        };
+        let bytes = bincode::serialize(&approve_recurring).unwrap();
+        assert_eq!(bytes[..4], 13u32.to_le_bytes());
+        assert_eq!(
+            bincode::deserialize::<InnerOperation>(&bytes).unwrap(),
+            approve_recurring
+        );
+
+        let multi_transfer = InnerOperation::MultiTransfer {
+            caller: owner,
+            from: owner,
+            outputs: vec![(spender, 1u32.into()), (owner, 2u32.into())],
+            fee: 1u32.into(),
+        };
+        let bytes = bincode::serialize(&multi_transfer).unwrap();
+        assert_eq!(bytes[..4], 14u32.to_le_bytes());
+        assert_eq!(
+            bincode::deserialize::<InnerOperation>(&bytes).unwrap(),
+            multi_transfer
+        );
+        let mut expected = vec![owner, spender];
+        expected.sort();
+        assert_eq!(multi_transfer.accounts(), expected);
+        assert_eq!(
+            Operation::from(multi_transfer),
+            Operation::MultiTransfer {
+                caller: owner,
+                from: owner,
+                outputs: vec![(spender, 1u32.into()), (owner, 2u32.into())],
+                fee: 1u32.into(),
+            }
+        );
+
+        let merchant = spender;
+        let holds = vec![
+            InnerOperation::Hold {
+                caller: owner,
+                owner,
+                merchant,
+                amount: 100u32.into(),
+                expires_at: 1,
+                fee: 1u32.into(),
+            },
+            InnerOperation::CaptureHold {
+                caller: merchant,
+                hold_id: 1,
+                owner,
+                merchant,
+                amount: 60u32.into(),
+            },
+            InnerOperation::ReleaseHold {
+                caller: merchant,
+                hold_id: 1,
+                owner,
+                amount: 40u32.into(),
+            },
+        ];
+        for (index, operation) in holds.into_iter().enumerate() {
+            let bytes = bincode::serialize(&operation).unwrap();
+            assert_eq!(bytes[..4], (15 + index as u32).to_le_bytes());
+            assert_eq!(
+                bincode::deserialize::<InnerOperation>(&bytes).unwrap(),
+                operation
+            );
+            let mut expected = vec![owner, merchant];
+            expected.sort();
+            assert_eq!(operation.accounts(), expected);
+        }
+
+        let revoke_all = InnerOperation::RevokeAllAllowances {
+            caller: owner,
+            owner,
+            spenders: vec![spender],
+            fee: 0u32.into(),
+        };
+        let mut expected = vec![owner, spender];
+        expected.sort();
+        assert_eq!(revoke_all.accounts(), expected);
+        assert_eq!(
+            Operation::from(revoke_all),
+            Operation::RevokeAllAllowances {
+                caller: owner,
+                owner,
+                spenders: vec![spender],
+                fee: 0u32.into(),
+            }
+        );
+    }
+
     #[test]
     fn test_transaction_to_candid_transaction() {
         let tx = InnerTransaction {
@@ -1,16 +1,24 @@
 use std::collections::{BTreeMap, VecDeque};
 use std::convert::TryFrom;
+use std::fmt;
 
-use candid::Deserialize;
-use serde::Serialize;
+use ic_stable_structures::{Memory, StableBTreeMap, VectorMemory};
+use num_traits::ToPrimitive;
 
 use crate::{
     constants::{DEFAULT_MAX_TRANSACTIONS_IN_WINDOW, DEFAULT_TRANSACTION_WINDOW},
     *,
 };
 
-#[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
-pub struct TokenTransactionWindow {
+// Layout of the window when it was serialized with the rest of the blockchain.
+pub(crate) type HeapTransactionWindow = (
+    usize,
+    u64,
+    BTreeMap<TransactionHash, BlockHeight>,
+    VecDeque<TransactionInfo>,
+);
+
+pub struct TokenTransactionWindow<M: Memory> {
     /// Maximum number of transactions which ledger will accept
     /// within the transaction_window.
     max_transactions_in_window: usize,
@@ -19,21 +27,51 @@ pub struct TokenTransactionWindow {
     /// For each transaction, record the block in which the
     /// transaction was created. This only contains transactions from
     /// the last `transaction_window` period.
-    transactions_by_hash: BTreeMap<TransactionHash, BlockHeight>,
+    transactions_by_hash: StableBTreeMap<TransactionHash, u64, M>,
     /// The transactions in the transaction window, sorted by block
     /// index / block timestamp. (InnerBlock timestamps are monotonically
     /// non-decreasing, so this is the same.)
-    transactions_by_height: VecDeque<TransactionInfo>,
+    /// Used as a queue, keys are consecutive sequence numbers.
+    transactions_by_height: StableBTreeMap<u64, TransactionInfo, M>,
 }
 
-impl TokenTransactionWindow {
+impl TokenTransactionWindow<VectorMemory> {
     pub fn new() -> Self {
+        Self::init(VectorMemory::default(), VectorMemory::default())
+    }
+}
+
+impl Default for TokenTransactionWindow<VectorMemory> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Memory> fmt::Debug for TokenTransactionWindow<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenTransactionWindow")
+            .field(
+                "max_transactions_in_window",
+                &self.max_transactions_in_window,
+            )
+            .field("transaction_window", &self.transaction_window)
+            .field(
+                "transactions_count_in_window",
+                &self.transactions_count_in_window(),
+            )
+            .finish()
+    }
+}
+
+impl<M: Memory> TokenTransactionWindow<M> {
+    // load the transactions already stored in the memories, if any
+    pub fn init(by_hash_memory: M, by_height_memory: M) -> Self {
         TokenTransactionWindow {
             max_transactions_in_window: usize::try_from(DEFAULT_MAX_TRANSACTIONS_IN_WINDOW)
                 .unwrap(),
             transaction_window: DEFAULT_TRANSACTION_WINDOW,
-            transactions_by_hash: BTreeMap::new(),
-            transactions_by_height: VecDeque::new(),
+            transactions_by_hash: StableBTreeMap::init(by_hash_memory),
+            transactions_by_height: StableBTreeMap::init(by_height_memory),
         }
     }
 
@@ -42,7 +80,7 @@ impl TokenTransactionWindow {
     }
 
     pub fn transactions_count_in_window(&self) -> usize {
-        self.transactions_by_height.len()
+        self.transactions_by_height.len() as usize
     }
 
     pub fn transaction_window(&self) -> u64 {
@@ -53,14 +91,26 @@ impl TokenTransactionWindow {
         self.transactions_by_hash.contains_key(&transaction_hash)
     }
 
-    pub fn front_transaction(&self) -> Option<&TransactionInfo> {
-        self.transactions_by_height.front()
+    pub fn front_transaction(&self) -> Option<TransactionInfo> {
+        self.transactions_by_height
+            .first_key_value()
+            .map(|(_, transaction)| transaction)
+    }
+
+    // the transaction at `index` in the queue, 0 is the oldest one
+    fn transaction_at(&self, index: usize) -> Option<TransactionInfo> {
+        let (front_seq, _) = self.transactions_by_height.first_key_value()?;
+        self.transactions_by_height.get(&(front_seq + index as u64))
     }
 
     pub fn push_transaction(&mut self, block_height: BlockHeight, transaction: TransactionInfo) {
+        let seq = self
+            .transactions_by_height
+            .last_key_value()
+            .map_or(0, |(seq, _)| seq + 1);
         self.transactions_by_hash
-            .insert(transaction.tx_hash, block_height);
-        self.transactions_by_height.push_back(transaction);
+            .insert(transaction.tx_hash, block_height.to_u64().unwrap());
+        self.transactions_by_height.insert(seq, transaction);
     }
 
     /// Removes at most [MAX_TRANSACTIONS_TO_PURGE] transactions older
@@ -68,19 +118,22 @@ impl TokenTransactionWindow {
     /// transactions.
     pub fn purge_old_transactions(&mut self, now: u64) -> usize {
         let mut cnt = 0usize;
-        while let Some(TransactionInfo {
-            block_timestamp,
-            tx_hash,
-        }) = self.transactions_by_height.front()
+        while let Some((
+            seq,
+            TransactionInfo {
+                block_timestamp,
+                tx_hash,
+            },
+        )) = self.transactions_by_height.first_key_value()
         {
-            if *block_timestamp + self.transaction_window + constants::PERMITTED_DRIFT >= now {
+            if block_timestamp + self.transaction_window + constants::PERMITTED_DRIFT >= now {
                 // Stop at a sufficiently recent block.
                 break;
             }
-            let removed = self.transactions_by_hash.remove(tx_hash);
+            let removed = self.transactions_by_hash.remove(&tx_hash);
             assert!(removed.is_some());
 
-            self.transactions_by_height.pop_front();
+            self.transactions_by_height.remove(&seq);
             cnt += 1;
             if cnt >= constants::MAX_TRANSACTIONS_TO_PURGE {
                 break;
@@ -90,7 +143,13 @@ impl TokenTransactionWindow {
     }
 
     pub fn throttle_check(&self, now: u64) -> CommonResult<()> {
-        let num_in_window = self.transactions_by_height.len();
+        self.throttle_check_after(0, now)
+    }
+
+    // As `throttle_check`, once `pending` more transactions were added at `now`.
+    pub fn throttle_check_after(&self, pending: usize, now: u64) -> CommonResult<()> {
+        let count = self.transactions_count_in_window();
+        let num_in_window = count + pending;
         // We admit the first half of max_transactions_in_window freely.
         // After that we start throttling on per-second basis.
         // This way we guarantee that at most max_transactions_in_window will
@@ -101,52 +160,34 @@ impl TokenTransactionWindow {
                 / self.transaction_window as f64)
                 .ceil() as usize;
 
-            if self
-                .transactions_by_height
-                .get(num_in_window.saturating_sub(max_rate))
-                .map(|x| x.block_timestamp)
-                .unwrap_or_else(|| 0)
-                + 10u64.pow(9) // 1 second
-                > now
-            {
+            let index = num_in_window.saturating_sub(max_rate);
+            let block_timestamp = if index < count {
+                self.transaction_at(index)
+                    .map(|x| x.block_timestamp)
+                    .unwrap_or_else(|| 0)
+            } else {
+                now
+            };
+            // 1 second
+            if block_timestamp + 10u64.pow(9) > now {
                 return Err(DFTError::TooManyTransactionsInReplayPreventionWindow);
             }
         }
 
         Ok(())
     }
-}
-
-impl StableState for TokenTransactionWindow {
-    fn encode(&self) -> Vec<u8> {
-        bincode::serialize(&(
-            &self.max_transactions_in_window,
-            &self.transaction_window,
-            &self.transactions_by_hash,
-            &self.transactions_by_height,
-        ))
-        .unwrap()
-    }
 
-    fn decode(bytes: Vec<u8>) -> Result<Self, String> {
-        let (
-            max_transactions_in_window,
-            transaction_window,
-            transactions_by_hash,
-            transactions_by_height,
-        ): (
-            usize,
-            u64,
-            BTreeMap<TransactionHash, BlockHeight>,
-            VecDeque<TransactionInfo>,
-        ) = bincode::deserialize(&bytes).unwrap();
-
-        Ok(TokenTransactionWindow {
-            max_transactions_in_window,
-            transaction_window,
-            transactions_by_hash,
-            transactions_by_height,
-        })
+    // Move the window saved in the heap format into the stable maps.
+    pub(crate) fn migrate_from_heap_format(&mut self, window: HeapTransactionWindow) {
+        assert!(self.transactions_by_height.is_empty());
+        let (_, _, transactions_by_hash, transactions_by_height) = window;
+        for (tx_hash, block_height) in transactions_by_hash {
+            self.transactions_by_hash
+                .insert(tx_hash, block_height.to_u64().unwrap());
+        }
+        for (seq, transaction) in transactions_by_height.into_iter().enumerate() {
+            self.transactions_by_height.insert(seq as u64, transaction);
+        }
     }
 }
 
@@ -174,16 +215,12 @@ mod tests {
         assert_eq!(window.transactions_by_height.len(), 1);
         assert_eq!(window.transactions_by_hash.len(), 1);
         assert_eq!(
-            window
-                .transactions_by_height
-                .front()
-                .unwrap()
-                .block_timestamp,
+            window.front_transaction().unwrap().block_timestamp,
             block_timestamp
         );
         assert_eq!(
             window.transactions_by_hash.get(&tx_info.tx_hash).unwrap(),
-            &block_height
+            block_height.to_u64().unwrap()
         );
     }
 
@@ -239,6 +276,8 @@ mod tests {
     #[test]
     fn test_throttle_check() {
         let mut window = TokenTransactionWindow::new();
+        // keep the number of stable map inserts small, throttling only depends on the ratio
+        window.max_transactions_in_window = 10_000;
         let max_txs_in_window = window.max_transactions_in_window();
 
         let push_txs_count = (max_txs_in_window / 2).checked_sub(1).unwrap();
@@ -267,6 +306,11 @@ mod tests {
 
         let result = window.throttle_check(now);
         assert_eq!(result, Ok(()));
+        // one more transaction at `now` would be throttled
+        assert_eq!(
+            window.throttle_check_after(1, now),
+            Err(DFTError::TooManyTransactionsInReplayPreventionWindow)
+        );
 
         // push new transaction
         let block_height = BigUint::from(push_txs_count + 1);
@@ -288,8 +332,11 @@ mod tests {
     }
 
     #[test]
-    fn test_decode_encode() {
-        let mut window = TokenTransactionWindow::new();
+    fn test_window_reloaded_from_memory() {
+        let by_hash_memory = VectorMemory::default();
+        let by_height_memory = VectorMemory::default();
+        let mut window =
+            TokenTransactionWindow::init(by_hash_memory.clone(), by_height_memory.clone());
         let push_txs_count = 100;
 
         // get now timestamp
@@ -315,8 +362,49 @@ mod tests {
             assert_eq!(window.transactions_count_in_window(), (i + 1) as usize);
         }
 
-        let encoded = window.encode();
-        let decoded = TokenTransactionWindow::decode(encoded);
-        assert_eq!(decoded.unwrap(), window);
+        // the transactions are written to the memories as they are pushed
+        let reloaded = TokenTransactionWindow::init(by_hash_memory, by_height_memory);
+        assert_eq!(
+            reloaded.transactions_count_in_window(),
+            push_txs_count as usize
+        );
+        assert_eq!(reloaded.front_transaction(), window.front_transaction());
+        assert!(reloaded.contains_transaction(compute_hash("test99".as_bytes())));
+    }
+
+    #[test]
+    fn test_migrate_from_heap_format() {
+        let mut transactions_by_hash = BTreeMap::new();
+        let mut transactions_by_height = VecDeque::new();
+        for i in 0..10u32 {
+            let tx_info = TransactionInfo {
+                block_timestamp: 1000 + i as u64,
+                tx_hash: compute_hash(format!("test{}", i).as_bytes()),
+            };
+            transactions_by_hash.insert(tx_info.tx_hash, BigUint::from(i));
+            transactions_by_height.push_back(tx_info);
+        }
+
+        let mut window = TokenTransactionWindow::new();
+        window.migrate_from_heap_format((
+            1000,
+            DEFAULT_TRANSACTION_WINDOW,
+            transactions_by_hash,
+            transactions_by_height,
+        ));
+        assert_eq!(window.transactions_count_in_window(), 10);
+        assert_eq!(window.front_transaction().unwrap().block_timestamp, 1000);
+        assert_eq!(window.transaction_at(9).unwrap().block_timestamp, 1009);
+        assert!(window.contains_transaction(compute_hash("test5".as_bytes())));
+
+        // new transactions are queued after the migrated ones
+        window.push_transaction(
+            BigUint::from(10u32),
+            TransactionInfo {
+                block_timestamp: 1010,
+                tx_hash: compute_hash("test10".as_bytes()),
+            },
+        );
+        assert_eq!(window.transaction_at(10).unwrap().block_timestamp, 1010);
     }
 }
@@ -3,3 +3,4 @@ pub mod image_utils;
 pub mod principal;
 pub mod range_utils;
 pub mod sha256;
+pub mod signature;
@@ -0,0 +1,114 @@
+use candid::Principal;
+// the signature Verifier trait of both ed25519-dalek and k256
+use ed25519_dalek::Verifier as _;
+
+// DER SubjectPublicKeyInfo prefixes, as used by the IC for self-authenticating principals
+const ED25519_DER_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+const SECP256K1_DER_PREFIX: [u8; 23] = [
+    0x30, 0x56, 0x30, 0x10, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x05, 0x2b,
+    0x81, 0x04, 0x00, 0x0a, 0x03, 0x42, 0x00,
+];
+
+// Verify an Ed25519 signature of the message.
+// Returns the self-authenticating principal of the 32 bytes public key.
+pub fn verify_ed25519(
+    public_key: &[u8],
+    message: &[u8],
+    signature: &[u8],
+) -> Result<Principal, String> {
+    let public_key: [u8; 32] = public_key
+        .try_into()
+        .map_err(|_| "ed25519 public key must be 32 bytes".to_string())?;
+    let verifying_key = ed25519_dalek::VerifyingKey::from_bytes(&public_key)
+        .map_err(|e| format!("invalid ed25519 public key, {}", e))?;
+    let signature = ed25519_dalek::Signature::from_slice(signature)
+        .map_err(|e| format!("invalid ed25519 signature, {}", e))?;
+    verifying_key
+        .verify(message, &signature)
+        .map_err(|e| format!("ed25519 signature verification failed, {}", e))?;
+    Ok(Principal::self_authenticating(
+        [&ED25519_DER_PREFIX[..], &public_key[..]].concat(),
+    ))
+}
+
+// Verify a secp256k1 ECDSA signature (r || s, low s) of the SHA-256 of the message.
+// The public key is SEC1 encoded, compressed or not.
+// Returns the self-authenticating principal of the public key.
+pub fn verify_secp256k1(
+    public_key: &[u8],
+    message: &[u8],
+    signature: &[u8],
+) -> Result<Principal, String> {
+    let verifying_key = k256::ecdsa::VerifyingKey::from_sec1_bytes(public_key)
+        .map_err(|e| format!("invalid secp256k1 public key, {}", e))?;
+    let signature = k256::ecdsa::Signature::from_slice(signature)
+        .map_err(|e| format!("invalid secp256k1 signature, {}", e))?;
+    verifying_key
+        .verify(message, &signature)
+        .map_err(|e| format!("secp256k1 signature verification failed, {}", e))?;
+    let uncompressed = verifying_key.to_encoded_point(false);
+    Ok(Principal::self_authenticating(
+        [&SECP256K1_DER_PREFIX[..], uncompressed.as_bytes()].concat(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 8032, 7.1 TEST 2
+    const ED25519_PUBLIC_KEY: &str =
+        "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c";
+    const ED25519_MESSAGE: &str = "72";
+    const ED25519_SIGNATURE: &str = "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00";
+
+    #[test]
+    fn test_verify_ed25519() {
+        let public_key = hex::decode(ED25519_PUBLIC_KEY).unwrap();
+        let message = hex::decode(ED25519_MESSAGE).unwrap();
+        let signature = hex::decode(ED25519_SIGNATURE).unwrap();
+        let principal = verify_ed25519(&public_key, &message, &signature).unwrap();
+        assert_eq!(
+            principal,
+            Principal::self_authenticating(
+                hex::decode(format!("302a300506032b6570032100{}", ED25519_PUBLIC_KEY)).unwrap()
+            )
+        );
+
+        assert!(verify_ed25519(&public_key, b"other message", &signature).is_err());
+        assert!(verify_ed25519(&public_key[1..], &message, &signature).is_err());
+        assert!(verify_ed25519(&public_key, &message, &signature[1..]).is_err());
+    }
+
+    // RFC 6979 signature with the secret key [7; 32]
+    const SECP256K1_PUBLIC_KEY: &str =
+        "02989c0b76cb563971fdc9bef31ec06c3560f3249d6ee9e5d83c57625596e05f6f";
+    const SECP256K1_MESSAGE: &[u8] = b"dft secp256k1 test vector";
+    const SECP256K1_SIGNATURE: &str = "3d864709590302eae3cd0838ccf32d2847e7364d9afabd7efc9ccddfae67125e15f9181458a79c0806d0b5520ed36fcc33b9f649859b3e1e6541c11a399a519c";
+
+    #[test]
+    fn test_verify_secp256k1() {
+        let compressed = hex::decode(SECP256K1_PUBLIC_KEY).unwrap();
+        let uncompressed = k256::ecdsa::VerifyingKey::from_sec1_bytes(&compressed)
+            .unwrap()
+            .to_encoded_point(false);
+        let signature = hex::decode(SECP256K1_SIGNATURE).unwrap();
+
+        let principal = verify_secp256k1(&compressed, SECP256K1_MESSAGE, &signature).unwrap();
+        assert_eq!(
+            verify_secp256k1(uncompressed.as_bytes(), SECP256K1_MESSAGE, &signature),
+            Ok(principal)
+        );
+        assert_eq!(
+            principal,
+            Principal::self_authenticating(
+                [&SECP256K1_DER_PREFIX[..], uncompressed.as_bytes()].concat()
+            )
+        );
+        assert!(verify_secp256k1(&compressed, b"other message", &signature).is_err());
+        assert!(verify_secp256k1(&[2u8; 33], SECP256K1_MESSAGE, &signature).is_err());
+        assert!(verify_secp256k1(&compressed, SECP256K1_MESSAGE, &signature[1..]).is_err());
+    }
+}